@@ -2,10 +2,11 @@
 use std::io::{copy, prelude::*, Cursor};
 
 use anyhow::{Context, Result};
+use backpak_b2 as b2;
 use camino::{Utf8Path, Utf8PathBuf};
 use clap::{ArgAction, Parser, Subcommand};
 use serde_derive::{Deserialize, Serialize};
-use simplelog::*;
+use tracing::Level;
 
 #[derive(Debug, Parser)]
 struct Args {
@@ -49,7 +50,7 @@ enum Command {
 
 fn main() {
     run().unwrap_or_else(|e| {
-        log::error!("{:?}", e);
+        tracing::error!("{:?}", e);
         std::process::exit(1);
     });
 }
@@ -64,8 +65,8 @@ fn run() -> Result<()> {
     match args.subcommand {
         Command::List { prefix } => {
             let files = s.list(prefix.as_deref())?;
-            for f in files {
-                println!("{f}");
+            for (name, len) in files {
+                println!("{name} ({len} bytes)");
             }
             Ok(())
         }
@@ -89,39 +90,23 @@ fn run() -> Result<()> {
 
 // Copied from the backpak main:
 
-/// Set up simplelog to spit messages to stderr.
+/// Set up tracing to spit messages to stderr.
 fn init_logger(args: &Args) {
-    let mut builder = ConfigBuilder::new();
-    builder.set_target_level(LevelFilter::Off);
-    builder.set_thread_level(LevelFilter::Off);
-    if args.timestamps {
-        builder.set_time_format_rfc3339();
-        builder.set_time_level(LevelFilter::Error);
-    } else {
-        builder.set_time_level(LevelFilter::Off);
-    }
-
     let level = match args.verbose {
-        0 => LevelFilter::Warn,
-        1 => LevelFilter::Info,
-        2 => LevelFilter::Debug,
-        _ => LevelFilter::Trace,
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE,
     };
 
-    if level == LevelFilter::Trace {
-        builder.set_location_level(LevelFilter::Error);
+    let builder = tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_max_level(level)
+        .with_target(level == Level::TRACE);
+
+    if args.timestamps {
+        builder.init();
+    } else {
+        builder.without_time().init();
     }
-    builder.set_level_padding(LevelPadding::Left);
-
-    let config = builder.build();
-
-    TermLogger::init(
-        level,
-        config.clone(),
-        TerminalMode::Stderr,
-        ColorChoice::Auto,
-    )
-    .or_else(|_| SimpleLogger::init(level, config))
-    .context("Couldn't init logger")
-    .unwrap()
 }
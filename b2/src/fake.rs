@@ -0,0 +1,728 @@
+//! A fake B2 server, so we can test the client (and anything using it)
+//! without a network connection or a Backblaze account.
+//!
+//! It implements just the handful of API calls [`Session`](crate::Session) makes,
+//! with just enough fidelity to catch us doing something dumb.
+//! Everything is kept in memory and goes away when the server is dropped.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, prelude::*, BufReader};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use base64::prelude::*;
+use data_encoding::HEXLOWER;
+use serde_json as json;
+use sha1::{Digest, Sha1};
+
+/// An HTTP server on localhost that pretends to be B2.
+///
+/// Point [`Session::with_auth_url`](crate::Session::with_auth_url) at [`url()`](Self::url).
+pub struct FakeB2 {
+    url: String,
+    state: Arc<Mutex<State>>,
+    shutdown: Arc<AtomicBool>,
+}
+
+struct StoredFile {
+    id: String,
+    contents: Vec<u8>,
+}
+
+struct LargeUpload {
+    name: String,
+    /// Milliseconds since the Unix epoch
+    started: u64,
+    parts: BTreeMap<u32, (String, Vec<u8>)>,
+}
+
+struct State {
+    url: String,
+    key_id: String,
+    application_key: String,
+    bucket_name: String,
+    bucket_id: String,
+    /// Bumped to expire all tokens handed out so far.
+    token_generation: u64,
+    next_id: u64,
+    /// Every version of every file, oldest first
+    files: BTreeMap<String, Vec<StoredFile>>,
+    large_files: BTreeMap<String, LargeUpload>,
+    recommended_part_size: u64,
+    /// Fail this many of the next requests with a 503.
+    failures: u32,
+    /// Only allow access to files starting with this, like a restricted application key
+    name_prefix: Option<String>,
+}
+
+impl State {
+    fn account_token(&self) -> String {
+        format!("account-token-{}", self.token_generation)
+    }
+
+    fn upload_token(&self) -> String {
+        format!("upload-token-{}", self.token_generation)
+    }
+
+    fn new_id(&mut self) -> String {
+        self.next_id += 1;
+        format!("fake-file-{}", self.next_id)
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    query: HashMap<String, String>,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(|s| s.as_str())
+    }
+
+    fn query(&self, name: &str) -> Option<&str> {
+        self.query.get(name).map(|s| s.as_str())
+    }
+
+    fn json(&self) -> Result<json::Value, Response> {
+        json::from_slice(&self.body).map_err(|e| error(400, "bad_request", &e.to_string()))
+    }
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: Vec<u8>,
+}
+
+fn ok(v: json::Value) -> Response {
+    Response {
+        status: 200,
+        content_type: "application/json",
+        body: v.to_string().into_bytes(),
+    }
+}
+
+fn error(status: u16, code: &str, message: &str) -> Response {
+    Response {
+        status,
+        content_type: "application/json",
+        body: json::json!({ "status": status, "code": code, "message": message })
+            .to_string()
+            .into_bytes(),
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+impl FakeB2 {
+    /// Start a server (on some free port) with a single bucket
+    /// that accepts the given credentials.
+    pub fn start(key_id: &str, application_key: &str, bucket: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0")?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let state = Arc::new(Mutex::new(State {
+            url: url.clone(),
+            key_id: key_id.to_owned(),
+            application_key: application_key.to_owned(),
+            bucket_name: bucket.to_owned(),
+            bucket_id: format!("fake-bucket-{bucket}"),
+            token_generation: 0,
+            next_id: 0,
+            files: BTreeMap::new(),
+            large_files: BTreeMap::new(),
+            recommended_part_size: 100 * 1000 * 1000,
+            failures: 0,
+            name_prefix: None,
+        }));
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let s = state.clone();
+        let sd = shutdown.clone();
+        std::thread::spawn(move || {
+            for conn in listener.incoming() {
+                if sd.load(Ordering::Acquire) {
+                    break;
+                }
+                let Ok(conn) = conn else { continue };
+                let s = s.clone();
+                std::thread::spawn(move || {
+                    // The client will notice if we hang up on it.
+                    let _ = serve(conn, &s);
+                });
+            }
+        });
+
+        Ok(Self {
+            url,
+            state,
+            shutdown,
+        })
+    }
+
+    /// The URL to authorize against
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    /// Respond to the next `n` requests with 503 Service Unavailable
+    pub fn fail_next(&self, n: u32) {
+        self.state.lock().unwrap().failures = n;
+    }
+
+    /// Invalidate every auth token handed out so far,
+    /// like they do after a day.
+    pub fn expire_tokens(&self) {
+        self.state.lock().unwrap().token_generation += 1;
+    }
+
+    /// Act like our key can only access files starting with the given prefix
+    pub fn restrict_to_prefix(&self, prefix: &str) {
+        self.state.lock().unwrap().name_prefix = Some(prefix.to_owned());
+    }
+
+    /// Anything bigger than this should be uploaded as a large file.
+    pub fn set_recommended_part_size(&self, size: u64) {
+        self.state.lock().unwrap().recommended_part_size = size;
+    }
+
+    /// The names of all (finished) files in the bucket
+    pub fn file_names(&self) -> Vec<String> {
+        self.state.lock().unwrap().files.keys().cloned().collect()
+    }
+
+    /// The contents of the latest version of the given file
+    pub fn file(&self, name: &str) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        state
+            .files
+            .get(name)
+            .and_then(|v| v.last())
+            .map(|f| f.contents.clone())
+    }
+
+    /// How many versions of the given file the bucket holds
+    pub fn versions(&self, name: &str) -> usize {
+        let state = self.state.lock().unwrap();
+        state.files.get(name).map_or(0, |v| v.len())
+    }
+
+    /// Flip the bits of a byte in the latest version of the given file,
+    /// like it rotted on the server.
+    pub fn damage(&self, name: &str, offset: usize) {
+        let mut state = self.state.lock().unwrap();
+        let f = state
+            .files
+            .get_mut(name)
+            .and_then(|v| v.last_mut())
+            .unwrap();
+        f.contents[offset] ^= 0xff;
+    }
+
+    /// Pretend somebody started uploading a large file `age` ago and never finished.
+    pub fn add_unfinished_large_file(&self, name: &str, age: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let id = state.new_id();
+        let started = now_millis() - age.as_millis() as u64;
+        state.large_files.insert(
+            id,
+            LargeUpload {
+                name: name.to_owned(),
+                started,
+                parts: BTreeMap::new(),
+            },
+        );
+    }
+
+    /// The names of large files that were started but not finished (or canceled)
+    pub fn unfinished_large_files(&self) -> Vec<String> {
+        let state = self.state.lock().unwrap();
+        state.large_files.values().map(|l| l.name.clone()).collect()
+    }
+}
+
+impl Drop for FakeB2 {
+    fn drop(&mut self) {
+        self.shutdown.store(true, Ordering::Release);
+        // Poke the listener so it notices.
+        let _ = TcpStream::connect(self.url.trim_start_matches("http://"));
+    }
+}
+
+fn serve(conn: TcpStream, state: &Mutex<State>) -> io::Result<()> {
+    let mut reader = BufReader::new(conn.try_clone()?);
+    let request = read_request(&mut reader)?;
+    let response = respond(&request, &mut state.lock().unwrap());
+    write_response(conn, response)
+}
+
+fn read_request<R: BufRead>(r: &mut R) -> io::Result<Request> {
+    let bad = |what: &str| io::Error::new(io::ErrorKind::InvalidData, what.to_owned());
+
+    let mut line = String::new();
+    r.read_line(&mut line)?;
+    let mut request_line = line.split_whitespace();
+    let method = request_line.next().ok_or_else(|| bad("no method"))?;
+    let target = request_line.next().ok_or_else(|| bad("no target"))?;
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let query = query
+        .split('&')
+        .filter(|kv| !kv.is_empty())
+        .map(|kv| {
+            let (k, v) = kv.split_once('=').unwrap_or((kv, ""));
+            (percent_decode(k), percent_decode(v))
+        })
+        .collect();
+    let method = method.to_owned();
+    let path = percent_decode(path);
+
+    let mut headers = HashMap::new();
+    loop {
+        let mut line = String::new();
+        r.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        let (k, v) = line
+            .split_once(':')
+            .ok_or_else(|| bad("malformed header"))?;
+        headers.insert(k.trim().to_ascii_lowercase(), v.trim().to_owned());
+    }
+
+    let mut body = vec![];
+    if let Some(len) = headers.get("content-length") {
+        let len: usize = len.parse().map_err(|_| bad("bad Content-Length"))?;
+        body.resize(len, 0);
+        r.read_exact(&mut body)?;
+    } else if headers
+        .get("transfer-encoding")
+        .is_some_and(|te| te.eq_ignore_ascii_case("chunked"))
+    {
+        loop {
+            let mut size = String::new();
+            r.read_line(&mut size)?;
+            let size = size.trim().split(';').next().unwrap_or_default();
+            let size = usize::from_str_radix(size, 16).map_err(|_| bad("bad chunk size"))?;
+            let start = body.len();
+            body.resize(start + size, 0);
+            r.read_exact(&mut body[start..])?;
+            let mut crlf = String::new();
+            r.read_line(&mut crlf)?;
+            if size == 0 {
+                break;
+            }
+        }
+    }
+
+    Ok(Request {
+        method,
+        path,
+        query,
+        headers,
+        body,
+    })
+}
+
+fn percent_decode(s: &str) -> String {
+    let mut out = vec![];
+    let mut bytes = s.bytes();
+    while let Some(b) = bytes.next() {
+        match b {
+            b'%' => {
+                let hex = [bytes.next().unwrap_or(b'0'), bytes.next().unwrap_or(b'0')];
+                let hex = std::str::from_utf8(&hex).unwrap_or("00");
+                out.push(u8::from_str_radix(hex, 16).unwrap_or(b'?'));
+            }
+            b'+' => out.push(b' '),
+            b => out.push(b),
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+fn write_response(mut conn: TcpStream, response: Response) -> io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        503 => "Service Unavailable",
+        _ => "Something",
+    };
+    write!(
+        conn,
+        "HTTP/1.1 {} {reason}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status,
+        response.content_type,
+        response.body.len()
+    )?;
+    conn.write_all(&response.body)?;
+    conn.flush()
+}
+
+fn respond(req: &Request, state: &mut State) -> Response {
+    if state.failures > 0 {
+        state.failures -= 1;
+        return error(503, "service_unavailable", "the fake is feeling flaky");
+    }
+
+    let route = (req.method.as_str(), req.path.as_str());
+    if route == ("GET", "/b2api/v3/b2_authorize_account") {
+        return authorize(req, state);
+    }
+
+    // Uploads use their own tokens.
+    if let Some(id) = req.path.strip_prefix("/b2_upload_part/") {
+        if req.header("authorization") != Some(&state.upload_token()) {
+            return error(401, "expired_auth_token", "upload token expired");
+        }
+        return upload_part(req, state, id).unwrap_or_else(|e| e);
+    }
+    if route == ("POST", "/b2_upload_file") {
+        if req.header("authorization") != Some(&state.upload_token()) {
+            return error(401, "expired_auth_token", "upload token expired");
+        }
+        return upload(req, state).unwrap_or_else(|e| e);
+    }
+
+    if req.header("authorization") != Some(&state.account_token()) {
+        return error(401, "expired_auth_token", "account token expired");
+    }
+    if let Some(name) = req
+        .path
+        .strip_prefix("/file/")
+        .and_then(|p| p.strip_prefix(state.bucket_name.as_str()))
+        .and_then(|p| p.strip_prefix('/'))
+    {
+        if let Err(e) = check_name(name, state) {
+            return e;
+        }
+        return match state.files.get(name).and_then(|v| v.last()) {
+            Some(f) => Response {
+                status: 200,
+                content_type: "application/octet-stream",
+                body: f.contents.clone(),
+            },
+            None => error(404, "not_found", &format!("no such file {name}")),
+        };
+    }
+
+    let r = match route {
+        ("GET", "/b2api/v2/b2_list_buckets") => list_buckets(req, state),
+        ("GET", "/b2api/v2/b2_get_upload_url") => get_upload_url(req, state),
+        ("GET", "/b2api/v2/b2_list_file_names") => list_file_names(req, state),
+        ("GET", "/b2api/v2/b2_list_file_versions") => list_file_versions(req, state),
+        ("POST", "/b2api/v2/b2_delete_file_version") => delete_file_version(req, state),
+        ("POST", "/b2api/v2/b2_start_large_file") => start_large_file(req, state),
+        ("GET", "/b2api/v2/b2_get_upload_part_url") => get_upload_part_url(req, state),
+        ("POST", "/b2api/v2/b2_finish_large_file") => finish_large_file(req, state),
+        ("POST", "/b2api/v2/b2_cancel_large_file") => cancel_large_file(req, state),
+        ("GET", "/b2api/v2/b2_list_unfinished_large_files") => {
+            list_unfinished_large_files(req, state)
+        }
+        _ => Err(error(404, "not_found", &format!("{} {}", route.0, route.1))),
+    };
+    r.unwrap_or_else(|e| e)
+}
+
+/// Can our key touch files starting with the given name (or prefix)?
+fn check_name(name: &str, state: &State) -> Result<(), Response> {
+    match &state.name_prefix {
+        Some(p) if !name.starts_with(p.as_str()) => Err(error(
+            401,
+            "unauthorized",
+            &format!("key is restricted to names starting with {p}"),
+        )),
+        _ => Ok(()),
+    }
+}
+
+fn check_bucket(id: Option<&str>, state: &State) -> Result<(), Response> {
+    if id != Some(state.bucket_id.as_str()) {
+        return Err(error(400, "bad_bucket_id", "no such bucket"));
+    }
+    Ok(())
+}
+
+fn authorize(req: &Request, state: &State) -> Response {
+    let expected = String::from("Basic ")
+        + &BASE64_STANDARD.encode(format!("{}:{}", state.key_id, state.application_key));
+    if req.header("authorization") != Some(&expected) {
+        return error(401, "unauthorized", "bad key ID or application key");
+    }
+    ok(json::json!({
+        "accountId": "fake-account",
+        "authorizationToken": state.account_token(),
+        "apiInfo": {
+            "storageApi": {
+                "apiUrl": state.url,
+                "downloadUrl": state.url,
+                "recommendedPartSize": state.recommended_part_size,
+                "absoluteMinimumPartSize": 1,
+                "namePrefix": state.name_prefix,
+                "capabilities": ["listBuckets", "listFiles", "readFiles", "writeFiles", "deleteFiles"],
+            }
+        }
+    }))
+}
+
+fn list_buckets(req: &Request, state: &State) -> Result<Response, Response> {
+    let buckets = if req.query("bucketName") == Some(state.bucket_name.as_str()) {
+        vec![json::json!({ "bucketName": state.bucket_name, "bucketId": state.bucket_id })]
+    } else {
+        vec![]
+    };
+    Ok(ok(json::json!({ "buckets": buckets })))
+}
+
+fn get_upload_url(req: &Request, state: &State) -> Result<Response, Response> {
+    check_bucket(req.query("bucketId"), state)?;
+    Ok(ok(json::json!({
+        "bucketId": state.bucket_id,
+        "uploadUrl": state.url.clone() + "/b2_upload_file",
+        "authorizationToken": state.upload_token(),
+    })))
+}
+
+fn upload(req: &Request, state: &mut State) -> Result<Response, Response> {
+    let name = req
+        .header("x-bz-file-name")
+        .map(percent_decode)
+        .ok_or_else(|| error(400, "bad_request", "no file name"))?;
+    check_name(&name, state)?;
+    let (contents, _sha) = checked_contents(req)?;
+    let id = state.new_id();
+    // Like the real thing, uploading the same name again adds a new version.
+    state
+        .files
+        .entry(name.clone())
+        .or_default()
+        .push(StoredFile {
+            id: id.clone(),
+            contents,
+        });
+    Ok(ok(json::json!({ "fileId": id, "fileName": name })))
+}
+
+fn list_file_names(req: &Request, state: &State) -> Result<Response, Response> {
+    check_bucket(req.query("bucketId"), state)?;
+    let max: usize = req
+        .query("maxFileCount")
+        .and_then(|m| m.parse().ok())
+        .unwrap_or(100);
+    let prefix = req.query("prefix").unwrap_or_default();
+    let start = req.query("startFileName").unwrap_or_default();
+    check_name(prefix, state)?;
+
+    let mut matching = state
+        .files
+        .range(start.to_owned()..)
+        .filter(|(n, _)| n.starts_with(prefix));
+    let files: Vec<json::Value> = matching
+        .by_ref()
+        .take(max)
+        .map(|(n, v)| {
+            let f = v.last().unwrap();
+            json::json!({
+                "fileName": n,
+                "fileId": f.id,
+                "contentLength": f.contents.len(),
+                "action": "upload",
+            })
+        })
+        .collect();
+    let next = matching.next().map(|(n, _)| n.clone());
+    Ok(ok(json::json!({ "files": files, "nextFileName": next })))
+}
+
+fn list_file_versions(req: &Request, state: &State) -> Result<Response, Response> {
+    check_bucket(req.query("bucketId"), state)?;
+    let prefix = req.query("prefix").unwrap_or_default();
+    let start = req.query("startFileName").unwrap_or_default();
+    check_name(prefix, state)?;
+    // Newest versions first, like B2 does
+    let files: Vec<json::Value> = state
+        .files
+        .range(start.to_owned()..)
+        .filter(|(n, _)| n.starts_with(prefix))
+        .flat_map(|(n, v)| {
+            v.iter().rev().map(move |f| {
+                json::json!({
+                    "fileName": n,
+                    "fileId": f.id,
+                    "contentLength": f.contents.len(),
+                    "action": "upload",
+                })
+            })
+        })
+        .collect();
+    Ok(ok(json::json!({ "files": files, "nextFileName": null })))
+}
+
+fn delete_file_version(req: &Request, state: &mut State) -> Result<Response, Response> {
+    let j = req.json()?;
+    let (Some(name), Some(id)) = (j["fileName"].as_str(), j["fileId"].as_str()) else {
+        return Err(error(400, "bad_request", "need fileName and fileId"));
+    };
+    check_name(name, state)?;
+    let versions = state.files.get_mut(name);
+    match versions
+        .as_ref()
+        .and_then(|v| v.iter().position(|f| f.id == id))
+    {
+        Some(i) => {
+            let versions = versions.unwrap();
+            versions.remove(i);
+            if versions.is_empty() {
+                state.files.remove(name);
+            }
+            Ok(ok(json::json!({ "fileName": name, "fileId": id })))
+        }
+        None => Err(error(
+            400,
+            "file_not_present",
+            &format!("no {name} with ID {id}"),
+        )),
+    }
+}
+
+fn start_large_file(req: &Request, state: &mut State) -> Result<Response, Response> {
+    let j = req.json()?;
+    check_bucket(j["bucketId"].as_str(), state)?;
+    let Some(name) = j["fileName"].as_str() else {
+        return Err(error(400, "bad_request", "need fileName"));
+    };
+    check_name(name, state)?;
+    let id = state.new_id();
+    state.large_files.insert(
+        id.clone(),
+        LargeUpload {
+            name: name.to_owned(),
+            started: now_millis(),
+            parts: BTreeMap::new(),
+        },
+    );
+    Ok(ok(json::json!({ "fileId": id, "fileName": name })))
+}
+
+/// Split the uploaded contents from their SHA1 (which might be in a header, or at the end)
+/// and make sure they match.
+fn checked_contents(req: &Request) -> Result<(Vec<u8>, String), Response> {
+    let (contents, sha) = match req.header("x-bz-content-sha1") {
+        Some("hex_digits_at_end") if req.body.len() >= 40 => {
+            let (c, s) = req.body.split_at(req.body.len() - 40);
+            (c.to_vec(), String::from_utf8_lossy(s).into_owned())
+        }
+        Some(s) => (req.body.clone(), s.to_owned()),
+        None => return Err(error(400, "bad_request", "no SHA1")),
+    };
+    if HEXLOWER.encode(&Sha1::digest(&contents)) != sha {
+        return Err(error(400, "bad_request", "SHA1 mismatch"));
+    }
+    Ok((contents, sha))
+}
+
+fn get_upload_part_url(req: &Request, state: &State) -> Result<Response, Response> {
+    let id = req.query("fileId").unwrap_or_default();
+    if !state.large_files.contains_key(id) {
+        return Err(error(400, "bad_request", &format!("no large file {id}")));
+    }
+    Ok(ok(json::json!({
+        "fileId": id,
+        "uploadUrl": format!("{}/b2_upload_part/{id}", state.url),
+        "authorizationToken": state.upload_token(),
+    })))
+}
+
+fn upload_part(req: &Request, state: &mut State, id: &str) -> Result<Response, Response> {
+    let part_number: u32 = req
+        .header("x-bz-part-number")
+        .and_then(|p| p.parse().ok())
+        .filter(|p| (1..=10000).contains(p))
+        .ok_or_else(|| error(400, "bad_request", "bad part number"))?;
+    let (contents, sha) = checked_contents(req)?;
+    let large = state
+        .large_files
+        .get_mut(id)
+        .ok_or_else(|| error(400, "bad_request", &format!("no large file {id}")))?;
+    large.parts.insert(part_number, (sha.clone(), contents));
+    Ok(ok(
+        json::json!({ "fileId": id, "partNumber": part_number, "contentSha1": sha }),
+    ))
+}
+
+fn finish_large_file(req: &Request, state: &mut State) -> Result<Response, Response> {
+    let j = req.json()?;
+    let id = j["fileId"].as_str().unwrap_or_default();
+    let shas: Vec<&str> = j["partSha1Array"]
+        .as_array()
+        .map(|a| a.iter().filter_map(|s| s.as_str()).collect())
+        .unwrap_or_default();
+    let large = state
+        .large_files
+        .get(id)
+        .ok_or_else(|| error(400, "bad_request", &format!("no large file {id}")))?;
+    if large.parts.len() < 2 {
+        return Err(error(
+            400,
+            "bad_request",
+            "large files need at least two parts",
+        ));
+    }
+    let expected: Vec<&str> = large.parts.values().map(|(s, _)| s.as_str()).collect();
+    if shas != expected || large.parts.keys().copied().ne(1..=large.parts.len() as u32) {
+        return Err(error(400, "bad_request", "parts don't match"));
+    }
+
+    let large = state.large_files.remove(id).unwrap();
+    let contents = large.parts.into_values().flat_map(|(_, p)| p).collect();
+    state
+        .files
+        .entry(large.name.clone())
+        .or_default()
+        .push(StoredFile {
+            id: id.to_owned(),
+            contents,
+        });
+    Ok(ok(json::json!({ "fileId": id, "fileName": large.name })))
+}
+
+fn cancel_large_file(req: &Request, state: &mut State) -> Result<Response, Response> {
+    let j = req.json()?;
+    let id = j["fileId"].as_str().unwrap_or_default();
+    match state.large_files.remove(id) {
+        Some(l) => Ok(ok(json::json!({ "fileId": id, "fileName": l.name }))),
+        None => Err(error(400, "bad_request", &format!("no large file {id}"))),
+    }
+}
+
+fn list_unfinished_large_files(req: &Request, state: &State) -> Result<Response, Response> {
+    check_bucket(req.query("bucketId"), state)?;
+    let prefix = req.query("namePrefix").unwrap_or_default();
+    check_name(prefix, state)?;
+    let files: Vec<json::Value> = state
+        .large_files
+        .iter()
+        .filter(|(_, l)| l.name.starts_with(prefix))
+        .map(|(id, l)| {
+            json::json!({
+                "fileId": id,
+                "fileName": l.name,
+                "uploadTimestamp": l.started,
+                "action": "start",
+            })
+        })
+        .collect();
+    Ok(ok(json::json!({ "files": files, "nextFileId": null })))
+}
@@ -1,8 +1,17 @@
 use base64::prelude::*;
+use data_encoding::HEXLOWER;
 use serde_json as json;
+use sha1::{Digest, Sha1};
 use thiserror::Error;
 
 use std::io::{prelude::*, Cursor};
+use std::sync::{Mutex, RwLock};
+
+#[cfg(feature = "fake")]
+pub mod fake;
+
+/// Where we log in, unless told otherwise
+pub const AUTH_URL: &str = "https://api.backblazeb2.com";
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -14,6 +23,14 @@ pub enum Error {
     UnexpectedResponse { why: String, response: String },
     #[error("B2: Couldn't find {what}")]
     NotFound { what: String },
+    #[error("B2 returned {status} ({code}): {message}")]
+    Api {
+        status: u16,
+        code: String,
+        message: String,
+    },
+    #[error("B2 login failed: {message}")]
+    BadCredentials { message: String },
 }
 
 impl From<ureq::Error> for Error {
@@ -31,80 +48,311 @@ fn unexpected(w: &str, r: &json::Value) -> Error {
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-#[derive(Debug)]
-pub struct Session {
+impl Error {
+    /// Should we try again?
+    ///
+    /// B2 asks that we retry on 408, 429, and 5xx responses (as well as network hiccups),
+    /// and 401s when our auth token expires. (Sessions take care of getting a fresh token.)
+    /// Other 401s (bad credentials, a key that can't touch that file) won't get better.
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::Api { status, .. } => {
+                self.is_expired_token() || matches!(status, 408 | 429 | 500..=599)
+            }
+            Self::Http(e) => match &**e {
+                ureq::Error::StatusCode(c) => matches!(c, 408 | 429 | 500..=599),
+                ureq::Error::Io(_)
+                | ureq::Error::Timeout(_)
+                | ureq::Error::HostNotFound
+                | ureq::Error::ConnectionFailed
+                | ureq::Error::Protocol(_) => true,
+                _ => false,
+            },
+            _ => false,
+        }
+    }
+
+    fn is_expired_token(&self) -> bool {
+        matches!(
+            self,
+            Self::Api { status: 401, code, .. }
+                if code == "expired_auth_token" || code == "bad_auth_token"
+        )
+    }
+}
+
+type Response = ureq::http::Response<ureq::Body>;
+
+/// B2 explains what went wrong in a JSON body,
+/// but ureq throws that away if it turns error statuses into errors.
+/// Our agents don't, so check them here.
+trait Checked {
+    fn checked(self) -> Result<Response>;
+}
+
+impl Checked for std::result::Result<Response, ureq::Error> {
+    fn checked(self) -> Result<Response> {
+        let mut r = self?;
+        let status = r.status().as_u16();
+        if r.status().is_success() {
+            return Ok(r);
+        }
+        let body: json::Value = r.body_mut().read_json().unwrap_or_default();
+        let code = body["code"].as_str().unwrap_or_default().to_owned();
+        let message = body["message"]
+            .as_str()
+            .map(|m| m.to_owned())
+            .unwrap_or_else(|| r.status().to_string());
+        Err(Error::Api {
+            status,
+            code,
+            message,
+        })
+    }
+}
+
+/// What we get from `b2_authorize_account`
+#[derive(Clone)]
+struct Account {
+    id: String,
     token: String,
     url: String,
-    upload_url: String,
-    upload_token: String,
+    recommended_part_size: u64,
+    /// If the key can only access files starting with some prefix, what is it?
+    name_prefix: Option<String>,
+}
+
+/// What we get from `b2_get_upload_url` or `b2_get_upload_part_url`
+struct UploadUrl {
+    url: String,
+    token: String,
+}
+
+pub struct Session {
+    auth_url: String,
+    key_id: String,
+    application_key: String,
+    account: RwLock<Account>,
+    /// B2 wants one upload URL per thread uploading.
+    /// Each upload takes one from this pool (or gets a new one if it's empty),
+    /// and puts it back when it succeeds.
+    upload_urls: Mutex<Vec<UploadUrl>>,
     bucket_name: String,
     bucket_id: String,
 }
 
+/// A file we're uploading in parts with B2's large file API.
+///
+/// Start one with [`Session::start_large_file`], [`upload_part`](Session::upload_part)
+/// each piece in order, then [`finish_large_file`](Session::finish_large_file)
+/// (or [`cancel_large_file`](Session::cancel_large_file)).
+pub struct LargeFile {
+    id: String,
+    /// Unlike normal uploads, part URLs are for a specific file.
+    /// We upload parts one at a time, so we just need one (until it breaks).
+    upload_url: Mutex<Option<UploadUrl>>,
+}
+
+impl LargeFile {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+}
+
+/// A large file someone started uploading but never finished or canceled.
+#[derive(Debug, Clone)]
+pub struct UnfinishedFile {
+    pub id: String,
+    pub name: String,
+    /// Milliseconds since the Unix epoch
+    pub upload_timestamp: u64,
+}
+
+// Don't spill our secrets into logs.
+impl std::fmt::Debug for Session {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Session")
+            .field("auth_url", &self.auth_url)
+            .field("key_id", &self.key_id)
+            .field("bucket_name", &self.bucket_name)
+            .field("bucket_id", &self.bucket_id)
+            .finish_non_exhaustive()
+    }
+}
+
+fn agent() -> ureq::Agent {
+    ureq::Agent::new_with_config(
+        ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .build(),
+    )
+}
+
+// B2 wants the SHA1 hash (as hex), but we can provide it at the end.
+// Very nice.
+enum HashAppendingReader<R> {
+    Contents { inner: R, hasher: Option<Sha1> },
+    HashSuffix(Cursor<Vec<u8>>),
+}
+
+impl<R> HashAppendingReader<R> {
+    fn new(inner: R) -> Self {
+        Self::Contents {
+            inner,
+            hasher: Some(Sha1::new()),
+        }
+    }
+
+    /// The SHA1 (as hex) of everything we read, once we've read it all
+    fn sha(&self) -> Option<String> {
+        match self {
+            Self::Contents { .. } => None,
+            Self::HashSuffix(c) => Some(String::from_utf8(c.get_ref().clone()).unwrap()),
+        }
+    }
+}
+
+impl<R: Read> Read for HashAppendingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Contents { inner, hasher } => {
+                // Read some bytes from the inner Read trait object.
+                let bytes_read = inner.read(buf)?;
+                if bytes_read > 0 {
+                    // If we got some bytes, update the SHA1 hash with those and return.
+                    hasher.as_mut().unwrap().update(&buf[..bytes_read]);
+                    Ok(bytes_read)
+                } else {
+                    // Otherwise we're done reading.
+                    // Consume the hasher, get the hash,
+                    // and start feeding that to whoever's reading.
+                    let sha = hasher.take().unwrap().finalize();
+                    let sha_hex = HEXLOWER.encode(&sha).to_string().into_bytes();
+                    *self = Self::HashSuffix(Cursor::new(sha_hex));
+                    // Recurse (to the HashSuffix match arm).
+                    self.read(buf)
+                }
+            }
+            Self::HashSuffix(c) => c.read(buf),
+        }
+    }
+}
+
 // Once we authenticate in Session::new,
 // we shouldn't have any redirects as the API gives us URLs to use.
 fn noredir() -> ureq::Agent {
-    ureq::Agent::new_with_config(ureq::Agent::config_builder().max_redirects(0).build())
+    ureq::Agent::new_with_config(
+        ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .max_redirects(0)
+            .build(),
+    )
 }
 
-impl Session {
-    pub fn new<S: Into<String>>(key_id: &str, application_key: &str, bucket: S) -> Result<Self> {
-        let bucket = bucket.into();
+fn authorize(auth_url: &str, key_id: &str, application_key: &str) -> Result<Account> {
+    let creds = String::from(key_id) + ":" + application_key;
+    // The scheme and credentials are separated by a space (RFC 7617);
+    // the fake server checks for it, so a test would catch us dropping it again.
+    let auth = String::from("Basic ") + &BASE64_STANDARD.encode(creds);
+    let v: json::Value = agent()
+        .get(&(auth_url.to_owned() + "/b2api/v3/b2_authorize_account"))
+        .header("Authorization", &auth)
+        .call()
+        .checked()
+        // Retrying won't fix a typo in the key.
+        .map_err(|e| match e {
+            Error::Api {
+                status: 401,
+                message,
+                ..
+            } => Error::BadCredentials { message },
+            e => e,
+        })?
+        .body_mut()
+        .read_json()?;
+
+    let bad = |s| unexpected(s, &v);
+
+    let id: String = v["accountId"]
+        .as_str()
+        .ok_or_else(|| bad("login response missing authorization token"))?
+        .to_owned();
+
+    let token: String = v["authorizationToken"]
+        .as_str()
+        .ok_or_else(|| bad("login response missing authorization token"))?
+        .to_owned();
+
+    let url = v["apiInfo"]["storageApi"]["apiUrl"]
+        .as_str()
+        .ok_or_else(|| bad("login response missing API URL"))?
+        .to_owned();
+
+    let capes = v["apiInfo"]["storageApi"]["capabilities"]
+        .as_array()
+        .ok_or_else(|| bad("login response missing capabilities"))?;
+    let capes = capes
+        .iter()
+        .map(|v| {
+            v.as_str()
+                .ok_or_else(|| bad("login response had malformed capabilities"))
+        })
+        .collect::<Result<Vec<&str>>>()?;
 
-        let creds = String::from(key_id) + ":" + application_key;
-        let auth = String::from("Basic") + &BASE64_STANDARD.encode(creds);
-        let v: json::Value = ureq::get("https://api.backblazeb2.com/b2api/v3/b2_authorize_account")
-            .header("Authorization", &auth)
-            .call()?
-            .body_mut()
-            .read_json()?;
+    let recommended_part_size = v["apiInfo"]["storageApi"]["recommendedPartSize"]
+        .as_u64()
+        .ok_or_else(|| bad("login response missing recommended part size"))?;
 
-        let bad = |s| unexpected(s, &v);
+    let name_prefix = v["apiInfo"]["storageApi"]["namePrefix"]
+        .as_str()
+        .map(|s| s.to_owned());
 
-        let id: String = v["accountId"]
-            .as_str()
-            .ok_or_else(|| bad("login response missing authorization token"))?
-            .to_owned();
+    if !capes.contains(&"listFiles") {
+        return Err(bad("credentials can not list files"));
+    }
+    if !capes.contains(&"readFiles") {
+        return Err(bad("credentials can not read files"));
+    }
+    if !capes.contains(&"writeFiles") {
+        return Err(bad("credentials can not write files"));
+    }
+    if !capes.contains(&"deleteFiles") {
+        return Err(bad("credentials can not delete files"));
+    }
 
-        let token: String = v["authorizationToken"]
-            .as_str()
-            .ok_or_else(|| bad("login response missing authorization token"))?
-            .to_owned();
+    Ok(Account {
+        id,
+        token,
+        url,
+        recommended_part_size,
+        name_prefix,
+    })
+}
 
-        let url = v["apiInfo"]["storageApi"]["apiUrl"]
-            .as_str()
-            .ok_or_else(|| bad("login response missing API URL"))?
-            .to_owned();
+impl Session {
+    pub fn new<S: Into<String>>(key_id: &str, application_key: &str, bucket: S) -> Result<Self> {
+        Self::with_auth_url(AUTH_URL, key_id, application_key, bucket)
+    }
 
-        let capes = v["apiInfo"]["storageApi"]["capabilities"]
-            .as_array()
-            .ok_or_else(|| bad("login response missing capabilities"))?;
-        let capes = capes
-            .iter()
-            .map(|v| {
-                v.as_str()
-                    .ok_or_else(|| bad("login response had malformed capabilities"))
-            })
-            .collect::<Result<Vec<&str>>>()?;
-
-        if !capes.iter().any(|c| *c == "listFiles") {
-            return Err(bad("credentials can not list files"));
-        }
-        if !capes.iter().any(|c| *c == "readFiles") {
-            return Err(bad("credentials can not read files"));
-        }
-        if !capes.iter().any(|c| *c == "writeFiles") {
-            return Err(bad("credentials can not write files"));
-        }
-        if !capes.iter().any(|c| *c == "deleteFiles") {
-            return Err(bad("credentials can not delete files"));
-        }
+    /// Like [`new()`](Self::new), but logs in somewhere other than [`AUTH_URL`]
+    /// (like a [fake server](fake) for testing).
+    pub fn with_auth_url<S: Into<String>>(
+        auth_url: &str,
+        key_id: &str,
+        application_key: &str,
+        bucket: S,
+    ) -> Result<Self> {
+        let bucket = bucket.into();
+
+        let account = authorize(auth_url, key_id, application_key)?;
 
-        let br: json::Value = ureq::get(&(url.clone() + "/b2api/v2/b2_list_buckets"))
-            .header("Authorization", &token)
-            .query("accountId", &id)
+        let br: json::Value = agent()
+            .get(&(account.url.clone() + "/b2api/v2/b2_list_buckets"))
+            .header("Authorization", &account.token)
+            .query("accountId", &account.id)
             .query("bucketName", &bucket)
-            .call()?
+            .call()
+            .checked()?
             .body_mut()
             .read_json()?;
 
@@ -124,50 +372,96 @@ impl Session {
             None => return Err(Error::NotFound { what: bucket }),
         };
 
-        let ur: json::Value = ureq::get(&(url.clone() + "/b2api/v2/b2_get_upload_url"))
-            .header("Authorization", &token)
-            .query("bucketId", &bucket_id)
-            .call()?
-            .body_mut()
-            .read_json()?;
+        Ok(Session {
+            auth_url: auth_url.to_owned(),
+            key_id: key_id.to_owned(),
+            application_key: application_key.to_owned(),
+            account: RwLock::new(account),
+            upload_urls: Mutex::new(vec![]),
+            bucket_name: bucket,
+            bucket_id,
+        })
+    }
 
-        let upload_url = ur["uploadUrl"]
+    /// Make an API call with our account token,
+    /// getting a new one and trying again if it expired.
+    fn with_account<T, F: Fn(&Account) -> Result<T>>(&self, f: F) -> Result<T> {
+        let account = self.account.read().unwrap().clone();
+        match f(&account) {
+            Err(e) if e.is_expired_token() => {
+                self.reauthorize(&account)?;
+                let account = self.account.read().unwrap().clone();
+                f(&account)
+            }
+            otherwise => otherwise,
+        }
+    }
+
+    /// Replace the given (presumably expired) account token with a fresh one.
+    fn reauthorize(&self, stale: &Account) -> Result<()> {
+        let mut account = self.account.write().unwrap();
+        // Did another thread beat us to it?
+        if account.token != stale.token {
+            return Ok(());
+        }
+        *account = authorize(&self.auth_url, &self.key_id, &self.application_key)?;
+        Ok(())
+    }
+
+    fn get_upload_url(&self) -> Result<UploadUrl> {
+        let ur: json::Value = self.with_account(|a| {
+            Ok(agent()
+                .get(&(a.url.clone() + "/b2api/v2/b2_get_upload_url"))
+                .header("Authorization", &a.token)
+                .query("bucketId", &self.bucket_id)
+                .call()
+                .checked()?
+                .body_mut()
+                .read_json()?)
+        })?;
+
+        let url = ur["uploadUrl"]
             .as_str()
             .ok_or_else(|| unexpected("couldn't get bucket upload URL", &ur))?
             .to_owned();
 
-        let upload_token = ur["authorizationToken"]
+        let token = ur["authorizationToken"]
             .as_str()
             .ok_or_else(|| unexpected("couldn't get bucket upload token", &ur))?
             .to_owned();
 
-        Ok(Session {
-            token,
-            url,
-            upload_url,
-            upload_token,
-            bucket_name: bucket,
-            bucket_id,
-        })
+        Ok(UploadUrl { url, token })
+    }
+
+    /// How big B2 would like each part of a large file to be
+    pub fn recommended_part_size(&self) -> u64 {
+        self.account.read().unwrap().recommended_part_size
+    }
+
+    /// The prefix our key is restricted to, if any
+    /// (all files we touch must start with it)
+    pub fn name_prefix(&self) -> Option<String> {
+        self.account.read().unwrap().name_prefix.clone()
     }
 
     pub fn list(&self, prefix: Option<&str>) -> Result<Vec<(String, u64)>> {
         let mut fs = vec![];
         let mut start_name: Option<String> = None;
         loop {
-            let mut req = noredir()
-                .get(&(self.url.clone() + "/b2api/v2/b2_list_file_names"))
-                .header("Authorization", &self.token)
-                .query("bucketId", &self.bucket_id)
-                .query("maxFileCount", "10000");
-            if let Some(p) = prefix {
-                req = req.query("prefix", p);
-            }
-            if let Some(sn) = &start_name {
-                req = req.query("startFileName", sn);
-            }
-
-            let lfn = req.call()?.body_mut().read_json()?;
+            let lfn: json::Value = self.with_account(|a| {
+                let mut req = noredir()
+                    .get(&(a.url.clone() + "/b2api/v2/b2_list_file_names"))
+                    .header("Authorization", &a.token)
+                    .query("bucketId", &self.bucket_id)
+                    .query("maxFileCount", "10000");
+                if let Some(p) = prefix {
+                    req = req.query("prefix", p);
+                }
+                if let Some(sn) = &start_name {
+                    req = req.query("startFileName", sn);
+                }
+                Ok(req.call().checked()?.body_mut().read_json()?)
+            })?;
 
             let bad = |s| unexpected(s, &lfn);
 
@@ -197,106 +491,261 @@ impl Session {
     }
 
     pub fn get(&self, name: &str) -> Result<impl Read> {
-        let r = noredir()
-            .get(&(self.url.clone() + "/file/" + &self.bucket_name + "/" + name))
-            .header("Authorization", &self.token)
-            .call()?;
+        let r = self.with_account(|a| {
+            noredir()
+                .get(&(a.url.clone() + "/file/" + &self.bucket_name + "/" + name))
+                .header("Authorization", &a.token)
+                .call()
+                .checked()
+        })?;
 
         Ok(r.into_body().into_reader())
     }
 
     pub fn put(&self, name: &str, len: u64, contents: &mut dyn Read) -> Result<()> {
-        use data_encoding::HEXLOWER;
-        use sha1::{Digest, Sha1};
-
-        // B2 wants the SHA1 hash (as hex), but we can provide it at the end.
-        // Very nice.
-        enum HashAppendingReader<R> {
-            Contents { inner: R, hasher: Option<Sha1> },
-            HashSuffix(Cursor<Vec<u8>>),
-        }
-
-        impl<R> HashAppendingReader<R> {
-            fn new(inner: R) -> Self {
-                Self::Contents {
-                    inner,
-                    hasher: Some(Sha1::new()),
-                }
-            }
-        }
-
-        impl<R: Read> Read for HashAppendingReader<R> {
-            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-                match self {
-                    Self::Contents { inner, hasher } => {
-                        // Read some bytes from the inner Read trait object.
-                        let bytes_read = inner.read(buf)?;
-                        if bytes_read > 0 {
-                            // If we got some bytes, update the SHA1 hash with those and return.
-                            hasher.as_mut().unwrap().update(&buf[..bytes_read]);
-                            Ok(bytes_read)
-                        } else {
-                            // Otherwise we're done reading.
-                            // Consume the hasher, get the hash,
-                            // and start feeding that to whoever's reading.
-                            let sha = hasher.take().unwrap().finalize();
-                            let sha_hex = HEXLOWER.encode(&sha).to_string().into_bytes();
-                            *self = Self::HashSuffix(Cursor::new(sha_hex));
-                            // Recurse (to the HashSuffix match arm).
-                            self.read(buf)
-                        }
-                    }
-                    Self::HashSuffix(c) => c.read(buf),
-                }
-            }
-        }
-
         let mut hr = HashAppendingReader::new(contents);
 
+        let pooled = self.upload_urls.lock().unwrap().pop();
+        let upload = match pooled {
+            Some(u) => u,
+            None => self.get_upload_url()?,
+        };
+
         noredir()
-            .post(&self.upload_url)
-            .header("Authorization", &self.upload_token)
+            .post(&upload.url)
+            .header("Authorization", &upload.token)
             .header("Content-Length", &(len + 40).to_string()) // SHA1 is 40 hex digits long.
             .header("X-Bz-File-Name", name) // No need to URL-encode, our names are boring
             .header("Content-Type", "b2/x-auto") // Go ahead and guess
             .header("X-Bz-Content-Sha1", "hex_digits_at_end")
-            .send(ureq::SendBody::from_reader(&mut hr))?;
+            .send(ureq::SendBody::from_reader(&mut hr))
+            .checked()?;
 
+        // If that failed, B2 says to get a new upload URL next time;
+        // this one might be busy or expired. Only keep it if it worked.
+        self.upload_urls.lock().unwrap().push(upload);
         Ok(())
     }
 
-    pub fn delete(&self, name: &str) -> Result<()> {
-        let req = noredir()
-            .get(&(self.url.clone() + "/b2api/v2/b2_list_file_versions"))
-            .header("Authorization", &self.token)
-            .query("bucketId", &self.bucket_id)
-            .query("prefix", name);
+    pub fn start_large_file(&self, name: &str) -> Result<LargeFile> {
+        let sl: json::Value = self.with_account(|a| {
+            Ok(noredir()
+                .post(&(a.url.clone() + "/b2api/v2/b2_start_large_file"))
+                .header("Authorization", &a.token)
+                .send_json(json::json!({
+                    "bucketId": self.bucket_id,
+                    "fileName": name,
+                    "contentType": "b2/x-auto"
+                }))
+                .checked()?
+                .body_mut()
+                .read_json()?)
+        })?;
+        let id = sl["fileId"]
+            .as_str()
+            .ok_or_else(|| unexpected(&format!("couldn't start large file {name}"), &sl))?
+            .to_owned();
+        Ok(LargeFile {
+            id,
+            upload_url: Mutex::new(None),
+        })
+    }
+
+    fn get_upload_part_url(&self, file: &LargeFile) -> Result<UploadUrl> {
+        let ur: json::Value = self.with_account(|a| {
+            Ok(agent()
+                .get(&(a.url.clone() + "/b2api/v2/b2_get_upload_part_url"))
+                .header("Authorization", &a.token)
+                .query("fileId", &file.id)
+                .call()
+                .checked()?
+                .body_mut()
+                .read_json()?)
+        })?;
+
+        let url = ur["uploadUrl"]
+            .as_str()
+            .ok_or_else(|| unexpected("couldn't get part upload URL", &ur))?
+            .to_owned();
+
+        let token = ur["authorizationToken"]
+            .as_str()
+            .ok_or_else(|| unexpected("couldn't get part upload token", &ur))?
+            .to_owned();
+
+        Ok(UploadUrl { url, token })
+    }
+
+    /// Upload the given part (numbered from 1) of a large file,
+    /// returning its SHA1 (as hex) for [`finish_large_file`](Self::finish_large_file).
+    pub fn upload_part(
+        &self,
+        file: &LargeFile,
+        part_number: u32,
+        len: u64,
+        part: &mut dyn Read,
+    ) -> Result<String> {
+        let mut hr = HashAppendingReader::new(part);
+
+        let mut url_slot = file.upload_url.lock().unwrap();
+        let upload = match url_slot.take() {
+            Some(u) => u,
+            None => self.get_upload_part_url(file)?,
+        };
+
+        noredir()
+            .post(&upload.url)
+            .header("Authorization", &upload.token)
+            .header("X-Bz-Part-Number", &part_number.to_string())
+            .header("Content-Length", &(len + 40).to_string())
+            .header("X-Bz-Content-Sha1", "hex_digits_at_end")
+            .send(ureq::SendBody::from_reader(&mut hr))
+            .checked()?;
+
+        // Same as put(): only hang onto the URL if it worked.
+        *url_slot = Some(upload);
+        hr.sha().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                format!("part {part_number} was shorter than {len} bytes"),
+            )
+            .into()
+        })
+    }
 
-        let lfv = req.call()?.body_mut().read_json()?;
-        let where_name = || unexpected(&format!("couldn't find {name}"), &lfv);
+    /// Stitch the uploaded parts into the final file.
+    pub fn finish_large_file(&self, file: &LargeFile, part_shas: &[String]) -> Result<()> {
+        self.with_account(|a| {
+            noredir()
+                .post(&(a.url.clone() + "/b2api/v2/b2_finish_large_file"))
+                .header("Authorization", &a.token)
+                .send_json(json::json!({
+                    "fileId": file.id,
+                    "partSha1Array": part_shas
+                }))
+                .checked()?;
+            Ok(())
+        })
+    }
 
-        let versions = lfv["files"].as_array().ok_or_else(where_name)?;
+    /// Give up on a large file, deleting any parts uploaded so far.
+    pub fn cancel_large_file(&self, id: &str) -> Result<()> {
+        self.with_account(|a| {
+            noredir()
+                .post(&(a.url.clone() + "/b2api/v2/b2_cancel_large_file"))
+                .header("Authorization", &a.token)
+                .send_json(json::json!({ "fileId": id }))
+                .checked()?;
+            Ok(())
+        })
+    }
+
+    pub fn list_unfinished_large_files(&self, prefix: Option<&str>) -> Result<Vec<UnfinishedFile>> {
+        let mut fs = vec![];
+        let mut start_id: Option<String> = None;
+        loop {
+            let lu: json::Value = self.with_account(|a| {
+                let mut req = noredir()
+                    .get(&(a.url.clone() + "/b2api/v2/b2_list_unfinished_large_files"))
+                    .header("Authorization", &a.token)
+                    .query("bucketId", &self.bucket_id);
+                if let Some(p) = prefix {
+                    req = req.query("namePrefix", p);
+                }
+                if let Some(si) = &start_id {
+                    req = req.query("startFileId", si);
+                }
+                Ok(req.call().checked()?.body_mut().read_json()?)
+            })?;
 
-        if versions.is_empty() {
-            return Err(where_name());
+            let files = lu["files"]
+                .as_array()
+                .ok_or_else(|| unexpected("didn't list unfinished large files", &lu))?;
+            for f in files {
+                match (
+                    f["fileId"].as_str(),
+                    f["fileName"].as_str(),
+                    f["uploadTimestamp"].as_u64(),
+                ) {
+                    (Some(id), Some(name), Some(ts)) => fs.push(UnfinishedFile {
+                        id: id.to_owned(),
+                        name: name.to_owned(),
+                        upload_timestamp: ts,
+                    }),
+                    _ => return Err(unexpected("malformed unfinished large file", f)),
+                }
+            }
+
+            start_id = lu["nextFileId"].as_str().map(|s| s.to_owned());
+            if start_id.is_none() {
+                break;
+            }
         }
-        if versions.len() != 1 {
-            return Err(unexpected(
-                &format!("found multiple versions of {name}"),
-                &lfv,
-            ));
+        Ok(fs)
+    }
+
+    /// Delete every version of the given file.
+    ///
+    /// Uploading a file with the same name as an existing one doesn't replace it,
+    /// it adds another version, and we'd have to pay for both.
+    pub fn delete(&self, name: &str) -> Result<()> {
+        let mut ids = vec![];
+        let mut start_id: Option<String> = None;
+        loop {
+            let lfv: json::Value = self.with_account(|a| {
+                let mut req = noredir()
+                    .get(&(a.url.clone() + "/b2api/v2/b2_list_file_versions"))
+                    .header("Authorization", &a.token)
+                    .query("bucketId", &self.bucket_id)
+                    .query("startFileName", name)
+                    .query("prefix", name);
+                if let Some(si) = &start_id {
+                    req = req.query("startFileId", si);
+                }
+                Ok(req.call().checked()?.body_mut().read_json()?)
+            })?;
+
+            let versions = lfv["files"]
+                .as_array()
+                .ok_or_else(|| unexpected(&format!("couldn't find {name}"), &lfv))?;
+            // The prefix also matches anything that starts with our name.
+            for v in versions.iter().filter(|v| v["fileName"] == name) {
+                let id = v["fileId"]
+                    .as_str()
+                    .ok_or_else(|| unexpected(&format!("couldn't find ID for {name}"), v))?;
+                ids.push(id.to_owned());
+            }
+
+            start_id = match (lfv["nextFileName"].as_str(), lfv["nextFileId"].as_str()) {
+                (Some(n), Some(id)) if n == name => Some(id.to_owned()),
+                _ => None,
+            };
+            if start_id.is_none() {
+                break;
+            }
         }
-        let id = versions[0]["fileId"]
-            .as_str()
-            .ok_or_else(|| unexpected(&format!("couldn't find ID for {name}"), &lfv))?;
 
-        ureq::post(&(self.url.clone() + "/b2api/v2/b2_delete_file_version"))
-            .header("Authorization", &self.token)
-            .send_json(json::json!({
-                "fileName": name,
-                "fileId": id
-            }))?;
+        if ids.is_empty() {
+            return Err(Error::Api {
+                status: 404,
+                code: "not_found".to_owned(),
+                message: format!("couldn't find {name}"),
+            });
+        }
 
+        for id in &ids {
+            self.with_account(|a| {
+                agent()
+                    .post(&(a.url.clone() + "/b2api/v2/b2_delete_file_version"))
+                    .header("Authorization", &a.token)
+                    .send_json(json::json!({
+                        "fileName": name,
+                        "fileId": id
+                    }))
+                    .checked()?;
+                Ok(())
+            })?;
+        }
         Ok(())
     }
 }
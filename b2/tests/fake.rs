@@ -0,0 +1,137 @@
+use std::io::prelude::*;
+
+use anyhow::Result;
+use backpak_b2::{fake::FakeB2, Session};
+
+fn session(fake: &FakeB2) -> Result<Session> {
+    Ok(Session::with_auth_url(fake.url(), "id", "key", "bukkit")?)
+}
+
+#[test]
+fn round_trip() -> Result<()> {
+    let fake = FakeB2::start("id", "key", "bukkit")?;
+    let s = session(&fake)?;
+
+    s.put("a/one", 3, &mut b"one".as_slice())?;
+    s.put("a/two", 3, &mut b"two".as_slice())?;
+    s.put("b/three", 5, &mut b"three".as_slice())?;
+    assert_eq!(fake.file("a/two").as_deref(), Some(b"two".as_slice()));
+
+    assert_eq!(
+        s.list(Some("a/"))?,
+        [("a/one".to_owned(), 3), ("a/two".to_owned(), 3)]
+    );
+
+    let mut got = vec![];
+    s.get("b/three")?.read_to_end(&mut got)?;
+    assert_eq!(got, b"three");
+
+    s.delete("a/one")?;
+    assert_eq!(fake.file_names(), ["a/two", "b/three"]);
+    assert!(s.delete("a/one").is_err());
+    assert!(s.get("a/one").is_err());
+    Ok(())
+}
+
+#[test]
+fn delete_every_version() -> Result<()> {
+    let fake = FakeB2::start("id", "key", "bukkit")?;
+    let s = session(&fake)?;
+
+    s.put("a/one", 3, &mut b"one".as_slice())?;
+    s.put("a/one", 3, &mut b"uno".as_slice())?;
+    s.put("a/one.bak", 3, &mut b"one".as_slice())?;
+    assert_eq!(fake.versions("a/one"), 2);
+    let mut got = vec![];
+    s.get("a/one")?.read_to_end(&mut got)?;
+    assert_eq!(got, b"uno");
+
+    s.delete("a/one")?;
+    assert_eq!(fake.versions("a/one"), 0);
+    assert_eq!(fake.file_names(), ["a/one.bak"]);
+    Ok(())
+}
+
+#[test]
+fn bad_credentials() -> Result<()> {
+    let fake = FakeB2::start("id", "key", "bukkit")?;
+    // Retrying won't fix a bad key, so don't.
+    let e = Session::with_auth_url(fake.url(), "id", "nope", "bukkit").unwrap_err();
+    assert!(!e.is_transient());
+    assert!(Session::with_auth_url(fake.url(), "id", "key", "nope").is_err());
+    Ok(())
+}
+
+#[test]
+fn reauthorize() -> Result<()> {
+    let fake = FakeB2::start("id", "key", "bukkit")?;
+    let s = session(&fake)?;
+    s.put("before", 1, &mut b"1".as_slice())?;
+
+    // Account tokens are quietly refreshed...
+    fake.expire_tokens();
+    assert_eq!(s.list(None)?, [("before".to_owned(), 1)]);
+
+    // ...but upload tokens fail, and we get a new URL next time.
+    let e = s.put("after", 1, &mut b"2".as_slice()).unwrap_err();
+    assert!(e.is_transient());
+    s.put("after", 1, &mut b"2".as_slice())?;
+    assert_eq!(fake.file_names(), ["after", "before"]);
+    Ok(())
+}
+
+#[test]
+fn transient_failures() -> Result<()> {
+    let fake = FakeB2::start("id", "key", "bukkit")?;
+    let s = session(&fake)?;
+    fake.fail_next(1);
+    let e = s.list(None).unwrap_err();
+    assert!(e.is_transient());
+    assert!(s.list(None)?.is_empty());
+    Ok(())
+}
+
+#[test]
+fn large_files() -> Result<()> {
+    let fake = FakeB2::start("id", "key", "bukkit")?;
+    fake.set_recommended_part_size(4);
+    let s = session(&fake)?;
+    assert_eq!(s.recommended_part_size(), 4);
+
+    let f = s.start_large_file("big")?;
+    let shas = [
+        s.upload_part(&f, 1, 4, &mut b"0123".as_slice())?,
+        s.upload_part(&f, 2, 4, &mut b"4567".as_slice())?,
+        s.upload_part(&f, 3, 2, &mut b"89".as_slice())?,
+    ];
+    assert_eq!(fake.unfinished_large_files(), ["big"]);
+    s.finish_large_file(&f, &shas)?;
+    assert!(fake.unfinished_large_files().is_empty());
+    assert_eq!(fake.file("big").as_deref(), Some(b"0123456789".as_slice()));
+
+    let f = s.start_large_file("doomed")?;
+    s.upload_part(&f, 1, 4, &mut b"0123".as_slice())?;
+    let unfinished = s.list_unfinished_large_files(None)?;
+    assert_eq!(unfinished.len(), 1);
+    assert_eq!(unfinished[0].name, "doomed");
+    s.cancel_large_file(f.id())?;
+    assert!(s.list_unfinished_large_files(None)?.is_empty());
+    assert_eq!(fake.file_names(), ["big"]);
+    Ok(())
+}
+
+#[test]
+fn restricted_keys() -> Result<()> {
+    let fake = FakeB2::start("id", "key", "bukkit")?;
+    fake.restrict_to_prefix("mine/");
+    let s = session(&fake)?;
+    assert_eq!(s.name_prefix().as_deref(), Some("mine/"));
+
+    s.put("mine/one", 3, &mut b"one".as_slice())?;
+    let e = s.put("yours/two", 3, &mut b"two".as_slice()).unwrap_err();
+    assert!(!e.is_transient());
+    let e = s.list(None).unwrap_err();
+    assert!(!e.is_transient());
+    assert_eq!(s.list(Some("mine/"))?, [("mine/one".to_owned(), 3)]);
+    Ok(())
+}
@@ -4,18 +4,20 @@
 use std::fs::File;
 use std::io::{self, prelude::*};
 use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
 
 use anyhow::{Context, Result, anyhow, bail, ensure};
 use byte_unit::Byte;
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use serde::{Deserialize, Serialize};
 use tracing::*;
 
 use crate::{
+    chunk,
     counters::{Op, bump},
-    file_util::{move_opened, nice_size},
-    hashing::ObjectId,
-    pack, progress,
+    file_util::{move_opened, nice_size, safe_copy_to_file},
+    hashing::{self, ObjectId},
+    metrics, pack, parity, progress, throttle,
 };
 
 pub mod backblaze;
@@ -34,22 +36,65 @@ fn defsize() -> Byte {
 
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(tag = "type")]
+#[expect(clippy::large_enum_variant)] // We have one of these. Who cares?
 pub enum Kind {
     Filesystem {
         force_cache: bool,
     },
     Backblaze {
-        key_id: String,
-        application_key: String,
+        #[serde(flatten)]
+        credentials: backblaze::Credentials,
         bucket: String,
+        /// Keep the repo under this "directory" in the bucket
+        /// so that several can share it.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prefix: Option<String>,
         concurrent_connections: u32,
+        #[serde(flatten)]
+        retries: backblaze::Retries,
+        /// Cancel unfinished uploads after this long,
+        /// assuming whatever started them is gone.
+        #[serde(default = "backblaze::defabandoned")]
+        abandoned_upload_hours: u64,
+        /// Log in somewhere besides B2's usual API endpoint (mostly for testing)
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        auth_url: Option<String>,
     }, // ...?
 }
 
+/// The repository format this version of backpak writes.
+///
+/// 1. Everything before we started counting
+/// 2. All snapshots use the nanosecond-timestamp format (see [`snapshot`](crate::snapshot))
+/// 3. Object IDs might be BLAKE3 instead of SHA-224 (see [`hashing::Algorithm`])
+/// 4. Packs might be version 2, with raw blobs and a dictionary ID in their header,
+///    and trees might be compressed with a dictionary (see [`pack`])
+///
+/// `backpak migrate` brings older repositories up to this one.
+pub const VERSION: u32 = 4;
+
+/// The first repository version that can hold version 2 packs and dictionaries
+const PACKS_V2: u32 = 4;
+
+#[inline]
+fn unversioned() -> u32 {
+    1
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 struct ConfigFile {
+    #[serde(default = "unversioned")]
+    version: u32,
     #[serde(default = "defsize")]
     pack_size: Byte,
+    #[serde(default, skip_serializing_if = "pack::Compression::is_default")]
+    compression: pack::Compression,
+    #[serde(default, skip_serializing_if = "chunk::Chunking::is_default")]
+    chunking: chunk::Chunking,
+    #[serde(default, skip_serializing_if = "hashing::Algorithm::is_default")]
+    hash: hashing::Algorithm,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parity: Option<parity::Parity>,
     #[serde(rename = "backend")]
     kind: Kind,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -63,7 +108,13 @@ struct ConfigFile {
 /// Normalized version of `ConfigFile` where `filter` and `unfilter` must both be Some or None.
 #[derive(Debug)]
 pub struct Configuration {
+    pub version: u32,
     pub pack_size: Byte,
+    pub compression: pack::Compression,
+    pub chunking: chunk::Chunking,
+    pub hash: hashing::Algorithm,
+    /// Reed-Solomon parity to save alongside each new pack, if any
+    pub parity: Option<parity::Parity>,
     pub kind: Kind,
     pub filter: Option<(String, String)>,
 }
@@ -72,13 +123,47 @@ pub fn read_config(p: &Utf8Path) -> Result<Configuration> {
     let s = std::fs::read_to_string(p).with_context(|| format!("Couldn't read config from {p}"))?;
     let cf: ConfigFile =
         toml::from_str(&s).with_context(|| format!("Couldn't parse config in {p}"))?;
+    ensure!(
+        cf.version <= VERSION,
+        "{p} is a version {} repository, but this backpak only understands up to version {VERSION}. \
+         Please upgrade backpak.",
+        cf.version
+    );
     let filter = match (cf.filter, cf.unfilter) {
         (Some(f), Some(u)) => Some((f, u)),
         (None, None) => None,
         _ => bail!("{p} config should set `filter` and `unfilter` or neither."),
     };
+    cf.compression
+        .validate()
+        .with_context(|| format!("Bad compression settings in {p}"))?;
+    ensure!(
+        cf.version >= PACKS_V2 || cf.compression.tree_dictionary.is_none(),
+        "{p} is a version {} repository, which can't use a tree dictionary; \
+         run `backpak migrate` first",
+        cf.version
+    );
+    cf.chunking
+        .validate()
+        .with_context(|| format!("Bad chunking settings in {p}"))?;
+    if let Some(parity) = &cf.parity {
+        parity
+            .validate()
+            .with_context(|| format!("Bad parity settings in {p}"))?;
+        // Parity covers the packs we write, not what the filter turns them into,
+        // and a single flipped bit in (say) encrypted packs keeps us from unfiltering them.
+        ensure!(
+            filter.is_none(),
+            "{p} config sets `parity`, which can't protect filtered repositories"
+        );
+    }
     Ok(Configuration {
+        version: cf.version,
         pack_size: cf.pack_size,
+        compression: cf.compression,
+        chunking: cf.chunking,
+        hash: cf.hash,
+        parity: cf.parity,
         kind: cf.kind,
         filter,
     })
@@ -90,7 +175,12 @@ pub fn write_config<W: Write>(mut w: W, c: Configuration) -> Result<()> {
         None => (None, None),
     };
     let cf = ConfigFile {
+        version: c.version,
         pack_size: c.pack_size,
+        compression: c.compression,
+        chunking: c.chunking,
+        hash: c.hash,
+        parity: c.parity,
         kind: c.kind,
         filter,
         unfilter,
@@ -105,7 +195,9 @@ pub trait Backend {
     fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>>;
 
     /// Write the given read stream to the given key
-    fn write(&self, len: u64, from: &mut (dyn Read + Send), to: &str) -> Result<()>;
+    ///
+    /// Implementations that retry should seek back to the start before each try.
+    fn write(&self, len: u64, from: &mut dyn SeekableRead, to: &str) -> Result<()>;
 
     fn remove(&self, which: &str) -> Result<()>;
 
@@ -154,22 +246,56 @@ enum CachedBackendKind {
 
 pub struct CachedBackend {
     inner: CachedBackendKind,
-    pub bytes_downloaded: AtomicU64,
-    pub bytes_uploaded: AtomicU64,
+    /// The repository's format version (see [`VERSION`])
+    version: u32,
+    concurrency: u32,
+    hash: hashing::Algorithm,
+    parity: Option<parity::Parity>,
+    pub bytes_downloaded: Arc<AtomicU64>,
+    pub bytes_uploaded: Arc<AtomicU64>,
 }
 
 impl CachedBackend {
-    fn new(inner: CachedBackendKind) -> Self {
+    fn new(
+        inner: CachedBackendKind,
+        version: u32,
+        concurrency: u32,
+        hash: hashing::Algorithm,
+        parity: Option<parity::Parity>,
+    ) -> Self {
+        let bytes_downloaded = Arc::new(AtomicU64::new(0));
+        let bytes_uploaded = Arc::new(AtomicU64::new(0));
+        metrics::track_transfers(&bytes_downloaded, &bytes_uploaded);
         Self {
             inner,
-            bytes_downloaded: AtomicU64::new(0),
-            bytes_uploaded: AtomicU64::new(0),
+            version,
+            concurrency,
+            hash,
+            parity,
+            bytes_downloaded,
+            bytes_uploaded,
         }
     }
+
+    /// How many uploads we should have going at once
+    pub fn concurrency(&self) -> u32 {
+        self.concurrency
+    }
+
+    /// What the repository hashes its objects with
+    pub fn hash(&self) -> hashing::Algorithm {
+        self.hash
+    }
+
+    /// The parity to save for new packs, if any
+    pub fn parity(&self) -> Option<parity::Parity> {
+        self.parity
+    }
 }
 
-pub trait SeekableRead: Read + Seek + Send + 'static {}
-impl<T> SeekableRead for T where T: Read + Seek + Send + 'static {}
+/// Something we can read, and read again (e.g., to retry a failed upload).
+pub trait SeekableRead: Read + Seek + Send {}
+impl<T> SeekableRead for T where T: Read + Seek + Send {}
 
 // NB: We use a flat cache structure (where every file is just <hash>.pack/index/etc)
 // but prepend prefixes with `destination()` prior to giving the path to the backend.
@@ -213,7 +339,10 @@ impl CachedBackend {
                     // cache.insert() lest its hokey "waiting on a process inside drop()"
                     // breaks things.
                     let counter = progress::AtomicCountRead::new(
-                        backend.read(&destination(name))?,
+                        throttle::ThrottledRead::new(
+                            backend.read(&destination(name))?,
+                            throttle::download(),
+                        ),
                         &self.bytes_downloaded,
                     );
                     let mut inserted = cache.insert(name, counter)?;
@@ -234,12 +363,21 @@ impl CachedBackend {
     /// store it to an object with the appropriate key per
     /// `destination()`
     pub fn write(&self, name: &str, mut fh: File) -> Result<()> {
+        // Older backpaks would happily open the repository, then choke on these.
+        let extension = Utf8Path::new(name).extension();
+        ensure!(
+            self.version >= PACKS_V2 || !matches!(extension, Some("pack" | "dictionary")),
+            "This is a version {} repository, which older versions of backpak can read; \
+             run `backpak migrate` before adding packs to it",
+            self.version
+        );
         bump(Op::BackendWrite);
         let len = fh.metadata()?.len();
         match &self.inner {
             CachedBackendKind::File { backend } => {
                 debug!("Saving {name} ({})", nice_size(len));
                 let to = backend.path_of(&destination(name));
+                fs::ensure_parent(&to)?;
                 move_opened(name, fh, to)?;
                 self.bytes_uploaded.fetch_add(len, Ordering::Relaxed);
             }
@@ -248,10 +386,13 @@ impl CachedBackend {
                 fh.seek(std::io::SeekFrom::Start(0))?;
                 // Write it through to the backend.
                 debug!("Uploading {name} ({})", nice_size(len));
-                let mut counter = progress::AtomicCountRead::new(fh, &self.bytes_uploaded);
+                let mut counter = progress::AtomicCountRead::new(
+                    throttle::ThrottledRead::new(fh, throttle::upload()),
+                    &self.bytes_uploaded,
+                );
                 backend.write(len, &mut counter, &destination(name))?;
                 // Insert it into the cache.
-                cache.insert_file(name, counter.into_inner())?;
+                cache.insert_file(name, counter.into_inner().into_inner())?;
                 // Prune the cache.
                 cache.prune()?;
             }
@@ -306,6 +447,10 @@ impl CachedBackend {
         self.list("packs/")
     }
 
+    pub fn list_parity(&self) -> Result<Vec<(String, u64)>> {
+        self.list("parity/")
+    }
+
     pub fn read_pack(&self, id: &ObjectId) -> Result<Box<dyn SeekableRead>> {
         let base32 = id.to_string();
         let pack_path = format!("{}.pack", base32);
@@ -325,6 +470,19 @@ impl CachedBackend {
             .with_context(|| format!("Couldn't open {}", snapshot_path))
     }
 
+    pub fn read_dictionary(&self, id: &ObjectId) -> Result<Box<dyn SeekableRead>> {
+        let dictionary_path = format!("{}.dictionary", id);
+        self.read(&dictionary_path)
+            .with_context(|| format!("Couldn't open {}", dictionary_path))
+    }
+
+    /// Reads the parity for the pack with the given ID.
+    pub fn read_parity(&self, pack_id: &ObjectId) -> Result<Box<dyn SeekableRead>> {
+        let parity_path = format!("{}.par", pack_id);
+        self.read(&parity_path)
+            .with_context(|| format!("Couldn't open {}", parity_path))
+    }
+
     pub fn remove_pack(&self, id: &ObjectId) -> Result<()> {
         let base32 = id.to_string();
         let pack_path = format!("{}.pack", base32);
@@ -340,6 +498,11 @@ impl CachedBackend {
         let snapshot_path = format!("{}.snapshot", id);
         self.remove(&snapshot_path)
     }
+
+    pub fn remove_parity(&self, pack_id: &ObjectId) -> Result<()> {
+        let parity_path = format!("{}.par", pack_id);
+        self.remove(&parity_path)
+    }
 }
 
 /// Given a list of packs, find one with the given ID or return an error.
@@ -362,10 +525,149 @@ pub fn probe_pack(packs: &[(String, u64)], id: &ObjectId) -> Result<()> {
 }
 
 /// Initializes an in-memory cache for testing purposes.
+///
+/// It claims it can take a few uploads at once, like a cloud backend would.
 pub fn in_memory() -> CachedBackend {
-    CachedBackend::new(CachedBackendKind::Memory {
-        backend: memory::MemoryBackend::new(),
-    })
+    CachedBackend::new(
+        CachedBackendKind::Memory {
+            backend: memory::MemoryBackend::new(),
+        },
+        VERSION,
+        4,
+        hashing::Algorithm::default(),
+        None,
+    )
+}
+
+/// An [`in_memory()`] backend holding the chunks of a few of our reference files
+/// in packs of (roughly) the given size, along with an index of those packs
+/// and the chunks themselves (in order)
+#[cfg(test)]
+pub fn in_memory_with_references(
+    pack_size: Byte,
+) -> Result<(CachedBackend, crate::index::Index, Vec<crate::blob::Blob>)> {
+    use std::sync::mpsc::sync_channel;
+    use std::thread;
+
+    use crate::index;
+
+    let backend = in_memory();
+
+    let chunker = chunk::Chunker::new(&Default::default(), Default::default());
+    let mut chunks = Vec::new();
+    for f in [
+        "tests/references/sr71.txt",
+        "tests/references/index.stability",
+        "tests/references/pack.stability",
+        "tests/references/README.md",
+    ] {
+        chunks.extend(chunk::chunk_file(f, &chunker)?);
+    }
+    assert_eq!(chunks.len(), 4);
+
+    let (chunk_tx, chunk_rx) = sync_channel(0);
+    let (pack_tx, pack_rx) = sync_channel(0);
+    let (upload_tx, upload_rx) = sync_channel(0);
+
+    let unused_byte_count = AtomicU64::new(0);
+    let chunk_packer = thread::spawn(move || {
+        pack::pack(
+            pack_size,
+            pack::Compression::default(),
+            Default::default(),
+            None,
+            chunk_rx,
+            pack_tx,
+            upload_tx,
+            &unused_byte_count,
+            &unused_byte_count,
+        )
+    });
+    let uploader = thread::spawn(move || -> Result<CachedBackend> {
+        while let Ok((path, fh)) = upload_rx.recv() {
+            backend.write(&path, fh)?;
+        }
+        Ok(backend)
+    });
+    // Instead of writing out an index file with index::index()
+    // and reading it back in, let's just synthesize the needed info.
+    let indexer = thread::spawn(move || {
+        let mut packs = index::PackMap::new();
+        while let Ok(metadata) = pack_rx.recv() {
+            packs.insert(metadata.id, metadata.manifest);
+        }
+        index::Index {
+            packs,
+            supersedes: Default::default(),
+        }
+    });
+
+    for chunk in &chunks {
+        chunk_tx.send(chunk.clone())?
+    }
+    drop(chunk_tx);
+    chunk_packer.join().unwrap()?;
+    let backend = uploader.join().unwrap()?;
+    let index = indexer.join().unwrap();
+    Ok((backend, index, chunks))
+}
+
+/// The keys written to an [`in_memory()`] backend, in order
+#[cfg(test)]
+pub fn memory_writes(cached_backend: &CachedBackend) -> Vec<String> {
+    match &cached_backend.inner {
+        CachedBackendKind::Memory { backend } => backend.written(),
+        _ => panic!("Not an in-memory backend"),
+    }
+}
+
+/// Filesystem repositories keep their config inside;
+/// others are just the config file.
+pub fn config_path(repository: &Utf8Path) -> Result<Utf8PathBuf> {
+    let stat =
+        std::fs::metadata(repository).with_context(|| format!("Couldn't stat {repository}"))?;
+    if stat.is_dir() {
+        Ok(repository.join("config.toml"))
+    } else if stat.is_file() {
+        Ok(repository.to_owned())
+    } else {
+        bail!("{repository} is not a file or directory")
+    }
+}
+
+/// Start compressing trees with the given dictionary.
+pub fn set_tree_dictionary(repository: &Utf8Path, id: &ObjectId) -> Result<()> {
+    update_config(repository, |c| c.compression.tree_dictionary = Some(*id))
+}
+
+/// Mark the repository as the given format version (see [`VERSION`]).
+pub fn set_version(repository: &Utf8Path, version: u32) -> Result<()> {
+    update_config(repository, |c| c.version = version)
+}
+
+/// Rereads the config instead of taking the one from [`open()`]
+/// so that `--compression-level` doesn't get saved along with changes.
+fn update_config<F: FnOnce(&mut Configuration)>(repository: &Utf8Path, f: F) -> Result<()> {
+    let p = config_path(repository)?;
+    let mut c = read_config(&p)?;
+    f(&mut c);
+    let mut new_config = vec![];
+    write_config(&mut new_config, c)?;
+    safe_copy_to_file(new_config.as_slice(), &p).with_context(|| format!("Couldn't update {p}"))?;
+    Ok(())
+}
+
+static COMPRESSION_LEVEL: OnceLock<i32> = OnceLock::new();
+
+/// Use the given zstd level for this run instead of whatever repositories are configured with.
+pub fn override_compression_level(level: i32) -> Result<()> {
+    pack::Compression {
+        level,
+        ..Default::default()
+    }
+    .validate()?;
+    COMPRESSION_LEVEL.set(level).ok();
+    Ok(())
 }
 
 /// Factory function to open the appropriate type of backend from the repository path
@@ -375,17 +677,17 @@ pub fn open(
     behavior: CacheBehavior,
 ) -> Result<(Configuration, CachedBackend)> {
     info!("Opening repository {repository}");
-    let stat =
-        std::fs::metadata(repository).with_context(|| format!("Couldn't stat {repository}"))?;
-    let c = if stat.is_dir() {
-        let cfg_file = repository.join("config.toml");
-        read_config(&cfg_file)
-    } else if stat.is_file() {
-        read_config(repository)
-    } else {
-        bail!("{repository} is not a file or directory")
-    }?;
+    let mut c = read_config(&config_path(repository)?)?;
+    if let Some(l) = COMPRESSION_LEVEL.get() {
+        c.compression.level = *l;
+    }
     debug!("Read repository config: {c:?}");
+    if c.version < VERSION {
+        info!(
+            "{repository} is a version {} repository; `backpak migrate` upgrades it to {VERSION}",
+            c.version
+        );
+    }
     // Don't bother checking unfilter; we ensure both are set if one is above.
     let cached_backend = match &c.kind {
         Kind::Filesystem { force_cache: false } if c.filter.is_none() => {
@@ -400,12 +702,22 @@ pub fn open(
             let mut backend: Box<dyn Backend + Send + Sync> = match some_cached {
                 Kind::Filesystem { .. } => Box::new(fs::FilesystemBackend::open(repository)?),
                 Kind::Backblaze {
-                    key_id,
-                    application_key,
+                    credentials,
                     bucket,
+                    prefix,
                     concurrent_connections,
+                    retries,
+                    abandoned_upload_hours,
+                    auth_url,
                 } => Box::new(semaphored::Semaphored::new(
-                    backblaze::BackblazeBackend::open(key_id, application_key, bucket)?,
+                    backblaze::BackblazeBackend::open(
+                        credentials,
+                        bucket,
+                        prefix.as_deref(),
+                        auth_url.as_deref().unwrap_or(backpak_b2::AUTH_URL),
+                        retries,
+                        *abandoned_upload_hours,
+                    )?,
                     *concurrent_connections,
                 )),
             };
@@ -427,7 +739,15 @@ pub fn open(
             }
         }
     };
-    let cached_backend = CachedBackend::new(cached_backend);
+    let concurrency = match &c.kind {
+        Kind::Filesystem { .. } => 1,
+        Kind::Backblaze {
+            concurrent_connections,
+            ..
+        } => (*concurrent_connections).max(1),
+    };
+    let cached_backend =
+        CachedBackend::new(cached_backend, c.version, concurrency, c.hash, c.parity);
     Ok((c, cached_backend))
 }
 
@@ -437,6 +757,8 @@ fn destination(src: &str) -> String {
         Some("pack") => format!("packs/{}", src),
         Some("index") => format!("indexes/{}", src),
         Some("snapshot") => format!("snapshots/{}", src),
+        Some("dictionary") => format!("dictionaries/{}", src),
+        Some("par") => format!("parity/{}", src),
         _ => panic!("Unexpected extension on file: {}", src),
     }
 }
@@ -1,32 +1,226 @@
 use super::*;
 
 use std::fs;
+use std::time::Duration;
 
 use anyhow::Result;
 use b2::Session;
 use backpak_b2 as b2;
 use byte_unit::Byte;
+use camino::Utf8PathBuf;
 
 pub struct BackblazeBackend {
     pub session: Session,
+    retries: Retries,
+    /// Prepended to every file name so that repos can share a bucket.
+    /// Empty, or ends in a `/`.
+    prefix: String,
+    /// Cleans up abandoned uploads before our first write
+    /// (so read-only commands don't need to list or cancel anything).
+    cleanup: std::sync::Once,
+    /// Unfinished uploads older than this are abandoned.
+    abandoned_after: Duration,
 }
 
+#[inline]
+fn defattempts() -> u32 {
+    10
+}
+
+#[inline]
+fn defbackoff() -> u64 {
+    64
+}
+
+#[inline]
+pub fn defabandoned() -> u64 {
+    24
+}
+
+/// How hard we try when B2 is having a bad day.
+///
+/// We wait (exponentially longer, with some jitter) between each try,
+/// starting at a second.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Retries {
+    /// Give up after this many tries
+    #[serde(default = "defattempts")]
+    pub max_attempts: u32,
+    /// Never wait longer than this between tries
+    #[serde(default = "defbackoff")]
+    pub max_backoff_secs: u64,
+}
+
+impl Default for Retries {
+    fn default() -> Self {
+        Self {
+            max_attempts: defattempts(),
+            max_backoff_secs: defbackoff(),
+        }
+    }
+}
+
+/// Where to find the B2 key ID and application key.
+///
+/// Each is taken from the first of:
+/// 1. The output of `key_id_command` and `application_key_command`
+/// 2. A `credentials_file` (only readable by its owner) with `key_id` and `application_key`
+/// 3. `key_id` and `application_key` right here in the repo config
+///    (where anyone who can see the config can see them too!)
+/// 4. `B2_APPLICATION_KEY_ID` and `B2_APPLICATION_KEY` environment variables
+///
+/// The environment comes last since it's the same for every repository we might open
+/// (e.g., with several profiles in `backpak run`).
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Credentials {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub application_key: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_id_command: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub application_key_command: Option<String>,
+    /// Relative paths are relative to `~/.config/backpak/`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub credentials_file: Option<Utf8PathBuf>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct CredentialsFile {
+    key_id: Option<String>,
+    application_key: Option<String>,
+}
+
+impl Credentials {
+    /// Find the key ID and application key.
+    pub fn resolve(&self) -> Result<(String, String)> {
+        let file = self
+            .credentials_file
+            .as_deref()
+            .map(read_credentials_file)
+            .transpose()?;
+        let (file_key_id, file_app_key) = match file {
+            Some(f) => (f.key_id, f.application_key),
+            None => (None, None),
+        };
+        let key_id = resolve_secret(
+            "key ID",
+            "B2_APPLICATION_KEY_ID",
+            self.key_id_command.as_deref(),
+            file_key_id,
+            self.key_id.as_deref(),
+        )?;
+        let application_key = resolve_secret(
+            "application key",
+            "B2_APPLICATION_KEY",
+            self.application_key_command.as_deref(),
+            file_app_key,
+            self.application_key.as_deref(),
+        )?;
+        Ok((key_id, application_key))
+    }
+}
+
+fn resolve_secret(
+    what: &str,
+    env_var: &str,
+    command: Option<&str>,
+    from_file: Option<String>,
+    plaintext: Option<&str>,
+) -> Result<String> {
+    if let Some(c) = command {
+        debug!("Running {c} for B2 {what}");
+        return run_secret_command(c).with_context(|| format!("Couldn't get B2 {what}"));
+    }
+    if let Some(s) = from_file {
+        return Ok(s);
+    }
+    if let Some(s) = plaintext {
+        return Ok(s.to_owned());
+    }
+    if let Ok(s) = std::env::var(env_var) {
+        debug!("Using B2 {what} from ${env_var}");
+        return Ok(s);
+    }
+    bail!("No B2 {what} given (set ${env_var}, or see the repository config docs)")
+}
+
+fn run_secret_command(command: &str) -> Result<String> {
+    // Let stdin and stderr through in case we need a password, etc.
+    let child = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(command)
+        .stdout(std::process::Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Couldn't run {command}"))?;
+    let _cg = crate::ChildGuard::new(child.id());
+    let out = child.wait_with_output()?;
+    ensure!(out.status.success(), "{command} failed ({})", out.status);
+    let secret =
+        String::from_utf8(out.stdout).with_context(|| format!("{command} gave non-UTF-8"))?;
+    // Like `pass show` and friends, just take the first line.
+    let secret = secret.lines().next().unwrap_or_default().trim();
+    ensure!(!secret.is_empty(), "{command} printed nothing");
+    Ok(secret.to_owned())
+}
+
+fn read_credentials_file(path: &Utf8Path) -> Result<CredentialsFile> {
+    let path = if path.is_relative() {
+        let mut c: Utf8PathBuf = home::home_dir()
+            .ok_or_else(|| anyhow!("Can't find home directory"))?
+            .try_into()
+            .context("Home directory isn't UTF-8")?;
+        c.extend([".config", "backpak"]);
+        c.join(path)
+    } else {
+        path.to_owned()
+    };
+    let s = fs::read_to_string(&path).with_context(|| format!("Couldn't read {path}"))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path)?.permissions().mode();
+        ensure!(
+            mode & 0o077 == 0,
+            "{path} is accessible by other users (mode {:o}); chmod 600 it",
+            mode & 0o777
+        );
+    }
+    toml::from_str(&s).with_context(|| format!("Couldn't parse {path}"))
+}
+
+#[expect(clippy::too_many_arguments)] // We know, sit down.
 pub fn initialize(
     repository: &camino::Utf8Path,
     pack_size: Byte,
+    compression: crate::pack::Compression,
+    chunking: crate::chunk::Chunking,
+    hash: crate::hashing::Algorithm,
+    parity: Option<crate::parity::Parity>,
     filter: Option<(String, String)>,
-    key_id: String,
-    application_key: String,
+    credentials: Credentials,
     bucket: String,
+    prefix: Option<String>,
     concurrent_connections: u32,
+    auth_url: Option<String>,
 ) -> Result<()> {
     let c = super::Configuration {
+        version: super::VERSION,
         pack_size,
+        compression,
+        chunking,
+        hash,
+        parity,
         kind: super::Kind::Backblaze {
-            key_id,
-            application_key,
+            credentials,
             bucket,
+            prefix,
             concurrent_connections,
+            retries: Retries::default(),
+            abandoned_upload_hours: defabandoned(),
+            auth_url,
         },
         filter,
     };
@@ -41,49 +235,371 @@ pub fn initialize(
 }
 
 impl BackblazeBackend {
-    pub fn open(key_id: &str, application_key: &str, bucket: &str) -> Result<Self> {
-        let session = Session::new(key_id, application_key, bucket)?;
-        Ok(Self { session })
+    pub fn open(
+        credentials: &Credentials,
+        bucket: &str,
+        prefix: Option<&str>,
+        auth_url: &str,
+        retries: &Retries,
+        abandoned_upload_hours: u64,
+    ) -> Result<Self> {
+        let prefix = normalize_prefix(prefix.unwrap_or_default());
+        let (key_id, application_key) = credentials.resolve()?;
+        let session = retry(retries, || {
+            Session::with_auth_url(auth_url, &key_id, &application_key, bucket)
+        })?;
+        if let Some(np) = session.name_prefix() {
+            ensure!(
+                prefix.starts_with(&np),
+                "This B2 application key can only access files starting with {np:?}; \
+                 set the repository's prefix to match"
+            );
+        }
+        Ok(Self {
+            session,
+            retries: retries.clone(),
+            prefix,
+            cleanup: std::sync::Once::new(),
+            abandoned_after: Duration::from_secs(abandoned_upload_hours * 60 * 60),
+        })
+    }
+
+    /// Clean up large files that were interrupted partway through uploading.
+    ///
+    /// B2 keeps (and bills us for!) their parts until they're finished or canceled.
+    /// Only cancel ones that have sat around for a while, though,
+    /// so we don't pull the rug out from under another backup that's still running.
+    /// And only look where we keep our own files (see `destination()`),
+    /// since other tools or repositories might share the bucket.
+    fn cancel_abandoned_uploads(&self) -> Result<()> {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)?;
+        let mut unfinished = vec![];
+        for dir in [
+            "packs/",
+            "indexes/",
+            "snapshots/",
+            "dictionaries/",
+            "parity/",
+        ] {
+            let prefix = self.prefix.clone() + dir;
+            unfinished.extend(retry(&self.retries, || {
+                self.session.list_unfinished_large_files(Some(&prefix))
+            })?);
+        }
+        for f in unfinished {
+            let started = Duration::from_millis(f.upload_timestamp);
+            if now.saturating_sub(started) < self.abandoned_after {
+                debug!("Leaving in-progress large file {} alone", f.name);
+                continue;
+            }
+            info!("Canceling abandoned upload of {}", f.name);
+            retry(&self.retries, || self.session.cancel_large_file(&f.id))?;
+        }
+        Ok(())
+    }
+
+    /// Upload a file in parts so that we can go past B2's 5 GB limit for single uploads,
+    /// and so a hiccup only means retrying a part instead of the whole thing.
+    fn write_large(&self, len: u64, from: &mut dyn SeekableRead, to: &str) -> Result<()> {
+        let part_size = self.session.recommended_part_size();
+        debug!("Uploading {to} in {} parts", parts(len, part_size).count());
+        from.rewind()?;
+        let file = retry(&self.retries, || self.session.start_large_file(to))?;
+
+        let mut upload = || -> Result<()> {
+            let mut shas = vec![];
+            let mut start = 0;
+            for (part_number, part_len) in parts(len, part_size) {
+                // Stream each part straight from the file instead of buffering it,
+                // seeking back to its start if we have to try again.
+                shas.push(retry(&self.retries, || {
+                    from.seek(std::io::SeekFrom::Start(start))?;
+                    self.session
+                        .upload_part(&file, part_number, part_len, &mut from.take(part_len))
+                })?);
+                start += part_len;
+            }
+            retry(&self.retries, || {
+                self.session.finish_large_file(&file, &shas)
+            })?;
+            Ok(())
+        };
+
+        upload().inspect_err(|_| {
+            if let Err(e) = retry(&self.retries, || self.session.cancel_large_file(file.id())) {
+                warn!("Couldn't cancel upload of {to}: {e}");
+            }
+        })
+    }
+}
+
+/// `foo`, `/foo`, and `foo/` all mean `foo/`
+fn normalize_prefix(prefix: &str) -> String {
+    let p = prefix.trim_matches('/');
+    if p.is_empty() {
+        String::new()
+    } else {
+        format!("{p}/")
     }
 }
 
-// We could make the timeout configurable, make this generic across different errors, etc.
-fn retry<T, F: FnMut() -> b2::Result<T>>(mut f: F) -> b2::Result<T> {
+/// Split `len` bytes into numbered (from 1) parts of (at most) `part_size`
+fn parts(len: u64, part_size: u64) -> impl Iterator<Item = (u32, u64)> {
+    (0..len.div_ceil(part_size)).map(move |i| {
+        let start = i * part_size;
+        (i as u32 + 1, part_size.min(len - start))
+    })
+}
+
+fn retry<T, F: FnMut() -> b2::Result<T>>(retries: &Retries, mut f: F) -> b2::Result<T> {
+    let mut attempt = 1;
     loop {
         match f() {
             Ok(k) => return Ok(k),
-            Err(e) => {
-                // Assume IO errors are issues with our machine that won't resolve quickly,
-                // and everything else to be server-side, temporary sadness.
-                if matches!(e, b2::Error::Io(_)) {
-                    return Err(e);
-                } else {
-                    warn!("{e}, retrying");
-                    std::thread::sleep(std::time::Duration::from_secs(1));
-                }
+            Err(e) if e.is_transient() && attempt < retries.max_attempts => {
+                let wait = backoff(retries, attempt, jitter());
+                warn!("{e}, retrying in {:.1}s", wait.as_secs_f64());
+                std::thread::sleep(wait);
+                attempt += 1;
             }
+            Err(e) => return Err(e),
         }
     }
 }
 
+/// How long to wait after the given (1-based) attempt,
+/// given some random `jitter` in [0, 1).
+///
+/// Double the wait each time, then pick something in the back half of that
+/// so that a bunch of threads that failed at once don't all come back at once.
+fn backoff(retries: &Retries, attempt: u32, jitter: f64) -> Duration {
+    let max = Duration::from_secs(retries.max_backoff_secs);
+    let ceiling = Duration::from_secs(1)
+        .checked_mul(1 << (attempt - 1).min(31))
+        .map_or(max, |c| c.min(max));
+    ceiling.mul_f64(0.5 + jitter / 2.0)
+}
+
+/// Not cryptographically anything, but plenty to spread out retries.
+fn jitter() -> f64 {
+    use std::hash::{BuildHasher, Hasher};
+    let r = std::collections::hash_map::RandomState::new()
+        .build_hasher()
+        .finish();
+    (r >> 11) as f64 / (1u64 << 53) as f64
+}
+
 impl Backend for BackblazeBackend {
     fn read(&self, from: &str) -> Result<Box<dyn Read + Send + 'static>> {
-        let r = retry(|| self.session.get(from))?;
+        let from = self.prefix.clone() + from;
+        let r = retry(&self.retries, || self.session.get(&from))?;
         Ok(Box::new(r))
     }
 
-    fn write(&self, len: u64, from: &mut (dyn Read + Send), to: &str) -> Result<()> {
-        retry(|| self.session.put(to, len, from))?;
+    fn write(&self, len: u64, from: &mut dyn SeekableRead, to: &str) -> Result<()> {
+        self.cleanup.call_once(|| {
+            if let Err(e) = self.cancel_abandoned_uploads() {
+                warn!("Couldn't clean up abandoned uploads: {e:?}");
+            }
+        });
+        let to = &(self.prefix.clone() + to);
+        // B2 wants large files to have at least two parts,
+        // and the recommended part size is about a pack's size.
+        // Only bother splitting things that are well past it.
+        if len > 2 * self.session.recommended_part_size() {
+            return self.write_large(len, from, to);
+        }
+        retry(&self.retries, || {
+            from.rewind()?;
+            self.session.put(to, len, from)
+        })?;
         Ok(())
     }
 
     fn remove(&self, which: &str) -> Result<()> {
-        retry(|| self.session.delete(which))?;
+        let which = self.prefix.clone() + which;
+        retry(&self.retries, || self.session.delete(&which))?;
         Ok(())
     }
 
     fn list(&self, prefix: &str) -> Result<Vec<(String, u64)>> {
-        let l = retry(|| self.session.list(Some(prefix)))?;
-        Ok(l)
+        let prefix = self.prefix.clone() + prefix;
+        let l = retry(&self.retries, || self.session.list(Some(&prefix)))?;
+        Ok(l.into_iter()
+            .map(|(name, len)| (name[self.prefix.len()..].to_owned(), len))
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use crate::backend::{ConfigFile, Configuration, Kind, VERSION, write_config};
+
+    #[test]
+    fn old_configs_still_parse() -> Result<()> {
+        let old = r#"
+pack_size = "100 MiB"
+
+[backend]
+type = "Backblaze"
+key_id = "deadbeef"
+application_key = "SOMEBASE64"
+bucket = "matts-bakpak"
+concurrent_connections = 4
+"#;
+        let cf: ConfigFile = toml::from_str(old)?;
+        assert_eq!(cf.version, 1);
+        let Kind::Backblaze {
+            credentials,
+            retries,
+            abandoned_upload_hours,
+            ..
+        } = &cf.kind
+        else {
+            panic!("Not a B2 config");
+        };
+        assert_eq!(*retries, Retries::default());
+        assert_eq!(*abandoned_upload_hours, defabandoned());
+        assert_eq!(credentials.key_id.as_deref(), Some("deadbeef"));
+        assert_eq!(credentials.application_key.as_deref(), Some("SOMEBASE64"));
+        assert!(credentials.key_id_command.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn no_plaintext_keys_in_config() -> Result<()> {
+        let c = Configuration {
+            version: VERSION,
+            pack_size: crate::pack::DEFAULT_PACK_SIZE,
+            compression: Default::default(),
+            chunking: Default::default(),
+            hash: Default::default(),
+            parity: None,
+            kind: Kind::Backblaze {
+                credentials: Credentials {
+                    key_id_command: Some("pass show b2/id".to_owned()),
+                    credentials_file: Some("b2.toml".into()),
+                    ..Default::default()
+                },
+                bucket: "b".to_owned(),
+                prefix: None,
+                concurrent_connections: 4,
+                retries: Retries::default(),
+                abandoned_upload_hours: defabandoned(),
+                auth_url: None,
+            },
+            filter: None,
+        };
+        let mut buf = vec![];
+        write_config(&mut buf, c)?;
+        let s = String::from_utf8(buf)?;
+        assert!(!s.contains("key_id ="));
+        assert!(!s.contains("application_key ="));
+
+        let cf: ConfigFile = toml::from_str(&s)?;
+        let Kind::Backblaze { credentials, .. } = &cf.kind else {
+            panic!("Not a B2 config");
+        };
+        assert_eq!(
+            credentials.key_id_command.as_deref(),
+            Some("pass show b2/id")
+        );
+        assert_eq!(
+            credentials.credentials_file.as_deref(),
+            Some("b2.toml".into())
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn secret_precedence() -> Result<()> {
+        const NO_ENV: &str = "BACKPAK_TEST_VARIABLE_THAT_DOES_NOT_EXIST";
+        let from_command = resolve_secret(
+            "test",
+            NO_ENV,
+            Some("printf 'hunter2\\nextra'"),
+            Some("from file".to_owned()),
+            Some("plaintext"),
+        )?;
+        assert_eq!(from_command, "hunter2");
+
+        let from_file = resolve_secret(
+            "test",
+            NO_ENV,
+            None,
+            Some("from file".to_owned()),
+            Some("plaintext"),
+        )?;
+        assert_eq!(from_file, "from file");
+
+        // The environment is just a fallback (and $PATH is surely set).
+        let path = std::env::var("PATH")?;
+        let from_config = resolve_secret("test", "PATH", None, None, Some("plaintext"))?;
+        assert_eq!(from_config, "plaintext");
+        assert_eq!(resolve_secret("test", "PATH", None, None, None)?, path);
+
+        assert!(resolve_secret("test", NO_ENV, Some("false"), None, None).is_err());
+        assert!(resolve_secret("test", NO_ENV, None, None, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn credentials_file_permissions() -> Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir()?;
+        let path = Utf8PathBuf::try_from(dir.path().join("b2.toml"))?;
+        fs::write(&path, "key_id = \"id\"\napplication_key = \"key\"\n")?;
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644))?;
+        assert!(read_credentials_file(&path).is_err());
+
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o600))?;
+        let c = read_credentials_file(&path)?;
+        assert_eq!(c.key_id.as_deref(), Some("id"));
+        assert_eq!(c.application_key.as_deref(), Some("key"));
+        Ok(())
+    }
+
+    #[test]
+    fn backoff_bounds() {
+        let r = Retries {
+            max_attempts: 20,
+            max_backoff_secs: 30,
+        };
+        let secs = |attempt, jitter| backoff(&r, attempt, jitter).as_secs_f64();
+        assert_eq!(secs(1, 0.0), 0.5);
+        assert_eq!(secs(1, 0.999), 0.9995);
+        assert_eq!(secs(3, 0.0), 2.0);
+        assert_eq!(secs(5, 1.0), 16.0);
+        // Capped
+        assert_eq!(secs(6, 1.0), 30.0);
+        assert_eq!(secs(100, 0.0), 15.0);
+
+        for _ in 0..100 {
+            let j = jitter();
+            assert!((0.0..1.0).contains(&j));
+        }
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(normalize_prefix(""), "");
+        assert_eq!(normalize_prefix("/"), "");
+        assert_eq!(normalize_prefix("foo"), "foo/");
+        assert_eq!(normalize_prefix("/foo/bar/"), "foo/bar/");
+    }
+
+    #[test]
+    fn part_sizes() {
+        assert_eq!(
+            parts(250, 100).collect::<Vec<_>>(),
+            [(1, 100), (2, 100), (3, 50)]
+        );
+        assert_eq!(parts(200, 100).collect::<Vec<_>>(), [(1, 100), (2, 100)]);
+        assert_eq!(parts(101, 100).collect::<Vec<_>>(), [(1, 100), (2, 1)]);
     }
 }
@@ -17,15 +17,30 @@ fn create_dir(d: &Utf8Path) -> Result<()> {
     fs::create_dir(d).with_context(|| format!("Couldn't create {d}"))
 }
 
+/// Repositories from before we had some type of file (e.g., dictionaries)
+/// won't have a directory for it yet.
+pub fn ensure_parent(p: &Utf8Path) -> Result<()> {
+    let parent = p.parent().unwrap();
+    if !parent.exists() {
+        create_dir(parent)?;
+    }
+    Ok(())
+}
+
 #[inline]
 fn ensure_exists(e: &Utf8Path) -> Result<()> {
     ensure!(e.exists(), "{e} doesn't exist");
     Ok(())
 }
 
+#[expect(clippy::too_many_arguments)]
 pub fn initialize(
     repository: &Utf8Path,
     pack_size: Byte,
+    compression: pack::Compression,
+    chunking: chunk::Chunking,
+    hash: hashing::Algorithm,
+    parity: Option<parity::Parity>,
     filter: Option<(String, String)>,
     force_cache: bool,
 ) -> Result<()> {
@@ -44,9 +59,15 @@ pub fn initialize(
     create_dir(&repository.join("packs"))?;
     create_dir(&repository.join("indexes"))?;
     create_dir(&repository.join("snapshots"))?;
+    create_dir(&repository.join("dictionaries"))?;
 
     let c = super::Configuration {
+        version: super::VERSION,
         pack_size,
+        compression,
+        chunking,
+        hash,
+        parity,
         kind: super::Kind::Filesystem { force_cache },
         filter,
     };
@@ -84,8 +105,9 @@ impl Backend for FilesystemBackend {
         ))
     }
 
-    fn write(&self, _len: u64, from: &mut (dyn Read + Send), to: &str) -> Result<()> {
+    fn write(&self, _len: u64, from: &mut dyn SeekableRead, to: &str) -> Result<()> {
         let to = self.path_of(to);
+        ensure_parent(&to)?;
         file_util::safe_copy_to_file(from, &to)?;
         Ok(())
     }
@@ -102,6 +124,11 @@ impl Backend for FilesystemBackend {
         if prefix.is_file() {
             return Ok(vec![(prefix.to_string(), prefix.metadata()?.len())]);
         }
+        // Like an empty prefix in a bucket;
+        // see ensure_parent() for repositories that predate some file type.
+        if !prefix.exists() {
+            return Ok(vec![]);
+        }
 
         let str_and_len = |(p, len): &(Utf8PathBuf, u64)| -> Result<(String, u64)> {
             let s = p.strip_prefix(&self.base_directory).unwrap().to_string();
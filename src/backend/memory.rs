@@ -11,15 +11,25 @@ use rustc_hash::FxHashMap;
 /// Great for testing
 pub struct MemoryBackend {
     files: Mutex<FxHashMap<String, Vec<u8>>>,
+    /// Everything written, in order, so tests can check what went up when.
+    #[cfg(test)]
+    written: Mutex<Vec<String>>,
 }
 
 impl MemoryBackend {
     pub fn new() -> Self {
         Self {
             files: Mutex::new(FxHashMap::default()),
+            #[cfg(test)]
+            written: Mutex::new(Vec::new()),
         }
     }
 
+    #[cfg(test)]
+    pub fn written(&self) -> Vec<String> {
+        self.written.lock().unwrap().clone()
+    }
+
     // Cursor is also seek - expose that to `CachedBackend`
     pub fn read_cursor(&self, from: &str) -> Result<Cursor<Vec<u8>>> {
         let buf: Vec<u8> = self
@@ -44,10 +54,12 @@ impl Backend for MemoryBackend {
         Ok(Box::new(self.read_cursor(from)?))
     }
 
-    fn write(&self, _len: u64, from: &mut (dyn Read + Send), to: &str) -> Result<()> {
+    fn write(&self, _len: u64, from: &mut dyn SeekableRead, to: &str) -> Result<()> {
         let mut vec = Vec::new();
         io::copy(from, &mut vec)?;
         self.files.lock().unwrap().insert(to.to_owned(), vec);
+        #[cfg(test)]
+        self.written.lock().unwrap().push(to.to_owned());
         Ok(())
     }
 
@@ -62,7 +62,8 @@ use tracing::*;
 use crate::backend;
 use crate::blob::Blob;
 use crate::concurrently::named_concurrently;
-use crate::hashing::ObjectId;
+use crate::dictionary;
+use crate::hashing::{self, ObjectId};
 use crate::index;
 use crate::pack;
 use crate::upload;
@@ -76,6 +77,8 @@ pub struct Backup<'scope, 'env> {
     pub chunk_tx: SyncSender<Blob>,
     pub tree_tx: SyncSender<Blob>,
     pub upload_tx: SyncSender<(String, File)>,
+    /// What new trees should be hashed with (the repository's hash)
+    pub hash: hashing::Algorithm,
     pub statistics: &'env BackupStatistics,
     threads: thread::ScopedJoinHandle<'scope, ()>,
 }
@@ -153,6 +156,7 @@ pub fn spawn_backup_threads<'scope, 'env>(
         chunk_tx,
         tree_tx,
         upload_tx,
+        hash: backend_config.hash,
         statistics,
         threads,
     }
@@ -180,6 +184,8 @@ fn backup_master_thread<'env>(
     let tree_pack_upload_tx = chunk_pack_upload_tx.clone();
     let index_upload_tx = chunk_pack_upload_tx.clone();
     let pack_size = backend_config.pack_size;
+    let compression = backend_config.compression;
+    let hash = backend_config.hash;
 
     let chunk_bytes = &statistics.chunk_bytes;
     let tree_bytes = &statistics.tree_bytes;
@@ -190,12 +196,16 @@ fn backup_master_thread<'env>(
         Mode::LiveFire => upload::Mode::LiveFire,
         Mode::DryRun => upload::Mode::DryRun,
     };
+    #[expect(clippy::type_complexity)]
     let jobs: [(&str, Box<dyn FnOnce() -> Result<()> + Send>); 4] = [
         (
             "chunk packer",
             Box::new(move || {
                 pack::pack(
                     pack_size,
+                    compression,
+                    hash,
+                    None,
                     chunk_rx,
                     chunk_index_tx,
                     chunk_pack_upload_tx,
@@ -207,8 +217,15 @@ fn backup_master_thread<'env>(
         (
             "tree packer",
             Box::new(move || {
+                let dictionary = compression
+                    .tree_dictionary
+                    .map(|id| dictionary::load(&id, cached_backend))
+                    .transpose()?;
                 pack::pack(
                     pack_size,
+                    compression,
+                    hash,
+                    dictionary,
                     tree_rx,
                     tree_index_tx,
                     tree_pack_upload_tx,
@@ -227,6 +244,8 @@ fn backup_master_thread<'env>(
                 };
                 index::index(
                     resumable,
+                    compression,
+                    hash,
                     starting_index,
                     index_rx,
                     index_upload_tx,
@@ -259,7 +278,7 @@ pub struct ResumableBackup {
 /// Prune will want to be more careful, since it's destructive.
 /// (Is the set of superseded packs the same? Are the packs to keep the same? Else chicken out.)
 pub fn find_resumable(backend: &backend::CachedBackend) -> Result<Option<ResumableBackup>> {
-    let wip_index = match index::read_wip()? {
+    let wip_index = match index::read_wip(backend.hash())? {
         Some(i) => i,
         None => {
             trace!("No WIP index file found, nothing to resume");
@@ -1,18 +1,205 @@
 //! Cut files into content-based chunks.
 
+use std::borrow::Cow;
 use std::sync::{Arc, mpsc};
 use std::thread;
 
-use anyhow::{Context, Result};
+use anyhow::{Context, Result, ensure};
+use byte_unit::Byte;
 use camino::Utf8Path;
-use fastcdc::v2020::{Chunk, FastCDC};
+use fastcdc::v2020::{self as cdc, Chunk};
 use ouroboros::self_referencing;
+use serde_derive::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 
 use crate::blob::{self, Blob};
 use crate::file_util::{self, LoadedFile};
-use crate::hashing::ObjectId;
+use crate::hashing;
 
-/// Cuts a file into content-based chunks between 512kiB and 8MiB, aiming for 1MiB.
+/// How a repository cuts files into chunks.
+///
+/// Changing any of this changes the ID of (almost) every chunk,
+/// so it's picked once, at `backpak init`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Chunking {
+    pub min_size: Byte,
+    pub target_size: Byte,
+    pub max_size: Byte,
+    /// Key for a gear table of our own, so that chunk sizes
+    /// (which anyone holding the packs can see, encrypted or not)
+    /// don't give away which well-known files we backed up.
+    /// `None` uses FastCDC's public table, like repositories made before this existed.
+    #[serde(skip_serializing_if = "Option::is_none", with = "hex_seed")]
+    pub seed: Option<[u8; 32]>,
+}
+
+/// Chunk sizes similar to Restic's; see [`chunk_file()`]
+impl Default for Chunking {
+    fn default() -> Self {
+        Self {
+            min_size: Byte::from_u64(512 * 1024),
+            target_size: Byte::from_u64(1024 * 1024),
+            max_size: Byte::from_u64(8 * 1024 * 1024),
+            seed: None,
+        }
+    }
+}
+
+/// TOML doesn't do byte arrays, and its integers are signed 64-bit.
+mod hex_seed {
+    use data_encoding::HEXLOWER;
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    pub fn serialize<S: Serializer>(seed: &Option<[u8; 32]>, s: S) -> Result<S::Ok, S::Error> {
+        match seed {
+            Some(seed) => s.serialize_some(&HEXLOWER.encode(seed)),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<[u8; 32]>, D::Error> {
+        let s: Option<String> = Option::deserialize(d)?;
+        s.map(|s| {
+            let bytes = HEXLOWER.decode(s.as_bytes()).map_err(D::Error::custom)?;
+            <[u8; 32]>::try_from(bytes)
+                .map_err(|_| D::Error::custom("chunking seed should be 32 bytes of hex"))
+        })
+        .transpose()
+    }
+}
+
+impl Chunking {
+    /// Settings for a new repository: the given target size (or the default),
+    /// with minimum and maximum sizes in the same proportion as the defaults,
+    /// and a fresh random seed.
+    pub fn new_repository(target_size: Option<Byte>) -> Result<Self> {
+        let mut c = Self::default();
+        if let Some(t) = target_size {
+            let t = t.as_u64();
+            c = Self {
+                min_size: Byte::from_u64(t / 2),
+                target_size: Byte::from_u64(t),
+                max_size: Byte::from_u64(t.saturating_mul(8)),
+                ..c
+            };
+        }
+        let mut seed = [0u8; 32];
+        getrandom::getrandom(&mut seed).context("Couldn't generate a chunking seed")?;
+        c.seed = Some(seed);
+        c.validate()?;
+        Ok(c)
+    }
+
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let (min, target, max) = self.sizes();
+        ensure!(
+            (cdc::MINIMUM_MIN..=cdc::MINIMUM_MAX).contains(&min),
+            "Minimum chunk size must be between {} and {}",
+            Byte::from_u64(cdc::MINIMUM_MIN as u64),
+            Byte::from_u64(cdc::MINIMUM_MAX as u64),
+        );
+        ensure!(
+            (cdc::AVERAGE_MIN..=cdc::AVERAGE_MAX).contains(&target),
+            "Target chunk size must be between {} and {}",
+            Byte::from_u64(cdc::AVERAGE_MIN as u64),
+            Byte::from_u64(cdc::AVERAGE_MAX as u64),
+        );
+        ensure!(
+            (cdc::MAXIMUM_MIN..=cdc::MAXIMUM_MAX).contains(&max),
+            "Maximum chunk size must be between {} and {}",
+            Byte::from_u64(cdc::MAXIMUM_MIN as u64),
+            Byte::from_u64(cdc::MAXIMUM_MAX as u64),
+        );
+        ensure!(
+            min <= target && target <= max,
+            "Chunk sizes should go minimum <= target <= maximum"
+        );
+        Ok(())
+    }
+
+    fn sizes(&self) -> (usize, usize, usize) {
+        (
+            self.min_size.as_u64() as usize,
+            self.target_size.as_u64() as usize,
+            self.max_size.as_u64() as usize,
+        )
+    }
+}
+
+/// [`Chunking`] settings, ready to cut with,
+/// and the repository's hash to name the chunks with.
+///
+/// Cheap to clone; chunkers for big files share it with a cutting thread.
+#[derive(Clone)]
+pub struct Chunker(Arc<ChunkerInner>);
+
+struct ChunkerInner {
+    min_size: usize,
+    target_size: usize,
+    max_size: usize,
+    mask_s: u64,
+    mask_l: u64,
+    gear: Cow<'static, [u64]>,
+    gear_ls: Cow<'static, [u64]>,
+    hash: hashing::Algorithm,
+}
+
+impl Chunker {
+    pub fn new(c: &Chunking, hash: hashing::Algorithm) -> Self {
+        let (min_size, target_size, max_size) = c.sizes();
+        // Same masks as FastCDC::new(), which normalizes at level 1.
+        let bits = (target_size as f64).log2().round() as usize;
+        let mask_s = cdc::MASKS[bits + 1];
+        let mask_l = cdc::MASKS[bits - 1];
+        let (gear, gear_ls) = match &c.seed {
+            None => cdc::get_gear_with_seed(0),
+            Some(seed) => {
+                // FastCDC's own seeding just XORs every entry with the same value,
+                // which doesn't hide much. Derive each entry from the key instead.
+                let gear: Vec<u64> = (0..=255u8)
+                    .map(|i| {
+                        let h = Sha256::new()
+                            .chain_update(seed)
+                            .chain_update([i])
+                            .finalize();
+                        u64::from_le_bytes(h[..8].try_into().unwrap())
+                    })
+                    .collect();
+                let gear_ls = gear.iter().map(|g| g << 1).collect::<Vec<_>>();
+                (Cow::Owned(gear), Cow::Owned(gear_ls))
+            }
+        };
+        Self(Arc::new(ChunkerInner {
+            min_size,
+            target_size,
+            max_size,
+            mask_s,
+            mask_l,
+            gear,
+            gear_ls,
+            hash,
+        }))
+    }
+
+    pub fn hash(&self) -> hashing::Algorithm {
+        self.0.hash
+    }
+
+    fn cuts<'a>(&self, source: &'a [u8]) -> Cuts<'a> {
+        Cuts {
+            chunker: self.clone(),
+            source,
+            processed: 0,
+        }
+    }
+}
+
+/// Cuts a file into content-based chunks, by default between 512kiB and 8MiB, aiming for 1MiB.
 ///
 /// Duplicati makes a convincing argument that heavyweight attempts to
 /// deduplicate data at the chunk level (as opposed to the file level) isn't
@@ -37,17 +224,62 @@ use crate::hashing::ObjectId;
 /// ASAP.
 ///
 /// See <https://crates.io/crates/fastcdc>
-pub fn chunk_file<P: AsRef<Utf8Path>>(path: P) -> Result<impl Iterator<Item = Blob>> {
+pub fn chunk_file<P: AsRef<Utf8Path>>(
+    path: P,
+    chunker: &Chunker,
+) -> Result<impl Iterator<Item = Blob> + use<P>> {
     let path: &Utf8Path = path.as_ref();
     let file = file_util::read_file(path).with_context(|| format!("Couldn't read {path}"))?;
-    Ok(ChunkIterator::new(file))
+    Ok(chunk_loaded(file, chunker))
+}
+
+/// Like [`chunk_file()`], for a file we've already loaded
+pub fn chunk_loaded(
+    file: Arc<LoadedFile>,
+    chunker: &Chunker,
+) -> impl Iterator<Item = Blob> + use<> {
+    ChunkIterator::new(file, chunker.clone())
+}
+
+/// [`cdc::FastCDC`], but with our own gear tables
+struct Cuts<'a> {
+    chunker: Chunker,
+    source: &'a [u8],
+    processed: usize,
 }
 
-fn new_cdc(src: &[u8]) -> FastCDC<'_> {
-    const MIN_SIZE: usize = 1024 * 512;
-    const TARGET_SIZE: usize = 1024 * 1024;
-    const MAX_SIZE: usize = 1024 * 1024 * 8;
-    FastCDC::new(src, MIN_SIZE, TARGET_SIZE, MAX_SIZE)
+impl Iterator for Cuts<'_> {
+    type Item = Chunk;
+
+    fn next(&mut self) -> Option<Chunk> {
+        let c = &self.chunker.0;
+        let rest = &self.source[self.processed..];
+        if rest.is_empty() {
+            return None;
+        }
+        let (hash, length) = cdc::cut_gear(
+            rest,
+            c.min_size,
+            c.target_size,
+            c.max_size,
+            c.mask_s,
+            c.mask_l,
+            c.mask_s << 1,
+            c.mask_l << 1,
+            &c.gear,
+            &c.gear_ls,
+        );
+        if length == 0 {
+            return None;
+        }
+        let offset = self.processed;
+        self.processed += length;
+        Some(Chunk {
+            hash,
+            offset,
+            length,
+        })
+    }
 }
 
 /// For small files, use a simple iterator that just wraps the file and FastCDC iterator.
@@ -58,11 +290,11 @@ enum ChunkIterator {
 }
 
 impl ChunkIterator {
-    fn new(file: Arc<LoadedFile>) -> Self {
+    fn new(file: Arc<LoadedFile>, chunker: Chunker) -> Self {
         // "small" is decided by whether we read or memory-mapped the file in `read_file()`
         match *file {
-            LoadedFile::Buffered(_) => ChunkIterator::Simple(SmallFileChunker::from(file)),
-            LoadedFile::Mapped(_) => ChunkIterator::Threaded(ThreadedChunker::from(file)),
+            LoadedFile::Buffered(_) => ChunkIterator::Simple(SmallFileChunker::from(file, chunker)),
+            LoadedFile::Mapped(_) => ChunkIterator::Threaded(ThreadedChunker::from(file, chunker)),
         }
     }
 }
@@ -85,15 +317,15 @@ struct SmallFileChunker {
     file: Arc<LoadedFile>,
     #[borrows(file)]
     #[not_covariant]
-    chunker: FastCDC<'this>,
+    cuts: Cuts<'this>,
 }
 
 impl SmallFileChunker {
-    fn from(file: Arc<LoadedFile>) -> Self {
+    fn from(file: Arc<LoadedFile>, chunker: Chunker) -> Self {
         assert!(matches!(*file, LoadedFile::Buffered(_)));
         SmallFileChunkerBuilder {
             file,
-            chunker_builder: |f: &Arc<LoadedFile>| new_cdc(f.bytes()),
+            cuts_builder: |f: &Arc<LoadedFile>| chunker.cuts(f.bytes()),
         }
         .build()
     }
@@ -103,14 +335,19 @@ impl Iterator for SmallFileChunker {
     type Item = Blob;
 
     fn next(&mut self) -> Option<Self::Item> {
-        self.with_mut(|s| s.chunker.next().map(|c| chunk_to_blob(s.file.clone(), c)))
+        self.with_mut(|s| {
+            let hash = s.cuts.chunker.hash();
+            s.cuts
+                .next()
+                .map(|c| chunk_to_blob(s.file.clone(), c, hash))
+        })
     }
 }
 
 struct ThreadedChunker(mpsc::IntoIter<Blob>);
 
 impl ThreadedChunker {
-    fn from(file: Arc<LoadedFile>) -> Self {
+    fn from(file: Arc<LoadedFile>, chunker: Chunker) -> Self {
         assert!(matches!(*file, LoadedFile::Mapped(_)));
         // Arbitrary-sized channels, but bust our usual "no buffering" rule -
         // the code that calls `chunk_file()` is only doing this once at a time,
@@ -118,8 +355,9 @@ impl ThreadedChunker {
         let (cuts_tx, cuts_rx) = mpsc::sync_channel(128);
         let (blobs_tx, blobs_rx) = mpsc::sync_channel(128);
         let file2 = file.clone();
+        let hash = chunker.hash();
         thread::spawn(move || {
-            for cut in new_cdc(file.bytes()) {
+            for cut in chunker.cuts(file.bytes()) {
                 if cuts_tx.send(cut).is_err() {
                     break;
                 }
@@ -127,7 +365,10 @@ impl ThreadedChunker {
         });
         thread::spawn(move || {
             while let Ok(cut) = cuts_rx.recv() {
-                if blobs_tx.send(chunk_to_blob(file2.clone(), cut)).is_err() {
+                if blobs_tx
+                    .send(chunk_to_blob(file2.clone(), cut, hash))
+                    .is_err()
+                {
                     break;
                 }
             }
@@ -159,12 +400,12 @@ impl AsRef<[u8]> for FileSpan {
     }
 }
 
-fn chunk_to_blob(file: Arc<LoadedFile>, chunk: Chunk) -> Blob {
+fn chunk_to_blob(file: Arc<LoadedFile>, chunk: Chunk, hash: hashing::Algorithm) -> Blob {
     let start = chunk.offset;
     let end = chunk.offset + chunk.length;
     let span = FileSpan { file, start, end };
 
-    let id = ObjectId::hash(span.as_ref());
+    let id = hash.hash(span.as_ref());
 
     // trace!("{}: [{}..{}] {}", path, start, end, id);
 
@@ -181,7 +422,8 @@ mod test {
 
     #[test]
     fn smoke() -> Result<()> {
-        let chunked: Vec<_> = chunk_file("tests/references/sr71.txt")?.collect();
+        let chunker = Chunker::new(&Chunking::default(), hashing::Algorithm::Sha224);
+        let chunked: Vec<_> = chunk_file("tests/references/sr71.txt", &chunker)?.collect();
         assert_eq!(chunked.len(), 1);
 
         let chunked = &chunked[0];
@@ -192,4 +434,61 @@ mod test {
         );
         Ok(())
     }
+
+    #[test]
+    fn keyed_gear() -> Result<()> {
+        // Something that doesn't cut the same way everywhere
+        let mut x = 0x9e3779b97f4a7c15u64;
+        let noise: Vec<u8> = (0..256 * 1024)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        let small = Chunking {
+            min_size: Byte::from_u64(1024),
+            target_size: Byte::from_u64(4096),
+            max_size: Byte::from_u64(16384),
+            seed: None,
+        };
+        small.validate()?;
+        let cut = |c: &Chunking| -> Vec<(usize, usize)> {
+            Chunker::new(c, hashing::Algorithm::default())
+                .cuts(&noise)
+                .map(|c| (c.offset, c.length))
+                .collect()
+        };
+
+        // No seed cuts exactly like FastCDC always has, so old repositories dedupe.
+        let fastcdc: Vec<_> = cdc::FastCDC::new(&noise, 1024, 4096, 16384)
+            .map(|c| (c.offset, c.length))
+            .collect();
+        assert_eq!(cut(&small), fastcdc);
+
+        let keyed = Chunking {
+            seed: Some([1; 32]),
+            ..small
+        };
+        let other_key = Chunking {
+            seed: Some([2; 32]),
+            ..small
+        };
+        assert_eq!(cut(&keyed), cut(&keyed));
+        assert_ne!(cut(&keyed), fastcdc);
+        assert_ne!(cut(&keyed), cut(&other_key));
+        assert_eq!(
+            cut(&keyed).iter().map(|(_, l)| l).sum::<usize>(),
+            noise.len()
+        );
+
+        // Seeds make it through the config.
+        let fresh = Chunking::new_repository(None)?;
+        assert!(fresh.seed.is_some());
+        let round_trip: Chunking = toml::from_str(&toml::to_string(&fresh)?)?;
+        assert_eq!(fresh, round_trip);
+        assert!(Chunking::new_repository(Some(Byte::from_u64(10))).is_err());
+        Ok(())
+    }
 }
@@ -38,20 +38,23 @@ where
     })
 }
 
+/// Run each of the given functions in its own named thread.
+/// If one fails, kill the program instead of waiting around.
+///
+/// Unlike the others, this *doesn't* limit concurrency to the number of CPUs:
+/// it's for pipeline stages that talk to each other over channels,
+/// and would deadlock if some weren't running.
 pub fn named_concurrently<S, F, I>(funs: I)
 where
     S: Into<String>,
     F: FnOnce() -> Result<()> + Send,
     I: Iterator<Item = (S, F)>,
 {
-    let conc = atomic_cpus();
     thread::scope(|s| {
         for (n, f) in funs {
-            let sem = semaphored::dec(&conc);
             thread::Builder::new()
                 .name(n.into())
                 .spawn_scoped(s, || {
-                    let _sem = sem;
                     if let Err(e) = f() {
                         fatal(e);
                     }
@@ -1,6 +1,7 @@
+use std::collections::BTreeMap;
 use std::{fs, io};
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result, anyhow, ensure};
 use byte_unit::Byte;
 use camino::Utf8PathBuf;
 use serde_derive::Deserialize;
@@ -18,6 +19,105 @@ fn defcachesize() -> Byte {
 pub struct BackupConfiguration {
     #[serde(default)]
     pub dereference: bool,
+
+    #[serde(default)]
+    pub hooks: BackupHooks,
+}
+
+/// Shell commands to run around a backup, e.g., to snapshot a filesystem first.
+#[derive(Debug, Default, Clone, Deserialize)]
+pub struct BackupHooks {
+    /// Run before anything else; the backup is aborted if it fails.
+    pub pre: Option<String>,
+    /// Run after the backup finishes.
+    pub post: Option<String>,
+    /// Run if the backup (or any other hook) fails.
+    pub on_error: Option<String>,
+}
+
+/// A named backup for `backpak run`, so you don't have to type it all out every time.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub repository: Utf8PathBuf,
+
+    pub paths: Vec<Utf8PathBuf>,
+
+    /// Added to the global skips
+    #[serde(default)]
+    pub skips: Vec<String>,
+
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    pub author: Option<String>,
+
+    /// Forget snapshots of these paths after backing up
+    pub retention: Option<Retention>,
+
+    /// Prune after backing up (and forgetting)
+    #[serde(default)]
+    pub prune: bool,
+
+    /// Replaces any `[backup.hooks]`
+    pub hooks: Option<BackupHooks>,
+}
+
+/// How many snapshots to keep, newest first.
+///
+/// Each `keep_<period>` keeps the newest snapshot of that many distinct periods.
+/// A snapshot is kept if any rule wants it.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Retention {
+    pub keep_last: Option<usize>,
+    pub keep_daily: Option<usize>,
+    pub keep_weekly: Option<usize>,
+    pub keep_monthly: Option<usize>,
+    pub keep_yearly: Option<usize>,
+}
+
+impl Retention {
+    /// Make sure we don't have rules that keep nothing at all,
+    /// which would forget every snapshot.
+    pub fn validate(&self) -> Result<()> {
+        let rules = [
+            self.keep_last,
+            self.keep_daily,
+            self.keep_weekly,
+            self.keep_monthly,
+            self.keep_yearly,
+        ];
+        ensure!(
+            rules.iter().all(Option::is_none) || rules.iter().any(|r| r.is_some_and(|n| n > 0)),
+            "Every keep_* rule is zero, which would forget every snapshot"
+        );
+        Ok(())
+    }
+}
+
+/// Bandwidth limits (per second) for talking to the repository
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BandwidthConfiguration {
+    pub upload: Option<Byte>,
+    pub download: Option<Byte>,
+
+    #[serde(default)]
+    pub schedule: Vec<BandwidthWindow>,
+}
+
+/// Different limits for part of the day, e.g., work hours.
+/// Directions left unset keep their usual limit.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BandwidthWindow {
+    /// Local time, inclusive
+    pub start: jiff::civil::Time,
+    /// Local time, exclusive. Can be before `start` to wrap around midnight.
+    pub end: jiff::civil::Time,
+    pub upload: Option<Byte>,
+    pub download: Option<Byte>,
 }
 
 #[derive(Debug, Default, Deserialize)]
@@ -38,6 +138,26 @@ pub struct Configuration {
 
     #[serde(default)]
     pub restore: RestoreConfiguration,
+
+    #[serde(default)]
+    pub bandwidth: BandwidthConfiguration,
+
+    #[serde(default, rename = "profile")]
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+impl Configuration {
+    pub fn profile(&self, name: &str) -> Result<&Profile> {
+        let p = self
+            .profiles
+            .get(name)
+            .ok_or_else(|| anyhow!("No [profile.{name}] in the config file"))?;
+        if let Some(r) = &p.retention {
+            r.validate()
+                .with_context(|| format!("Bad retention in [profile.{name}]"))?;
+        }
+        Ok(p)
+    }
 }
 
 impl Default for Configuration {
@@ -47,6 +167,8 @@ impl Default for Configuration {
             skips: vec![],
             backup: Default::default(),
             restore: Default::default(),
+            bandwidth: Default::default(),
+            profiles: Default::default(),
         }
     }
 }
@@ -37,15 +37,20 @@ pub fn add(to: Op, amount: usize) {
     COUNTER_MAP[to].fetch_add(amount, Ordering::Relaxed);
 }
 
-pub fn log_counts() {
+/// Everything we did at least once, and how many times we did it.
+pub fn counts() -> Vec<(Op, usize)> {
     // Probably not needed; but we're probably calling this once at program exit.
     fence(Ordering::SeqCst);
 
-    let counts = COUNTER_MAP
+    COUNTER_MAP
         .iter()
         .map(|(k, v)| (k, v.load(Ordering::Relaxed)))
         .filter(|(_k, v)| *v > 0) // Ignore things we didn't do
-        .collect::<Vec<_>>();
+        .collect()
+}
+
+pub fn log_counts() {
+    let counts = counts();
 
     if counts.is_empty() {
         return;
@@ -0,0 +1,96 @@
+//! Zstd dictionaries, trained on a repository's trees
+//!
+//! Trees are small CBOR maps with the same handful of keys
+//! (`chunks`, `metadata`, `mode`, `uid`, `mtime`...) over and over.
+//! zstd does what it can with them, but gets a big head start from a dictionary
+//! trained on trees we've already backed up.
+//!
+//! A dictionary file contains:
+//! 1. Magic bytes
+//! 2. The dictionary, as trained by zstd.
+//!
+//! The hash of the dictionary is its ID.
+//! The repository config names the dictionary to compress new tree packs with,
+//! and each pack's header names the one it was compressed with (if any).
+
+use std::fs::File;
+use std::io::prelude::*;
+use std::sync::{Arc, LazyLock, Mutex};
+
+use anyhow::{Context, Result, ensure};
+use rustc_hash::FxHashMap;
+use tracing::*;
+
+use crate::backend;
+use crate::file_util::check_magic;
+use crate::hashing::{self, ObjectId};
+
+const MAGIC_BYTES: &[u8] = b"MKBAKDCT1";
+
+/// zstd's own default (110 KB), which is plenty for a bunch of trees
+pub const DEFAULT_SIZE: usize = 112_640;
+
+#[derive(Debug)]
+pub struct Dictionary {
+    pub id: ObjectId,
+    pub bytes: Vec<u8>,
+}
+
+/// Train a dictionary from the given samples (presumably trees).
+pub fn train<S: AsRef<[u8]>>(
+    samples: &[S],
+    max_size: usize,
+    hash: hashing::Algorithm,
+) -> Result<Dictionary> {
+    let bytes = zstd::dict::from_samples(samples, max_size).with_context(|| {
+        format!(
+            "Couldn't train a dictionary from {} samples (not enough trees yet?)",
+            samples.len()
+        )
+    })?;
+    let id = hash.hash(&bytes);
+    Ok(Dictionary { id, bytes })
+}
+
+/// Upload a dictionary to the backend.
+pub fn upload(dictionary: &Dictionary, backend: &backend::CachedBackend) -> Result<()> {
+    let mut fh = tempfile::Builder::new()
+        .prefix("temp-backpak-")
+        .suffix(".dictionary")
+        .tempfile_in(".") // TODO: Configurable?
+        .context("Couldn't open temporary dictionary for writing")?;
+    fh.write_all(MAGIC_BYTES)?;
+    fh.write_all(&dictionary.bytes)?;
+    fh.as_file().sync_all()?;
+
+    let dictionary_name = format!("{}.dictionary", dictionary.id);
+    let persisted: File = fh
+        .persist(&dictionary_name)
+        .with_context(|| format!("Couldn't persist finished dictionary {dictionary_name}"))?;
+    backend.write(&dictionary_name, persisted)
+}
+
+/// Dictionaries are small and we use the same one over and over; load each once.
+static LOADED: LazyLock<Mutex<FxHashMap<ObjectId, Arc<Dictionary>>>> =
+    LazyLock::new(|| Mutex::new(FxHashMap::default()));
+
+/// Loads the dictionary with the given ID from the backend,
+/// verifying its contents match its ID.
+pub fn load(id: &ObjectId, cached_backend: &backend::CachedBackend) -> Result<Arc<Dictionary>> {
+    if let Some(d) = LOADED.lock().unwrap().get(id) {
+        return Ok(d.clone());
+    }
+    debug!("Loading dictionary {id}");
+    let mut fh = cached_backend.read_dictionary(id)?;
+    check_magic(&mut fh, MAGIC_BYTES).context("Wrong magic bytes for dictionary file")?;
+    let mut bytes = vec![];
+    fh.read_to_end(&mut bytes)?;
+    let calculated_id = cached_backend.hash().hash(&bytes);
+    ensure!(
+        *id == calculated_id,
+        "Dictionary {id}'s contents changed! Now hashes to {calculated_id}"
+    );
+    let d = Arc::new(Dictionary { id: *id, bytes });
+    LOADED.lock().unwrap().insert(*id, d.clone());
+    Ok(d)
+}
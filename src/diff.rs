@@ -66,7 +66,8 @@ pub trait Callbacks {
 pub fn null_forest() -> &'static (ObjectId, Forest) {
     static NF: LazyLock<(ObjectId, Forest)> = LazyLock::new(|| {
         let empty_tree = Tree::new();
-        let (_, eid) = tree::serialize_and_hash(&empty_tree).unwrap();
+        // We only compare this against itself, so the hash doesn't matter.
+        let (_, eid) = tree::serialize_and_hash(&empty_tree, Default::default()).unwrap();
         let mut empty_forest = Forest::default();
         empty_forest.insert(eid, std::sync::Arc::new(empty_tree));
         (eid, empty_forest)
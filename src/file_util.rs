@@ -41,9 +41,13 @@ impl LoadedFile {
 
 /// Read an entire file if it's small enough; memory map it otherwise.
 pub fn read_file(path: &Utf8Path) -> Result<Arc<LoadedFile>> {
+    load_file(File::open(path)?)
+}
+
+/// Like [`read_file()`], for a file we already have open (at its start)
+pub fn load_file(mut fh: File) -> Result<Arc<LoadedFile>> {
     const MEGA: u64 = 1024 * 1024;
 
-    let mut fh = File::open(path)?;
     let file_length = fh.metadata()?.len();
 
     let file = if file_length < 10 * MEGA {
@@ -178,20 +178,27 @@ where
 
 /// Hashes the forest for the given paths,
 /// reusing chunks from the previous tree when able.
-pub fn forest_from_fs(
+///
+/// Files are cut with the given chunker, which should be the repository's,
+/// or nothing that changed will match.
+/// Paths `filter` returns false for are left out.
+pub fn forest_from_fs<Filter>(
     symlink_behavior: tree::Symlink,
     paths: &BTreeSet<Utf8PathBuf>,
     previous_tree: Option<&ObjectId>,
     previous_forest: &tree::Forest,
-) -> Result<(ObjectId, tree::Forest)> {
-    let mut filter = |_: &Utf8Path| true;
-    fn visit(
-        (tree, forest): &mut (tree::Tree, tree::Forest),
-        path: &Utf8Path,
-        metadata: tree::NodeMetadata,
-        previous_node: Option<&tree::Node>,
-        entry: DirectoryEntry<(ObjectId, tree::Forest)>,
-    ) -> Result<()> {
+    chunker: &chunk::Chunker,
+    filter: &mut Filter,
+) -> Result<(ObjectId, tree::Forest)>
+where
+    Filter: FnMut(&Utf8Path) -> bool,
+{
+    let mut visit = |(tree, forest): &mut (tree::Tree, tree::Forest),
+                     path: &Utf8Path,
+                     metadata: tree::NodeMetadata,
+                     previous_node: Option<&tree::Node>,
+                     entry: DirectoryEntry<(ObjectId, tree::Forest)>|
+     -> Result<()> {
         let node = match entry {
             DirectoryEntry::Directory((subtree, subforest)) => {
                 forest.extend(subforest);
@@ -209,7 +216,7 @@ pub fn forest_from_fs(
                 contents: previous_node.unwrap().contents.clone(),
             },
             DirectoryEntry::ChangedFile => {
-                let chunks = chunk::chunk_file(path)?.map(|c| c.id).collect();
+                let chunks = chunk::chunk_file(path, chunker)?.map(|c| c.id).collect();
                 tree::Node {
                     metadata,
                     contents: tree::NodeContents::File { chunks },
@@ -222,13 +229,11 @@ pub fn forest_from_fs(
             "Duplicate tree entries"
         );
         Ok(())
-    }
+    };
 
     // Turn the tree into its ID and add it to the forest.
-    fn finalize(
-        (tree, mut forest): (tree::Tree, tree::Forest),
-    ) -> Result<(ObjectId, tree::Forest)> {
-        let (_bytes, id) = tree::serialize_and_hash(&tree)?;
+    let mut finalize = |(tree, mut forest): (tree::Tree, tree::Forest)| -> Result<_> {
+        let (_bytes, id) = tree::serialize_and_hash(&tree, chunker.hash())?;
 
         let tree = Arc::new(tree);
 
@@ -236,14 +241,14 @@ pub fn forest_from_fs(
             debug_assert_eq!(*previous, *tree);
         }
         Ok((id, forest))
-    }
+    };
 
     walk_fs(
         symlink_behavior,
         paths,
         previous_tree,
         previous_forest,
-        &mut filter,
+        filter,
         &mut visit,
         &mut finalize,
     )
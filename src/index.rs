@@ -36,8 +36,8 @@ use crate::backend;
 use crate::concurrently::concurrently;
 use crate::counters;
 use crate::file_util::{check_magic, nice_size};
-use crate::hashing::{HashingReader, HashingWriter, ObjectId};
-use crate::pack::{PackManifest, PackMetadata};
+use crate::hashing::{self, HashingReader, HashingWriter, ObjectId};
+use crate::pack::{Compression, PackManifest, PackMetadata};
 
 const MAGIC_BYTES: &[u8] = b"MKBAKIDX1";
 
@@ -77,6 +77,8 @@ pub enum Resumable {
 /// and upload the index files when they reach a sufficient size.
 pub fn index(
     resumable: Resumable,
+    compression: Compression,
+    hash: hashing::Algorithm,
     starting_index: Index,
     rx: Receiver<PackMetadata>,
     to_upload: SyncSender<(String, File)>,
@@ -89,7 +91,7 @@ pub fn index(
     // (For example, it could be an index from `prune` that omits packs
     // we no longer need. If we don't write it but delete those packs anyways...)
     if !index.is_empty() && resumable == Resumable::Yes {
-        persisted = Some(to_temp_file(&index)?);
+        persisted = Some(to_temp_file(&index, compression, hash)?);
     }
 
     // For each pack...
@@ -106,12 +108,12 @@ pub fn index(
             // Rewrite the index every time we get a pack.
             // That way the temp index should always contain a complete list of packs,
             // allowing us to resume a backup from the last finished pack.
-            persisted = Some(to_temp_file(&index)?);
+            persisted = Some(to_temp_file(&index, compression, hash)?);
         }
     }
     // If we haven't been saving a WIP index, write it all out now.
     if !index.is_empty() && resumable == Resumable::No {
-        persisted = Some(to_temp_file(&index)?);
+        persisted = Some(to_temp_file(&index, compression, hash)?);
     }
 
     if let Some((index_id, mut fh)) = persisted {
@@ -151,7 +153,11 @@ pub fn index(
     }
 }
 
-fn to_temp_file(index: &Index) -> Result<(ObjectId, File)> {
+fn to_temp_file(
+    index: &Index,
+    compression: Compression,
+    hash: hashing::Algorithm,
+) -> Result<(ObjectId, File)> {
     // Could we speed things up by reusing the same file handle instead of
     // opening, writing, and closing each time we update the WIP index file?
     // Probably, but we'd have to seek back to the beginning each time,
@@ -163,20 +169,22 @@ fn to_temp_file(index: &Index) -> Result<(ObjectId, File)> {
         .tempfile_in(".")
         .context("Couldn't open temporary index for writing")?;
 
-    let id = to_file(tf.as_file_mut(), index)?;
+    let id = to_file(tf.as_file_mut(), index, compression, hash)?;
     let f = tf
         .persist(WIP_NAME)
         .with_context(|| format!("Couldn't persist WIP index to {}", WIP_NAME))?;
     Ok((id, f))
 }
 
-fn to_file(fh: &mut fs::File, index: &Index) -> Result<ObjectId> {
+fn to_file(
+    fh: &mut fs::File,
+    index: &Index,
+    compression: Compression,
+    hash: hashing::Algorithm,
+) -> Result<ObjectId> {
     fh.write_all(MAGIC_BYTES)?;
 
-    let mut zstd = zstd::stream::write::Encoder::new(fh, 0)?;
-    zstd.multithread(num_cpus::get_physical() as u32)?;
-
-    let mut hasher = HashingWriter::new(zstd);
+    let mut hasher = HashingWriter::new(compression.encoder(fh, None)?, hash);
 
     ciborium::into_writer(index, &mut hasher)?;
 
@@ -323,18 +331,18 @@ pub fn blob_to_size_map(index: &Index) -> Result<FxHashMap<ObjectId, u32>> {
 
 /// Load the index from the given reader,
 /// also returning its calculated ID.
-fn from_reader<R: Read>(r: &mut R) -> Result<(Index, ObjectId)> {
+fn from_reader<R: Read>(r: &mut R, hash: hashing::Algorithm) -> Result<(Index, ObjectId)> {
     check_magic(r, MAGIC_BYTES).context("Wrong magic bytes for index file")?;
 
     let decoder =
         zstd::stream::read::Decoder::new(r).context("Decompression of index file failed")?;
-    let mut hasher = HashingReader::new(decoder);
+    let mut hasher = HashingReader::new(decoder, hash);
     let index = ciborium::from_reader(&mut hasher).context("CBOR decoding of index file failed")?;
     let (id, _) = hasher.finalize();
     Ok((index, id))
 }
 
-pub fn read_wip() -> Result<Option<Index>> {
+pub fn read_wip(hash: hashing::Algorithm) -> Result<Option<Index>> {
     let mut fd = match File::open(WIP_NAME) {
         Ok(w) => w,
         Err(e) => {
@@ -345,15 +353,16 @@ pub fn read_wip() -> Result<Option<Index>> {
             return Err(e);
         }
     };
-    let (index, _) = from_reader(&mut fd)?;
+    let (index, _) = from_reader(&mut fd, hash)?;
     Ok(Some(index))
 }
 
 /// Load the index with the given ID from the backend,
 /// verifying its contents match its ID.
 pub fn load(id: &ObjectId, cached_backend: &backend::CachedBackend) -> Result<Index> {
-    let (index, calculated_id) = from_reader(&mut cached_backend.read_index(id)?)
-        .with_context(|| format!("Couldn't load index {}", id))?;
+    let (index, calculated_id) =
+        from_reader(&mut cached_backend.read_index(id)?, cached_backend.hash())
+            .with_context(|| format!("Couldn't load index {}", id))?;
     ensure!(
         *id == calculated_id,
         "Index {}'s now hashes to {} - Consider running backpak rebuild-index.",
@@ -386,11 +395,13 @@ mod test {
                     blob_type: blob::Type::Chunk,
                     length: 42,
                     id: ObjectId::hash(b"a chunk"),
+                    raw: false,
                 },
                 PackManifestEntry {
                     blob_type: blob::Type::Chunk,
                     length: 9001,
                     id: ObjectId::hash(b"another chunk"),
+                    raw: false,
                 },
             ],
         );
@@ -401,16 +412,19 @@ mod test {
                     blob_type: blob::Type::Tree,
                     length: 182,
                     id: ObjectId::hash(b"first tree"),
+                    raw: false,
                 },
                 PackManifestEntry {
                     blob_type: blob::Type::Tree,
                     length: 22,
                     id: ObjectId::hash(b"second tree"),
+                    raw: false,
                 },
                 PackManifestEntry {
                     blob_type: blob::Type::Tree,
                     length: 11,
                     id: ObjectId::hash(b"third tree"),
+                    raw: false,
                 },
             ],
         );
@@ -449,14 +463,16 @@ mod test {
     #[test]
     fn round_trip() -> Result<()> {
         let index = build_test_index();
-        let mut fh = tempfile()?;
-        let written_id = to_file(&mut fh, &index)?;
+        for hash in [hashing::Algorithm::Sha224, hashing::Algorithm::Blake3] {
+            let mut fh = tempfile()?;
+            let written_id = to_file(&mut fh, &index, Compression::default(), hash)?;
 
-        fh.seek(std::io::SeekFrom::Start(0))?;
-        let (read_index, read_id) = from_reader(&mut fh)?;
+            fh.seek(std::io::SeekFrom::Start(0))?;
+            let (read_index, read_id) = from_reader(&mut fh, hash)?;
 
-        assert_eq!(index, read_index);
-        assert_eq!(written_id, read_id);
+            assert_eq!(index, read_index);
+            assert_eq!(written_id, read_id);
+        }
         Ok(())
     }
 }
@@ -13,6 +13,7 @@ pub mod chunk;
 pub mod concurrently;
 pub mod config;
 pub mod counters;
+pub mod dictionary;
 pub mod diff;
 pub mod file_util;
 pub mod filter;
@@ -20,14 +21,18 @@ pub mod fs_tree;
 pub mod hashing;
 pub mod index;
 pub mod ls;
+pub mod metrics;
 pub mod pack;
+pub mod parity;
 pub mod prettify;
 pub mod progress;
 pub mod rcu;
 pub mod read;
 pub mod repack;
+pub mod restore;
 pub mod semaphored;
 pub mod snapshot;
+pub mod throttle;
 pub mod tree;
 pub mod upload;
 
@@ -75,6 +80,9 @@ pub fn fatal(e: Error) -> ! {
     use rustix::process::{self, Pid, Signal};
 
     error!("{e:?}");
+    if let Err(me) = metrics::write(false) {
+        error!("{me:?}");
+    }
     for i in CHILDREN.lock().unwrap().iter() {
         let p = unsafe { Pid::from_raw_unchecked(*i as i32) };
         let _ = process::kill_process(p, Signal::TERM);
@@ -1,10 +1,11 @@
 //! Print [trees](crate::tree)
 
 use anyhow::anyhow;
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
+use serde_derive::Serialize;
 
 use crate::hashing::ObjectId;
-use crate::tree::{Forest, Node, NodeContents, Tree};
+use crate::tree::{FileSize, Forest, Node, NodeContents, NodeType, Tree};
 
 // Should this live somewhere else?
 #[cfg(windows)]
@@ -34,6 +35,47 @@ fn printer(prefix: &str, path: &Utf8Path, node: &Node) {
     println!();
 }
 
+/// A single node, as printed (one per line) by `--json` output from `ls`, `diff`, etc.
+#[derive(Debug, Serialize)]
+pub struct JsonNode {
+    /// The diff code (`+`, `-`, `C`, or a metadata change code),
+    /// if we're printing a diff.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub change: Option<char>,
+    pub path: Utf8PathBuf,
+    #[serde(rename = "type")]
+    pub kind: NodeType,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub target: Option<Utf8PathBuf>,
+    /// Data introduced and reused by this file, for `snapshots --stat --file-sizes`
+    #[serde(flatten)]
+    pub size: Option<FileSize>,
+}
+
+impl JsonNode {
+    pub fn new(change: Option<char>, path: &Utf8Path, node: &Node) -> Self {
+        let target = match &node.contents {
+            NodeContents::Symlink { target } => Some(target.clone()),
+            NodeContents::Directory { .. } | NodeContents::File { .. } => None,
+        };
+        Self {
+            change,
+            path: path.to_owned(),
+            kind: node.kind(),
+            target,
+            size: None,
+        }
+    }
+
+    /// Print as a line of JSON.
+    pub fn print(&self) {
+        println!(
+            "{}",
+            serde_json::to_string(self).expect("Couldn't serialize node to JSON")
+        );
+    }
+}
+
 // I tried turning walk_node() and walk_tree() into something general we could use for all
 // tree-walking activities - forest_size(), blobs_in_forest, etc. but it doesn't seem worth it.
 // For printing things, our action ("visitor"? I've almost cured myself of the OOP-brain)
@@ -87,3 +129,18 @@ pub fn print_tree(prefix: &str, tree_path: &Utf8Path, tree_id: &ObjectId, forest
     let mut v = |p: &Utf8Path, n: &Node| printer(prefix, p, n);
     walk_tree(&mut v, tree_path, tree_id, forest);
 }
+
+pub fn print_json_node(
+    change: Option<char>,
+    path: &Utf8Path,
+    node: &Node,
+    should_recurse: Recurse,
+) {
+    let mut v = |p: &Utf8Path, n: &Node| JsonNode::new(change, p, n).print();
+    walk_node(&mut v, path, node, should_recurse);
+}
+
+pub fn print_json_tree(tree_path: &Utf8Path, tree_id: &ObjectId, forest: &Forest) {
+    let mut v = |p: &Utf8Path, n: &Node| JsonNode::new(None, p, n).print();
+    walk_tree(&mut v, tree_path, tree_id, forest);
+}
@@ -1,11 +1,17 @@
-use anyhow::Result;
-use camino::Utf8PathBuf;
-use clap::{ArgAction, Parser, Subcommand};
+use std::process::ExitCode;
+
+use anyhow::{Context, Result, bail};
+use byte_unit::Byte;
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::{ArgAction, CommandFactory, FromArgMatches, Parser, Subcommand};
 use tracing::*;
 
+use backpak::backend;
 use backpak::config;
 use backpak::counters;
 use backpak::fatal;
+use backpak::metrics;
+use backpak::throttle;
 use backpak::ui::*;
 
 #[derive(Debug, Parser)]
@@ -32,8 +38,34 @@ struct Args {
     #[clap(short = 'C', long, name = "PATH")]
     working_directory: Option<Utf8PathBuf>,
 
-    #[clap(short, long)]
-    repository: Utf8PathBuf,
+    /// The repository to use
+    /// (required for everything but `run`, whose profile names one)
+    #[clap(short, long, verbatim_doc_comment)]
+    repository: Option<Utf8PathBuf>,
+
+    /// Print machine-readable JSON from `snapshots`, `usage`, and `check`,
+    /// and JSON Lines (one object per line) from `ls` and `diff`.
+    #[clap(long, verbatim_doc_comment)]
+    json: bool,
+
+    /// Write OpenMetrics text to the given file when we're done,
+    /// e.g., for node_exporter's textfile collector.
+    /// Metrics are labeled with the command name.
+    #[clap(long, name = "FILE", verbatim_doc_comment)]
+    metrics_file: Option<Utf8PathBuf>,
+
+    /// Limit uploads to the given rate per second, e.g., "500 KB"
+    /// (overrides [bandwidth] in the config file)
+    #[clap(long, value_name = "RATE", verbatim_doc_comment)]
+    limit_upload: Option<String>,
+
+    /// Limit downloads to the given rate per second
+    #[clap(long, value_name = "RATE")]
+    limit_download: Option<String>,
+
+    /// Compress with the given zstd level instead of the repository's
+    #[clap(long, value_name = "LEVEL", allow_negative_numbers = true)]
+    compression_level: Option<i32>,
 
     #[clap(subcommand)]
     subcommand: Command,
@@ -47,6 +79,7 @@ enum Color {
 }
 
 #[derive(Debug, Subcommand)]
+#[expect(clippy::large_enum_variant)] // We parse one of these. Who cares?
 enum Command {
     /// Initialize a backup repository
     Init(init::Args),
@@ -59,52 +92,107 @@ enum Command {
     FilterSnapshot(filter_snapshot::Args),
     Forget(forget::Args),
     Ls(ls::Args),
+    Migrate(migrate::Args),
     Prune(prune::Args),
     Restore(restore::Args),
     Snapshots(snapshots::Args),
     /// Build a new index from all existing packs and delete all old ones.
     RebuildIndex(rebuild_index::Args),
+    Repair(repair::Args),
+    Run(run::Args),
+    TrainDictionary(train_dictionary::Args),
     /// Print repository size stats.
     Usage,
+    VerifyRestore(verify_restore::Args),
+}
+
+fn parse_rate(r: Option<&str>) -> Result<Option<Byte>> {
+    // Don't interpret b as bits.
+    Ok(r.map(|r| Byte::parse_str(r, true)).transpose()?)
 }
 
-fn main() {
+fn main() -> ExitCode {
     run().unwrap_or_else(|e| fatal(e))
 }
 
-fn run() -> Result<()> {
-    let args = Args::parse();
+fn run() -> Result<ExitCode> {
+    let matches = Args::command().get_matches();
+    let args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
     let logmode = match args.subcommand {
         Command::Cat(_) | Command::Diff(_) | Command::Dump(_) | Command::Ls(_) => LogMode::Quiet,
+        // Keep stdout clean for JSON consumers.
+        Command::Backup(ref b) if b.quiet_stdout() => LogMode::Quiet,
+        Command::Run(ref r) if r.quiet_stdout() => LogMode::Quiet,
+        _ if args.json => LogMode::Quiet,
         _ => LogMode::InfoStdout,
     };
     init_logger(&args, logmode);
     let conf = config::load(args.config)?;
+    let cache_size = conf.cache_size;
+    throttle::init(
+        &conf.bandwidth,
+        parse_rate(args.limit_upload.as_deref()).context("Couldn't parse --limit-upload")?,
+        parse_rate(args.limit_download.as_deref()).context("Couldn't parse --limit-download")?,
+    )?;
+
+    // -r overrides whatever a profile says.
+    let repository = match (&args.subcommand, args.repository) {
+        (_, Some(r)) => r,
+        (Command::Run(r), None) => conf.profile(r.profile())?.repository.clone(),
+        (_, None) => bail!("--repository is required"),
+    };
+
+    if let Some(l) = args.compression_level {
+        backend::override_compression_level(l)?;
+    }
+
+    if let Some(mf) = &args.metrics_file {
+        metrics::init(mf, matches.subcommand_name().unwrap())?;
+    }
 
     if let Some(dir) = &args.working_directory {
         std::env::set_current_dir(dir).expect("Couldn't change working directory");
     }
 
+    let mut exit_code = ExitCode::SUCCESS;
     match args.subcommand {
-        Command::Init(i) => init::run(&args.repository, i),
-        Command::Backup(b) => backup::run(conf, &args.repository, b),
-        Command::Cat(c) => cat::run(&conf, &args.repository, c),
-        Command::Check(c) => check::run(&conf, &args.repository, c),
-        Command::Copy(c) => copy::run(conf, &args.repository, c),
-        Command::Diff(d) => diff::run(&conf, &args.repository, d),
-        Command::Dump(d) => dump::run(&conf, &args.repository, d),
-        Command::FilterSnapshot(f) => filter_snapshot::run(conf, &args.repository, f),
-        Command::Forget(f) => forget::run(&conf, &args.repository, f),
-        Command::Ls(l) => ls::run(&conf, &args.repository, l),
-        Command::Prune(p) => prune::run(&conf, &args.repository, p),
-        Command::Restore(r) => restore::run(conf, &args.repository, r),
-        Command::Snapshots(s) => snapshots::run(&conf, &args.repository, s),
-        Command::RebuildIndex(r) => rebuild_index::run(&conf, &args.repository, r),
-        Command::Usage => usage::run(&conf, &args.repository),
+        Command::Init(i) => init::run(&repository, i),
+        Command::Backup(b) => backup::run(conf, &repository, b).map(|o| exit_code = o.into()),
+        Command::Cat(c) => cat::run(&conf, &repository, c),
+        Command::Check(c) => check::run(&conf, &repository, args.json, c),
+        Command::Copy(c) => copy::run(conf, &repository, c),
+        Command::Diff(d) => diff::run(&conf, &repository, args.json, d),
+        Command::Dump(d) => dump::run(&conf, &repository, d),
+        Command::FilterSnapshot(f) => filter_snapshot::run(conf, &repository, f),
+        Command::Forget(f) => forget::run(&conf, &repository, f),
+        Command::Ls(l) => ls::run(&conf, &repository, args.json, l),
+        Command::Migrate(m) => migrate::run(&conf, &repository, m),
+        Command::Prune(p) => prune::run(&conf, &repository, p),
+        Command::Restore(r) => restore::run(conf, &repository, r),
+        Command::Snapshots(s) => snapshots::run(&conf, &repository, args.json, s),
+        Command::RebuildIndex(r) => rebuild_index::run(&conf, &repository, r),
+        Command::Repair(r) => repair::run(&conf, &repository, r),
+        Command::Run(r) => run::run(conf, &repository, r).map(|o| exit_code = o.into()),
+        Command::TrainDictionary(t) => train_dictionary::run(&conf, &repository, t),
+        Command::Usage => usage::run(&conf, &repository, args.json),
+        Command::VerifyRestore(v) => verify_restore::run(&conf, &repository, v),
     }?;
 
     counters::log_counts();
-    Ok(())
+    if metrics::enabled() {
+        // The command already succeeded; don't fail it over some extra stats.
+        if let Err(e) = record_repository_metrics(&repository, cache_size) {
+            warn!("Couldn't collect repository metrics: {e:?}");
+        }
+        metrics::write(true)?;
+    }
+    Ok(exit_code)
+}
+
+fn record_repository_metrics(repository: &Utf8Path, cache_size: Byte) -> Result<()> {
+    let (_cfg, cached_backend) =
+        backend::open(repository, cache_size, backend::CacheBehavior::Normal)?;
+    metrics::record_repository(&cached_backend)
 }
 
 enum LogMode {
@@ -0,0 +1,255 @@
+//! Export stats as OpenMetrics text, e.g., for node_exporter's textfile collector.
+//!
+//! Commands [`set()`] whatever gauges they care about as they go,
+//! and we [`write()`] them all out (along with [`counters`](crate::counters))
+//! at exit - successful or otherwise.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, LazyLock, Mutex, OnceLock};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use tracing::*;
+
+use crate::backend;
+use crate::counters;
+
+struct Target {
+    path: Utf8PathBuf,
+    command: String,
+    start: Instant,
+}
+
+static TARGET: OnceLock<Target> = OnceLock::new();
+
+struct Gauge {
+    help: &'static str,
+    samples: BTreeMap<Option<(&'static str, String)>, f64>,
+}
+
+static GAUGES: LazyLock<Mutex<BTreeMap<&'static str, Gauge>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// (Downloaded, uploaded) byte counts of every backend we've opened.
+///
+/// We hang onto these instead of waiting for backends to report them when they're dropped
+/// so that we still have them if we die with a backend open.
+static TRANSFERS: Mutex<Vec<(Arc<AtomicU64>, Arc<AtomicU64>)>> = Mutex::new(Vec::new());
+
+/// Start collecting metrics for `command`, to be written to `path` at exit.
+pub fn init(path: &Utf8Path, command: &str) -> Result<()> {
+    // We might change directories (-C) before we write, so pin down where this goes now.
+    let path = if path.is_relative() {
+        let cwd: Utf8PathBuf = std::env::current_dir()?
+            .try_into()
+            .context("Current directory isn't UTF-8")?;
+        cwd.join(path)
+    } else {
+        path.to_owned()
+    };
+    let t = Target {
+        path,
+        command: command.to_owned(),
+        start: Instant::now(),
+    };
+    assert!(TARGET.set(t).is_ok(), "metrics::init() called twice");
+    Ok(())
+}
+
+#[inline]
+pub fn enabled() -> bool {
+    TARGET.get().is_some()
+}
+
+/// Set the gauge `backpak_<name>` to the given value.
+pub fn set(name: &'static str, help: &'static str, value: u64) {
+    set_labeled(name, help, None, value as f64);
+}
+
+/// Set the gauge `backpak_<name>{<label>="<value>"}` to the given value.
+pub fn set_labeled(
+    name: &'static str,
+    help: &'static str,
+    label: Option<(&'static str, &str)>,
+    value: f64,
+) {
+    if !enabled() {
+        return;
+    }
+    let mut gauges = GAUGES.lock().unwrap();
+    let g = gauges.entry(name).or_insert_with(|| Gauge {
+        help,
+        samples: BTreeMap::new(),
+    });
+    g.samples
+        .insert(label.map(|(k, v)| (k, v.to_owned())), value);
+}
+
+/// Report how many bytes the given backend counters downloaded and uploaded
+/// whenever we [`write()`].
+pub fn track_transfers(downloaded: &Arc<AtomicU64>, uploaded: &Arc<AtomicU64>) {
+    if !enabled() {
+        return;
+    }
+    TRANSFERS
+        .lock()
+        .unwrap()
+        .push((downloaded.clone(), uploaded.clone()));
+}
+
+/// Record how many snapshots the repository has and how big it is.
+///
+/// This just lists the repo, which is much cheaper than `usage` walking every snapshot.
+pub fn record_repository(cached_backend: &backend::CachedBackend) -> Result<()> {
+    let snapshots = cached_backend.list_snapshots()?;
+    set(
+        "repository_snapshots",
+        "Number of snapshots in the repository",
+        snapshots.len() as u64,
+    );
+    let kinds = [
+        ("snapshots", snapshots),
+        ("indexes", cached_backend.list_indexes()?),
+        ("packs", cached_backend.list_packs()?),
+    ];
+    for (kind, files) in &kinds {
+        let size: u64 = files.iter().map(|(_, len)| len).sum();
+        set_labeled(
+            "repository_bytes",
+            "Repository size after compression and filtering",
+            Some(("kind", kind)),
+            size as f64,
+        );
+    }
+    Ok(())
+}
+
+/// Write all our metrics to the file given to [`init()`], if any.
+pub fn write(success: bool) -> Result<()> {
+    let Some(target) = TARGET.get() else {
+        return Ok(());
+    };
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs_f64();
+
+    let last_success = if success {
+        Some(now)
+    } else {
+        // Keep the last one we wrote out, so failures don't make us forget it.
+        previous_success(&target.path, &target.command)
+    };
+
+    let mut out = String::new();
+    let command = &target.command;
+
+    let mut gauge = |name: &str, help: &str, samples: &mut dyn Iterator<Item = (String, f64)>| {
+        writeln!(out, "# TYPE backpak_{name} gauge").unwrap();
+        writeln!(out, "# HELP backpak_{name} {help}").unwrap();
+        for (labels, value) in samples {
+            writeln!(
+                out,
+                "backpak_{name}{{command=\"{command}\"{labels}}} {value}"
+            )
+            .unwrap();
+        }
+    };
+    let one = |v: f64| std::iter::once((String::new(), v));
+
+    gauge(
+        "success",
+        "1 if the command succeeded, 0 otherwise",
+        &mut one(if success { 1.0 } else { 0.0 }),
+    );
+    gauge(
+        "duration_seconds",
+        "How long the command ran",
+        &mut one(target.start.elapsed().as_secs_f64()),
+    );
+    if let Some(ls) = last_success {
+        gauge(
+            LAST_SUCCESS,
+            "When the command last succeeded",
+            &mut one(ls),
+        );
+    }
+    let transfers = TRANSFERS.lock().unwrap();
+    if !transfers.is_empty() {
+        let sum = |f: fn(&(Arc<AtomicU64>, Arc<AtomicU64>)) -> &AtomicU64| {
+            transfers
+                .iter()
+                .map(|t| f(t).load(Ordering::Relaxed))
+                .sum::<u64>() as f64
+        };
+        gauge(
+            "downloaded_bytes",
+            "Bytes read from the backend",
+            &mut one(sum(|t| &t.0)),
+        );
+        gauge(
+            "uploaded_bytes",
+            "Bytes written to the backend",
+            &mut one(sum(|t| &t.1)),
+        );
+    }
+    drop(transfers);
+    for (name, g) in GAUGES.lock().unwrap().iter() {
+        let mut samples = g.samples.iter().map(|(label, v)| {
+            let l = match label {
+                Some((k, lv)) => format!(",{k}=\"{lv}\""),
+                None => String::new(),
+            };
+            (l, *v)
+        });
+        gauge(name, g.help, &mut samples);
+    }
+
+    // Plenty of parsers (like node_exporter's) want the _total in the TYPE line too.
+    writeln!(out, "# TYPE backpak_operations_total counter").unwrap();
+    writeln!(out, "# HELP backpak_operations_total Operation counts").unwrap();
+    for (op, count) in counters::counts() {
+        writeln!(
+            out,
+            "backpak_operations_total{{command=\"{command}\",op=\"{op:?}\"}} {count}"
+        )
+        .unwrap();
+    }
+    out.push_str("# EOF\n");
+
+    write_readable(out.as_bytes(), &target.path)
+        .with_context(|| format!("Couldn't write metrics to {}", target.path))?;
+    debug!("Wrote metrics to {}", target.path);
+    Ok(())
+}
+
+/// Like [`file_util::safe_copy_to_file()`](crate::file_util::safe_copy_to_file),
+/// but readable by everyone - node_exporter probably isn't running as us,
+/// and temporary files are only readable by their owner.
+fn write_readable(contents: &[u8], to: &Utf8Path) -> Result<()> {
+    let dir = to.parent().unwrap();
+    let pre = to.file_name().unwrap().to_owned() + ".";
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(&pre).suffix(".part");
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        builder.permissions(std::fs::Permissions::from_mode(0o644));
+    }
+    let mut fh = builder.tempfile_in(dir)?;
+    fh.write_all(contents)?;
+    fh.persist(to)?.sync_all()?;
+    Ok(())
+}
+
+const LAST_SUCCESS: &str = "last_success_timestamp_seconds";
+
+fn previous_success(path: &Utf8Path, command: &str) -> Option<f64> {
+    let prev = std::fs::read_to_string(path).ok()?;
+    let prefix = format!("backpak_{LAST_SUCCESS}{{command=\"{command}\"");
+    prev.lines()
+        .find(|l| l.starts_with(&prefix))
+        .and_then(|l| l.rsplit_once(' '))
+        .and_then(|(_, v)| v.parse().ok())
+}
@@ -3,10 +3,17 @@
 //!
 //! A pack file contains:
 //! 1. Magic bytes
-//! 2. A zstd-compressed stream of all blobs in the file
-//! 3. A *separate* zstd stream of the CBOR-encoded manifest.
-//!    Each manifest entry contains its blob's type, length, and ID.
-//! 4. A 32-bit, big-endian manifest length.
+//! 2. The ID of the [dictionary] its zstd frames were compressed with, if any:
+//!    a zero byte for none, or a one followed by the ID.
+//! 3. All blobs in the file, in zstd frames.
+//!    Blobs that don't compress (JPEGs, videos, other archives...) are stored raw
+//!    between frames instead of wasting CPU time on them.
+//! 4. A *separate* zstd stream of the CBOR-encoded manifest.
+//!    Each manifest entry contains its blob's type, length, ID, and whether it's raw.
+//! 5. A 32-bit, big-endian manifest length.
+//!
+//! Version 1 packs have no dictionary ID
+//! and always compress all of their blobs into a single frame.
 //!
 //! Compressing the manifest separately and ending with its length makes it trivial to read
 //! without having to decompress or read any blobs first.
@@ -18,13 +25,14 @@
 
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::{self, SeekFrom};
+use std::io::{self, BufReader, SeekFrom};
 use std::sync::{
+    Arc,
     atomic::{AtomicU64, Ordering},
     mpsc::{Receiver, SyncSender},
 };
 
-use anyhow::{Context, Result, ensure};
+use anyhow::{Context, Result, bail, ensure};
 use byte_unit::Byte;
 use serde_derive::{Deserialize, Serialize};
 use tempfile::NamedTempFile;
@@ -32,22 +40,114 @@ use tracing::*;
 
 use crate::backend;
 use crate::blob::{self, Blob};
-use crate::file_util::{self, nice_size};
-use crate::hashing::{HashingReader, ObjectId};
+use crate::dictionary::{self, Dictionary};
+use crate::file_util::nice_size;
+use crate::hashing::{self, HashingReader, ObjectId};
 use crate::progress::AtomicCountWrite;
 use crate::tree;
 
-pub const MAGIC_BYTES: &[u8] = b"MKBAKPAK1";
+pub const MAGIC_BYTES: &[u8] = b"MKBAKPAK2";
+
+/// Packs from before we stored incompressible blobs raw
+const V1_MAGIC_BYTES: &[u8] = b"MKBAKPAK1";
 
 /// The desired size of [crate::pack] files
 pub const DEFAULT_PACK_SIZE: Byte = Byte::from_u64(100_000_000); // 100 MB
 
+/// How hard zstd should work on packs and indexes.
+///
+/// Set per repository, since some want to save every byte
+/// and others want to back up a laptop before its battery dies.
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Compression {
+    /// 1 (fastest) to 22 (smallest), or negative for even faster.
+    /// 0 is zstd's default (currently 3).
+    #[serde(default)]
+    pub level: i32,
+    /// Look for matches much further back than usual.
+    /// Good for big files with repeats far apart.
+    #[serde(default)]
+    pub long_distance_matching: bool,
+    /// Log2 of the window to look for matches in.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub window_log: Option<u32>,
+    /// Compress trees with this [dictionary] (see `backpak train-dictionary`)
+    #[serde(default, skip_serializing_if = "Option::is_none", with = "base32_id")]
+    pub tree_dictionary: Option<ObjectId>,
+}
+
+/// TOML wants IDs as strings, not the raw bytes we usually serialize them as.
+mod base32_id {
+    use serde::{Deserialize, Deserializer, Serializer, de::Error};
+
+    use crate::hashing::ObjectId;
+
+    pub fn serialize<S: Serializer>(id: &Option<ObjectId>, s: S) -> Result<S::Ok, S::Error> {
+        match id {
+            Some(id) => s.serialize_some(&id.to_string()),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<Option<ObjectId>, D::Error> {
+        let s: Option<String> = Option::deserialize(d)?;
+        s.map(|s| s.parse().map_err(D::Error::custom)).transpose()
+    }
+}
+
+impl Compression {
+    pub fn is_default(&self) -> bool {
+        *self == Self::default()
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        let levels = zstd::compression_level_range();
+        ensure!(
+            self.level == 0 || levels.contains(&self.level),
+            "zstd compression level must be between {} and {}",
+            levels.start(),
+            levels.end()
+        );
+        if let Some(w) = self.window_log {
+            // Decompressors need to opt in to anything bigger.
+            ensure!(
+                (10..=27).contains(&w),
+                "zstd window_log must be between 10 and 27"
+            );
+        }
+        Ok(())
+    }
+
+    /// Make a (multithreaded) zstd encoder with these settings
+    /// and the given dictionary
+    pub fn encoder<W: Write>(
+        &self,
+        w: W,
+        dictionary: Option<&Dictionary>,
+    ) -> Result<ZstdEncoder<W>> {
+        let mut zstd = match dictionary {
+            Some(d) => ZstdEncoder::with_dictionary(w, self.level, &d.bytes)?,
+            None => ZstdEncoder::new(w, self.level)?,
+        };
+        zstd.multithread(num_cpus::get_physical() as u32)?;
+        zstd.long_distance_matching(self.long_distance_matching)?;
+        if let Some(wl) = self.window_log {
+            zstd.window_log(wl)?;
+        }
+        Ok(zstd)
+    }
+}
+
 #[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct PackManifestEntry {
     #[serde(rename = "type")]
     pub blob_type: blob::Type,
     pub length: u32,
     pub id: ObjectId,
+    /// Stored uncompressed, outside any zstd frame (version 2+ packs)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub raw: bool,
 }
 
 pub type PackManifest = Vec<PackManifestEntry>;
@@ -61,18 +161,25 @@ pub struct PackMetadata {
 /// Serializes a pack's manifest and get its ID.
 ///
 /// A pack file is identified by the hash of its (uncompressed) manifest.
-fn serialize_and_hash(manifest: &[PackManifestEntry]) -> Result<(Vec<u8>, ObjectId)> {
+fn serialize_and_hash(
+    manifest: &[PackManifestEntry],
+    hash: hashing::Algorithm,
+) -> Result<(Vec<u8>, ObjectId)> {
     let mut manifest_cbor = Vec::new();
     ciborium::into_writer(&manifest, &mut manifest_cbor)?;
-    let id = ObjectId::hash(&manifest_cbor);
+    let id = hash.hash(&manifest_cbor);
 
     Ok((manifest_cbor, id))
 }
 
 /// Packs blobs received from the given channel.
 /// Returns the number of bytes packed
+#[expect(clippy::too_many_arguments)]
 pub fn pack(
     target_size: Byte,
+    compression: Compression,
+    hash: hashing::Algorithm,
+    dictionary: Option<Arc<Dictionary>>,
     rx: Receiver<Blob>,
     to_index: SyncSender<PackMetadata>,
     to_upload: SyncSender<(String, File)>,
@@ -80,7 +187,12 @@ pub fn pack(
     total_bytes_compressed: &AtomicU64,
 ) -> Result<()> {
     let target_size = target_size.as_u64();
-    let mut writer = PackfileWriter::new(total_bytes_compressed)?;
+    let mut writer = PackfileWriter::new(
+        compression,
+        hash,
+        dictionary.clone(),
+        total_bytes_compressed,
+    )?;
 
     let mut pass_bytes_written: u64 = 0; // Bytes written since the last size check
     let mut bytes_in_pack: u64 = 0;
@@ -179,7 +291,12 @@ pub fn pack(
                 .send(metadata)
                 .context("packer -> indexer channel exited early")?;
 
-            writer = PackfileWriter::new(total_bytes_compressed)?;
+            writer = PackfileWriter::new(
+                compression,
+                hash,
+                dictionary.clone(),
+                total_bytes_compressed,
+            )?;
             pass_bytes_written = 0;
             bytes_in_pack = 0;
             bytes_before_next_check = target_size;
@@ -198,18 +315,62 @@ pub fn pack(
     Ok(())
 }
 
-type ZstdEncoder<W> = zstd::stream::write::Encoder<'static, W>;
+pub type ZstdEncoder<W> = zstd::stream::write::Encoder<'static, W>;
 type ZstdDecoder<R> = zstd::stream::read::Decoder<'static, R>;
 
+/// Where [`PackfileWriter`] is putting blobs: between frames or inside one.
+enum BlobSink<'a> {
+    Raw(AtomicCountWrite<'a, NamedTempFile>),
+    Compressed(ZstdEncoder<AtomicCountWrite<'a, NamedTempFile>>),
+}
+
+impl BlobSink<'_> {
+    fn file(&self) -> &NamedTempFile {
+        match self {
+            BlobSink::Raw(w) => w.get_ref(),
+            BlobSink::Compressed(z) => z.get_ref().get_ref(),
+        }
+    }
+}
+
 struct PackfileWriter<'a> {
-    writer: ZstdEncoder<AtomicCountWrite<'a, NamedTempFile>>,
+    /// Only `None` if we errored out switching between raw and compressed blobs.
+    sink: Option<BlobSink<'a>>,
+    compression: Compression,
+    hash: hashing::Algorithm,
+    dictionary: Option<Arc<Dictionary>>,
     manifest: PackManifest,
 }
 
+/// Should we bother compressing the given blob?
+///
+/// Test-compress a sample and see if zstd can shave off at least ~3% -
+/// if it can't, we'll just burn CPU time for nothing.
+fn worth_compressing(bytes: &[u8]) -> bool {
+    const SAMPLE_SIZE: usize = 64 * 1024;
+
+    // Small stuff (like trees) is cheap to compress anyways.
+    if bytes.len() <= SAMPLE_SIZE {
+        return true;
+    }
+    // Sample from the middle; file headers tend to compress better than what follows.
+    let start = (bytes.len() - SAMPLE_SIZE) / 2;
+    let sample = &bytes[start..start + SAMPLE_SIZE];
+    match zstd::bulk::compress(sample, 1) {
+        Ok(compressed) => compressed.len() < SAMPLE_SIZE - SAMPLE_SIZE / 32,
+        Err(_) => true,
+    }
+}
+
 // TODO: Obviously this should all take place in a configurable temp directory
 
 impl<'a> PackfileWriter<'a> {
-    fn new(byte_count: &'a AtomicU64) -> Result<Self> {
+    fn new(
+        compression: Compression,
+        hash: hashing::Algorithm,
+        dictionary: Option<Arc<Dictionary>>,
+        byte_count: &'a AtomicU64,
+    ) -> Result<Self> {
         let mut fh = tempfile::Builder::new()
             .prefix("temp-backpak-")
             .suffix(".pack")
@@ -217,27 +378,61 @@ impl<'a> PackfileWriter<'a> {
             .context("Couldn't open temporary packfile for writing")?;
 
         fh.write_all(MAGIC_BYTES)?;
+        write_header(&mut fh, dictionary.as_deref())?;
         let acw = AtomicCountWrite::new(fh, byte_count);
-        let mut zstd = ZstdEncoder::new(acw, 0)?;
-        zstd.multithread(num_cpus::get_physical() as u32)?;
         Ok(Self {
-            writer: zstd,
+            sink: Some(BlobSink::Raw(acw)),
+            compression,
+            hash,
+            dictionary,
             manifest: Vec::new(),
         })
     }
 
+    fn sink(&self) -> &BlobSink<'a> {
+        self.sink
+            .as_ref()
+            .expect("packfile writer used after an error")
+    }
+
+    /// End the current zstd frame (if we want a raw blob next)
+    /// or start a new one (if we want a compressed blob).
+    fn switch_to(&mut self, raw: bool) -> Result<()> {
+        let sink = self
+            .sink
+            .take()
+            .expect("packfile writer used after an error");
+        self.sink = Some(match (sink, raw) {
+            (BlobSink::Compressed(z), true) => BlobSink::Raw(z.finish()?),
+            (BlobSink::Raw(w), false) => {
+                BlobSink::Compressed(self.compression.encoder(w, self.dictionary.as_deref())?)
+            }
+            (s, _) => s,
+        });
+        Ok(())
+    }
+
     /// Write the given file chunk or tree to the packfile and add it to the manifest.
-    fn write_blob(&mut self, blob: Blob) -> io::Result<u64> {
+    fn write_blob(&mut self, blob: Blob) -> Result<u64> {
         let blob_bytes: &[u8] = blob.bytes();
 
         let blob_length = blob_bytes.len();
         assert!(blob_length <= u32::MAX as usize);
 
-        self.writer.write_all(blob_bytes)?;
+        let raw = !worth_compressing(blob_bytes);
+        if raw {
+            trace!("{} doesn't compress well; storing it raw", blob.id);
+        }
+        self.switch_to(raw)?;
+        match self.sink.as_mut().unwrap() {
+            BlobSink::Raw(w) => w.write_all(blob_bytes)?,
+            BlobSink::Compressed(z) => z.write_all(blob_bytes)?,
+        }
         self.manifest.push(PackManifestEntry {
             blob_type: blob.kind,
             length: blob_length as u32,
             id: blob.id,
+            raw,
         });
         Ok(blob_length as u64)
     }
@@ -246,9 +441,10 @@ impl<'a> PackfileWriter<'a> {
     ///
     /// **Warning:** Doing this too frequently hurts the compression ratio, at least a little.
     fn flush_and_check_size(&mut self) -> Result<u64> {
-        self.writer.flush()?;
-        let pos = self.writer.get_ref().get_ref().stream_position()?;
-        Ok(pos)
+        if let Some(BlobSink::Compressed(z)) = &mut self.sink {
+            z.flush()?;
+        }
+        self.check_size()
     }
 
     /// Check the size of the underlying compressed file *without* flushing.
@@ -256,24 +452,27 @@ impl<'a> PackfileWriter<'a> {
     /// Doesn't account for whatever data is in the Zstd buffer,
     /// but doesn't change compression ratios either.
     fn check_size(&self) -> Result<u64> {
-        let pos = self.writer.get_ref().get_ref().stream_position()?;
+        let pos = self.sink().file().stream_position()?;
         Ok(pos)
     }
 
     /// Finalize the packfile, returning the manifest & ID with a handle to
     /// the persisted file (so that the uploader doesn't have to reopen it).
     fn finalize(self) -> Result<(PackMetadata, File)> {
-        let (manifest, id) = serialize_and_hash(&self.manifest)?;
+        let (manifest, id) = serialize_and_hash(&self.manifest, self.hash)?;
 
         // Finish the compression stream for blobs and trees.
         // We'll compress the manifest separately so we can decompress it
         // without reading everything before it.
-        let mut fh: NamedTempFile = self.writer.finish()?.into_inner();
+        let mut fh: NamedTempFile = match self.sink.expect("packfile writer used after an error") {
+            BlobSink::Raw(w) => w.into_inner(),
+            BlobSink::Compressed(z) => z.finish()?.into_inner(),
+        };
 
         // The manifest CBOR will have lots of redundant data - compress it down.
         // TODO: Is multithreading worth it here?
         // This shouldn't be much data compared to blobs and trees.
-        let mut manifest = zstd::bulk::compress(&manifest, 0)?;
+        let mut manifest = zstd::bulk::compress(&manifest, self.compression.level)?;
 
         // Write the length of the (compressed) manifest to the end of the file,
         // making it simple and fast to examine the manifest:
@@ -304,14 +503,14 @@ impl<'a> PackfileWriter<'a> {
 pub fn verify<R: Read + Seek>(
     packfile: &mut R,
     manifest_from_index: &[PackManifestEntry],
+    cached_backend: &backend::CachedBackend,
     blobs_read: &AtomicU64,
 ) -> Result<()> {
-    check_magic(packfile)?;
-
-    let mut decoder = ZstdDecoder::new(packfile).context("Decompression of blob stream failed")?;
+    let mut blobs = BlobReader::new(packfile, cached_backend)?;
 
     for entry in manifest_from_index {
-        let mut hashing_decoder = HashingReader::new((&mut decoder).take(entry.length as u64));
+        let mut hashing_decoder =
+            HashingReader::new(blobs.next_blob(entry)?, cached_backend.hash());
 
         io::copy(&mut hashing_decoder, &mut io::sink())?;
 
@@ -330,8 +529,8 @@ pub fn verify<R: Read + Seek>(
     // Should we rearrange the file so that isn't a problem?
     // Or is that fine, since verification isn't as performance critical
     // as other interactions?
-    let mut packfile = decoder.finish();
-    let (manifest_from_file, _id) = manifest_from_reader(&mut packfile)?;
+    let packfile = blobs.into_inner();
+    let (manifest_from_file, _id) = manifest_from_reader(packfile, cached_backend.hash())?;
 
     ensure!(
         manifest_from_index == manifest_from_file,
@@ -345,7 +544,10 @@ pub fn verify<R: Read + Seek>(
 /// also returning its calculated ID.
 ///
 /// _Does not_ check the pack's magic bytes or anything besides the manifest.
-fn manifest_from_reader<R: Seek + Read>(r: &mut R) -> Result<(PackManifest, ObjectId)> {
+fn manifest_from_reader<R: Seek + Read>(
+    r: &mut R,
+    hash: hashing::Algorithm,
+) -> Result<(PackManifest, ObjectId)> {
     r.seek(SeekFrom::End(-4))?;
     let mut manifest_length: [u8; 4] = [0; 4];
     r.read_exact(&mut manifest_length)?;
@@ -360,7 +562,7 @@ fn manifest_from_reader<R: Seek + Read>(r: &mut R) -> Result<(PackManifest, Obje
     })?;
     let decoder = ZstdDecoder::new(r.take(manifest_length as u64))
         .context("Decompression of pack manifest failed")?;
-    let mut hasher = HashingReader::new(decoder);
+    let mut hasher = HashingReader::new(decoder, hash);
 
     let manifest: PackManifest =
         ciborium::from_reader(&mut hasher).context("CBOR decoding of the pack manifest failed")?;
@@ -376,10 +578,19 @@ pub fn load_manifest(
 ) -> Result<PackManifest> {
     debug!("Loading pack manifest {}", id);
     let mut fh = cached_backend.read_pack(id)?;
-    check_magic(&mut fh)?;
+    checked_manifest(&mut fh, id, cached_backend.hash())
+}
+
+/// Reads the given pack's manifest, verifying its contents match the pack's ID.
+fn checked_manifest<R: Read + Seek>(
+    r: &mut R,
+    id: &ObjectId,
+    hash: hashing::Algorithm,
+) -> Result<PackManifest> {
+    check_magic(r)?;
 
     let (manifest, calculated_id) =
-        manifest_from_reader(&mut fh).with_context(|| format!("Couldn't load pack {}", id))?;
+        manifest_from_reader(r, hash).with_context(|| format!("Couldn't load pack {}", id))?;
     ensure!(
         *id == calculated_id,
         "Pack {}'s manifest changed! Now hashes to {}",
@@ -389,27 +600,40 @@ pub fn load_manifest(
     Ok(manifest)
 }
 
+/// Like [`verify()`], but for a pack we don't have an index entry for:
+/// make sure its manifest matches its ID and its blobs match its manifest.
+pub fn verify_whole<R: Read + Seek>(
+    packfile: &mut R,
+    id: &ObjectId,
+    cached_backend: &backend::CachedBackend,
+) -> Result<PackManifest> {
+    let manifest = checked_manifest(packfile, id, cached_backend.hash())?;
+    packfile.seek(SeekFrom::Start(0))?;
+    verify(packfile, &manifest, cached_backend, &AtomicU64::new(0))?;
+    Ok(manifest)
+}
+
 /// Extracts a single blob from a packfile.
 /// Useful for `cat blob`.
 pub fn extract_blob<R: Read>(
     packfile: &mut R,
     blob_id: &ObjectId,
     manifest_from_index: &[PackManifestEntry],
+    cached_backend: &backend::CachedBackend,
 ) -> Result<(PackManifestEntry, Vec<u8>)> {
     assert!(
         manifest_from_index.iter().any(|entry| entry.id == *blob_id),
         "Given blob ID isn't in the given index"
     );
 
-    check_magic(packfile)?;
-
-    let mut decoder = ZstdDecoder::new(packfile).context("Decompression of blob stream failed")?;
+    let mut blobs = BlobReader::new(packfile, cached_backend)?;
 
     let mut sink = io::sink();
 
     for entry in manifest_from_index {
         if entry.id == *blob_id {
-            let mut hashing_decoder = HashingReader::new(decoder.take(entry.length as u64));
+            let mut hashing_decoder =
+                HashingReader::new(blobs.next_blob(entry)?, cached_backend.hash());
 
             let mut buf = Vec::with_capacity(entry.length as usize);
             hashing_decoder.read_to_end(&mut buf)?;
@@ -424,7 +648,7 @@ pub fn extract_blob<R: Read>(
 
             return Ok((*entry, buf));
         } else {
-            io::copy(&mut (&mut decoder).take(entry.length as u64), &mut sink)?;
+            io::copy(&mut blobs.next_blob(entry)?, &mut sink)?;
         }
     }
 
@@ -436,10 +660,9 @@ pub fn append_to_forest<R: Read + Seek>(
     packfile: &mut R,
     manifest_from_index: &[PackManifestEntry],
     forest: &mut tree::Forest,
+    cached_backend: &backend::CachedBackend,
 ) -> Result<()> {
-    check_magic(packfile)?;
-
-    let mut decoder = ZstdDecoder::new(packfile).context("Decompression of blob stream failed")?;
+    let mut blobs = BlobReader::new(packfile, cached_backend)?;
 
     for entry in manifest_from_index {
         // If it's not a tree, or if we have it already, skip it!
@@ -456,12 +679,13 @@ pub fn append_to_forest<R: Read + Seek>(
         if skip {
             assert_eq!(
                 entry_length,
-                io::copy(&mut (&mut decoder).take(entry_length), &mut io::sink())?
+                io::copy(&mut blobs.next_blob(entry)?, &mut io::sink())?
             );
             continue;
         }
 
-        let mut hashing_decoder = HashingReader::new((&mut decoder).take(entry_length));
+        let mut hashing_decoder =
+            HashingReader::new(blobs.next_blob(entry)?, cached_backend.hash());
 
         let to_add: tree::Tree = ciborium::from_reader(&mut hashing_decoder)
             .with_context(|| format!("CBOR decoding of tree {} failed", entry.id))?;
@@ -483,8 +707,150 @@ pub fn append_to_forest<R: Read + Seek>(
     Ok(())
 }
 
-pub fn check_magic<R: Read>(r: &mut R) -> Result<()> {
-    file_util::check_magic(r, MAGIC_BYTES).context("Wrong magic bytes for packfile")
+/// Checks the pack's magic bytes, returning its version.
+pub fn check_magic<R: Read>(r: &mut R) -> Result<u8> {
+    let mut magic = [0u8; MAGIC_BYTES.len()];
+    r.read_exact(&mut magic)
+        .context("Couldn't read packfile magic bytes")?;
+    if magic == MAGIC_BYTES {
+        Ok(2)
+    } else if magic == V1_MAGIC_BYTES {
+        Ok(1)
+    } else {
+        bail!(
+            "Wrong magic bytes for packfile: expected {}, found {}",
+            String::from_utf8_lossy(MAGIC_BYTES),
+            String::from_utf8_lossy(&magic)
+        )
+    }
+}
+
+/// Writes the ID of the dictionary the pack's frames are compressed with, if any.
+fn write_header<W: Write>(w: &mut W, dictionary: Option<&Dictionary>) -> io::Result<()> {
+    match dictionary {
+        Some(d) => {
+            w.write_all(&[1])?;
+            w.write_all(d.id.as_bytes())
+        }
+        None => w.write_all(&[0]),
+    }
+}
+
+/// Which dictionary (if any) the given pack was compressed with
+pub fn dictionary_of(
+    id: &ObjectId,
+    cached_backend: &backend::CachedBackend,
+) -> Result<Option<ObjectId>> {
+    let mut fh = cached_backend.read_pack(id)?;
+    if check_magic(&mut fh)? >= 2 {
+        read_header(&mut fh).with_context(|| format!("Couldn't read pack {id} header"))
+    } else {
+        Ok(None)
+    }
+}
+
+/// Reads the ID of the dictionary a (version 2+) pack was compressed with, if any.
+fn read_header<R: Read>(r: &mut R) -> Result<Option<ObjectId>> {
+    let mut has_dictionary = [0u8; 1];
+    r.read_exact(&mut has_dictionary)?;
+    match has_dictionary[0] {
+        0 => Ok(None),
+        1 => {
+            let mut id = [0u8; ObjectId::LEN];
+            r.read_exact(&mut id)?;
+            Ok(Some(ObjectId::from_bytes(&id)?))
+        }
+        wut => bail!("Unexpected byte {wut} in pack header"),
+    }
+}
+
+/// Where [`BlobReader`] is reading blobs from: between frames or inside one.
+enum BlobSource<R> {
+    Raw(BufReader<R>),
+    Compressed(ZstdDecoder<BufReader<R>>),
+}
+
+impl<R: Read> Read for BlobSource<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            BlobSource::Raw(r) => r.read(buf),
+            BlobSource::Compressed(z) => z.read(buf),
+        }
+    }
+}
+
+/// Reads blobs out of a pack (of any version), one manifest entry at a time.
+pub struct BlobReader<R> {
+    version: u8,
+    dictionary: Option<Arc<Dictionary>>,
+    /// Only `None` if we errored out switching between raw and compressed blobs.
+    source: Option<BlobSource<R>>,
+}
+
+impl<R: Read> BlobReader<R> {
+    /// Checks the pack's magic bytes, loads its dictionary (if it has one),
+    /// and gets ready to read its first blob.
+    pub fn new(mut packfile: R, cached_backend: &backend::CachedBackend) -> Result<Self> {
+        let version = check_magic(&mut packfile)?;
+        let dictionary = if version >= 2 {
+            read_header(&mut packfile)?
+                .map(|id| dictionary::load(&id, cached_backend))
+                .transpose()?
+        } else {
+            None
+        };
+        Ok(Self {
+            version,
+            dictionary,
+            source: Some(BlobSource::Raw(BufReader::new(packfile))),
+        })
+    }
+
+    /// Reads the blob for the given manifest entry.
+    ///
+    /// Entries must be read in manifest order,
+    /// and each must be read to the end before asking for the next.
+    pub fn next_blob(&mut self, entry: &PackManifestEntry) -> Result<impl Read + '_> {
+        ensure!(
+            !entry.raw || self.version >= 2,
+            "Version {} packs can't have raw blobs, but {} claims to be one",
+            self.version,
+            entry.id
+        );
+        let source = self.source.take().expect("pack reader used after an error");
+        let source = self.source.insert(match (source, entry.raw) {
+            (BlobSource::Compressed(mut z), true) => {
+                // We should have read everything in the frame before the raw blob.
+                let leftovers = io::copy(&mut z, &mut io::sink())?;
+                ensure!(
+                    leftovers == 0,
+                    "Found {leftovers} unexpected bytes in the pack before {}",
+                    entry.id
+                );
+                BlobSource::Raw(z.finish())
+            }
+            (BlobSource::Raw(r), false) => {
+                let z = match &self.dictionary {
+                    Some(d) => ZstdDecoder::with_dictionary(r, &d.bytes),
+                    None => ZstdDecoder::with_buffer(r),
+                };
+                BlobSource::Compressed(
+                    z.context("Decompression of blob stream failed")?
+                        .single_frame(),
+                )
+            }
+            (s, _) => s,
+        });
+        Ok(source.take(entry.length as u64))
+    }
+
+    /// Done with blobs; get back the file (e.g., to look at the manifest).
+    pub fn into_inner(self) -> R {
+        match self.source.expect("pack reader used after an error") {
+            BlobSource::Raw(r) => r.into_inner(),
+            BlobSource::Compressed(z) => z.finish().into_inner(),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -504,20 +870,23 @@ mod test {
                 blob_type: blob::Type::Chunk,
                 length: 42,
                 id: ObjectId::hash(b"first"),
+                raw: false,
             },
             PackManifestEntry {
                 blob_type: blob::Type::Tree,
                 length: 22,
                 id: ObjectId::hash(b"second"),
+                raw: false,
             },
             PackManifestEntry {
                 blob_type: blob::Type::Chunk,
                 length: 42,
                 id: ObjectId::hash(b"third"),
+                raw: false,
             },
         ];
 
-        let (manifest, id) = serialize_and_hash(&manifest)?;
+        let (manifest, id) = serialize_and_hash(&manifest, hashing::Algorithm::Sha224)?;
 
         // ID remains stable
         assert_eq!(
@@ -534,7 +903,8 @@ mod test {
 
     #[test]
     fn smoke() -> Result<()> {
-        let chunks: Vec<_> = chunk::chunk_file("tests/references/sr71.txt")
+        let chunker = chunk::Chunker::new(&Default::default(), Default::default());
+        let chunks: Vec<_> = chunk::chunk_file("tests/references/sr71.txt", &chunker)
             .context("Couldn't chunk reference file")?
             .collect();
         let (chunk_tx, chunk_rx) = sync_channel(0);
@@ -545,6 +915,9 @@ mod test {
         let chunk_packer = std::thread::spawn(move || {
             pack(
                 DEFAULT_PACK_SIZE,
+                Compression::default(),
+                Default::default(),
+                None,
                 chunk_rx,
                 pack_tx,
                 upload_tx,
@@ -584,4 +957,160 @@ mod test {
         }
         Ok(())
     }
+
+    /// Some bytes zstd can't do anything with
+    fn noise(len: usize, mut seed: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                // xorshift64
+                seed ^= seed << 13;
+                seed ^= seed >> 7;
+                seed ^= seed << 17;
+                seed as u8
+            })
+            .collect()
+    }
+
+    fn chunk_of(bytes: Vec<u8>) -> Blob {
+        Blob {
+            id: ObjectId::hash(&bytes),
+            contents: blob::Contents::Buffer(bytes),
+            kind: blob::Type::Chunk,
+        }
+    }
+
+    fn read_back<R: Read + Seek>(
+        pack: &mut R,
+        blobs: &[Blob],
+        manifest: &[PackManifestEntry],
+        backend: &backend::CachedBackend,
+    ) -> Result<()> {
+        let unused_byte_count = AtomicU64::new(0);
+        pack.rewind()?;
+        verify(pack, manifest, backend, &unused_byte_count)?;
+        for blob in blobs {
+            pack.rewind()?;
+            let (entry, bytes) = extract_blob(pack, &blob.id, manifest, backend)?;
+            assert_eq!(entry.id, blob.id);
+            assert_eq!(bytes, blob.bytes());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn raw_blobs() -> Result<()> {
+        let text = fs::read("tests/references/sr71.txt")?;
+        let blobs = [
+            chunk_of(text.clone()),
+            chunk_of(noise(200_000, 1)),
+            chunk_of(noise(300_000, 2)),
+            chunk_of(b"short and sweet".to_vec()),
+            chunk_of(noise(100_000, 3)),
+            chunk_of(text.repeat(2)),
+        ];
+
+        let unused_byte_count = AtomicU64::new(0);
+        let mut writer = PackfileWriter::new(
+            Compression::default(),
+            Default::default(),
+            None,
+            &unused_byte_count,
+        )?;
+        for blob in &blobs {
+            writer.write_blob(blob.clone())?;
+        }
+        let (metadata, mut fh) = writer.finalize()?;
+        let pack_name = format!("{}.pack", metadata.id);
+
+        let raws: Vec<bool> = metadata.manifest.iter().map(|e| e.raw).collect();
+        assert_eq!(raws, [false, true, true, false, true, false]);
+
+        let read = read_back(&mut fh, &blobs, &metadata.manifest, &backend::in_memory());
+        fs::remove_file(pack_name)?;
+        read
+    }
+
+    #[test]
+    fn dictionaries() -> Result<()> {
+        let trees: Vec<Blob> = (0..300)
+            .map(|i| {
+                let tree = format!(
+                    r#"{{"file{i}.txt": {{"chunks": ["{}"], "metadata": {{"mode": {}, "uid": 1000, "mtime": {}}}}}}}"#,
+                    ObjectId::hash(&i.to_string().into_bytes()),
+                    0o644 + i % 3,
+                    1_700_000_000 + i * 7919
+                )
+                .into_bytes();
+                Blob {
+                    id: ObjectId::hash(&tree),
+                    contents: blob::Contents::Buffer(tree),
+                    kind: blob::Type::Tree,
+                }
+            })
+            .collect();
+        let samples: Vec<&[u8]> = trees.iter().map(|t| t.bytes()).collect();
+        let backend = backend::in_memory();
+        let trained = dictionary::train(&samples, 4096, backend.hash())?;
+        dictionary::upload(&trained, &backend)?;
+        let dictionary = dictionary::load(&trained.id, &backend)?;
+
+        let unused_byte_count = AtomicU64::new(0);
+        let mut writer = PackfileWriter::new(
+            Compression::default(),
+            backend.hash(),
+            Some(dictionary.clone()),
+            &unused_byte_count,
+        )?;
+        for tree in &trees {
+            writer.write_blob(tree.clone())?;
+        }
+        let (metadata, mut fh) = writer.finalize()?;
+        let pack_name = format!("{}.pack", metadata.id);
+
+        // The pack knows what it was compressed with.
+        fh.rewind()?;
+        assert_eq!(check_magic(&mut fh)?, 2);
+        assert_eq!(read_header(&mut fh)?, Some(dictionary.id));
+
+        let read = read_back(&mut fh, &trees[..20], &metadata.manifest, &backend);
+        fs::remove_file(pack_name)?;
+        read
+    }
+
+    #[test]
+    fn reads_v1() -> Result<()> {
+        let blobs = [
+            chunk_of(fs::read("tests/references/sr71.txt")?),
+            chunk_of(noise(100_000, 4)),
+            chunk_of(fs::read("tests/references/README.md")?),
+        ];
+        let mut manifest: PackManifest = blobs
+            .iter()
+            .map(|b| PackManifestEntry {
+                blob_type: b.kind,
+                length: b.bytes().len() as u32,
+                id: b.id,
+                raw: false,
+            })
+            .collect();
+
+        // Everything in one frame, like we used to.
+        let mut pack = V1_MAGIC_BYTES.to_vec();
+        let all_blobs: Vec<u8> = blobs.iter().flat_map(|b| b.bytes()).copied().collect();
+        pack.extend(zstd::encode_all(all_blobs.as_slice(), 0)?);
+        let (manifest_cbor, _id) = serialize_and_hash(&manifest, Default::default())?;
+        let manifest_zstd = zstd::encode_all(manifest_cbor.as_slice(), 0)?;
+        pack.extend(&manifest_zstd);
+        pack.extend((manifest_zstd.len() as u32).to_be_bytes());
+
+        let backend = backend::in_memory();
+        let mut pack = io::Cursor::new(pack);
+        read_back(&mut pack, &blobs, &manifest, &backend)?;
+
+        // V1 packs can't have raw blobs.
+        manifest[1].raw = true;
+        pack.rewind()?;
+        assert!(verify(&mut pack, &manifest, &backend, &AtomicU64::new(0)).is_err());
+        Ok(())
+    }
 }
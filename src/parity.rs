@@ -0,0 +1,380 @@
+//! Reed-Solomon parity for packs, to survive bit rot
+//!
+//! One flipped bit in a pack garbles everything after it in its zstd frame.
+//! Repositories can opt into saving parity for each pack alongside it,
+//! as `parity/<pack ID>.par`.
+//!
+//! The pack is cut into equal shards (the last padded out with zeroes),
+//! and each stripe of `data_shards` of them gets `parity_shards` more.
+//! We hash every shard, data and parity alike, to find which ones are damaged;
+//! Reed-Solomon can rebuild a stripe with up to `parity_shards` of them gone.
+//!
+//! A parity file contains:
+//! 1. Magic bytes
+//! 2. CBOR: the pack's length, the shard size and counts,
+//!    the hash of every shard, and the parity shards themselves.
+
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::prelude::*;
+
+use anyhow::{Context, Result, ensure};
+use byte_unit::Byte;
+use camino::Utf8Path;
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use rustc_hash::FxHashSet;
+use serde_derive::{Deserialize, Serialize};
+use tracing::*;
+
+use crate::backend;
+use crate::file_util::{self, check_magic};
+use crate::hashing::{self, ObjectId};
+
+const MAGIC_BYTES: &[u8] = b"MKBAKPAR1";
+
+/// How much parity a repository saves for each pack
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Parity {
+    pub shard_size: Byte,
+    pub data_shards: u16,
+    pub parity_shards: u16,
+}
+
+/// Two parity shards for every 32 shards of 64 KiB:
+/// about 6% more space, and any two damaged spots in each 2 MiB of a pack can be fixed.
+impl Default for Parity {
+    fn default() -> Self {
+        Self {
+            shard_size: Byte::from_u64(64 * 1024),
+            data_shards: 32,
+            parity_shards: 2,
+        }
+    }
+}
+
+impl Parity {
+    pub fn validate(&self) -> Result<()> {
+        ensure!(
+            self.data_shards > 0 && self.parity_shards > 0,
+            "Parity needs at least one data shard and one parity shard"
+        );
+        ensure!(
+            self.data_shards as usize + self.parity_shards as usize <= 256,
+            "Parity can't have more than 256 shards (data + parity) per stripe"
+        );
+        ensure!(
+            (512..=16 * 1024 * 1024).contains(&self.shard_size.as_u64()),
+            "Parity shard size must be between 512 B and 16 MiB"
+        );
+        Ok(())
+    }
+
+    fn codec(&self) -> Result<ReedSolomon> {
+        Ok(ReedSolomon::new(
+            self.data_shards as usize,
+            self.parity_shards as usize,
+        )?)
+    }
+}
+
+/// Parity for a single pack
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ParityFile {
+    pack_length: u64,
+    shard_size: u32,
+    data_shards: u16,
+    parity_shards: u16,
+    /// Every data shard's hash, then every parity shard's
+    hashes: Vec<ObjectId>,
+    #[serde(with = "serde_bytes")]
+    parity: Vec<u8>,
+}
+
+/// What [`ParityFile::heal()`] found (and fixed)
+#[derive(Debug, Default)]
+pub struct Healing {
+    /// The fixed pack, if it needed fixing
+    pub pack: Option<Vec<u8>>,
+    pub damaged_data_shards: usize,
+    /// Damaged parity doesn't hurt the pack, but should be replaced.
+    pub damaged_parity_shards: usize,
+}
+
+impl Healing {
+    pub fn is_clean(&self) -> bool {
+        self.pack.is_none() && self.damaged_parity_shards == 0
+    }
+}
+
+/// The `i`th shard of the pack, padded with zeroes to the shard size
+fn shard(pack: &[u8], i: usize, size: usize) -> Cow<'_, [u8]> {
+    let start = (i * size).min(pack.len());
+    let end = ((i + 1) * size).min(pack.len());
+    let s = &pack[start..end];
+    if s.len() == size {
+        Cow::Borrowed(s)
+    } else {
+        let mut padded = s.to_vec();
+        padded.resize(size, 0);
+        Cow::Owned(padded)
+    }
+}
+
+/// Compute parity for the given pack.
+pub fn compute(pack: &[u8], settings: &Parity, hash: hashing::Algorithm) -> Result<ParityFile> {
+    settings.validate()?;
+    let codec = settings.codec()?;
+    let size = settings.shard_size.as_u64() as usize;
+    let d = settings.data_shards as usize;
+    let p = settings.parity_shards as usize;
+
+    let data_count = pack.len().div_ceil(size);
+    let stripes = data_count.div_ceil(d);
+    let mut hashes = Vec::with_capacity(data_count + stripes * p);
+    let mut parity = vec![0u8; stripes * p * size];
+    for (s, parity_stripe) in parity.chunks_mut(p * size).enumerate() {
+        let data: Vec<_> = (0..d).map(|i| shard(pack, s * d + i, size)).collect();
+        let mut parity_shards: Vec<&mut [u8]> = parity_stripe.chunks_mut(size).collect();
+        codec.encode_sep(&data, &mut parity_shards)?;
+        let real = d.min(data_count - s * d);
+        hashes.extend(data[..real].iter().map(|sh| hash.hash(sh)));
+    }
+    hashes.extend(parity.chunks(size).map(|sh| hash.hash(sh)));
+
+    Ok(ParityFile {
+        pack_length: pack.len() as u64,
+        shard_size: size as u32,
+        data_shards: settings.data_shards,
+        parity_shards: settings.parity_shards,
+        hashes,
+        parity,
+    })
+}
+
+impl ParityFile {
+    /// The settings this parity was made with
+    pub fn settings(&self) -> Parity {
+        Parity {
+            shard_size: Byte::from_u64(self.shard_size as u64),
+            data_shards: self.data_shards,
+            parity_shards: self.parity_shards,
+        }
+    }
+
+    /// Find damaged shards in the pack (and in the parity itself),
+    /// rebuilding the pack if we can.
+    ///
+    /// Fails if any stripe has more damaged shards than parity shards.
+    pub fn heal(&self, pack: &[u8], hash: hashing::Algorithm) -> Result<Healing> {
+        let settings = self.settings();
+        settings.validate()?;
+        let codec = settings.codec()?;
+        let size = self.shard_size as usize;
+        let d = self.data_shards as usize;
+        let p = self.parity_shards as usize;
+        let pack_length = self.pack_length as usize;
+        // Extra junk on the end isn't in any shard.
+        let trimmed = &pack[..pack.len().min(pack_length)];
+
+        let data_count = pack_length.div_ceil(size);
+        let stripes = data_count.div_ceil(d);
+        ensure!(
+            self.hashes.len() == data_count + stripes * p
+                && self.parity.len() == stripes * p * size,
+            "Parity file doesn't match its own pack length"
+        );
+
+        let mut healing = Healing::default();
+        for s in 0..stripes {
+            let data_ok: Vec<bool> = (0..d)
+                .map(|i| {
+                    let g = s * d + i;
+                    // Shards past the end of the pack are just zeroes.
+                    g >= data_count || hash.hash(&shard(trimmed, g, size)) == self.hashes[g]
+                })
+                .collect();
+            let parity_ok: Vec<bool> = (0..p)
+                .map(|j| {
+                    let k = s * p + j;
+                    hash.hash(&self.parity[k * size..(k + 1) * size]) == self.hashes[data_count + k]
+                })
+                .collect();
+            let bad_data = data_ok.iter().filter(|ok| !**ok).count();
+            let bad_parity = parity_ok.iter().filter(|ok| !**ok).count();
+            healing.damaged_data_shards += bad_data;
+            healing.damaged_parity_shards += bad_parity;
+            if bad_data == 0 {
+                continue;
+            }
+            ensure!(
+                bad_data + bad_parity <= p,
+                "{} of the {} shards in stripe {s} are damaged; parity can only fix {p}",
+                bad_data + bad_parity,
+                d + p
+            );
+            debug!("Rebuilding {bad_data} shards in stripe {s}");
+
+            let mut shards: Vec<Option<Vec<u8>>> = data_ok
+                .iter()
+                .enumerate()
+                .map(|(i, ok)| ok.then(|| shard(trimmed, s * d + i, size).into_owned()))
+                .chain(parity_ok.iter().enumerate().map(|(j, ok)| {
+                    let k = s * p + j;
+                    ok.then(|| self.parity[k * size..(k + 1) * size].to_vec())
+                }))
+                .collect();
+            codec.reconstruct_data(&mut shards)?;
+
+            let healed = healing.pack.get_or_insert_with(|| {
+                let mut h = trimmed.to_vec();
+                h.resize(pack_length, 0);
+                h
+            });
+            for (i, ok) in data_ok.iter().enumerate() {
+                let g = s * d + i;
+                if *ok || g >= data_count {
+                    continue;
+                }
+                let start = g * size;
+                let end = (start + size).min(pack_length);
+                healed[start..end].copy_from_slice(&shards[i].as_ref().unwrap()[..end - start]);
+            }
+        }
+        if healing.pack.is_none() && pack.len() > pack_length {
+            healing.pack = Some(trimmed.to_vec());
+        }
+        Ok(healing)
+    }
+}
+
+/// Compute parity for the pack at the given path (waiting to be uploaded)
+/// and save it alongside, returning the parity file's name and handle.
+pub fn write_for(
+    pack_path: &str,
+    settings: &Parity,
+    hash: hashing::Algorithm,
+) -> Result<(String, File)> {
+    let id = backend::id_from_path(pack_path)?;
+    let pack = file_util::read_file(Utf8Path::new(pack_path))
+        .with_context(|| format!("Couldn't read {pack_path} to compute its parity"))?;
+    let parity = compute(pack.bytes(), settings, hash)?;
+    save(&parity, &id)
+}
+
+fn save(parity: &ParityFile, pack_id: &ObjectId) -> Result<(String, File)> {
+    let mut fh = tempfile::Builder::new()
+        .prefix("temp-backpak-")
+        .suffix(".par")
+        .tempfile_in(".") // TODO: Configurable?
+        .context("Couldn't open temporary parity file for writing")?;
+    fh.write_all(MAGIC_BYTES)?;
+    ciborium::into_writer(parity, &mut fh)?;
+    fh.as_file().sync_all()?;
+
+    let parity_name = format!("{pack_id}.par");
+    let persisted = fh
+        .persist(&parity_name)
+        .with_context(|| format!("Couldn't persist finished parity {parity_name}"))?;
+    Ok((parity_name, persisted))
+}
+
+/// Upload parity for the given pack to the backend.
+pub fn upload(
+    parity: &ParityFile,
+    pack_id: &ObjectId,
+    backend: &backend::CachedBackend,
+) -> Result<()> {
+    let (name, fh) = save(parity, pack_id)?;
+    backend.write(&name, fh)
+}
+
+/// Loads the parity for the given pack from the backend.
+pub fn load(pack_id: &ObjectId, cached_backend: &backend::CachedBackend) -> Result<ParityFile> {
+    debug!("Loading parity for pack {pack_id}");
+    let mut fh = cached_backend.read_parity(pack_id)?;
+    check_magic(&mut fh, MAGIC_BYTES).context("Wrong magic bytes for parity file")?;
+    let parity = ciborium::from_reader(fh)
+        .with_context(|| format!("CBOR decoding of pack {pack_id}'s parity failed"))?;
+    Ok(parity)
+}
+
+/// The packs we have parity for
+pub fn list(cached_backend: &backend::CachedBackend) -> Result<FxHashSet<ObjectId>> {
+    cached_backend
+        .list_parity()?
+        .iter()
+        .map(|(p, _len)| backend::id_from_path(p))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn settings() -> Parity {
+        Parity {
+            shard_size: Byte::from_u64(512),
+            data_shards: 4,
+            parity_shards: 2,
+        }
+    }
+
+    #[test]
+    fn heals_damage() -> Result<()> {
+        let hash = hashing::Algorithm::default();
+        // Not a whole number of shards or stripes
+        let pack: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+        let parity = compute(&pack, &settings(), hash)?;
+
+        assert!(parity.heal(&pack, hash)?.is_clean());
+
+        // Two bad shards in the first stripe, one in the last (partial) shard
+        let mut damaged = pack.clone();
+        damaged[10] ^= 1;
+        damaged[1500] ^= 0x80;
+        damaged[4999] = 0;
+        let healing = parity.heal(&damaged, hash)?;
+        assert_eq!(healing.damaged_data_shards, 3);
+        assert_eq!(healing.damaged_parity_shards, 0);
+        assert_eq!(healing.pack.unwrap(), pack);
+
+        // Truncation is just more damaged shards.
+        let healing = parity.heal(&pack[..4800], hash)?;
+        assert_eq!(healing.pack.unwrap(), pack);
+        // Junk on the end is just cut off.
+        let mut long = pack.clone();
+        long.extend_from_slice(b"junk");
+        let healing = parity.heal(&long, hash)?;
+        assert_eq!(healing.damaged_data_shards, 0);
+        assert_eq!(healing.pack.unwrap(), pack);
+
+        // Too much damage in one stripe
+        let mut ruined = pack.clone();
+        for i in [0, 600, 1100] {
+            ruined[i] ^= 1;
+        }
+        assert!(parity.heal(&ruined, hash).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn damaged_parity() -> Result<()> {
+        let hash = hashing::Algorithm::Blake3;
+        let pack = vec![42u8; 3000];
+        let mut parity = compute(&pack, &settings(), hash)?;
+        parity.parity[0] ^= 1;
+        let healing = parity.heal(&pack, hash)?;
+        assert!(healing.pack.is_none());
+        assert_eq!(healing.damaged_parity_shards, 1);
+
+        // Bad parity counts against what we can fix.
+        let mut damaged = pack.clone();
+        damaged[0] = 0;
+        damaged[600] = 0;
+        assert!(parity.heal(&damaged, hash).is_err());
+        damaged[600] = 42;
+        assert_eq!(parity.heal(&damaged, hash)?.pack.unwrap(), pack);
+        Ok(())
+    }
+}
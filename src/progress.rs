@@ -1,5 +1,5 @@
 use std::{
-    io::{self, Read, Write},
+    io::{self, Read, Seek, Write},
     sync::{
         Arc,
         atomic::{AtomicBool, AtomicU64, Ordering},
@@ -41,6 +41,13 @@ impl<R: Read> Read for AtomicCountRead<'_, R> {
     }
 }
 
+// Seeking back (say, to retry an upload) counts the bytes again, since we'll really read them again.
+impl<R: Seek> Seek for AtomicCountRead<'_, R> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
 pub struct AtomicCountWrite<'a, W> {
     inner: W,
     count: &'a AtomicU64,
@@ -72,6 +79,23 @@ impl<W: Write> Write for AtomicCountWrite<'_, W> {
     }
 }
 
+/// How commands should report their progress
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq, clap::ValueEnum)]
+pub enum ProgressMode {
+    /// Redraw a few lines of the terminal as we go
+    #[default]
+    Term,
+    /// Print a JSON object (one per line) every second
+    Json,
+    /// Don't
+    None,
+}
+
+/// How often we print `--progress=json` events
+///
+/// Slower than our terminal refresh - nobody wants ten lines a second in their logs.
+pub const JSON_PROGRESS_RATE: Duration = Duration::from_secs(1);
+
 pub struct ProgressThread<'scope> {
     handle: ScopedJoinHandle<'scope, Result<()>>,
     done_flag: Arc<AtomicBool>,
@@ -82,7 +106,13 @@ impl<'scope> ProgressThread<'scope> {
     where
         F: FnMut(usize) -> Result<()> + Send + 'scope,
     {
-        let rate = Duration::from_millis(100);
+        Self::spawn_at_rate(s, Duration::from_millis(100), f)
+    }
+
+    pub fn spawn_at_rate<'env, F>(s: &'scope Scope<'scope, 'env>, rate: Duration, f: F) -> Self
+    where
+        F: FnMut(usize) -> Result<()> + Send + 'scope,
+    {
         let done_flag = Arc::new(AtomicBool::new(false));
         let df = done_flag.clone();
         let handle = thread::Builder::new()
@@ -172,7 +202,7 @@ pub fn print_backup_lines(
     println!("{spin} P {cb} + {tb} | R {rb} | Z {cz} | U {ub}");
 
     let idxd = bstats.indexed_packs.load(Ordering::Relaxed);
-    let ispin = if idxd % 2 != 0 { 'i' } else { 'I' };
+    let ispin = if !idxd.is_multiple_of(2) { 'i' } else { 'I' };
     println!("{ispin} {idxd} packs indexed");
 }
 
@@ -1,7 +1,8 @@
 //! Tools to traverse a repository, reading blobs
 //!
-//! This is ultimately how we read backups back out for restore, repack, etc.
-use std::{cmp::Ordering, io::prelude::*, rc::Rc, time::Instant};
+//! This is ultimately how we read backups back out for dump, repack, etc.
+//! (Restores read many packs at once; see [`restore`](crate::restore).)
+use std::{cmp::Ordering, io, io::prelude::*, rc::Rc, time::Instant};
 
 use anyhow::{Context, Result, anyhow, ensure};
 use mut_binary_heap::{BinaryHeap, FnComparator};
@@ -16,8 +17,6 @@ use crate::hashing::{HashingReader, ObjectId};
 use crate::index;
 use crate::pack;
 
-type ZstdDecoder<R> = zstd::stream::read::Decoder<'static, R>;
-
 struct TimestampedChunk {
     stamp: Instant,
     chunk: Rc<Vec<u8>>,
@@ -177,8 +176,8 @@ impl<'a> ChunkReader<'a> {
     }
 
     fn load_pack(&mut self, id: ObjectId) -> Result<usize> {
-        let mut file = self.cached_backend.read_pack(&id)?;
-        pack::check_magic(&mut file)?;
+        let file = self.cached_backend.read_pack(&id)?;
+        let mut blobs = pack::BlobReader::new(file, self.cached_backend)?;
 
         let manifest = self
             .index
@@ -186,9 +185,6 @@ impl<'a> ChunkReader<'a> {
             .get(&id)
             .ok_or_else(|| anyhow!("Couldn't find pack {} manifest in the index", id))?;
 
-        let mut blob_stream =
-            ZstdDecoder::new(file).context("Decompression of blob stream failed")?;
-
         let mut bytes_read = 0;
         let mut blob_buf = vec![];
         for entry in manifest {
@@ -197,6 +193,7 @@ impl<'a> ChunkReader<'a> {
                     "Tree {} found in pack where we expected only chunks",
                     entry.id
                 );
+                io::copy(&mut blobs.next_blob(entry)?, &mut io::sink())?;
                 continue;
             }
 
@@ -204,7 +201,7 @@ impl<'a> ChunkReader<'a> {
             blob_buf.reserve(entry.length as usize);
 
             let mut hashing_decoder =
-                HashingReader::new((&mut blob_stream).take(entry.length as u64));
+                HashingReader::new(blobs.next_blob(entry)?, self.cached_backend.hash());
             hashing_decoder.read_to_end(&mut blob_buf)?;
             let (hash, _) = hashing_decoder.finalize();
             ensure!(
@@ -226,76 +223,16 @@ impl<'a> ChunkReader<'a> {
 mod test {
     use super::*;
 
-    use std::collections::BTreeSet;
-    use std::sync::mpsc::sync_channel;
-
     use crate::blob;
-    use crate::chunk;
 
     #[test]
     fn smoke() -> Result<()> {
         // Create a backend with a single pack from our reference files
-        let backend = backend::in_memory();
-
-        let mut chunks = Vec::new();
-
-        chunks.extend(chunk::chunk_file("tests/references/sr71.txt")?);
-        chunks.extend(chunk::chunk_file("tests/references/index.stability")?);
-        chunks.extend(chunk::chunk_file("tests/references/pack.stability")?);
-        chunks.extend(chunk::chunk_file("tests/references/README.md")?);
-        assert_eq!(chunks.len(), 4);
-
-        let (chunk_tx, chunk_rx) = sync_channel(0);
-        let (pack_tx, pack_rx) = sync_channel(0);
-        let (upload_tx, upload_rx) = sync_channel(0);
-
-        let unused_byte_count = std::sync::atomic::AtomicU64::new(0);
-        let chunk_packer = std::thread::spawn(move || {
-            pack::pack(
-                pack::DEFAULT_PACK_SIZE,
-                chunk_rx,
-                pack_tx,
-                upload_tx,
-                &unused_byte_count,
-                &unused_byte_count,
-            )
-        });
-
-        let uploader = std::thread::spawn(move || -> Result<backend::CachedBackend> {
-            let mut num_packs = 0;
-            while let Ok((path, fh)) = upload_rx.recv() {
-                backend.write(&path, fh)?;
-                num_packs += 1;
-            }
-
-            assert_eq!(num_packs, 1);
-            Ok(backend)
-        });
-
-        for chunk in &chunks {
-            chunk_tx.send(chunk.clone())?
-        }
-        drop(chunk_tx);
-
-        // Instead of writing out an index file with index::index()
-        // and reading it back in, let's just synthesize the needed info.
-        let index = {
-            let metadata = pack_rx.recv()?;
-            let supersedes = BTreeSet::new();
-            let mut packs = index::PackMap::new();
-            packs.insert(metadata.id, metadata.manifest);
-
-            index::Index { packs, supersedes }
-        };
+        let (backend, index, chunks) = backend::in_memory_with_references(pack::DEFAULT_PACK_SIZE)?;
+        assert_eq!(index.packs.len(), 1);
         let blob_map = index::blob_to_pack_map(&index)?;
 
-        chunk_packer.join().unwrap()?;
-        let backend = uploader.join().unwrap()?;
-
-        // With all that fun over with,
-        // (Should we wrap that in some utility function(s) for testing?
-        // Or is each test bespoke enough that it wouldn't be helpful?)
-        // let's test our reader.
+        // With all that fun over with, let's test our reader.
         let mut reader = ChunkReader::new(&backend, &index, &blob_map);
 
         // Read the first chunk:
@@ -1,5 +1,6 @@
 //! Shared utilities to repack blobs,
 //! either loose ones in `backpak prune` or to another repo in `backpak copy`
+use std::io::prelude::*;
 use std::sync::atomic::{AtomicU64, Ordering};
 
 use anyhow::{Context, Result, anyhow};
@@ -8,7 +9,7 @@ use rustc_hash::FxHashSet;
 use tracing::*;
 
 use crate::{
-    backup, blob,
+    backup, blob, chunk, file_util,
     hashing::ObjectId,
     rcu::Rcu,
     read,
@@ -67,10 +68,16 @@ pub struct WalkStatistics {
 /// This returns a new list of snapshots since filtering a tree changes its contents,
 /// which changes its ID, which changes the IDs of all trees above it.
 /// Aren't Merkle trees fun?
+///
+/// If `rechunk` is given, files are put back together and cut with it
+/// instead of copying their chunks as-is
+/// (for copying to a repository that chunks differently).
+#[expect(clippy::too_many_arguments)] // We know, sit down.
 pub fn walk_snapshots<Filter>(
     op: Op,
     snapshots_and_forests: &[SnapshotAndForest],
     mut filter: Filter,
+    rechunk: Option<&chunk::Chunker>,
     reader: &mut read::ChunkReader,
     packed_blobs: &mut FxHashSet<ObjectId>,
     backup: &mut backup::Backup,
@@ -84,16 +91,29 @@ where
 {
     let new_snaps = snapshots_and_forests
         .iter()
-        .map(|snf| walk_snapshot(op, snf, &mut filter, reader, packed_blobs, backup, stats))
+        .map(|snf| {
+            walk_snapshot(
+                op,
+                snf,
+                &mut filter,
+                rechunk,
+                reader,
+                packed_blobs,
+                backup,
+                stats,
+            )
+        })
         .collect::<Result<Vec<_>>>()?;
     Ok(new_snaps)
 }
 
 /// Walk the given snapshot, copying to the given backup. Return the new (filtered) snapshot.
+#[expect(clippy::too_many_arguments)] // We know, sit down.
 fn walk_snapshot<Filter>(
     op: Op,
     snapshot_and_forest: &SnapshotAndForest,
     filter: &mut Filter,
+    rechunk: Option<&chunk::Chunker>,
     reader: &mut read::ChunkReader,
     packed_blobs: &mut FxHashSet<ObjectId>,
     backup: &mut backup::Backup,
@@ -119,6 +139,7 @@ where
     let new_root = walk_tree(
         op,
         filter,
+        rechunk,
         Utf8Path::new(""),
         &snapshot_and_forest.snapshot.tree,
         &snapshot_and_forest.forest,
@@ -138,6 +159,7 @@ where
 fn walk_tree<Filter>(
     op: Op,
     filter: &mut Filter,
+    rechunk: Option<&chunk::Chunker>,
     tree_path: &Utf8Path,
     tree_id: &ObjectId,
     forest: &tree::Forest,
@@ -181,22 +203,34 @@ where
                     Op::Prune => "repacked",
                 };
 
-                for chunk in chunks {
-                    if packed_blobs.insert(*chunk) {
-                        repack_chunk(chunk, reader, backup)?;
-                        chunks_repacked = true;
-                    } else {
-                        let cs = reader.blob_size(chunk)? as u64;
-                        stats.reused_bytes.fetch_add(cs, Ordering::Relaxed);
+                let new_node = if let Some(chunker) = rechunk {
+                    let (new_chunks, any_new) =
+                        rechunk_file(chunks, chunker, reader, packed_blobs, backup, stats)
+                            .with_context(|| format!("Couldn't rechunk {node_path}"))?;
+                    chunks_repacked = any_new;
+                    tree::Node {
+                        contents: tree::NodeContents::File { chunks: new_chunks },
+                        metadata: node.metadata.clone(),
                     }
-                }
+                } else {
+                    for chunk in chunks {
+                        if packed_blobs.insert(*chunk) {
+                            repack_chunk(chunk, reader, backup)?;
+                            chunks_repacked = true;
+                        } else {
+                            let cs = reader.blob_size(chunk)? as u64;
+                            stats.reused_bytes.fetch_add(cs, Ordering::Relaxed);
+                        }
+                    }
+                    // We're not changing any files, the node stays the same.
+                    node.clone()
+                };
                 if chunks_repacked {
                     debug!("  {verb:>9} {node_path}");
                 } else {
                     debug!("  {:>9} {node_path}", "deduped"); // Sorta; "unneeded"? Bleh.
                 }
-                // We're not changing any files, the node stays the same.
-                node.clone()
+                new_node
             }
             tree::NodeContents::Symlink { .. } => {
                 debug!("  {:>9} {node_path}", "deduped"); // Keep consistent with above
@@ -208,6 +242,7 @@ where
                 let new_tree = walk_tree(
                     op,
                     filter,
+                    rechunk,
                     &node_path,
                     subtree,
                     forest,
@@ -234,7 +269,7 @@ where
     // Serialize and hash it to find out.
     // (Again, we could have a separate "we didn't filter anything" path,
     // but it doesn't seem worth it at the moment.)
-    let (serialized, new_tree_id) = tree::serialize_and_hash(&new_tree)?;
+    let (serialized, new_tree_id) = tree::serialize_and_hash(&new_tree, backup.hash)?;
     // If we don't have this tree, new or old, in the backup, add it.
     if packed_blobs.insert(new_tree_id) {
         backup.tree_tx.send(blob::Blob {
@@ -266,3 +301,37 @@ fn repack_chunk<'a, 'b: 'a>(
     })?;
     Ok(())
 }
+
+/// Put a file back together from its chunks and cut it up again with the given chunker.
+///
+/// Returns the new chunk IDs, and whether any of them were new to the backup.
+fn rechunk_file(
+    chunks: &[ObjectId],
+    chunker: &chunk::Chunker,
+    reader: &mut read::ChunkReader,
+    packed_blobs: &mut FxHashSet<ObjectId>,
+    backup: &mut backup::Backup,
+    stats: &WalkStatistics,
+) -> Result<(Vec<ObjectId>, bool)> {
+    // Files can be huge; let the OS page them in and out like any other.
+    let mut fh = tempfile::tempfile()?;
+    for chunk in chunks {
+        fh.write_all(&reader.read_blob(chunk)?)?;
+    }
+    fh.rewind()?;
+    let file = file_util::load_file(fh)?;
+
+    let mut new_chunks = Vec::with_capacity(chunks.len());
+    let mut any_new = false;
+    for blob in chunk::chunk_loaded(file, chunker) {
+        new_chunks.push(blob.id);
+        if packed_blobs.insert(blob.id) {
+            backup.chunk_tx.send(blob)?;
+            any_new = true;
+        } else {
+            let cs = blob.bytes().len() as u64;
+            stats.reused_bytes.fetch_add(cs, Ordering::Relaxed);
+        }
+    }
+    Ok((new_chunks, any_new))
+}
@@ -0,0 +1,550 @@
+//! Fill restored files with their chunks, several packs at a time
+//!
+//! Reading chunks one after another (like [`read::ChunkReader`](crate::read::ChunkReader) does)
+//! means downloading one pack at a time, even if the backend could have several going.
+//! Instead, [`Plan`] works out which chunks we need from each pack and where they go
+//! (which files, at which offsets). [`fill`] then has the backend's
+//! [concurrency](backend::CachedBackend::concurrency) worth of threads download packs,
+//! and a thread per CPU decompress them and write each chunk everywhere it belongs.
+//! Since every chunk knows its offset, files can be written in whatever order packs show up.
+//!
+//! Files are filled under a [partial name](partial_path) and moved into place once they're done,
+//! so anything with its real name is complete. As we go, we note each chunk we write
+//! in a [`Journal`], so that an interrupted restore can pick up where it left off.
+//!
+//! Afterwards, [`verify_files`] can re-read what we wrote and make sure it chunks the same way
+//! it did when we backed it up.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, prelude::*};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, mpsc::sync_channel};
+use std::thread;
+
+use anyhow::{Context, Result, anyhow, bail, ensure};
+use camino::{Utf8Path, Utf8PathBuf};
+use rustc_hash::{FxHashMap, FxHashSet};
+use serde_derive::{Deserialize, Serialize};
+use tracing::*;
+
+use crate::backend;
+use crate::blob;
+use crate::chunk;
+use crate::concurrently;
+use crate::file_util;
+use crate::hashing::{HashingReader, ObjectId};
+use crate::index;
+use crate::pack;
+
+// Like the WIP index for backups, keep this in the working directory
+// so that resuming is just running the same command again (with --resume).
+pub const JOURNAL_NAME: &str = "backpak-restore.journal";
+
+const PARTIAL_SUFFIX: &str = ".backpak-part";
+
+/// Where we fill the file at `path` before moving it into place
+pub fn partial_path(path: &Utf8Path) -> Utf8PathBuf {
+    let mut partial = path.as_str().to_owned();
+    partial.push_str(PARTIAL_SUFFIX);
+    partial.into()
+}
+
+/// Is this something a restore keeps while it works (a partial file or the journal)?
+pub fn is_ours(path: &Utf8Path) -> bool {
+    path.as_str().ends_with(PARTIAL_SUFFIX) || path.file_name() == Some(JOURNAL_NAME)
+}
+
+/// A line in the journal, which is JSON lines so that a torn write only loses its last one.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum JournalEntry {
+    /// Starts the journal, so we don't resume restoring some other snapshot.
+    Snapshot(String),
+    /// We wrote the chunk at `offset` into `path`'s partial file.
+    Wrote { path: Utf8PathBuf, offset: u64 },
+    /// We moved `path`'s partial file into place.
+    Finished(Utf8PathBuf),
+}
+
+/// Records what a restore has written so far
+pub struct Journal {
+    fh: Mutex<BufWriter<File>>,
+}
+
+/// What an interrupted restore got done, according to its journal
+#[derive(Debug, Default)]
+pub struct Progress {
+    /// Chunks (by offset) already in each file's partial file
+    pub written: FxHashMap<Utf8PathBuf, FxHashSet<u64>>,
+    /// How many files were finished and moved into place
+    pub finished: usize,
+}
+
+impl Journal {
+    /// Start a new journal for restoring the given snapshot.
+    pub fn create(snapshot: &ObjectId) -> Result<Self> {
+        let fh = File::create(JOURNAL_NAME)
+            .with_context(|| format!("Couldn't create {JOURNAL_NAME}"))?;
+        let j = Self {
+            fh: Mutex::new(BufWriter::new(fh)),
+        };
+        j.record(&JournalEntry::Snapshot(snapshot.to_string()))?;
+        j.flush()?;
+        Ok(j)
+    }
+
+    /// Read back the journal of an interrupted restore of the given snapshot
+    /// and keep adding to it.
+    pub fn resume(snapshot: &ObjectId) -> Result<(Self, Progress)> {
+        let journal = fs::read_to_string(JOURNAL_NAME)
+            .with_context(|| format!("Couldn't open {JOURNAL_NAME} to resume a restore"))?;
+        let mut lines = journal.lines();
+
+        let first = lines.next().unwrap_or_default();
+        match serde_json::from_str(first) {
+            Ok(JournalEntry::Snapshot(s)) => ensure!(
+                s == snapshot.to_string(),
+                "{JOURNAL_NAME} is from restoring snapshot {s}, not {snapshot}"
+            ),
+            _ => bail!("{JOURNAL_NAME} doesn't start with the snapshot it's restoring"),
+        }
+
+        let mut progress = Progress::default();
+        for line in lines.filter(|l| !l.is_empty()) {
+            // We could have been interrupted partway through the last line.
+            let Ok(entry) = serde_json::from_str(line) else {
+                warn!("Ignoring garbled line in {JOURNAL_NAME}: {line}");
+                continue;
+            };
+            match entry {
+                JournalEntry::Snapshot(_) => bail!("{JOURNAL_NAME} names more than one snapshot"),
+                JournalEntry::Wrote { path, offset } => {
+                    progress.written.entry(path).or_default().insert(offset);
+                }
+                JournalEntry::Finished(path) => {
+                    progress.written.remove(&path);
+                    progress.finished += 1;
+                }
+            }
+        }
+
+        let fh = OpenOptions::new()
+            .append(true)
+            .open(JOURNAL_NAME)
+            .with_context(|| format!("Couldn't open {JOURNAL_NAME} for writing"))?;
+        let mut fh = BufWriter::new(fh);
+        // Start on a fresh line if the last one was torn.
+        if !journal.ends_with('\n') {
+            writeln!(fh)?;
+        }
+        let j = Self { fh: Mutex::new(fh) };
+        Ok((j, progress))
+    }
+
+    fn record(&self, entry: &JournalEntry) -> Result<()> {
+        let mut fh = self.fh.lock().unwrap();
+        serde_json::to_writer(&mut *fh, entry)?;
+        writeln!(fh)?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.fh
+            .lock()
+            .unwrap()
+            .flush()
+            .with_context(|| format!("Couldn't write {JOURNAL_NAME}"))
+    }
+
+    /// The restore finished; we don't need the journal anymore.
+    pub fn remove(self) -> Result<()> {
+        drop(self.fh);
+        fs::remove_file(JOURNAL_NAME).with_context(|| format!("Couldn't remove {JOURNAL_NAME}"))
+    }
+}
+
+/// Somewhere a chunk goes: an index into [`Plan::files`] and an offset into that file
+type Destination = (usize, u64);
+
+struct PlannedFile {
+    path: Utf8PathBuf,
+    /// How many chunks we have left to write before we can move it into place
+    remaining: AtomicUsize,
+}
+
+/// Which chunks to get from which packs, and where to write them
+pub struct Plan<'a> {
+    blob_map: &'a index::BlobMap,
+    chunk_sizes: FxHashMap<ObjectId, u32>,
+    journal: Option<&'a Journal>,
+    files: Vec<PlannedFile>,
+    packs: BTreeMap<ObjectId, FxHashMap<ObjectId, Vec<Destination>>>,
+    bytes: u64,
+}
+
+impl<'a> Plan<'a> {
+    pub fn new(
+        index: &index::Index,
+        blob_map: &'a index::BlobMap,
+        journal: Option<&'a Journal>,
+    ) -> Self {
+        let chunk_sizes = index
+            .packs
+            .values()
+            .flatten()
+            .filter(|e| e.blob_type == blob::Type::Chunk)
+            .map(|e| (e.id, e.length))
+            .collect();
+        Self {
+            blob_map,
+            chunk_sizes,
+            journal,
+            files: vec![],
+            packs: BTreeMap::new(),
+            bytes: 0,
+        }
+    }
+
+    /// Plan to restore the given chunks to `path`.
+    ///
+    /// We create its partial file now, unless we're picking up where an interrupted
+    /// restore left off, in which case chunks at the offsets in `written` are already there.
+    pub fn add_file(
+        &mut self,
+        path: Utf8PathBuf,
+        chunks: &[ObjectId],
+        written: Option<&FxHashSet<u64>>,
+    ) -> Result<()> {
+        let partial = partial_path(&path);
+        // If the partial file went missing, so did whatever we wrote to it.
+        let written = written.filter(|_| partial.exists());
+        if written.is_none() {
+            File::create(&partial).with_context(|| format!("Couldn't create file {partial}"))?;
+        }
+
+        let file = self.files.len();
+        let mut offset = 0u64;
+        let mut remaining = 0;
+        for chunk in chunks {
+            let pack = self
+                .blob_map
+                .get(chunk)
+                .ok_or_else(|| anyhow!("Chunk {chunk} not found in any pack"))?;
+            let size = self
+                .chunk_sizes
+                .get(chunk)
+                .ok_or_else(|| anyhow!("Chunk {chunk} isn't in pack {pack} like the index said"))?;
+            if written.is_some_and(|w| w.contains(&offset)) {
+                offset += *size as u64;
+                continue;
+            }
+            remaining += 1;
+            self.packs
+                .entry(*pack)
+                .or_default()
+                .entry(*chunk)
+                .or_default()
+                .push((file, offset));
+            offset += *size as u64;
+        }
+        self.bytes += offset;
+
+        // Nothing to write? Done already!
+        if remaining == 0 {
+            finish_file(&path, self.journal)?;
+        }
+        self.files.push(PlannedFile {
+            path,
+            remaining: AtomicUsize::new(remaining),
+        });
+        Ok(())
+    }
+}
+
+fn finish_file(path: &Utf8Path, journal: Option<&Journal>) -> Result<()> {
+    let partial = partial_path(path);
+    fs::rename(&partial, path).with_context(|| format!("Couldn't move {partial} to {path}"))?;
+    if let Some(j) = journal {
+        j.record(&JournalEntry::Finished(path.to_owned()))?;
+    }
+    Ok(())
+}
+
+/// Download the planned packs and write their chunks into the planned files.
+pub fn fill(
+    plan: &Plan,
+    index: &index::Index,
+    cached_backend: &backend::CachedBackend,
+) -> Result<()> {
+    if plan.packs.is_empty() {
+        return Ok(());
+    }
+    let downloaders = (cached_backend.concurrency() as usize).min(plan.packs.len());
+    let workers = num_cpus::get().min(plan.packs.len());
+    debug!(
+        "Writing {} to {} files from {} packs ({downloaders} downloading, {workers} writing)",
+        file_util::nice_size(plan.bytes),
+        plan.files.len(),
+        plan.packs.len()
+    );
+
+    let to_download = Mutex::new(plan.packs.keys());
+    // Don't let downloads get too far ahead of the writers;
+    // we'd just fill up the cache (and maybe evict what we're about to read).
+    let (pack_tx, pack_rx) = sync_channel::<(ObjectId, Box<dyn backend::SeekableRead>)>(workers);
+    // Shared so that it hangs up on the downloaders once every writer is gone.
+    let pack_rx = Arc::new(Mutex::new(pack_rx));
+    // If anybody fails, everyone else should stop instead of plowing through the rest.
+    let failed = AtomicBool::new(false);
+
+    thread::scope(|s| -> Result<()> {
+        let mut threads = Vec::with_capacity(downloaders + workers);
+        for i in 0..downloaders {
+            let pack_tx = pack_tx.clone();
+            let to_download = &to_download;
+            let failed = &failed;
+            let t = thread::Builder::new()
+                .name(format!("restore download {i}"))
+                .spawn_scoped(s, move || -> Result<()> {
+                    while !failed.load(Ordering::Relaxed) {
+                        let Some(id) = to_download.lock().unwrap().next() else {
+                            break;
+                        };
+                        let fh = cached_backend.read_pack(id).inspect_err(|_| {
+                            failed.store(true, Ordering::Relaxed);
+                        })?;
+                        // Writers only hang up if they failed.
+                        if pack_tx.send((*id, fh)).is_err() {
+                            break;
+                        }
+                    }
+                    Ok(())
+                })
+                .unwrap();
+            threads.push(t);
+        }
+        drop(pack_tx);
+
+        for i in 0..workers {
+            let pack_rx = pack_rx.clone();
+            let failed = &failed;
+            let t = thread::Builder::new()
+                .name(format!("restore write {i}"))
+                .spawn_scoped(s, move || -> Result<()> {
+                    while !failed.load(Ordering::Relaxed) {
+                        // Drop the lock before decompressing so others can grab the next pack.
+                        let next = pack_rx.lock().unwrap().recv();
+                        let Ok((id, fh)) = next else {
+                            break;
+                        };
+                        write_pack(plan, index, cached_backend, &id, fh)
+                            .with_context(|| format!("Couldn't restore from pack {id}"))
+                            .inspect_err(|_| failed.store(true, Ordering::Relaxed))?;
+                    }
+                    Ok(())
+                })
+                .unwrap();
+            threads.push(t);
+        }
+        drop(pack_rx);
+
+        // Threads that stop because someone else failed return Ok,
+        // so whatever errors we find here are the real problem.
+        let mut first_error = None;
+        for t in threads {
+            if let Err(e) = t.join().unwrap() {
+                first_error.get_or_insert(e);
+            }
+        }
+        first_error.map_or(Ok(()), Err)
+    })
+}
+
+fn write_pack(
+    plan: &Plan,
+    index: &index::Index,
+    cached_backend: &backend::CachedBackend,
+    id: &ObjectId,
+    fh: Box<dyn backend::SeekableRead>,
+) -> Result<()> {
+    let wanted = &plan.packs[id];
+    let manifest = index
+        .packs
+        .get(id)
+        .ok_or_else(|| anyhow!("Couldn't find pack {id} manifest in the index"))?;
+
+    let mut blobs = pack::BlobReader::new(fh, cached_backend)?;
+    // Packs hold chunks in the order we backed them up,
+    // so keep the last file open instead of reopening it for each chunk.
+    // (Keeping every file open could run us out of descriptors.)
+    let mut last_file: Option<(usize, File)> = None;
+    let mut remaining = wanted.len();
+    let mut blob_buf = vec![];
+    for entry in manifest {
+        // Nothing left we need from this pack? Don't bother decompressing the rest.
+        if remaining == 0 {
+            break;
+        }
+        let Some(destinations) = wanted.get(&entry.id) else {
+            io::copy(&mut blobs.next_blob(entry)?, &mut io::sink())?;
+            continue;
+        };
+        remaining -= 1;
+
+        blob_buf.clear();
+        blob_buf.reserve(entry.length as usize);
+        let mut hashing_decoder =
+            HashingReader::new(blobs.next_blob(entry)?, cached_backend.hash());
+        hashing_decoder.read_to_end(&mut blob_buf)?;
+        let (hash, _) = hashing_decoder.finalize();
+        ensure!(
+            entry.id == hash,
+            "Calculated hash of blob ({}) doesn't match ID {}",
+            hash,
+            entry.id
+        );
+
+        for (file, offset) in destinations {
+            let planned = &plan.files[*file];
+            let path = &planned.path;
+            if last_file.as_ref().is_none_or(|(f, _)| f != file) {
+                let partial = partial_path(path);
+                let fh = OpenOptions::new()
+                    .write(true)
+                    .open(&partial)
+                    .with_context(|| format!("Couldn't open {partial} to restore it"))?;
+                last_file = Some((*file, fh));
+            }
+            write_at(&last_file.as_ref().unwrap().1, &blob_buf, *offset, path)?;
+            if let Some(j) = plan.journal {
+                j.record(&JournalEntry::Wrote {
+                    path: path.clone(),
+                    offset: *offset,
+                })?;
+            }
+            if planned.remaining.fetch_sub(1, Ordering::AcqRel) == 1 {
+                last_file = None; // Some systems won't move open files.
+                finish_file(path, plan.journal)?;
+            }
+        }
+    }
+    ensure!(
+        remaining == 0,
+        "Pack {id} was missing {remaining} chunks the index said it had"
+    );
+    // Write down our progress a pack at a time.
+    // We record each chunk after writing it, so if we're interrupted,
+    // the journal only ever falls behind what we've written, never ahead.
+    // (We don't sync anything though, so all bets are off if the machine goes down.)
+    if let Some(j) = plan.journal {
+        j.flush()?;
+    }
+    Ok(())
+}
+
+/// A restored path that doesn't match its snapshot
+#[derive(Debug)]
+pub struct Mismatch {
+    pub path: Utf8PathBuf,
+    pub problem: String,
+}
+
+/// Re-chunk each file with the repository's chunker and compare to the chunks it should have,
+/// returning the ones that don't match (sorted by path).
+pub fn verify_files(
+    files: &[(Utf8PathBuf, Vec<ObjectId>)],
+    chunker: &chunk::Chunker,
+) -> Vec<Mismatch> {
+    let checks = files.iter().map(|(path, chunks)| {
+        move || -> Result<Option<Mismatch>> {
+            Ok(verify_file(path, chunks, chunker).map(|problem| Mismatch {
+                path: path.clone(),
+                problem,
+            }))
+        }
+    });
+    let mut mismatches: Vec<Mismatch> = concurrently::map_concurrently(checks)
+        .into_iter()
+        .flatten()
+        .collect();
+    mismatches.sort_by(|a, b| a.path.cmp(&b.path));
+    mismatches
+}
+
+/// Returns what's wrong with the file, if anything.
+fn verify_file(path: &Utf8Path, expected: &[ObjectId], chunker: &chunk::Chunker) -> Option<String> {
+    trace!("Verifying {path}");
+    let actual: Vec<ObjectId> = match chunk::chunk_file(path, chunker) {
+        Ok(chunks) => chunks.map(|c| c.id).collect(),
+        Err(e) => return Some(format!("{e:#}")),
+    };
+    if actual == expected {
+        return None;
+    }
+    let first_difference = actual
+        .iter()
+        .zip(expected)
+        .position(|(a, e)| a != e)
+        .unwrap_or(actual.len().min(expected.len()));
+    Some(format!(
+        "Contents differ from the snapshot starting at chunk {} of {}",
+        first_difference + 1,
+        expected.len()
+    ))
+}
+
+#[cfg(unix)]
+fn write_at(fh: &File, buf: &[u8], offset: u64, path: &Utf8Path) -> Result<()> {
+    use std::os::unix::fs::FileExt;
+    fh.write_all_at(buf, offset)
+        .with_context(|| format!("Couldn't write to {path}"))
+}
+
+#[cfg(windows)]
+fn write_at(fh: &File, mut buf: &[u8], mut offset: u64, path: &Utf8Path) -> Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        let written = fh
+            .seek_write(buf, offset)
+            .with_context(|| format!("Couldn't write to {path}"))?;
+        buf = &buf[written..];
+        offset += written as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use byte_unit::Byte;
+
+    #[test]
+    fn fill_from_many_packs() -> Result<()> {
+        // Tiny packs so that we have to read several.
+        let (backend, index, chunks) = backend::in_memory_with_references(Byte::from_u64(1))?;
+        assert!(index.packs.len() > 1);
+        let blob_map = index::blob_to_pack_map(&index)?;
+
+        // Scatter chunks across files, repeating some, to make sure offsets work out.
+        let dir = tempfile::tempdir()?;
+        let dir = Utf8Path::from_path(dir.path()).unwrap();
+        let layouts: [&[usize]; 3] = [&[0, 1, 0], &[3, 2], &[]];
+        let mut plan = Plan::new(&index, &blob_map, None);
+        for (i, layout) in layouts.iter().enumerate() {
+            let ids: Vec<ObjectId> = layout.iter().map(|c| chunks[*c].id).collect();
+            plan.add_file(dir.join(format!("{i}")), &ids, None)?;
+        }
+        fill(&plan, &index, &backend)?;
+
+        for (i, layout) in layouts.iter().enumerate() {
+            let expected: Vec<u8> = layout
+                .iter()
+                .flat_map(|c| chunks[*c].bytes().to_vec())
+                .collect();
+            assert_eq!(std::fs::read(dir.join(format!("{i}")))?, expected);
+        }
+        Ok(())
+    }
+}
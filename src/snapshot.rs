@@ -35,7 +35,7 @@ use serde_derive::{Deserialize, Serialize};
 use crate::{
     backend, concurrently, counters,
     file_util::check_magic,
-    hashing::{HashingReader, HashingWriter, ObjectId},
+    hashing::{self, HashingReader, HashingWriter, ObjectId},
 };
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -129,11 +129,14 @@ fn undiskfmt(s2: SnapshotV2) -> Snapshot {
 
 const MAGIC_BYTES: &[u8] = b"MKBAKSNP";
 
-fn to_file(fh: &mut fs::File, snapshot: &Snapshot) -> Result<ObjectId> {
+/// The file version we write, just after the magic bytes
+const VERSION: u8 = b'2';
+
+fn to_file(fh: &mut fs::File, snapshot: &Snapshot, hash: hashing::Algorithm) -> Result<ObjectId> {
     fh.write_all(MAGIC_BYTES)?;
-    fh.write_all(b"2")?;
+    fh.write_all(&[VERSION])?;
 
-    let mut hasher = HashingWriter::new(fh);
+    let mut hasher = HashingWriter::new(fh, hash);
 
     ciborium::into_writer(&diskfmt(snapshot), &mut hasher)?;
 
@@ -151,7 +154,8 @@ pub fn upload(snapshot: &Snapshot, backend: &backend::CachedBackend) -> Result<O
         .tempfile_in(".") // TODO: Configurable?
         .context("Couldn't open temporary snapshot for writing")?;
 
-    let id = to_file(fh.as_file_mut(), snapshot).context("Couldn't save snapshot")?;
+    let id =
+        to_file(fh.as_file_mut(), snapshot, backend.hash()).context("Couldn't save snapshot")?;
 
     // Once the snapshot is done, let's persist it and upload it!
     let snapshot_name = format!("{}.snapshot", id);
@@ -168,11 +172,11 @@ pub fn upload(snapshot: &Snapshot, backend: &backend::CachedBackend) -> Result<O
 
 /// Loads the snapshot from the given reader,
 /// also returning its calculated ID.
-fn from_reader<R: Read>(r: &mut R) -> Result<(Snapshot, ObjectId)> {
+fn from_reader<R: Read>(r: &mut R, hash: hashing::Algorithm) -> Result<(Snapshot, ObjectId)> {
     check_magic(r, MAGIC_BYTES).context("Wrong magic bytes for snapshot file")?;
     let mut version = [0; 1];
     r.read_exact(&mut version)?;
-    let mut hasher = HashingReader::new(r);
+    let mut hasher = HashingReader::new(r, hash);
     let failmsg = "CBOR decoding of snapshot file failed";
     let snapshot = match version[0] {
         b'1' => ciborium::from_reader(&mut hasher).context(failmsg)?,
@@ -186,11 +190,25 @@ fn from_reader<R: Read>(r: &mut R) -> Result<(Snapshot, ObjectId)> {
     Ok((snapshot, id))
 }
 
+/// Is the given snapshot saved in an older format than the one we write?
+/// (`backpak migrate` rewrites them.)
+pub fn is_outdated(id: &ObjectId, cached_backend: &backend::CachedBackend) -> Result<bool> {
+    let mut r = cached_backend.read_snapshot(id)?;
+    check_magic(&mut r, MAGIC_BYTES)
+        .with_context(|| format!("Wrong magic bytes for snapshot {id}"))?;
+    let mut version = [0; 1];
+    r.read_exact(&mut version)?;
+    Ok(version[0] < VERSION)
+}
+
 /// Loads the snapshot with the given ID from the backend,
 /// verifying its contents match its ID.
 pub fn load(id: &ObjectId, cached_backend: &backend::CachedBackend) -> Result<Snapshot> {
-    let (snapshot, calculated_id) = from_reader(&mut cached_backend.read_snapshot(id)?)
-        .with_context(|| format!("Couldn't load snapshot {}", id))?;
+    let (snapshot, calculated_id) = from_reader(
+        &mut cached_backend.read_snapshot(id)?,
+        cached_backend.hash(),
+    )
+    .with_context(|| format!("Couldn't load snapshot {}", id))?;
     ensure!(
         *id == calculated_id,
         "Snapshot {}'s contents changed! Now hashes to {}",
@@ -355,14 +373,16 @@ mod test {
     #[test]
     fn round_trip() -> Result<()> {
         let snapshot = build_test_snapshot();
-        let mut fh = tempfile()?;
-        let written_id = to_file(&mut fh, &snapshot)?;
+        for hash in [hashing::Algorithm::Sha224, hashing::Algorithm::Blake3] {
+            let mut fh = tempfile()?;
+            let written_id = to_file(&mut fh, &snapshot, hash)?;
 
-        fh.seek(std::io::SeekFrom::Start(0))?;
-        let (read_snapshot, read_id) = from_reader(&mut fh)?;
+            fh.seek(std::io::SeekFrom::Start(0))?;
+            let (read_snapshot, read_id) = from_reader(&mut fh, hash)?;
 
-        assert_eq!(snapshot, read_snapshot);
-        assert_eq!(written_id, read_id);
+            assert_eq!(snapshot, read_snapshot);
+            assert_eq!(written_id, read_id);
+        }
         Ok(())
     }
 }
@@ -0,0 +1,282 @@
+//! Bandwidth limits for talking to the backend.
+//!
+//! Each direction gets a token bucket shared by every connection,
+//! so `concurrent_connections = 8` doesn't mean eight times the limit.
+
+use std::io::{self, Read, Seek};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use anyhow::{Result, ensure};
+use byte_unit::Byte;
+use jiff::civil::Time;
+use tracing::*;
+
+use crate::config::{BandwidthConfiguration, BandwidthWindow};
+
+static UPLOAD: OnceLock<Throttle> = OnceLock::new();
+static DOWNLOAD: OnceLock<Throttle> = OnceLock::new();
+
+/// Limit uploads and downloads per the config file.
+///
+/// `--limit-upload` and `--limit-download` (`upload` and `download` here)
+/// override the config's limits and schedule for their direction.
+pub fn init(
+    config: &BandwidthConfiguration,
+    upload: Option<Byte>,
+    download: Option<Byte>,
+) -> Result<()> {
+    let up = match upload {
+        Some(u) => Schedule::fixed(u),
+        None => Schedule::new(config, |w| w.upload, config.upload),
+    }?;
+    let down = match download {
+        Some(d) => Schedule::fixed(d),
+        None => Schedule::new(config, |w| w.download, config.download),
+    }?;
+    if up.is_limited() {
+        debug!("Limiting uploads: {up:?}");
+        UPLOAD.set(Throttle::new(up)).ok();
+    }
+    if down.is_limited() {
+        debug!("Limiting downloads: {down:?}");
+        DOWNLOAD.set(Throttle::new(down)).ok();
+    }
+    Ok(())
+}
+
+pub fn upload() -> Option<&'static Throttle> {
+    UPLOAD.get()
+}
+
+pub fn download() -> Option<&'static Throttle> {
+    DOWNLOAD.get()
+}
+
+/// Bytes per second, depending on the time of day
+#[derive(Debug, Clone)]
+struct Schedule {
+    /// Outside of any window (`None` is unlimited)
+    default: Option<u64>,
+    /// Start, end, and rate - the first window containing the current time wins.
+    windows: Vec<(Time, Time, Option<u64>)>,
+}
+
+fn rate(b: Byte) -> Result<u64> {
+    let r = b.as_u64();
+    ensure!(
+        r > 0,
+        "Bandwidth limits must be more than zero bytes per second"
+    );
+    Ok(r)
+}
+
+impl Schedule {
+    fn fixed(b: Byte) -> Result<Self> {
+        Ok(Self {
+            default: Some(rate(b)?),
+            windows: vec![],
+        })
+    }
+
+    fn new<F: Fn(&BandwidthWindow) -> Option<Byte>>(
+        config: &BandwidthConfiguration,
+        direction: F,
+        default: Option<Byte>,
+    ) -> Result<Self> {
+        let default = default.map(rate).transpose()?;
+        let windows = config
+            .schedule
+            .iter()
+            .map(|w| {
+                // Leave the default alone if the window doesn't say otherwise.
+                let r = match direction(w) {
+                    Some(b) => Some(rate(b)?),
+                    None => default,
+                };
+                Ok((w.start, w.end, r))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { default, windows })
+    }
+
+    fn is_limited(&self) -> bool {
+        self.default.is_some() || self.windows.iter().any(|(_, _, r)| r.is_some())
+    }
+
+    fn rate_at(&self, t: Time) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|(start, end, _)| {
+                if start <= end {
+                    *start <= t && t < *end
+                } else {
+                    // Wraps around midnight
+                    t >= *start || t < *end
+                }
+            })
+            .map_or(self.default, |(_, _, r)| *r)
+    }
+}
+
+/// A token bucket, refilled at the scheduled rate.
+pub struct Throttle {
+    schedule: Schedule,
+    bucket: Mutex<Bucket>,
+}
+
+struct Bucket {
+    /// Negative when we've gone over and callers are waiting it out.
+    tokens: f64,
+    last_fill: Instant,
+    rate: Option<u64>,
+    rate_checked: Option<Instant>,
+}
+
+impl Throttle {
+    fn new(schedule: Schedule) -> Self {
+        Self {
+            schedule,
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last_fill: Instant::now(),
+                rate: None,
+                rate_checked: None,
+            }),
+        }
+    }
+
+    /// We just moved `n` bytes; wait until that's allowed.
+    pub fn take(&self, n: usize) {
+        let wait = {
+            let mut b = self.bucket.lock().unwrap();
+            let now = Instant::now();
+            // Checking the clock is cheap, but not free. Once a second is plenty.
+            if b.rate_checked
+                .is_none_or(|c| now - c >= Duration::from_secs(1))
+            {
+                b.rate = self.schedule.rate_at(jiff::Zoned::now().time());
+                b.rate_checked = Some(now);
+            }
+            let since = now - b.last_fill;
+            b.last_fill = now;
+            let Some(rate) = b.rate else {
+                b.tokens = 0.0;
+                return;
+            };
+            let rate = rate as f64;
+            // Allow up to a second of burst.
+            b.tokens = (b.tokens + since.as_secs_f64() * rate).min(rate);
+            b.tokens -= n as f64;
+            if b.tokens >= 0.0 {
+                return;
+            }
+            // Everyone who comes after us will wait for our debt too,
+            // so we share the limit between however many connections we have.
+            Duration::from_secs_f64(-b.tokens / rate)
+        };
+        std::thread::sleep(wait);
+    }
+}
+
+/// Wait as needed to stay under the limit while reading
+pub struct ThrottledRead<R> {
+    inner: R,
+    throttle: Option<&'static Throttle>,
+}
+
+impl<R> ThrottledRead<R> {
+    /// Pass the [`upload()`] or [`download()`] throttle (or `None` to not throttle).
+    pub fn new(inner: R, throttle: Option<&'static Throttle>) -> Self {
+        Self { inner, throttle }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+impl<R: Read> Read for ThrottledRead<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let num_read = self.inner.read(buf)?;
+        if let Some(t) = self.throttle {
+            t.take(num_read);
+        }
+        Ok(num_read)
+    }
+}
+
+impl<R: Seek> Seek for ThrottledRead<R> {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn t(s: &str) -> Time {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn schedules() -> Result<()> {
+        let config: BandwidthConfiguration = toml::from_str(
+            r#"
+upload = "1 MB"
+
+[[schedule]]
+start = "09:00"
+end = "17:30"
+upload = "100 KB"
+download = "1 MB"
+
+[[schedule]]
+start = "22:00"
+end = "02:00"
+download = "5 MB"
+"#,
+        )?;
+        let up = Schedule::new(&config, |w| w.upload, config.upload)?;
+        let down = Schedule::new(&config, |w| w.download, config.download)?;
+        assert!(up.is_limited());
+        assert!(down.is_limited());
+
+        assert_eq!(up.rate_at(t("08:59")), Some(1_000_000));
+        assert_eq!(up.rate_at(t("09:00")), Some(100_000));
+        assert_eq!(up.rate_at(t("17:29")), Some(100_000));
+        assert_eq!(up.rate_at(t("17:30")), Some(1_000_000));
+        // Windows that don't set a direction leave it alone.
+        assert_eq!(up.rate_at(t("23:00")), Some(1_000_000));
+
+        assert_eq!(down.rate_at(t("08:00")), None);
+        assert_eq!(down.rate_at(t("12:00")), Some(1_000_000));
+        assert_eq!(down.rate_at(t("23:00")), Some(5_000_000));
+        assert_eq!(down.rate_at(t("01:59")), Some(5_000_000));
+        assert_eq!(down.rate_at(t("02:00")), None);
+
+        let nothing = BandwidthConfiguration::default();
+        assert!(!Schedule::new(&nothing, |w| w.upload, nothing.upload)?.is_limited());
+        assert!(Schedule::fixed(Byte::from_u64(0)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn throttling() {
+        let throttle: &'static Throttle = Box::leak(Box::new(Throttle::new(
+            Schedule::fixed(Byte::from_u64(100_000)).unwrap(),
+        )));
+        let data = vec![0u8; 50_000];
+        let start = Instant::now();
+        let mut r = ThrottledRead::new(data.as_slice(), Some(throttle));
+        io::copy(&mut r, &mut io::sink()).unwrap();
+        io::copy(
+            &mut ThrottledRead::new(data.as_slice(), Some(throttle)),
+            &mut io::sink(),
+        )
+        .unwrap();
+        // 100 KB at 100 KB/s should take about a second.
+        assert!(start.elapsed() >= Duration::from_millis(900));
+    }
+}
@@ -2,7 +2,7 @@
 
 use std::collections::BTreeMap;
 use std::fs;
-use std::sync::{Arc, LazyLock};
+use std::sync::Arc;
 
 use anyhow::{Context, Result, anyhow, ensure};
 use camino::{Utf8Path, Utf8PathBuf};
@@ -13,7 +13,7 @@ use tracing::*;
 
 use crate::backend;
 use crate::counters;
-use crate::hashing::ObjectId;
+use crate::hashing::{self, ObjectId};
 use crate::index;
 use crate::pack;
 use crate::prettify;
@@ -157,7 +157,8 @@ pub fn meta_diff_char(l: &NodeMetadata, r: &NodeMetadata) -> Option<char> {
     Some(c)
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
 pub enum NodeType {
     File,
     Directory,
@@ -332,15 +333,17 @@ pub type Tree = BTreeMap<Utf8PathBuf, Node>;
 
 /// Serialize the tree into its on-disk CBOR representation and return its
 /// ID (hash)
-pub fn serialize_and_hash(tree: &Tree) -> Result<(Vec<u8>, ObjectId)> {
+pub fn serialize_and_hash(tree: &Tree, hash: hashing::Algorithm) -> Result<(Vec<u8>, ObjectId)> {
     let mut tree_cbor = Vec::new();
     ciborium::into_writer(tree, &mut tree_cbor)?;
-    let id = ObjectId::hash(&tree_cbor);
+    let id = hash.hash(&tree_cbor);
     Ok((tree_cbor, id))
 }
 
-pub static EMPTY_ID: LazyLock<ObjectId> =
-    LazyLock::new(|| serialize_and_hash(&Tree::default()).unwrap().1);
+/// The ID of an empty tree in a repository using the given hash
+pub fn empty_id(hash: hashing::Algorithm) -> ObjectId {
+    serialize_and_hash(&Tree::default(), hash).unwrap().1
+}
 
 /// A collection of trees (which can reference each other as subtrees),
 /// used to represent a directory hierarchy.
@@ -383,7 +386,7 @@ impl<'a> Cache<'a> {
     ) -> Self {
         let mut tree_cache = FxHashMap::default();
         // See ui/backup.rs: We don't bother uploading nothing, so let's precache nothing.
-        tree_cache.insert(*EMPTY_ID, Arc::new(Tree::default()));
+        tree_cache.insert(empty_id(pack_cache.hash()), Arc::new(Tree::default()));
 
         Self {
             index,
@@ -416,7 +419,12 @@ impl<'a> Cache<'a> {
             .get(pack_id)
             .expect("Pack ID in blob -> pack map but not the index");
 
-        pack::append_to_forest(&mut pack_containing_tree, manifest, &mut self.tree_cache)?;
+        pack::append_to_forest(
+            &mut pack_containing_tree,
+            manifest,
+            &mut self.tree_cache,
+            self.pack_cache,
+        )?;
 
         self.tree_cache
             .get(id)
@@ -482,13 +490,13 @@ fn chunks_in_node(node: &Node) -> &[ObjectId] {
     }
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Copy, Clone, Serialize)]
 pub struct FileSize {
     pub introduced: u64,
     pub reused: u64,
 }
 
-#[derive(Default)]
+#[derive(Default, Serialize)]
 pub struct ForestSizes {
     pub tree_bytes: u64,
     pub chunk_bytes: u64,
@@ -496,6 +504,7 @@ pub struct ForestSizes {
     pub reused: u64,
     // &'a Utf8Path would be more ideal, but tying ourselves to the lifetime of the forest
     // we get the path names from is a pretty big PITA.
+    #[serde(skip)]
     pub per_file: Vec<(Utf8PathBuf, FileSize)>,
 }
 
@@ -651,7 +660,7 @@ mod test {
             },
         );
 
-        let (serialized_tree, id) = serialize_and_hash(&tree)?;
+        let (serialized_tree, id) = serialize_and_hash(&tree, hashing::Algorithm::Sha224)?;
 
         /*
         use std::io::Write;
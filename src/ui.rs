@@ -8,8 +8,13 @@ pub mod filter_snapshot;
 pub mod forget;
 pub mod init;
 pub mod ls;
+pub mod migrate;
 pub mod prune;
 pub mod rebuild_index;
+pub mod repair;
 pub mod restore;
+pub mod run;
 pub mod snapshots;
+pub mod train_dictionary;
 pub mod usage;
+pub mod verify_restore;
@@ -1,6 +1,8 @@
 use std::cell::RefCell;
 use std::collections::BTreeSet;
 use std::io;
+use std::process;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
 use std::thread;
 
@@ -9,6 +11,7 @@ use camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
 use console::Term;
 use rustc_hash::FxHashSet;
+use serde_derive::Serialize;
 use tracing::*;
 
 use crate::backend;
@@ -21,13 +24,24 @@ use crate::filter;
 use crate::fs_tree;
 use crate::hashing::{HashingWriter, ObjectId};
 use crate::index;
-use crate::progress::{ProgressThread, print_backup_lines, print_download_line, truncate_path};
+use crate::metrics;
+use crate::progress::{
+    JSON_PROGRESS_RATE, ProgressMode, ProgressThread, print_backup_lines, print_download_line,
+    truncate_path,
+};
 use crate::rcu::Rcu;
 use crate::snapshot::{self, Snapshot};
 use crate::tree;
 
 /// Create a snapshot of the given files and directories.
+///
+/// Exits with
+///   0 when a snapshot was made,
+///   1 on failure (or 2 if the command line is wrong),
+///   10 when nothing changed (so no snapshot was made; see --allow-empty and --allow-repeat),
+///   11 when a snapshot was made, but some files couldn't be read.
 #[derive(Debug, Parser)]
+#[command(verbatim_doc_comment)]
 pub struct Args {
     /// Dereference symbolic links instead of just saving their target.
     #[clap(short = 'L', long)]
@@ -60,6 +74,14 @@ pub struct Args {
     #[clap(short = 'n', long)]
     dry_run: bool,
 
+    /// How to print progress.
+    ///
+    /// `json` prints an object per line each second
+    /// (`"event": "check"` while scanning paths, then `"event": "progress"`),
+    /// and a final `"event": "summary"` with the outcome and snapshot ID.
+    #[clap(long, value_enum, default_value_t, verbatim_doc_comment)]
+    progress: ProgressMode,
+
     /// The paths to back up
     ///
     /// These paths are canonicalized into absolute ones.
@@ -69,7 +91,151 @@ pub struct Args {
     paths: Vec<Utf8PathBuf>,
 }
 
-pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
+impl Args {
+    /// Back up a profile's paths, with its skips, tags, and author.
+    ///
+    /// (Its hooks live in the [`Configuration`] we pass to [`run()`].)
+    pub fn from_profile(profile: &config::Profile, dry_run: bool, progress: ProgressMode) -> Self {
+        Self {
+            dereference: false,
+            no_dereference: false,
+            allow_empty: false,
+            allow_repeat: false,
+            author: profile.author.clone(),
+            tags: profile.tags.clone(),
+            skips: profile.skips.clone(),
+            dry_run,
+            progress,
+            paths: profile.paths.clone(),
+        }
+    }
+
+    /// True if we want stdout to ourselves, i.e., no INFO messages there.
+    pub fn quiet_stdout(&self) -> bool {
+        self.progress == ProgressMode::Json
+    }
+}
+
+/// How a backup went, reported via our exit code.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// We made a snapshot.
+    Success,
+    /// Nothing changed, so we didn't make a snapshot.
+    Unchanged,
+    /// We made a snapshot, but skipped some files we couldn't read.
+    Partial,
+}
+
+impl Outcome {
+    fn code(self) -> u8 {
+        match self {
+            Outcome::Success => 0,
+            // Stay out of the way of failures (1) and usage errors (2).
+            Outcome::Unchanged => 10,
+            Outcome::Partial => 11,
+        }
+    }
+}
+
+impl From<Outcome> for std::process::ExitCode {
+    fn from(o: Outcome) -> Self {
+        Self::from(o.code())
+    }
+}
+
+pub fn run(mut config: Configuration, repository: &Utf8Path, args: Args) -> Result<Outcome> {
+    let hooks = std::mem::take(&mut config.backup.hooks);
+    let quiet_stdout = args.quiet_stdout();
+    let mut hook_env = HookEnv::default();
+
+    let res = (|| {
+        // The pre hook might be what makes the paths we're backing up, e.g., mounting an LVM
+        // snapshot, so run it before we go looking at them.
+        run_hook(
+            "pre",
+            hooks.pre.as_deref(),
+            &hook_env.vars(repository, None),
+            quiet_stdout,
+        )?;
+        let outcome = backup(config, repository, args, &mut hook_env)?;
+        let vars = hook_env.vars(repository, Some(outcome.code()));
+        run_hook("post", hooks.post.as_deref(), &vars, quiet_stdout)?;
+        Ok(outcome)
+    })();
+
+    if let Err(e) = &res {
+        let mut vars = hook_env.vars(repository, Some(1));
+        vars.push(("BACKPAK_ERROR", format!("{e:#}")));
+        // Don't let a failing on_error hook bury the original error.
+        if let Err(he) = run_hook("on_error", hooks.on_error.as_deref(), &vars, quiet_stdout) {
+            error!("{he:?}");
+        }
+    }
+    res
+}
+
+/// What we tell hooks about the backup (via `BACKPAK_*` environment variables)
+#[derive(Debug, Default)]
+struct HookEnv {
+    snapshot: Option<ObjectId>,
+    uploaded_bytes: Option<Arc<AtomicU64>>,
+}
+
+impl HookEnv {
+    fn vars(&self, repository: &Utf8Path, exit_status: Option<u8>) -> Vec<(&'static str, String)> {
+        let mut vars = vec![("BACKPAK_REPOSITORY", repository.to_string())];
+        if let Some(es) = exit_status {
+            vars.push(("BACKPAK_EXIT_STATUS", es.to_string()));
+            let ub = self
+                .uploaded_bytes
+                .as_ref()
+                .map_or(0, |u| u.load(Ordering::Relaxed));
+            vars.push(("BACKPAK_BYTES_UPLOADED", ub.to_string()));
+        }
+        if let Some(s) = &self.snapshot {
+            vars.push(("BACKPAK_SNAPSHOT", s.to_string()));
+        }
+        vars
+    }
+}
+
+fn run_hook(
+    name: &str,
+    command: Option<&str>,
+    vars: &[(&str, String)],
+    quiet_stdout: bool,
+) -> Result<()> {
+    let Some(command) = command else {
+        return Ok(());
+    };
+    info!("Running {name} hook");
+    debug!("sh -c {command:?}");
+
+    let mut cmd = process::Command::new("sh");
+    cmd.arg("-c").arg(command).envs(vars.iter().cloned());
+    cmd.stdin(process::Stdio::null());
+    // Keep stdout clean for --progress=json
+    if quiet_stdout {
+        cmd.stdout(io::stderr());
+    }
+    let mut child = cmd
+        .spawn()
+        .with_context(|| format!("Couldn't run {name} hook"))?;
+    // Make sure the hook dies with us if some other thread hits a fatal error.
+    let _cg = crate::ChildGuard::new(child.id());
+    let status = child.wait()?;
+    ensure!(status.success(), "{name} hook failed ({status})");
+    Ok(())
+}
+
+fn backup(
+    config: Configuration,
+    repository: &Utf8Path,
+    args: Args,
+    hook_env: &mut HookEnv,
+) -> Result<Outcome> {
     // Let's canonicalize our paths (and make sure they're real!)
     // before we spin up a bunch of supporting infrastructure.
     let paths: BTreeSet<Utf8PathBuf> = args
@@ -103,12 +269,24 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
     // and threads and all manner of craziness going.
     let bytes_checked = AtomicU64::default();
     thread::scope(|s| -> Result<_> {
-        let progress_thread =
-            ProgressThread::spawn(s, |i| print_path_check(i, &Term::stdout(), &bytes_checked));
+        let progress_thread = match args.progress {
+            ProgressMode::Term => Some(ProgressThread::spawn(s, |i| {
+                print_path_check(i, &Term::stdout(), &bytes_checked)
+            })),
+            ProgressMode::Json => {
+                Some(ProgressThread::spawn_at_rate(s, JSON_PROGRESS_RATE, |_| {
+                    let bytes_checked = bytes_checked.load(Ordering::Relaxed);
+                    print_json_event(&JsonEvent::Check { bytes_checked })
+                }))
+            }
+            ProgressMode::None => None,
+        };
 
         let check_res = check_paths(symlink_behavior, &paths, &skips, &bytes_checked)
             .context("Failed FS check prior to backup");
-        progress_thread.join();
+        if let Some(p) = progress_thread {
+            p.join();
+        }
         check_res
     })?;
 
@@ -118,6 +296,9 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
         backend::CacheBehavior::Normal,
     )?;
 
+    // Whatever happens, let the hooks know how much we uploaded.
+    hook_env.uploaded_bytes = Some(cached_backend.bytes_uploaded.clone());
+
     let index = index::build_master_index(&cached_backend)?;
     let blob_map = index::blob_to_pack_map(&index)?;
 
@@ -165,16 +346,27 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
             &back_stats,
         );
 
-        let progress_thread = ProgressThread::spawn(s, |i| {
-            print_progress(
-                i,
-                &Term::stdout(),
-                &back_stats,
-                &walk_stats,
-                &cached_backend.bytes_uploaded,
-                &cached_backend.bytes_downloaded,
-            )
-        });
+        let progress_thread = match args.progress {
+            ProgressMode::Term => Some(ProgressThread::spawn(s, |i| {
+                print_progress(
+                    i,
+                    &Term::stdout(),
+                    &back_stats,
+                    &walk_stats,
+                    &cached_backend.bytes_uploaded,
+                    &cached_backend.bytes_downloaded,
+                )
+            })),
+            ProgressMode::Json => {
+                Some(ProgressThread::spawn_at_rate(s, JSON_PROGRESS_RATE, |_| {
+                    let cf = walk_stats.current_file.borrow();
+                    let stats =
+                        JsonStatistics::new(&back_stats, &walk_stats, &cached_backend, Some(&cf));
+                    print_json_event(&JsonEvent::Progress(stats))
+                }))
+            }
+            ProgressMode::None => None,
+        };
 
         let run_res = (|| {
             // Finish the WIP resume business.
@@ -194,6 +386,7 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
                 &mut packed_blobs,
                 &mut backup,
                 &walk_stats,
+                &chunk::Chunker::new(&backend_config.chunking, backend_config.hash),
             )?;
             drop(parent_forest);
             drop(packed_blobs);
@@ -206,10 +399,26 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
             Ok(root)
         })();
 
-        progress_thread.join();
+        if let Some(p) = progress_thread {
+            p.join();
+        }
         run_res
     })?;
-    if root == *tree::EMPTY_ID && !args.allow_empty {
+
+    // Tell --progress=json watchers (and --metrics-file) how it all shook out.
+    let summarize = |status: Outcome, snapshot: Option<&ObjectId>| -> Result<Outcome> {
+        record_metrics(&back_stats, &walk_stats);
+        if args.progress == ProgressMode::Json {
+            print_json_event(&JsonEvent::Summary {
+                status,
+                snapshot: snapshot.map(|id| id.to_string()),
+                stats: JsonStatistics::new(&back_stats, &walk_stats, &cached_backend, None),
+            })?;
+        }
+        Ok(status)
+    };
+
+    if root == tree::empty_id(backend_config.hash) && !args.allow_empty {
         // We really did nothing, huh?
         assert_eq!(back_stats.chunk_bytes.load(Ordering::Relaxed), 0);
         assert_eq!(back_stats.tree_bytes.load(Ordering::Relaxed), 0);
@@ -219,7 +428,7 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
         assert_eq!(cached_backend.bytes_uploaded.load(Ordering::Relaxed), 0);
 
         info!("Nothing backed up! Pass --allow-empty to create an empty snapshot.");
-        return Ok(());
+        return summarize(Outcome::Unchanged, None);
     }
 
     debug!("Root tree packed as {}", root);
@@ -257,7 +466,7 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
             assert_eq!(cached_backend.bytes_uploaded.load(Ordering::Relaxed), 0);
 
             info!("Snapshot is the same as the last! Pass --allow-repeat to create a duplicate.");
-            return Ok(());
+            return summarize(Outcome::Unchanged, None);
         }
         _ => (),
     };
@@ -273,18 +482,28 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
     let ubytes = nice_size(cached_backend.bytes_uploaded.load(Ordering::Relaxed));
     let dbytes = nice_size(cached_backend.bytes_downloaded.load(Ordering::Relaxed));
     debug!("{zbytes} compressed, {ubytes} uploaded, {dbytes} downloaded");
+    let skipped_files = walk_stats.skipped_files.load(Ordering::Relaxed);
 
     let snap_id = if !args.dry_run {
         snapshot::upload(&snapshot, &cached_backend)?
     } else {
-        let mut hasher = HashingWriter::new(io::sink());
+        let mut hasher = HashingWriter::new(io::sink(), backend_config.hash);
         ciborium::into_writer(&snapshot, &mut hasher)?;
         let (id, _) = hasher.finalize();
         id
     };
 
-    println!("\nSnaphsot {} done", snap_id.short_name());
-    Ok(())
+    let outcome = if skipped_files > 0 {
+        warn!("Skipped {skipped_files} files we couldn't read");
+        Outcome::Partial
+    } else {
+        Outcome::Success
+    };
+    if args.progress != ProgressMode::Json {
+        println!("\nSnaphsot {} done", snap_id.short_name());
+    }
+    hook_env.snapshot = Some(snap_id);
+    summarize(outcome, Some(&snap_id))
 }
 
 fn print_path_check(i: usize, term: &Term, b: &AtomicU64) -> Result<()> {
@@ -302,6 +521,106 @@ fn print_path_check(i: usize, term: &Term, b: &AtomicU64) -> Result<()> {
 struct WalkStatistics {
     current_file: Rcu<Utf8PathBuf>,
     reused_bytes: AtomicU64,
+    /// Files that vanished or became unreadable after we checked them.
+    skipped_files: AtomicU64,
+}
+
+/// `--progress=json` output
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum JsonEvent<'a> {
+    /// Making sure we can read everything before the backup proper
+    Check {
+        bytes_checked: u64,
+    },
+    Progress(JsonStatistics<'a>),
+    Summary {
+        status: Outcome,
+        // NB: We can't prettify_serialize() an ObjectId here;
+        // that would hit the indexes and snapshots we're writing too!
+        snapshot: Option<String>,
+        #[serde(flatten)]
+        stats: JsonStatistics<'a>,
+    },
+}
+
+/// The same stats as [`print_progress()`], in bytes.
+#[derive(Serialize)]
+struct JsonStatistics<'a> {
+    chunk_bytes: u64,
+    tree_bytes: u64,
+    reused_bytes: u64,
+    compressed_bytes: u64,
+    uploaded_bytes: u64,
+    downloaded_bytes: u64,
+    indexed_packs: u64,
+    skipped_files: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    current_file: Option<&'a Utf8Path>,
+}
+
+impl<'a> JsonStatistics<'a> {
+    fn new(
+        bstats: &backup::BackupStatistics,
+        wstats: &WalkStatistics,
+        cached_backend: &backend::CachedBackend,
+        current_file: Option<&'a Utf8Path>,
+    ) -> Self {
+        Self {
+            chunk_bytes: bstats.chunk_bytes.load(Ordering::Relaxed),
+            tree_bytes: bstats.tree_bytes.load(Ordering::Relaxed),
+            reused_bytes: wstats.reused_bytes.load(Ordering::Relaxed),
+            compressed_bytes: bstats.compressed_bytes.load(Ordering::Relaxed),
+            uploaded_bytes: cached_backend.bytes_uploaded.load(Ordering::Relaxed),
+            downloaded_bytes: cached_backend.bytes_downloaded.load(Ordering::Relaxed),
+            indexed_packs: bstats.indexed_packs.load(Ordering::Relaxed),
+            skipped_files: wstats.skipped_files.load(Ordering::Relaxed),
+            current_file,
+        }
+    }
+}
+
+fn record_metrics(bstats: &backup::BackupStatistics, wstats: &WalkStatistics) {
+    let stats = [
+        (
+            "backup_chunk_bytes",
+            "File data packed",
+            bstats.chunk_bytes.load(Ordering::Relaxed),
+        ),
+        (
+            "backup_tree_bytes",
+            "Metadata packed",
+            bstats.tree_bytes.load(Ordering::Relaxed),
+        ),
+        (
+            "backup_compressed_bytes",
+            "Packed data after compression",
+            bstats.compressed_bytes.load(Ordering::Relaxed),
+        ),
+        (
+            "backup_reused_bytes",
+            "Data deduplicated with previous backups",
+            wstats.reused_bytes.load(Ordering::Relaxed),
+        ),
+        (
+            "backup_indexed_packs",
+            "Packs indexed",
+            bstats.indexed_packs.load(Ordering::Relaxed),
+        ),
+        (
+            "backup_skipped_files",
+            "Files skipped because they couldn't be read",
+            wstats.skipped_files.load(Ordering::Relaxed),
+        ),
+    ];
+    for (name, help, value) in stats {
+        metrics::set(name, help, value);
+    }
+}
+
+fn print_json_event(e: &JsonEvent) -> Result<()> {
+    println!("{}", serde_json::to_string(e)?);
+    Ok(())
 }
 
 fn print_progress(
@@ -408,6 +727,7 @@ fn backup_tree(
     packed_blobs: &mut FxHashSet<ObjectId>,
     backup: &mut Backup,
     walk_stats: &WalkStatistics,
+    chunker: &chunk::Chunker,
 ) -> Result<ObjectId> {
     use fs_tree::DirectoryEntry;
 
@@ -466,8 +786,27 @@ fn backup_tree(
                     contents: previous_node.unwrap().contents.clone(),
                 }
             }
-            DirectoryEntry::ChangedFile => {
-                let chunks = chunk::chunk_file(path)?;
+            DirectoryEntry::ChangedFile => 'changed: {
+                // We checked that we could read everything before we started,
+                // but things could have changed out from under us since.
+                // Skip the file instead of failing the whole backup.
+                let chunks = match chunk::chunk_file(path, chunker) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        walk_stats.skipped_files.fetch_add(1, Ordering::Relaxed);
+                        // If the parent snapshot has it, keep the last version we could read
+                        // instead of dropping it from this one.
+                        let Some(prev) = previous_node.filter(|p| p.kind() == tree::NodeType::File)
+                        else {
+                            warn!("Skipping {path}: {e:?}");
+                            return Ok(());
+                        };
+                        warn!("Keeping the previous version of {path}: {e:?}");
+                        let pb = prev.metadata.size().expect("files have sizes");
+                        walk_stats.reused_bytes.fetch_add(pb, Ordering::Relaxed);
+                        break 'changed prev.clone();
+                    }
+                };
 
                 let mut chunk_ids = Vec::new();
                 let mut new_chunks = false;
@@ -525,10 +864,10 @@ fn backup_tree(
         // NB: For this to work, anything reading trees must also work in kind.
         //     Thankfully all go through tree::Cache, so we can do that once, there.
         if tree == tree::Tree::default() {
-            return Ok(*tree::EMPTY_ID);
+            return Ok(tree::empty_id(backup.hash));
         }
 
-        let (bytes, id) = tree::serialize_and_hash(&tree)?;
+        let (bytes, id) = tree::serialize_and_hash(&tree, backup.hash)?;
 
         if packed_blobs.borrow_mut().insert(id) {
             backup
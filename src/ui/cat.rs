@@ -76,7 +76,8 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) ->
 
             let mut reader = cached_backend.read_pack(containing_pack_id)?;
 
-            let (manifest_entry, blob) = pack::extract_blob(&mut reader, &id, index_manifest)?;
+            let (manifest_entry, blob) =
+                pack::extract_blob(&mut reader, &id, index_manifest, &cached_backend)?;
 
             debug_assert!(manifest_entry.id == id);
             assert!(!blob.is_empty());
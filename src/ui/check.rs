@@ -1,10 +1,16 @@
+use std::io::{self, prelude::*};
+use std::str::FromStr;
+use std::sync::Arc;
 use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::thread;
 
-use anyhow::{Result, bail};
+use anyhow::{Context, Result, bail, ensure};
+use byte_unit::Byte;
+use camino::Utf8PathBuf;
 use clap::Parser;
 use console::Term;
 use rustc_hash::{FxHashMap, FxHashSet};
+use serde_derive::Serialize;
 use tracing::*;
 
 use crate::backend;
@@ -13,6 +19,7 @@ use crate::config::Configuration;
 use crate::hashing::ObjectId;
 use crate::index;
 use crate::pack;
+use crate::parity;
 use crate::progress::{ProgressThread, print_download_line, spinner};
 use crate::snapshot;
 use crate::tree;
@@ -23,12 +30,165 @@ use crate::tree;
 /// and only ensures that needed files can be found and downloaded.
 /// If `--read-packs` is specified, ensure that each pack has the expected blobs,
 /// that those blobs match its manifest, and that those blobs match the index.
+/// `--read-packs-subset` does the same for only some of them,
+/// so a big repository can be read bit by bit (e.g., `1/30` through `30/30` over a month).
+/// `--deep` reads every tree of every snapshot and makes sure each file and directory
+/// in them is whole, listing exactly which paths are damaged.
+/// `--parity` checks each pack against its parity (for repositories that save it),
+/// finding bit rot and whether `repair --from-parity` can fix it.
 #[derive(Debug, Parser)]
 #[clap(verbatim_doc_comment)]
 pub struct Args {
     /// Check the contents of packs, not just that they exist
     #[clap(short, long)]
     read_packs: bool,
+
+    /// Check the contents of some packs: part N of M (N/M),
+    /// a random percentage (5%), or random packs up to a size (50GB)
+    #[clap(
+        long,
+        value_name = "SUBSET",
+        conflicts_with = "read_packs",
+        verbatim_doc_comment
+    )]
+    read_packs_subset: Option<Subset>,
+
+    /// Check that every tree in every snapshot can be read,
+    /// and that each file's size matches its chunks
+    #[clap(long, verbatim_doc_comment)]
+    deep: bool,
+
+    /// Check each pack against its parity,
+    /// and whether that parity can fix any damage
+    #[clap(long, verbatim_doc_comment)]
+    parity: bool,
+}
+
+/// Which packs `--read-packs-subset` reads
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Subset {
+    /// The Nth of M (1-based) groups.
+    /// Packs are grouped by ID, so each stays in the same group as the repository grows.
+    Part { n: u64, m: u64 },
+    /// A random sample of this percentage of all packs
+    Percent(f64),
+    /// Random packs, until we've read this many bytes
+    Size(Byte),
+}
+
+impl FromStr for Subset {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if let Some((n, m)) = s.split_once('/') {
+            let n: u64 = n
+                .trim()
+                .parse()
+                .with_context(|| format!("Bad subset {s}"))?;
+            let m: u64 = m
+                .trim()
+                .parse()
+                .with_context(|| format!("Bad subset {s}"))?;
+            ensure!(
+                (1..=m).contains(&n),
+                "Subset {s} should be N/M, with N from 1 to M"
+            );
+            Ok(Subset::Part { n, m })
+        } else if let Some(p) = s.strip_suffix('%') {
+            let p: f64 = p
+                .trim()
+                .parse()
+                .with_context(|| format!("Bad subset {s}"))?;
+            ensure!(
+                p > 0.0 && p <= 100.0,
+                "Subset {s} should be more than 0% and at most 100%"
+            );
+            Ok(Subset::Percent(p))
+        } else {
+            let b = Byte::parse_str(s, true) // Don't interpret b as bits.
+                .with_context(|| format!("Subset {s} isn't N/M, a percentage, or a size"))?;
+            Ok(Subset::Size(b))
+        }
+    }
+}
+
+impl Subset {
+    /// Pick packs to read from the given index.
+    /// (Sizes come from the backend's pack list.)
+    fn select<'a>(
+        &self,
+        packs: &'a index::PackMap,
+        all_packs: &[(String, u64)],
+    ) -> Result<FxHashSet<&'a ObjectId>> {
+        let selected = match *self {
+            Subset::Part { n, m } => packs
+                .keys()
+                .filter(|id| partition_of(id, m) == n - 1)
+                .collect(),
+            Subset::Percent(p) => {
+                let count = (packs.len() as f64 * p / 100.0).ceil() as usize;
+                shuffled(packs)?.into_iter().take(count).collect()
+            }
+            Subset::Size(b) => {
+                let sizes = all_packs
+                    .iter()
+                    .map(|(file, len)| Ok((backend::id_from_path(file)?, *len)))
+                    .collect::<Result<FxHashMap<ObjectId, u64>>>()?;
+                let mut remaining = b.as_u64();
+                shuffled(packs)?
+                    .into_iter()
+                    .take_while(|id| {
+                        if remaining == 0 {
+                            return false;
+                        }
+                        remaining = remaining.saturating_sub(*sizes.get(id).unwrap_or(&0));
+                        true
+                    })
+                    .collect()
+            }
+        };
+        Ok(selected)
+    }
+}
+
+/// Which of `m` groups the pack goes in, based on its ID (which is already nicely random).
+fn partition_of(id: &ObjectId, m: u64) -> u64 {
+    let leading = u64::from_be_bytes(id.as_bytes()[..8].try_into().unwrap());
+    leading % m
+}
+
+/// The packs in a different random order each time
+fn shuffled(packs: &index::PackMap) -> Result<Vec<&ObjectId>> {
+    let mut key = [0u8; 32];
+    getrandom::getrandom(&mut key)?;
+    let mut ids: Vec<_> = packs.keys().collect();
+    ids.sort_by_cached_key(|id| *blake3::keyed_hash(&key, id.as_bytes()).as_bytes());
+    Ok(ids)
+}
+
+/// `check --json` output
+#[derive(Debug, Serialize)]
+struct JsonCheck {
+    ok: bool,
+    read_packs: bool,
+    packs: usize,
+    packs_read: usize,
+    broken_packs: u32,
+    unreachable_packs: usize,
+    missing_chunks: usize,
+    deep: bool,
+    damaged_paths: Vec<JsonDamage>,
+    parity: bool,
+    fixable_packs: u32,
+    unfixable_packs: u32,
+    packs_without_parity: usize,
+}
+
+#[derive(Debug, Serialize)]
+struct JsonDamage {
+    snapshot: String,
+    path: Utf8PathBuf,
+    problem: String,
 }
 
 #[derive(Default)]
@@ -39,43 +199,80 @@ pub struct ReadStatus {
     blobs_read: AtomicU64,
 }
 
-pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) -> Result<()> {
+pub fn run(
+    config: &Configuration,
+    repository: &camino::Utf8Path,
+    json: bool,
+    args: Args,
+) -> Result<()> {
     let mut trouble = false;
 
     // NB: We always want to read when checking the backend!
     // Just because it's in-cache doesn't mean it's backed up.
-    let (_cfg, cached_backend) = backend::open(
+    let (cfg, cached_backend) = backend::open(
         repository,
         config.cache_size,
         backend::CacheBehavior::AlwaysRead,
     )?;
+    ensure!(
+        !args.parity || cfg.filter.is_none(),
+        "Filtered repositories don't save parity"
+    );
 
     let index = index::build_master_index(&cached_backend)?;
 
     info!("Downloading pack list");
     let all_packs = cached_backend.list_packs()?;
     let borked_packs = AtomicU32::new(0);
-    if args.read_packs {
+
+    let to_read: FxHashSet<&ObjectId> = if args.read_packs {
+        index.packs.keys().collect()
+    } else if let Some(subset) = &args.read_packs_subset {
+        let s = subset.select(&index.packs, &all_packs)?;
+        info!(
+            "Reading {} of {} packs ({:?})",
+            s.len(),
+            index.packs.len(),
+            subset
+        );
+        s
+    } else {
+        FxHashSet::default()
+    };
+
+    // If we don't have to read a pack, just make sure we find it in the list.
+    info!("Checking that all indexed packs are present");
+    for pack_id in index.packs.keys().filter(|id| !to_read.contains(id)) {
+        match backend::probe_pack(&all_packs, pack_id) {
+            Ok(()) => debug!("Pack {} found", pack_id),
+            Err(e) => {
+                error!("{e:?}"); // Error already has a message about specific pack
+                borked_packs.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    if !to_read.is_empty() {
         let stats = ReadStatus {
-            packs_total: index.packs.len() as u32,
-            blobs_total: index
-                .packs
-                .values()
-                .map(|manifest| manifest.len() as u64)
-                .sum(),
+            packs_total: to_read.len() as u32,
+            blobs_total: to_read.iter().map(|id| index.packs[*id].len() as u64).sum(),
             ..Default::default()
         };
         thread::scope(|s| -> Result<()> {
-            let progress = ProgressThread::spawn(s, |i| {
-                print_progress(i, &Term::stdout(), &stats, &cached_backend.bytes_downloaded)
+            // Don't garble JSON output with progress updates.
+            let progress = (!json).then(|| {
+                ProgressThread::spawn(s, |i| {
+                    print_progress(i, &Term::stdout(), &stats, &cached_backend.bytes_downloaded)
+                })
             });
             // Actually read the packs; do this in parallel as much as the backend allows
-            let checks = index.packs.iter().map(|(pack_id, manifest)| {
+            let checks = to_read.iter().map(|pack_id| {
+                let manifest = &index.packs[*pack_id];
                 let cb = &cached_backend;
                 let s = &stats;
                 let b = &borked_packs;
                 move || {
-                    match check_pack(cb, &pack_id, &manifest, &s.blobs_read) {
+                    match check_pack(cb, pack_id, manifest, &s.blobs_read) {
                         Ok(()) => debug!("Pack {pack_id} verified"),
                         Err(e) => {
                             error!("Pack {pack_id}: {e:?}");
@@ -88,22 +285,11 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) ->
             });
             concurrently::concurrently(checks);
 
-            progress.join();
+            if let Some(p) = progress {
+                p.join();
+            }
             Ok(())
         })?;
-    } else {
-        // If we don't have to read the packs, just list them all
-        // and make sure we find the indexed ones in that list.
-        info!("Checking that all indexed packs are present");
-        for pack_id in index.packs.keys() {
-            match backend::probe_pack(&all_packs, pack_id) {
-                Ok(()) => debug!("Pack {} found", pack_id),
-                Err(e) => {
-                    error!("{e:?}"); // Error already has a message about specific pack
-                    borked_packs.fetch_add(1, Ordering::Relaxed);
-                }
-            }
-        }
     }
     let borked_packs = borked_packs.load(Ordering::SeqCst);
     if borked_packs != 0 {
@@ -111,38 +297,148 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) ->
         trouble = true;
     }
 
+    let fixable_packs = AtomicU32::new(0);
+    let unfixable_packs = AtomicU32::new(0);
+    let mut packs_without_parity = 0;
+    if args.parity {
+        info!("Checking packs against their parity");
+        let with_parity = parity::list(&cached_backend)?;
+        let (checkable, without): (Vec<&ObjectId>, Vec<&ObjectId>) =
+            index.packs.keys().partition(|id| with_parity.contains(id));
+        for pack_id in &without {
+            debug!("Pack {pack_id} has no parity");
+        }
+        packs_without_parity = without.len();
+
+        let checks = checkable.into_iter().map(|pack_id| {
+            let manifest = &index.packs[pack_id];
+            let cb = &cached_backend;
+            let f = &fixable_packs;
+            let u = &unfixable_packs;
+            move || {
+                match check_parity(cb, pack_id, manifest) {
+                    Ok(h) if h.is_clean() => debug!("Pack {pack_id} matches its parity"),
+                    Ok(h) => {
+                        error!(
+                            "Pack {pack_id} has {} damaged shards and {} damaged parity shards; \
+                             `repair --from-parity` can fix them",
+                            h.damaged_data_shards, h.damaged_parity_shards
+                        );
+                        f.fetch_add(1, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        error!("Pack {pack_id}: {e:?}");
+                        u.fetch_add(1, Ordering::Relaxed);
+                    }
+                };
+                Ok(())
+            }
+        });
+        concurrently::concurrently(checks);
+
+        if packs_without_parity > 0 {
+            warn!("{packs_without_parity} packs have no parity");
+        }
+    }
+    let fixable_packs = fixable_packs.load(Ordering::SeqCst);
+    let unfixable_packs = unfixable_packs.load(Ordering::SeqCst);
+    if fixable_packs != 0 {
+        error!("{fixable_packs} damaged packs can be fixed with `repair --from-parity`");
+        trouble = true;
+    }
+    if unfixable_packs != 0 {
+        error!("{unfixable_packs} damaged packs can't be fixed from their parity");
+        trouble = true;
+    }
+
     info!("Checking for unreachable packs (not listed in indexes)");
-    warn_on_unreachable_packs(&index, &all_packs)?;
+    let (_pack_size, unreachable_packs) = warn_on_unreachable_packs(&index, &all_packs)?;
 
-    info!("Checking that all chunks in snapshots are reachable");
     let blob_map = index::blob_to_pack_map(&index)?;
+    let mut tree_cache = tree::Cache::new(&index, &blob_map, &cached_backend);
 
-    // Map the chunks that belong in each snapshot.
-    let chunks_to_snapshots = map_chunks_to_snapshots(
-        &cached_backend,
-        &mut tree::Cache::new(&index, &blob_map, &cached_backend),
-    )?;
+    let mut damaged_paths = vec![];
+    let missing_chunks = if args.deep {
+        info!("Checking every tree and file in every snapshot");
+        let mut deep = DeepCheck {
+            tree_cache,
+            chunk_sizes: index::blob_to_size_map(&index)?,
+            checked: FxHashMap::default(),
+            stack: FxHashSet::default(),
+            missing_chunks: FxHashSet::default(),
+        };
+        for (snapshot_path, _snapshot_len) in cached_backend.list_snapshots()? {
+            let snapshot_id = backend::id_from_path(&snapshot_path)?;
+            let damage = match snapshot::load(&snapshot_id, &cached_backend) {
+                Ok(snapshot) => deep.check_tree(&snapshot.tree),
+                Err(e) => Arc::new(vec![Damage {
+                    path: Utf8PathBuf::new(),
+                    problem: format!("{e:#}"),
+                }]),
+            };
+            if damage.is_empty() {
+                debug!("Snapshot {snapshot_id} is whole");
+                continue;
+            }
+            let short = snapshot_id.short_name();
+            for d in damage.iter() {
+                error!("Snapshot {short}: {}: {}", d.display_path(), d.problem);
+                damaged_paths.push(JsonDamage {
+                    snapshot: snapshot_id.to_string(),
+                    path: d.path.clone(),
+                    problem: d.problem.clone(),
+                });
+            }
+            error!("Snapshot {short} has {} damaged paths", damage.len());
+            trouble = true;
+        }
+        deep.missing_chunks.len()
+    } else {
+        info!("Checking that all chunks in snapshots are reachable");
+        // Map the chunks that belong in each snapshot.
+        let chunks_to_snapshots = map_chunks_to_snapshots(&cached_backend, &mut tree_cache)?;
 
-    let mut missing_chunks: usize = 0;
-    for (chunk, snapshots) in &chunks_to_snapshots {
-        if !blob_map.contains_key(chunk) {
-            error!(
-                "Chunk {} is unreachable! (Used by snapshots {})",
-                chunk,
-                snapshots
-                    .iter()
-                    .map(|id| id.short_name())
-                    .collect::<Vec<String>>()
-                    .join(", ")
-            );
-            missing_chunks += 1;
+        let mut missing_chunks: usize = 0;
+        for (chunk, snapshots) in &chunks_to_snapshots {
+            if !blob_map.contains_key(chunk) {
+                error!(
+                    "Chunk {} is unreachable! (Used by snapshots {})",
+                    chunk,
+                    snapshots
+                        .iter()
+                        .map(|id| id.short_name())
+                        .collect::<Vec<String>>()
+                        .join(", ")
+                );
+                missing_chunks += 1;
+            }
         }
-    }
+        missing_chunks
+    };
     if missing_chunks > 0 {
         error!("{} missing chunks", missing_chunks);
         trouble = true;
     }
 
+    if json {
+        let check = JsonCheck {
+            ok: !trouble,
+            read_packs: args.read_packs || args.read_packs_subset.is_some(),
+            packs: index.packs.len(),
+            packs_read: to_read.len(),
+            broken_packs: borked_packs,
+            unreachable_packs: unreachable_packs.len(),
+            missing_chunks,
+            deep: args.deep,
+            damaged_paths,
+            parity: args.parity,
+            fixable_packs,
+            unfixable_packs,
+            packs_without_parity,
+        };
+        println!("{}", serde_json::to_string(&check)?);
+    }
+
     if trouble {
         bail!("Check failed!");
     } else {
@@ -158,12 +454,38 @@ fn check_pack(
     blobs_read: &AtomicU64,
 ) -> Result<()> {
     let mut pack = cached_backend.read_pack(pack_id)?;
-    pack::verify(&mut pack, manifest, blobs_read)?;
+    pack::verify(&mut pack, manifest, cached_backend, blobs_read)?;
     Ok(())
 }
 
-/// Warns about unreachable packs. Returns the total pack size for usage stats.
-pub fn warn_on_unreachable_packs(index: &index::Index, all_packs: &[(String, u64)]) -> Result<u64> {
+/// Checks the pack against its parity,
+/// making sure that whatever parity fixes matches the pack's manifest.
+fn check_parity(
+    cached_backend: &backend::CachedBackend,
+    pack_id: &ObjectId,
+    manifest: &[pack::PackManifestEntry],
+) -> Result<parity::Healing> {
+    let mut pack = vec![];
+    cached_backend.read_pack(pack_id)?.read_to_end(&mut pack)?;
+    let healing = parity::load(pack_id, cached_backend)?.heal(&pack, cached_backend.hash())?;
+    if let Some(healed) = &healing.pack {
+        pack::verify(
+            &mut io::Cursor::new(healed),
+            manifest,
+            cached_backend,
+            &AtomicU64::new(0),
+        )
+        .context("Pack is still broken after fixing it from parity")?;
+    }
+    Ok(healing)
+}
+
+/// Warns about unreachable packs.
+/// Returns the total pack size (for usage stats) and the unreachable packs.
+pub fn warn_on_unreachable_packs(
+    index: &index::Index,
+    all_packs: &[(String, u64)],
+) -> Result<(u64, Vec<ObjectId>)> {
     let mut total_pack_size = 0u64;
     let pack_ids = all_packs
         .iter()
@@ -173,24 +495,135 @@ pub fn warn_on_unreachable_packs(index: &index::Index, all_packs: &[(String, u64
         })
         .map(backend::id_from_path)
         .collect::<Result<Vec<_>>>()?;
-    let mut unlisted_packs: usize = 0;
+    let mut unlisted_packs = vec![];
     for pack_id in pack_ids {
         if !index.packs.contains_key(&pack_id) {
             warn!("Pack {pack_id} not listed in any index");
-            unlisted_packs += 1;
+            unlisted_packs.push(pack_id);
         }
     }
-    if unlisted_packs > 0 {
+    if !unlisted_packs.is_empty() {
         warn!(
-            "{unlisted_packs} {} unreachable. Consider running `rebuild-index` if you aren't running `backup` right now.",
-            if unlisted_packs == 1 {
+            "{} {} unreachable. Consider running `rebuild-index` if you aren't running `backup` right now.",
+            unlisted_packs.len(),
+            if unlisted_packs.len() == 1 {
                 "pack is"
             } else {
                 "packs are"
             }
         );
     }
-    Ok(total_pack_size)
+    Ok((total_pack_size, unlisted_packs))
+}
+
+/// Something wrong with a path in a snapshot
+struct Damage {
+    /// Relative to the tree we found it in (empty for that tree itself)
+    path: Utf8PathBuf,
+    problem: String,
+}
+
+impl Damage {
+    fn display_path(&self) -> &str {
+        if self.path.as_str().is_empty() {
+            "(top-level tree)"
+        } else {
+            self.path.as_str()
+        }
+    }
+}
+
+/// Walks every tree for `check --deep`
+struct DeepCheck<'a> {
+    tree_cache: tree::Cache<'a>,
+    chunk_sizes: FxHashMap<ObjectId, u32>,
+    /// Trees are shared between snapshots (and directories); only check each once.
+    checked: FxHashMap<ObjectId, Arc<Vec<Damage>>>,
+    /// The trees we're in the middle of, to catch cycles
+    stack: FxHashSet<ObjectId>,
+    missing_chunks: FxHashSet<ObjectId>,
+}
+
+impl DeepCheck<'_> {
+    /// Returns everything wrong in the given tree, relative to it.
+    fn check_tree(&mut self, id: &ObjectId) -> Arc<Vec<Damage>> {
+        if let Some(d) = self.checked.get(id) {
+            return d.clone();
+        }
+        if !self.stack.insert(*id) {
+            return Arc::new(vec![Damage {
+                path: Utf8PathBuf::new(),
+                problem: format!("Tree {id} loops back on itself"),
+            }]);
+        }
+        let damage = match self.tree_cache.read(id) {
+            Ok(tree) => self.check_nodes(&tree),
+            Err(e) => vec![Damage {
+                path: Utf8PathBuf::new(),
+                problem: format!("Couldn't read tree {id}: {e:#}"),
+            }],
+        };
+        assert!(self.stack.remove(id));
+        let damage = Arc::new(damage);
+        self.checked.insert(*id, damage.clone());
+        damage
+    }
+
+    fn check_nodes(&mut self, tree: &tree::Tree) -> Vec<Damage> {
+        let mut damage = vec![];
+        for (name, node) in tree {
+            let mut problem = |problem| {
+                damage.push(Damage {
+                    path: name.clone(),
+                    problem,
+                })
+            };
+            // Don't use node.kind(); it asserts they match!
+            let expected = match &node.contents {
+                tree::NodeContents::File { .. } => tree::NodeType::File,
+                tree::NodeContents::Directory { .. } => tree::NodeType::Directory,
+                tree::NodeContents::Symlink { .. } => tree::NodeType::Symlink,
+            };
+            let actual = node.metadata.kind();
+            if expected != actual {
+                problem(format!(
+                    "Is a {expected:?} but its metadata says {actual:?}"
+                ));
+            }
+            match &node.contents {
+                tree::NodeContents::File { chunks } => {
+                    let mut size = 0u64;
+                    let mut whole = true;
+                    for chunk in chunks {
+                        match self.chunk_sizes.get(chunk) {
+                            Some(s) => size += *s as u64,
+                            None => {
+                                problem(format!("Missing chunk {chunk}"));
+                                self.missing_chunks.insert(*chunk);
+                                whole = false;
+                            }
+                        }
+                    }
+                    match node.metadata.size() {
+                        Some(s) if whole && s != size => problem(format!(
+                            "Should be {s} bytes, but its chunks add up to {size}"
+                        )),
+                        _ => {}
+                    }
+                }
+                tree::NodeContents::Directory { subtree } => {
+                    for d in self.check_tree(subtree).iter() {
+                        damage.push(Damage {
+                            path: name.join(&d.path),
+                            problem: d.problem.clone(),
+                        });
+                    }
+                }
+                tree::NodeContents::Symlink { .. } => {}
+            }
+        }
+        damage
+    }
 }
 
 /// Maps all reachable chunks to the set of snapshots that use them
@@ -238,3 +671,50 @@ fn print_progress(i: usize, term: &Term, stats: &ReadStatus, down: &AtomicU64) -
     print_download_line(db);
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn subsets() -> Result<()> {
+        assert_eq!("3/7".parse::<Subset>()?, Subset::Part { n: 3, m: 7 });
+        assert_eq!("5%".parse::<Subset>()?, Subset::Percent(5.0));
+        assert_eq!(
+            "50GB".parse::<Subset>()?,
+            Subset::Size(Byte::from_u64(50_000_000_000))
+        );
+        for nope in ["0/3", "4/3", "1/0", "0%", "101%", "lots"] {
+            assert!(nope.parse::<Subset>().is_err(), "{nope} parsed");
+        }
+
+        let packs: index::PackMap = (0..100u32)
+            .map(|i| (ObjectId::hash(&i.to_le_bytes()), vec![]))
+            .collect();
+        let all_packs: Vec<_> = packs
+            .keys()
+            .map(|id| (format!("{id}.pack"), 1000))
+            .collect();
+
+        // Every pack is in exactly one part.
+        let mut seen = FxHashSet::default();
+        for n in 1..=4 {
+            let part = Subset::Part { n, m: 4 }.select(&packs, &all_packs)?;
+            assert!(!part.is_empty());
+            assert_eq!(part, Subset::Part { n, m: 4 }.select(&packs, &all_packs)?);
+            for id in part {
+                assert!(seen.insert(id));
+            }
+        }
+        assert_eq!(seen.len(), packs.len());
+
+        assert_eq!(Subset::Percent(5.0).select(&packs, &all_packs)?.len(), 5);
+        assert_eq!(
+            Subset::Percent(100.0).select(&packs, &all_packs)?.len(),
+            100
+        );
+        let sized = Subset::Size(Byte::from_u64(2500)).select(&packs, &all_packs)?;
+        assert_eq!(sized.len(), 3);
+        Ok(())
+    }
+}
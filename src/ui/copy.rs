@@ -3,9 +3,11 @@ use std::thread;
 use anyhow::Result;
 use camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
+use tracing::*;
 
 use crate::backend;
 use crate::backup;
+use crate::chunk;
 use crate::config::{self, Configuration};
 use crate::filter;
 use crate::index;
@@ -55,7 +57,7 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
     let skips = config::merge_skips(config.skips, args.skips);
 
     // Build the usual suspects.
-    let (_, src_cached_backend) = backend::open(
+    let (src_backend_config, src_cached_backend) = backend::open(
         repository,
         config.cache_size,
         backend::CacheBehavior::Normal,
@@ -83,6 +85,23 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
         backend::open(&args.to, config.cache_size, backend::CacheBehavior::Normal)?;
     let dst_index = index::build_master_index(&dst_cached_backend)?;
 
+    // Chunk IDs only mean something in repositories that cut and hash files the same way.
+    // (Cutting the same way with a different hash gets us the same chunks with new IDs.)
+    let rechunk = if src_backend_config.chunking != dst_backend_config.chunking
+        || src_backend_config.hash != dst_backend_config.hash
+    {
+        info!(
+            "{} chunks or hashes files differently; rechunking as we copy",
+            args.to
+        );
+        Some(chunk::Chunker::new(
+            &dst_backend_config.chunking,
+            dst_backend_config.hash,
+        ))
+    } else {
+        None
+    };
+
     // Track all the blobs already in the destination.
     let mut packed_blobs = index::blob_id_set(&dst_index)?;
 
@@ -135,6 +154,7 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
                 repack::Op::Copy,
                 &src_snapshots_and_forests,
                 filter,
+                rechunk.as_ref(),
                 &mut reader,
                 &mut packed_blobs,
                 &mut backup,
@@ -4,6 +4,7 @@ use clap::Parser;
 use tracing::*;
 
 use crate::backend;
+use crate::chunk;
 use crate::config::Configuration;
 use crate::diff;
 use crate::fs_tree;
@@ -45,8 +46,8 @@ pub struct Args {
     // Should we provide options for remapping to an arbitrary directory, like `restore`?
 }
 
-pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
-    let (_cfg, cached_backend) = backend::open(
+pub fn run(config: &Configuration, repository: &Utf8Path, json: bool, args: Args) -> Result<()> {
+    let (backend_config, cached_backend) = backend::open(
         repository,
         config.cache_size,
         backend::CacheBehavior::Normal,
@@ -66,6 +67,7 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
         &args.second_snapshot,
         &snapshots,
         &mut tree_cache,
+        &chunk::Chunker::new(&backend_config.chunking, backend_config.hash),
     )?;
 
     diff::compare_trees(
@@ -74,6 +76,7 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
         Utf8Path::new(""),
         &mut PrintDiffs {
             metadata: args.metadata,
+            json,
         },
     )
 }
@@ -85,6 +88,7 @@ fn load_snapshot2_or_paths(
     second_snapshot: &Option<String>,
     snapshots: &[(snapshot::Snapshot, ObjectId)],
     tree_cache: &mut tree::Cache,
+    chunker: &chunk::Chunker,
 ) -> Result<(ObjectId, tree::Forest)> {
     if let Some(second_snapshot) = second_snapshot {
         let (snapshot2, id2) = snapshot::find(snapshots, second_snapshot)?;
@@ -107,6 +111,8 @@ fn load_snapshot2_or_paths(
             &snapshot1.paths,
             Some(&snapshot1.tree),
             snapshot1_forest,
+            chunker,
+            &mut |_| true,
         )
     }
 }
@@ -114,11 +120,23 @@ fn load_snapshot2_or_paths(
 #[derive(Debug, Default)]
 pub struct PrintDiffs {
     pub metadata: bool,
+    /// Print JSON Lines (see [`ls::JsonNode`]) instead of text.
+    pub json: bool,
+}
+
+impl PrintDiffs {
+    fn print_node(&self, code: char, path: &Utf8Path, node: &Node, should_recurse: ls::Recurse) {
+        if self.json {
+            ls::print_json_node(Some(code), path, node, should_recurse);
+        } else {
+            ls::print_node(&format!("{code} "), path, node, should_recurse);
+        }
+    }
 }
 
 impl diff::Callbacks for PrintDiffs {
     fn node_added(&mut self, node_path: &Utf8Path, new_node: &Node, forest: &Forest) -> Result<()> {
-        ls::print_node("+ ", node_path, new_node, ls::Recurse::Yes(forest));
+        self.print_node('+', node_path, new_node, ls::Recurse::Yes(forest));
         Ok(())
     }
 
@@ -128,7 +146,7 @@ impl diff::Callbacks for PrintDiffs {
         old_node: &Node,
         forest: &Forest,
     ) -> Result<()> {
-        ls::print_node("- ", node_path, old_node, ls::Recurse::Yes(forest));
+        self.print_node('-', node_path, old_node, ls::Recurse::Yes(forest));
         Ok(())
     }
 
@@ -142,10 +160,10 @@ impl diff::Callbacks for PrintDiffs {
         assert_eq!(old_node.kind(), new_node.kind());
 
         if old_node.kind() == NodeType::Symlink {
-            ls::print_node("- ", node_path, old_node, ls::Recurse::No);
-            ls::print_node("+ ", node_path, new_node, ls::Recurse::No);
+            self.print_node('-', node_path, old_node, ls::Recurse::No);
+            self.print_node('+', node_path, new_node, ls::Recurse::No);
         } else {
-            ls::print_node("C ", node_path, old_node, ls::Recurse::No);
+            self.print_node('C', node_path, old_node, ls::Recurse::No);
         }
         Ok(())
     }
@@ -157,11 +175,8 @@ impl diff::Callbacks for PrintDiffs {
         new_node: &Node,
     ) -> Result<()> {
         if self.metadata {
-            let leading_char = format!(
-                "{} ",
-                meta_diff_char(&old_node.metadata, &new_node.metadata).unwrap()
-            );
-            ls::print_node(&leading_char, node_path, new_node, ls::Recurse::No);
+            let code = meta_diff_char(&old_node.metadata, &new_node.metadata).unwrap();
+            self.print_node(code, node_path, new_node, ls::Recurse::No);
         }
         Ok(())
     }
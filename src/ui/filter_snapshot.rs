@@ -233,7 +233,7 @@ where
     // Serialize and hash it to find out.
     // (Again, we could have a separate "we didn't filter anything" path,
     // but it doesn't seem worth it at the moment.)
-    let (serialized, new_tree_id) = tree::serialize_and_hash(&new_tree)?;
+    let (serialized, new_tree_id) = tree::serialize_and_hash(&new_tree, backup.hash)?;
     // If we don't have this tree, new or old, in the backup, add it.
     if packed_blobs.insert(new_tree_id) {
         backup.tree_tx.send(blob::Blob {
@@ -1,9 +1,14 @@
+use std::collections::BTreeSet;
+
 use anyhow::{Result, bail};
+use camino::Utf8PathBuf;
 use clap::Parser;
+use jiff::Zoned;
+use rustc_hash::FxHashSet;
 use tracing::*;
 
 use crate::backend;
-use crate::config::Configuration;
+use crate::config::{Configuration, Retention};
 use crate::hashing::ObjectId;
 use crate::snapshot;
 
@@ -101,6 +106,93 @@ fn forget_snapshot_list(
     success
 }
 
+/// Forget snapshots of the given paths that the retention policy doesn't want to keep.
+///
+/// Snapshots of other paths are left alone, since they're presumably someone else's business.
+pub fn forget_by_retention(
+    config: &Configuration,
+    repository: &camino::Utf8Path,
+    paths: &BTreeSet<Utf8PathBuf>,
+    retention: &Retention,
+    dry_run: bool,
+) -> Result<()> {
+    let (_cfg, cached_backend) = backend::open(
+        repository,
+        config.cache_size,
+        backend::CacheBehavior::Normal,
+    )?;
+
+    let snapshots: Vec<_> = snapshot::load_chronologically(&cached_backend)?
+        .into_iter()
+        .filter(|(s, _)| s.paths == *paths)
+        .collect();
+    let keep = retained(&snapshots, retention);
+
+    let mut success = true;
+    for (_snap, id) in &snapshots {
+        if !keep.contains(id) {
+            success &= forget_snapshot(&cached_backend, id, dry_run);
+        }
+    }
+
+    if success {
+        Ok(())
+    } else {
+        bail!("Couldn't forget snapshots!");
+    }
+}
+
+/// Returns the IDs of the (chronologically-sorted) snapshots we should keep.
+fn retained(
+    snapshots: &[(snapshot::Snapshot, ObjectId)],
+    retention: &Retention,
+) -> FxHashSet<ObjectId> {
+    // Map each snapshot time to a unique number per period.
+    type Period = fn(&Zoned) -> i64;
+    let periods: [(Option<usize>, Period); 4] = [
+        (retention.keep_daily, |t| {
+            t.year() as i64 * 10000 + t.month() as i64 * 100 + t.day() as i64
+        }),
+        (retention.keep_weekly, |t| {
+            let w = t.date().iso_week_date();
+            w.year() as i64 * 100 + w.week() as i64
+        }),
+        (retention.keep_monthly, |t| {
+            t.year() as i64 * 100 + t.month() as i64
+        }),
+        (retention.keep_yearly, |t| t.year() as i64),
+    ];
+    // Don't forget *everything* just because nobody told us what to keep.
+    if retention.keep_last.is_none() && periods.iter().all(|(n, _)| n.is_none()) {
+        warn!("No keep_* rules given; keeping all snapshots");
+        return snapshots.iter().map(|(_, id)| *id).collect();
+    }
+
+    let mut keep = FxHashSet::default();
+    if let Some(n) = retention.keep_last {
+        keep.extend(snapshots.iter().rev().take(n).map(|(_, id)| *id));
+    }
+    for (count, period_of) in periods {
+        let Some(mut count) = count else {
+            continue;
+        };
+        let mut last_period = None;
+        // Keep the newest snapshot from each period.
+        for (snap, id) in snapshots.iter().rev() {
+            if count == 0 {
+                break;
+            }
+            let period = period_of(&snap.time);
+            if last_period != Some(period) {
+                keep.insert(*id);
+                last_period = Some(period);
+                count -= 1;
+            }
+        }
+    }
+    keep
+}
+
 fn forget_snapshot(cached_backend: &backend::CachedBackend, id: &ObjectId, dry_run: bool) -> bool {
     if dry_run {
         info!("Would remove {id}");
@@ -117,3 +209,105 @@ fn forget_snapshot(cached_backend: &backend::CachedBackend, id: &ObjectId, dry_r
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn snapshots_at(times: &[&str]) -> Vec<(snapshot::Snapshot, ObjectId)> {
+        times
+            .iter()
+            .map(|t| {
+                let s = snapshot::Snapshot {
+                    time: format!("{t}[UTC]").parse().unwrap(),
+                    author: String::from("test"),
+                    tags: BTreeSet::new(),
+                    paths: BTreeSet::new(),
+                    tree: ObjectId::hash(b"tree"),
+                };
+                (s, ObjectId::hash(t.as_bytes()))
+            })
+            .collect()
+    }
+
+    fn kept(snapshots: &[(snapshot::Snapshot, ObjectId)], retention: &Retention) -> Vec<usize> {
+        let keep = retained(snapshots, retention);
+        (0..snapshots.len())
+            .filter(|i| keep.contains(&snapshots[*i].1))
+            .collect()
+    }
+
+    #[test]
+    fn retention() {
+        let snaps = snapshots_at(&[
+            "2023-06-01T12:00:00Z",
+            "2024-01-15T12:00:00Z",
+            "2024-02-10T12:00:00Z",
+            "2024-02-26T12:00:00Z", // Monday
+            "2024-02-28T12:00:00Z",
+            "2024-03-01T08:00:00Z",
+            "2024-03-01T20:00:00Z",
+        ]);
+
+        let none = Retention::default();
+        assert_eq!(kept(&snaps, &none), [0, 1, 2, 3, 4, 5, 6]);
+
+        let last = Retention {
+            keep_last: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(kept(&snaps, &last), [5, 6]);
+
+        let daily = Retention {
+            keep_daily: Some(3),
+            ..Default::default()
+        };
+        assert_eq!(kept(&snaps, &daily), [3, 4, 6]);
+
+        let weekly = Retention {
+            keep_weekly: Some(2),
+            ..Default::default()
+        };
+        assert_eq!(kept(&snaps, &weekly), [2, 6]);
+
+        let mixed = Retention {
+            keep_last: Some(1),
+            keep_monthly: Some(3),
+            keep_yearly: Some(5),
+            ..Default::default()
+        };
+        assert_eq!(kept(&snaps, &mixed), [0, 1, 4, 6]);
+    }
+
+    #[test]
+    fn retention_must_keep_something() -> Result<()> {
+        let conf: Configuration = toml::from_str(
+            r#"
+            [profile.nothing]
+            repository = "r"
+            paths = ["p"]
+            retention = { keep_last = 0 }
+
+            [profile.zeroes]
+            repository = "r"
+            paths = ["p"]
+            retention = { keep_last = 0, keep_daily = 0, keep_yearly = 0 }
+
+            [profile.something]
+            repository = "r"
+            paths = ["p"]
+            retention = { keep_last = 0, keep_daily = 7 }
+
+            [profile.unset]
+            repository = "r"
+            paths = ["p"]
+            retention = {}
+            "#,
+        )?;
+        assert!(conf.profile("nothing").is_err());
+        assert!(conf.profile("zeroes").is_err());
+        conf.profile("something")?;
+        conf.profile("unset")?;
+        Ok(())
+    }
+}
@@ -5,10 +5,14 @@ use std::{
 
 use anyhow::{Context, Result, bail, ensure};
 use byte_unit::Byte;
+use camino::Utf8PathBuf;
 use clap::{Parser, Subcommand};
 
 use crate::backend;
+use crate::chunk;
+use crate::hashing;
 use crate::pack;
+use crate::parity;
 
 #[derive(Debug, Parser)]
 pub struct Args {
@@ -18,6 +22,44 @@ pub struct Args {
     #[clap(long)]
     gpg: Option<String>,
 
+    /// zstd compression level, from 1 (fastest) to 22 (smallest)
+    #[clap(long, value_name = "LEVEL", allow_negative_numbers = true)]
+    compression: Option<i32>,
+
+    /// Have zstd look for matches much further back than usual
+    #[clap(long)]
+    long_distance_matching: bool,
+
+    /// Log2 of the window zstd looks for matches in (10 to 27)
+    #[clap(long)]
+    window_log: Option<u32>,
+
+    /// The average size to cut files into (default 1 MiB).
+    /// Chunks range from half this to eight times it.
+    #[clap(long, value_name = "SIZE", verbatim_doc_comment)]
+    chunk_size: Option<String>,
+
+    /// Chunk files exactly like the given repository,
+    /// so that `backpak copy` between them doesn't have to recut every file.
+    #[clap(
+        long,
+        value_name = "REPOSITORY",
+        conflicts_with = "chunk_size",
+        verbatim_doc_comment
+    )]
+    copy_chunking_from: Option<Utf8PathBuf>,
+
+    /// How to hash everything in the repository.
+    /// BLAKE3 is much faster, especially on CPUs without SHA instructions.
+    #[clap(long, value_enum, default_value_t, verbatim_doc_comment)]
+    hash: hashing::Algorithm,
+
+    /// Save Reed-Solomon parity for each pack (about 6% more space),
+    /// so that `repair --from-parity` can fix packs that rot.
+    /// Not available with --gpg: parity can't fix encrypted packs.
+    #[clap(long, conflicts_with = "gpg", verbatim_doc_comment)]
+    parity: bool,
+
     #[clap(subcommand)]
     subcommand: Command,
 }
@@ -34,15 +76,39 @@ enum Command {
         force_cache: bool,
     },
     /// Backup to Backblaze B2
+    ///
+    /// The key ID and application key can come from
+    /// commands that print them, a credentials file,
+    /// or (if none of those are given) $B2_APPLICATION_KEY_ID and $B2_APPLICATION_KEY.
+    /// Any given with -k/-a are saved in the repository config in plaintext!
+    #[clap(verbatim_doc_comment)]
     Backblaze {
-        #[clap(short, long)]
-        key_id: String,
-        #[clap(short, long)]
-        application_key: String,
+        #[clap(short, long, conflicts_with = "key_id_command")]
+        key_id: Option<String>,
+        #[clap(short, long, conflicts_with = "application_key_command")]
+        application_key: Option<String>,
+        /// A command that prints the key ID, e.g., `pass show b2/key-id`
+        #[clap(long)]
+        key_id_command: Option<String>,
+        /// A command that prints the application key
+        #[clap(long)]
+        application_key_command: Option<String>,
+        /// A TOML file with `key_id` and `application_key`, only readable by its owner.
+        /// Relative paths are relative to ~/.config/backpak/
+        #[clap(long, verbatim_doc_comment)]
+        credentials_file: Option<Utf8PathBuf>,
         #[clap(short, long)]
         bucket: String,
+        /// Keep the repository under this prefix in the bucket
+        /// (so that several repositories can share it)
+        #[clap(short, long, verbatim_doc_comment)]
+        prefix: Option<String>,
         #[clap(short, long, default_value_t = 4)]
         concurrent_connections: u32,
+        /// Log in to a B2 API other than https://api.backblazeb2.com
+        /// (mostly for testing)
+        #[clap(long, hide = true, verbatim_doc_comment)]
+        auth_url: Option<String>,
     },
 }
 
@@ -53,6 +119,26 @@ pub fn run(repository: &camino::Utf8Path, args: Args) -> Result<()> {
         .transpose()
         .context("Couldn't parse --pack-size")?;
     let pack_size = pack_size.unwrap_or(pack::DEFAULT_PACK_SIZE);
+    let compression = pack::Compression {
+        level: args.compression.unwrap_or_default(),
+        long_distance_matching: args.long_distance_matching,
+        window_log: args.window_log,
+        tree_dictionary: None,
+    };
+    compression.validate()?;
+    let chunk_size = args
+        .chunk_size
+        .map(|s| Byte::parse_str(s, true))
+        .transpose()
+        .context("Couldn't parse --chunk-size")?;
+    let chunking = match args.copy_chunking_from {
+        Some(other) => {
+            backend::read_config(&backend::config_path(&other)?)
+                .with_context(|| format!("Couldn't read chunking settings from {other}"))?
+                .chunking
+        }
+        None => chunk::Chunking::new_repository(chunk_size)?,
+    };
     let filter = args.gpg.map(|g| {
         (
             "gpg --encrypt --quiet --recipient ".to_owned() + &g,
@@ -62,23 +148,47 @@ pub fn run(repository: &camino::Utf8Path, args: Args) -> Result<()> {
     if let Some((f, u)) = &filter {
         round_trip_filter_test(f, u)?;
     }
+    let parity = args.parity.then(parity::Parity::default);
     match args.subcommand {
-        Command::Filesystem { force_cache } => {
-            backend::fs::initialize(repository, pack_size, filter, force_cache)
-        }
+        Command::Filesystem { force_cache } => backend::fs::initialize(
+            repository,
+            pack_size,
+            compression,
+            chunking,
+            args.hash,
+            parity,
+            filter,
+            force_cache,
+        ),
         Command::Backblaze {
             key_id,
             application_key,
+            key_id_command,
+            application_key_command,
+            credentials_file,
             bucket,
+            prefix,
             concurrent_connections,
+            auth_url,
         } => backend::backblaze::initialize(
             repository,
             pack_size,
+            compression,
+            chunking,
+            args.hash,
+            parity,
             filter,
-            key_id,
-            application_key,
+            backend::backblaze::Credentials {
+                key_id,
+                application_key,
+                key_id_command,
+                application_key_command,
+                credentials_file,
+            },
             bucket,
+            prefix,
             concurrent_connections,
+            auth_url,
         ),
     }
 }
@@ -16,7 +16,7 @@ pub struct Args {
     snapshot: String,
 }
 
-pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
+pub fn run(config: &Configuration, repository: &Utf8Path, json: bool, args: Args) -> Result<()> {
     let (_cfg, cached_backend) = backend::open(
         repository,
         config.cache_size,
@@ -31,7 +31,11 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
     info!("Listing files for snapshot {}", id);
 
     let snapshot_tree = tree::forest_from_root(&snapshot.tree, &mut tree_cache)?;
-    ls::print_tree("", Utf8Path::new(""), &snapshot.tree, &snapshot_tree);
+    if json {
+        ls::print_json_tree(Utf8Path::new(""), &snapshot.tree, &snapshot_tree);
+    } else {
+        ls::print_tree("", Utf8Path::new(""), &snapshot.tree, &snapshot_tree);
+    }
 
     Ok(())
 }
@@ -0,0 +1,88 @@
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use clap::Parser;
+use tracing::*;
+
+use crate::backend;
+use crate::config::Configuration;
+use crate::index;
+use crate::snapshot;
+
+/// Upgrade the repository to the newest format this backpak knows.
+///
+/// Rewrites anything saved in older formats, then bumps the repository's version.
+/// It's safe to interrupt and run again.
+#[derive(Debug, Parser)]
+#[command(verbatim_doc_comment)]
+pub struct Args {
+    #[clap(short = 'n', long)]
+    dry_run: bool,
+}
+
+pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
+    let (backend_config, cached_backend) = backend::open(
+        repository,
+        config.cache_size,
+        backend::CacheBehavior::Normal,
+    )?;
+    let from = backend_config.version;
+    if from == backend::VERSION {
+        info!("{repository} is already version {from}; nothing to do");
+        return Ok(());
+    }
+
+    if from < 2 {
+        upgrade_snapshots(&cached_backend, args.dry_run)?;
+    }
+    // Version 3 just lets new repositories pick their hash; there's nothing to rewrite.
+    // Version 4 adds a new pack format, but we still read the old one.
+
+    // Indexes are still on their first format, but make sure they're all readable
+    // before we tell anyone the repository is up to date.
+    for (file, _len) in cached_backend.list_indexes()? {
+        let id = backend::id_from_path(&file)?;
+        index::load(&id, &cached_backend)?;
+    }
+
+    if args.dry_run {
+        info!(
+            "Would upgrade {repository} from version {from} to {}",
+            backend::VERSION
+        );
+    } else {
+        // Only once everything else is done,
+        // so that an interrupted migration picks up where it left off.
+        backend::set_version(repository, backend::VERSION)
+            .with_context(|| format!("Couldn't update {repository}'s version"))?;
+        info!(
+            "Upgraded {repository} from version {from} to {}",
+            backend::VERSION
+        );
+    }
+    Ok(())
+}
+
+/// Rewrite snapshots saved in older formats (e.g., with chrono-era time strings).
+///
+/// Since a snapshot's ID is the hash of its contents, this gives it a new ID.
+/// We upload the new one before removing the old, so if we're interrupted,
+/// the worst case is a duplicate snapshot that the next run cleans up.
+/// (Rewriting the old one again gives the same ID as before.)
+fn upgrade_snapshots(cached_backend: &backend::CachedBackend, dry_run: bool) -> Result<()> {
+    for (file, _len) in cached_backend.list_snapshots()? {
+        let id = backend::id_from_path(&file)?;
+        if !snapshot::is_outdated(&id, cached_backend)? {
+            continue;
+        }
+        let snap = snapshot::load(&id, cached_backend)?;
+        if dry_run {
+            info!("Would rewrite snapshot {id}");
+            continue;
+        }
+        let new_id = snapshot::upload(&snap, cached_backend)
+            .with_context(|| format!("Couldn't rewrite snapshot {id}"))?;
+        cached_backend.remove_snapshot(&id)?;
+        info!("Rewrote snapshot {id} as {new_id}");
+    }
+    Ok(())
+}
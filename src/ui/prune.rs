@@ -9,11 +9,13 @@ use tracing::*;
 
 use crate::backend;
 use crate::backup;
+use crate::blob;
 use crate::config::Configuration;
 use crate::file_util::nice_size;
 use crate::hashing::ObjectId;
 use crate::index;
 use crate::pack;
+use crate::parity;
 use crate::read;
 use crate::repack;
 use crate::snapshot;
@@ -34,7 +36,13 @@ use crate::tree;
 #[command(verbatim_doc_comment)]
 pub struct Args {
     #[clap(short = 'n', long)]
-    dry_run: bool,
+    pub dry_run: bool,
+
+    /// Rewrite packs of trees that weren't compressed with
+    /// the current dictionary (see `train-dictionary`),
+    /// even if they're all in use.
+    #[clap(long, verbatim_doc_comment)]
+    pub repack_trees: bool,
 }
 
 pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
@@ -63,7 +71,17 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
     // (Overall, O(n) vs. O(n * m), where n = # of packed blobs and m = # of snapshots.)
     let reachable_blobs = reachable_blobs(snapshots_and_forests.iter().map(|s| &s.forest));
 
-    let (reusable_packs, packs_to_prune) = partition_reusable_packs(&index, &reachable_blobs);
+    let stale_tree_packs = if args.repack_trees {
+        stale_tree_packs(
+            &index,
+            backend_config.compression.tree_dictionary.as_ref(),
+            &cached_backend,
+        )?
+    } else {
+        FxHashSet::default()
+    };
+    let (reusable_packs, packs_to_prune) =
+        partition_reusable_packs(&index, &reachable_blobs, &stale_tree_packs);
     let (droppable_packs, sparse_packs) =
         partition_droppable_packs(&packs_to_prune, &reachable_blobs);
 
@@ -202,6 +220,7 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
                 repack::Op::Prune,
                 &snapshots_and_forests,
                 filter,
+                None,
                 &mut reader,
                 &mut packed_blobs,
                 &mut backup,
@@ -229,8 +248,18 @@ pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<
         for old_index in &superseded {
             cached_backend.remove_index(old_index)?;
         }
-        for old_pack in packs_to_prune.keys() {
+        // Repacking the exact same blobs gives us the exact same pack ID
+        // (e.g., with --repack-trees), and we'd better not delete those.
+        let new_index = index::build_master_index(&cached_backend)?;
+        let with_parity = parity::list(&cached_backend)?;
+        for old_pack in packs_to_prune
+            .keys()
+            .filter(|p| !new_index.packs.contains_key(p))
+        {
             cached_backend.remove_pack(old_pack)?;
+            if with_parity.contains(old_pack) {
+                cached_backend.remove_parity(old_pack)?;
+            }
         }
     } else {
         info!("Prune complete");
@@ -258,19 +287,43 @@ fn blobs_in_forest(forest: &tree::Forest) -> FxHashSet<ObjectId> {
     blobs
 }
 
+/// Find packs of trees that weren't compressed with the given dictionary.
+///
+/// Repacking the same trees gives the same pack ID, so we'd better not rewrite packs
+/// that wouldn't change, or every --repack-trees would upload them all over again.
+fn stale_tree_packs(
+    index: &index::Index,
+    dictionary: Option<&ObjectId>,
+    cached_backend: &backend::CachedBackend,
+) -> Result<FxHashSet<ObjectId>> {
+    let mut stale = FxHashSet::default();
+    for (id, manifest) in &index.packs {
+        if manifest.iter().any(|e| e.blob_type == blob::Type::Tree)
+            && pack::dictionary_of(id, cached_backend)?.as_ref() != dictionary
+        {
+            stale.insert(*id);
+        }
+    }
+    Ok(stale)
+}
+
 /// Partition packs into those that have 100% reachable blobs
-/// and those that don't.
+/// and those that don't (or are stale tree packs we're repacking).
 ///
 /// We'll reuse the former, and repack blobs from the latter.
 #[allow(clippy::type_complexity)]
 fn partition_reusable_packs<'a>(
     index: &'a index::Index,
     reachable_blobs: &FxHashSet<ObjectId>,
+    stale_tree_packs: &FxHashSet<ObjectId>,
 ) -> (
     BTreeMap<&'a ObjectId, &'a pack::PackManifest>,
     BTreeMap<&'a ObjectId, &'a pack::PackManifest>,
 ) {
-    index.packs.iter().partition(|(_pack_id, manifest)| {
+    index.packs.iter().partition(|(pack_id, manifest)| {
+        if stale_tree_packs.contains(*pack_id) {
+            return false;
+        }
         // Reusable packs are ones where all blobs are reachable.
         manifest
             .iter()
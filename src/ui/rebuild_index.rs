@@ -23,12 +23,24 @@ pub struct Args {
 }
 
 pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) -> Result<()> {
-    let (_cfg, cached_backend) = backend::open(
+    let (backend_config, cached_backend) = backend::open(
         repository,
         config.cache_size,
         backend::CacheBehavior::Normal,
     )?;
+    rebuild(&backend_config, &cached_backend, false, args.dry_run)
+}
 
+/// Index every pack in the repository, replacing all existing indexes.
+///
+/// If `skip_damaged`, read each pack in full and leave out any that are damaged
+/// (instead of giving up). `repair index` uses this to index what survives.
+pub fn rebuild(
+    backend_config: &backend::Configuration,
+    cached_backend: &backend::CachedBackend,
+    skip_damaged: bool,
+    dry_run: bool,
+) -> Result<()> {
     let superseded = cached_backend
         .list_indexes()?
         .iter()
@@ -46,10 +58,14 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) ->
     let (pack_tx, pack_rx) = sync_channel(num_cpus::get_physical());
     let (upload_tx, upload_rx) = sync_channel(0);
 
+    let compression = backend_config.compression;
+    let hash = cached_backend.hash();
     let indexed_packs = AtomicU64::new(0); // TODO: Progress CLI!
     let indexer = thread::spawn(move || {
         index::index(
             index::Resumable::No,
+            compression,
+            hash,
             replacing,
             pack_rx,
             upload_tx,
@@ -65,7 +81,17 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) ->
             let ptx = &pack_tx;
             || {
                 let id = backend::id_from_path(pack_file)?;
-                let manifest = pack::load_manifest(&id, &cached_backend)?;
+                let manifest = if skip_damaged {
+                    match verified_manifest(&id, cached_backend)? {
+                        Some(m) => m,
+                        None => {
+                            error!("Skipping pack {id}");
+                            return Ok(());
+                        }
+                    }
+                } else {
+                    pack::load_manifest(&id, cached_backend)?
+                };
                 let metadata = pack::PackMetadata { id, manifest };
                 ptx.send(metadata)
                     .context("Pack thread closed unexpectedly")?;
@@ -75,12 +101,12 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) ->
     concurrently::concurrently(read_packs);
     drop(pack_tx);
 
-    let umode = if args.dry_run {
+    let umode = if dry_run {
         upload::Mode::DryRun
     } else {
         upload::Mode::LiveFire
     };
-    upload::upload(umode, &cached_backend, upload_rx)?;
+    upload::upload(umode, cached_backend, upload_rx)?;
 
     // NB: Before deleting the old indexes, we make sure the new one's been written.
     //     This ensures there's no point in time when we don't have a valid index
@@ -91,7 +117,7 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) ->
     //     making sure indexes never refer to missing packs. (I hope...)
     ensure!(indexer.join().unwrap()?, "No new index built");
 
-    if !args.dry_run {
+    if !dry_run {
         info!("Uploaded a new index; removing previous ones");
         for old_index in superseded {
             cached_backend.remove_index(&old_index)?;
@@ -100,3 +126,21 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, args: Args) ->
 
     Ok(())
 }
+
+/// Read the given pack in full and verify it, returning `None` if it's damaged.
+///
+/// Failing to read the pack at all (a network hiccup, say) is an error,
+/// not damage - we don't want to leave a good pack out of the index over it.
+pub fn verified_manifest(
+    id: &ObjectId,
+    cached_backend: &backend::CachedBackend,
+) -> Result<Option<pack::PackManifest>> {
+    let mut packfile = cached_backend.read_pack(id)?;
+    match pack::verify_whole(&mut packfile, id, cached_backend) {
+        Ok(m) => Ok(Some(m)),
+        Err(e) => {
+            error!("Pack {id} is damaged: {e:?}");
+            Ok(None)
+        }
+    }
+}
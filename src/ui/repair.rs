@@ -0,0 +1,600 @@
+use std::collections::BTreeSet;
+use std::io::{self, prelude::*};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread;
+
+use anyhow::{Context, Result, bail, ensure};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Parser;
+use rustc_hash::{FxHashMap, FxHashSet};
+use tracing::*;
+
+use crate::{
+    backend, backup, blob, chunk, concurrently,
+    config::Configuration,
+    hashing::ObjectId,
+    index::{self, Index},
+    pack, parity, snapshot, tree,
+};
+
+/// Fix a damaged repository
+///
+/// When `check` finds broken packs or missing chunks, try (in this order):
+///
+/// 1. `repair --from-parity` to fix packs from their parity, if the repository saves it.
+/// 2. `repair index` to index every pack that can still be read.
+/// 3. `repair chunks` to back up missing chunks again from the files they came from.
+/// 4. `repair snapshots` to cut whatever's still missing out of snapshots.
+/// 5. `repair unreachable-packs` to delete broken packs left out of the index.
+#[derive(Debug, Parser)]
+#[command(verbatim_doc_comment)]
+pub struct Args {
+    #[clap(short = 'n', long)]
+    dry_run: bool,
+
+    /// Before anything else, fix damaged packs from their parity
+    /// (and save parity for intact packs that don't have any yet)
+    #[clap(long, verbatim_doc_comment)]
+    from_parity: bool,
+
+    #[clap(subcommand)]
+    subcommand: Option<Subcommand>,
+}
+
+#[derive(Debug, Parser)]
+pub enum Subcommand {
+    /// Build a new index from all packs that can be read, replacing all old ones
+    ///
+    /// Unlike `rebuild-index`, this reads every pack in full,
+    /// and leaves out (instead of failing on) any that are damaged.
+    #[clap(verbatim_doc_comment)]
+    Index,
+
+    /// Back up missing chunks again from the files they came from
+    ///
+    /// Files are found at the paths their snapshots backed up.
+    /// Only chunks that are identical to the lost ones can replace them,
+    /// so this helps most when those files haven't changed since.
+    #[clap(verbatim_doc_comment)]
+    Chunks,
+
+    /// Replace snapshots missing chunks or trees with ones that aren't
+    ///
+    /// Damaged files are cut short before their first missing chunk,
+    /// and directories that can't be read are left empty.
+    /// The new snapshots are tagged `repaired`.
+    #[clap(verbatim_doc_comment)]
+    Snapshots {
+        /// Forget the damaged snapshots once they're replaced
+        #[clap(long)]
+        forget: bool,
+    },
+
+    /// Delete damaged packs that aren't listed in any index
+    ///
+    /// Each one is read again first; intact packs are left alone
+    /// (`repair index` will index them).
+    /// Don't run this while a backup is running!
+    /// Its packs aren't indexed until it finishes.
+    #[clap(verbatim_doc_comment)]
+    UnreachablePacks,
+}
+
+pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
+    // Like check, don't trust the cache when deciding what survived.
+    let behavior = match args.subcommand {
+        _ if args.from_parity => backend::CacheBehavior::AlwaysRead,
+        Some(Subcommand::Index) => backend::CacheBehavior::AlwaysRead,
+        _ => backend::CacheBehavior::Normal,
+    };
+    let (backend_config, cached_backend) = backend::open(repository, config.cache_size, behavior)?;
+
+    if args.from_parity {
+        ensure!(
+            backend_config.filter.is_none(),
+            "Filtered repositories don't save parity"
+        );
+        heal_packs(&cached_backend, args.dry_run)?;
+    }
+
+    let Some(subcommand) = args.subcommand else {
+        ensure!(args.from_parity, "Nothing to repair (see --help)");
+        return Ok(());
+    };
+    match subcommand {
+        Subcommand::Index => {
+            super::rebuild_index::rebuild(&backend_config, &cached_backend, true, args.dry_run)
+        }
+        Subcommand::Chunks => repair_chunks(&backend_config, &cached_backend, args.dry_run),
+        Subcommand::Snapshots { forget } => {
+            repair_snapshots(&backend_config, &cached_backend, forget, args.dry_run)
+        }
+        Subcommand::UnreachablePacks => remove_unreachable_packs(&cached_backend, args.dry_run),
+    }
+}
+
+/// Fix damaged packs from their parity,
+/// and save parity for packs without any (e.g., from before the repository saved it).
+fn heal_packs(cached_backend: &backend::CachedBackend, dry_run: bool) -> Result<()> {
+    let with_parity = parity::list(cached_backend)?;
+    let settings = cached_backend.parity();
+    ensure!(
+        !with_parity.is_empty() || settings.is_some(),
+        "This repository doesn't save parity"
+    );
+    let packs = cached_backend
+        .list_packs()?
+        .iter()
+        .map(|(p, _len)| backend::id_from_path(p))
+        .collect::<Result<Vec<_>>>()?;
+
+    info!("Checking {} packs against their parity", packs.len());
+    let failures = AtomicU32::new(0);
+    let heals = packs.iter().map(|id| {
+        let f = &failures;
+        let wp = &with_parity;
+        move || {
+            let res = if wp.contains(id) {
+                heal_pack(cached_backend, id, dry_run)
+            } else if let Some(s) = &settings {
+                add_parity(cached_backend, id, s, dry_run)
+            } else {
+                Ok(())
+            };
+            if let Err(e) = res {
+                error!("Pack {id}: {e:?}");
+                f.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok(())
+        }
+    });
+    concurrently::concurrently(heals);
+
+    let failures = failures.load(Ordering::SeqCst);
+    ensure!(failures == 0, "Couldn't fix {failures} packs from parity");
+    Ok(())
+}
+
+fn read_whole_pack(cached_backend: &backend::CachedBackend, id: &ObjectId) -> Result<Vec<u8>> {
+    let mut pack = vec![];
+    cached_backend.read_pack(id)?.read_to_end(&mut pack)?;
+    Ok(pack)
+}
+
+fn heal_pack(cached_backend: &backend::CachedBackend, id: &ObjectId, dry_run: bool) -> Result<()> {
+    let pack = read_whole_pack(cached_backend, id)?;
+    let parity = parity::load(id, cached_backend)?;
+    let healing = parity.heal(&pack, cached_backend.hash())?;
+    if healing.is_clean() {
+        debug!("Pack {id} matches its parity");
+        return Ok(());
+    }
+
+    // Make sure what parity gave us is actually the pack.
+    let healed = healing.pack.as_deref().unwrap_or(&pack);
+    pack::verify_whole(&mut io::Cursor::new(healed), id, cached_backend)
+        .context("Pack is still broken after fixing it from parity")?;
+    if dry_run {
+        info!(
+            "Would fix {} damaged shards in pack {id} and {} in its parity",
+            healing.damaged_data_shards, healing.damaged_parity_shards
+        );
+        return Ok(());
+    }
+
+    if healing.pack.is_some() {
+        let mut fh = tempfile::Builder::new()
+            .prefix("temp-backpak-")
+            .suffix(".pack")
+            .tempfile_in(".")
+            .context("Couldn't open temporary pack for writing")?;
+        fh.write_all(healed)?;
+        fh.as_file().sync_all()?;
+        let pack_name = format!("{id}.pack");
+        let persisted = fh
+            .persist(&pack_name)
+            .with_context(|| format!("Couldn't persist fixed pack {pack_name}"))?;
+        // Some backends (B2) keep the damaged file around as an older version
+        // instead of replacing it, but removing the pack gets rid of every version.
+        cached_backend.write(&pack_name, persisted)?;
+        info!(
+            "Fixed {} damaged shards in pack {id}",
+            healing.damaged_data_shards
+        );
+    }
+    if healing.damaged_parity_shards > 0 {
+        let new_parity = parity::compute(healed, &parity.settings(), cached_backend.hash())?;
+        parity::upload(&new_parity, id, cached_backend)?;
+        info!("Replaced pack {id}'s damaged parity");
+    }
+    Ok(())
+}
+
+fn add_parity(
+    cached_backend: &backend::CachedBackend,
+    id: &ObjectId,
+    settings: &parity::Parity,
+    dry_run: bool,
+) -> Result<()> {
+    let pack = read_whole_pack(cached_backend, id)?;
+    pack::verify_whole(&mut io::Cursor::new(&pack), id, cached_backend)
+        .context("Won't save parity for a broken pack")?;
+    if dry_run {
+        info!("Would save parity for pack {id}");
+        return Ok(());
+    }
+    let parity = parity::compute(&pack, settings, cached_backend.hash())?;
+    parity::upload(&parity, id, cached_backend)?;
+    info!("Saved parity for pack {id}");
+    Ok(())
+}
+
+fn backup_mode(dry_run: bool) -> backup::Mode {
+    if dry_run {
+        backup::Mode::DryRun
+    } else {
+        backup::Mode::LiveFire
+    }
+}
+
+fn repair_chunks(
+    backend_config: &backend::Configuration,
+    cached_backend: &backend::CachedBackend,
+    dry_run: bool,
+) -> Result<()> {
+    let index = index::build_master_index(cached_backend)?;
+    let blob_map = index::blob_to_pack_map(&index)?;
+
+    let mut finder = MissingChunkFinder {
+        tree_cache: tree::Cache::new(&index, &blob_map, cached_backend),
+        packed_blobs: index::blob_id_set(&index)?,
+        visited: FxHashSet::default(),
+        missing: FxHashSet::default(),
+        files: BTreeSet::new(),
+    };
+    for (snapshot, id) in snapshot::load_chronologically(cached_backend)? {
+        finder.visit_snapshot(&snapshot, &id);
+    }
+    let MissingChunkFinder {
+        mut missing, files, ..
+    } = finder;
+    if missing.is_empty() {
+        info!("No chunks are missing");
+        return Ok(());
+    }
+    info!(
+        "{} chunks are missing from {} files; looking for them",
+        missing.len(),
+        files.len()
+    );
+
+    let chunker = chunk::Chunker::new(&backend_config.chunking, backend_config.hash);
+    let back_stats = backup::BackupStatistics::default();
+    let mut found = 0usize;
+    thread::scope(|s| -> Result<()> {
+        let backup = backup::spawn_backup_threads(
+            s,
+            backup_mode(dry_run),
+            backend_config,
+            cached_backend,
+            Index::default(),
+            &back_stats,
+        );
+
+        for file in &files {
+            let chunks = match chunk::chunk_file(file, &chunker) {
+                Ok(c) => c,
+                Err(e) => {
+                    warn!("{e:#}");
+                    continue;
+                }
+            };
+            for chunk in chunks {
+                if missing.remove(&chunk.id) {
+                    debug!("Found chunk {} in {file}", chunk.id);
+                    found += 1;
+                    backup.chunk_tx.send(chunk)?;
+                }
+            }
+        }
+
+        backup.join()
+    })?;
+
+    info!("Backed up {found} missing chunks again");
+    if !missing.is_empty() {
+        bail!(
+            "Couldn't find {} chunks. Consider `repair snapshots` to cut them out of their files.",
+            missing.len()
+        );
+    }
+    Ok(())
+}
+
+/// Finds files with missing chunks, and where they were backed up from.
+struct MissingChunkFinder<'a> {
+    tree_cache: tree::Cache<'a>,
+    packed_blobs: FxHashSet<ObjectId>,
+    /// Trees are shared between snapshots (and directories); only look at each once
+    /// (per place on the filesystem).
+    visited: FxHashSet<(ObjectId, Utf8PathBuf)>,
+    missing: FxHashSet<ObjectId>,
+    files: BTreeSet<Utf8PathBuf>,
+}
+
+impl MissingChunkFinder<'_> {
+    fn visit_snapshot(&mut self, snapshot: &snapshot::Snapshot, id: &ObjectId) {
+        let root = match self.tree_cache.read(&snapshot.tree) {
+            Ok(r) => r,
+            Err(e) => {
+                warn!("Skipping snapshot {}: {e:#}", id.short_name());
+                return;
+            }
+        };
+        // The top-level tree is named after each path we backed up (see ui/backup.rs).
+        for (name, node) in root.iter() {
+            match snapshot
+                .paths
+                .iter()
+                .find(|p| p.file_name() == Some(name.as_str()))
+            {
+                Some(path) => self.visit_node(path, node),
+                None => warn!(
+                    "Snapshot {} has {name}, but didn't back up any path with that name",
+                    id.short_name()
+                ),
+            }
+        }
+    }
+
+    fn visit_node(&mut self, path: &Utf8Path, node: &tree::Node) {
+        match &node.contents {
+            tree::NodeContents::File { chunks } => {
+                let mut damaged = false;
+                for chunk in chunks {
+                    if !self.packed_blobs.contains(chunk) {
+                        self.missing.insert(*chunk);
+                        damaged = true;
+                    }
+                }
+                if damaged {
+                    self.files.insert(path.to_owned());
+                }
+            }
+            tree::NodeContents::Directory { subtree } => {
+                if !self.visited.insert((*subtree, path.to_owned())) {
+                    return;
+                }
+                match self.tree_cache.read(subtree) {
+                    Ok(tree) => {
+                        for (name, node) in tree.iter() {
+                            self.visit_node(&path.join(name), node);
+                        }
+                    }
+                    Err(e) => warn!("Skipping {path}: {e:#}"),
+                }
+            }
+            tree::NodeContents::Symlink { .. } => {}
+        }
+    }
+}
+
+fn repair_snapshots(
+    backend_config: &backend::Configuration,
+    cached_backend: &backend::CachedBackend,
+    forget: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let index = index::build_master_index(cached_backend)?;
+    let blob_map = index::blob_to_pack_map(&index)?;
+    let snapshots = snapshot::load_chronologically(cached_backend)?;
+
+    let mut repairer = TreeRepairer {
+        tree_cache: tree::Cache::new(&index, &blob_map, cached_backend),
+        chunk_sizes: index::blob_to_size_map(&index)?,
+        packed_blobs: index::blob_id_set(&index)?,
+        repaired: FxHashMap::default(),
+    };
+
+    let back_stats = backup::BackupStatistics::default();
+    let mut unrepairable = 0usize;
+    let replacements = thread::scope(|s| -> Result<_> {
+        let backup = backup::spawn_backup_threads(
+            s,
+            backup_mode(dry_run),
+            backend_config,
+            cached_backend,
+            Index::default(),
+            &back_stats,
+        );
+
+        let mut replacements = vec![];
+        for (snapshot, id) in &snapshots {
+            match repairer.repair_tree(Utf8Path::new(""), &snapshot.tree, &backup)? {
+                None => {
+                    error!(
+                        "Snapshot {} can't be repaired; its top-level tree is gone. Consider forgetting it.",
+                        id.short_name()
+                    );
+                    unrepairable += 1;
+                }
+                Some(tree) if tree == snapshot.tree => debug!("Snapshot {id} is whole"),
+                Some(tree) => {
+                    let mut repaired = snapshot.clone();
+                    repaired.tree = tree;
+                    repaired.tags.insert(String::from("repaired"));
+                    replacements.push((repaired, *id));
+                }
+            }
+        }
+
+        // Important: make sure all new trees and the index are written BEFORE
+        // we upload the new snapshots.
+        backup.join()?;
+        Ok(replacements)
+    })?;
+
+    if replacements.is_empty() {
+        info!("No snapshots need repair");
+    }
+    for (repaired, old_id) in &replacements {
+        if dry_run {
+            info!("Would replace snapshot {old_id}");
+            continue;
+        }
+        let new_id = snapshot::upload(repaired, cached_backend)?;
+        info!("Repaired snapshot {old_id} as {new_id}");
+        if forget {
+            cached_backend.remove_snapshot(old_id)?;
+            info!("Forgot snapshot {old_id}");
+        }
+    }
+
+    if unrepairable > 0 {
+        bail!("{unrepairable} snapshots couldn't be repaired");
+    }
+    Ok(())
+}
+
+/// Rebuilds trees without whatever they're missing
+struct TreeRepairer<'a> {
+    tree_cache: tree::Cache<'a>,
+    chunk_sizes: FxHashMap<ObjectId, u32>,
+    packed_blobs: FxHashSet<ObjectId>,
+    /// Old trees to new ones (which are the same if they weren't damaged)
+    repaired: FxHashMap<ObjectId, ObjectId>,
+}
+
+impl TreeRepairer<'_> {
+    /// Returns the ID of the repaired tree, or None if the tree itself can't be read.
+    fn repair_tree(
+        &mut self,
+        tree_path: &Utf8Path,
+        id: &ObjectId,
+        backup: &backup::Backup,
+    ) -> Result<Option<ObjectId>> {
+        if let Some(r) = self.repaired.get(id) {
+            return Ok(Some(*r));
+        }
+        let tree = match self.tree_cache.read(id) {
+            Ok(t) => t,
+            Err(e) => {
+                warn!("Couldn't read tree {id}: {e:#}");
+                return Ok(None);
+            }
+        };
+
+        let mut new_tree = tree::Tree::default();
+        for (name, node) in tree.iter() {
+            let node_path = tree_path.join(name);
+            let new_node = match &node.contents {
+                tree::NodeContents::File { chunks } => {
+                    let whole = chunks
+                        .iter()
+                        .take_while(|c| self.chunk_sizes.contains_key(c))
+                        .count();
+                    if whole == chunks.len() {
+                        node.clone()
+                    } else {
+                        let kept = chunks[..whole].to_vec();
+                        let size = kept.iter().map(|c| self.chunk_sizes[c] as u64).sum();
+                        warn!(
+                            "{node_path} is missing chunk {}; cutting it down to {size} bytes",
+                            chunks[whole]
+                        );
+                        let mut metadata = node.metadata.clone();
+                        match &mut metadata {
+                            tree::NodeMetadata::Posix(p) => p.size = Some(size),
+                            tree::NodeMetadata::Windows(w) => w.size = Some(size),
+                        }
+                        tree::Node {
+                            contents: tree::NodeContents::File { chunks: kept },
+                            metadata,
+                        }
+                    }
+                }
+                tree::NodeContents::Directory { subtree } => {
+                    let new_subtree = match self.repair_tree(&node_path, subtree, backup)? {
+                        Some(s) => s,
+                        None => {
+                            warn!(
+                                "{node_path}{} is gone; leaving it empty",
+                                std::path::MAIN_SEPARATOR
+                            );
+                            // We never upload empty trees; everyone just knows what they are.
+                            tree::empty_id(backup.hash)
+                        }
+                    };
+                    tree::Node {
+                        contents: tree::NodeContents::Directory {
+                            subtree: new_subtree,
+                        },
+                        metadata: node.metadata.clone(),
+                    }
+                }
+                tree::NodeContents::Symlink { .. } => node.clone(),
+            };
+            assert!(new_tree.insert(name.clone(), new_node).is_none());
+        }
+
+        let new_id = if new_tree == *tree {
+            *id
+        } else {
+            let (serialized, new_id) = tree::serialize_and_hash(&new_tree, backup.hash)?;
+            if self.packed_blobs.insert(new_id) {
+                backup.tree_tx.send(blob::Blob {
+                    contents: blob::Contents::Buffer(serialized),
+                    id: new_id,
+                    kind: blob::Type::Tree,
+                })?;
+            }
+            new_id
+        };
+        self.repaired.insert(*id, new_id);
+        Ok(Some(new_id))
+    }
+}
+
+fn remove_unreachable_packs(cached_backend: &backend::CachedBackend, dry_run: bool) -> Result<()> {
+    let index = index::build_master_index(cached_backend)?;
+    let all_packs = cached_backend.list_packs()?;
+    let (_pack_size, unreachable) = super::check::warn_on_unreachable_packs(&index, &all_packs)?;
+    if unreachable.is_empty() {
+        info!("No unreachable packs");
+    }
+
+    let mut removed = vec![];
+    for id in unreachable {
+        // Make sure it's actually broken, and not a good pack that something
+        // (like a hiccup during `repair index`) left out.
+        if super::rebuild_index::verified_manifest(&id, cached_backend)?.is_some() {
+            warn!("Keeping intact pack {id}; `repair index` can index it");
+            continue;
+        }
+        if dry_run {
+            info!("Would remove pack {id}");
+        } else {
+            cached_backend.remove_pack(&id)?;
+            info!("Removed pack {id}");
+        }
+        removed.push(id);
+    }
+
+    // While we're here, parity for packs that are gone is no use to anyone.
+    let packs = all_packs
+        .iter()
+        .map(|(p, _len)| backend::id_from_path(p))
+        .collect::<Result<FxHashSet<_>>>()?;
+    for id in parity::list(cached_backend)? {
+        if packs.contains(&id) && !removed.contains(&id) {
+            continue;
+        }
+        if dry_run {
+            info!("Would remove parity for pack {id}");
+        } else {
+            cached_backend.remove_parity(&id)?;
+            info!("Removed parity for pack {id}");
+        }
+    }
+    Ok(())
+}
@@ -1,11 +1,6 @@
-use std::{
-    collections::BTreeSet,
-    fs::{self, File},
-    io::prelude::*,
-    sync::Arc,
-};
+use std::{collections::BTreeSet, fs, sync::Arc};
 
-use anyhow::{Context, Result, anyhow};
+use anyhow::{Context, Result, anyhow, ensure};
 use camino::{Utf8Path, Utf8PathBuf};
 use clap::Parser;
 use jiff::Timestamp;
@@ -14,13 +9,11 @@ use rustix::fs::Timespec;
 use tracing::*;
 
 use crate::{
-    backend,
+    backend, chunk,
     config::Configuration,
     diff, fs_tree,
     hashing::ObjectId,
-    index,
-    read::ChunkReader,
-    snapshot,
+    index, restore, snapshot,
     tree::{self, Forest, Node, NodeContents, NodeMetadata, NodeType, Tree},
 };
 
@@ -87,6 +80,20 @@ pub struct Args {
     #[clap(short, long)]
     permissions: bool,
 
+    /// Pick up an interrupted restore of the same snapshot where it left off
+    ///
+    /// Restores keep a journal of their progress in the working directory
+    /// (backpak-restore.journal) and fill each file under a temporary name
+    /// (<file>.backpak-part) until it's done.
+    /// Without --resume, a restore starts those files over.
+    #[clap(long, verbatim_doc_comment)]
+    resume: bool,
+
+    /// Afterwards, re-read each file we wrote and make sure it matches the snapshot
+    /// (see also `verify-restore`)
+    #[clap(long, verbatim_doc_comment)]
+    verify: bool,
+
     #[clap(name = "SNAPSHOT")]
     restore_from: String,
 }
@@ -94,7 +101,7 @@ pub struct Args {
 pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
     let output = args.output.clone().or(config.restore.output);
 
-    let (_cfg, cached_backend) = backend::open(
+    let (backend_config, cached_backend) = backend::open(
         repository,
         config.cache_size,
         backend::CacheBehavior::Normal,
@@ -109,14 +116,46 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
         &mut tree::Cache::new(&index, &blob_map, &cached_backend),
     )?;
 
-    let tree_and_mapping = load_fs_tree_and_mapping(id, snapshot, &snapshot_forest, &output)?;
+    let chunker = chunk::Chunker::new(&backend_config.chunking, backend_config.hash);
+    let tree_and_mapping =
+        load_fs_tree_and_mapping(id, snapshot, &snapshot_forest, &output, &chunker)?;
 
     let metadata = args.times || args.permissions;
 
+    let (journal, progress) = if args.dry_run {
+        (None, restore::Progress::default())
+    } else if args.resume {
+        let (j, p) = restore::Journal::resume(id)?;
+        info!(
+            "Resuming restore: {} files done, {} partway through",
+            p.finished,
+            p.written.len()
+        );
+        (Some(j), p)
+    } else {
+        if Utf8Path::new(restore::JOURNAL_NAME).exists() {
+            warn!(
+                "Starting over instead of resuming the last restore (see --resume); \
+                 overwriting {}",
+                restore::JOURNAL_NAME
+            );
+        }
+        (
+            Some(restore::Journal::create(id)?),
+            restore::Progress::default(),
+        )
+    };
+
     let mut res = Restorer {
-        printer: super::diff::PrintDiffs { metadata },
+        printer: super::diff::PrintDiffs {
+            metadata,
+            json: false,
+        },
         path_map: tree_and_mapping.path_map,
-        blob_reader: ChunkReader::new(&cached_backend, &index, &blob_map),
+        plan: restore::Plan::new(&index, &blob_map, journal.as_ref()),
+        progress,
+        pending_metadata: vec![],
+        restored: vec![],
         args: &args,
     };
 
@@ -127,7 +166,31 @@ pub fn run(config: Configuration, repository: &Utf8Path, args: Args) -> Result<(
         (&snapshot.tree, &snapshot_forest),
         Utf8Path::new(""),
         &mut res,
-    )
+    )?;
+
+    // Now that we've made every file, fill them all at once.
+    restore::fill(&res.plan, &index, &cached_backend)?;
+    for (path, node) in &res.pending_metadata {
+        res.set_metadata(path, node)?;
+    }
+    let restored = res.restored;
+    if let Some(j) = journal {
+        j.remove()?;
+    }
+
+    if args.verify {
+        info!("Verifying {} restored files", restored.len());
+        let mismatches = restore::verify_files(&restored, &chunker);
+        for m in &mismatches {
+            error!("{}: {}", m.path, m.problem);
+        }
+        ensure!(
+            mismatches.is_empty(),
+            "{} restored files don't match the snapshot",
+            mismatches.len()
+        );
+    }
+    Ok(())
 }
 
 struct FsTreeAndMapping<'a> {
@@ -146,6 +209,7 @@ fn load_fs_tree_and_mapping<'a>(
     snapshot: &'a snapshot::Snapshot,
     snapshot_forest: &tree::Forest,
     restore_to: &Option<Utf8PathBuf>,
+    chunker: &chunk::Chunker,
 ) -> Result<FsTreeAndMapping<'a>> {
     let mut path_map =
         FxHashMap::with_capacity_and_hasher(snapshot.paths.len(), Default::default());
@@ -197,6 +261,10 @@ fn load_fs_tree_and_mapping<'a>(
                 &BTreeSet::from([canonical_to.clone()]),
                 Some(&snapshot.tree),
                 snapshot_forest,
+                chunker,
+                // Partially-restored files (and the journal) aren't part of what's there;
+                // we'll finish or replace them as we go.
+                &mut |p| !restore::is_ours(p),
             )?;
 
             // Fix up the forest so its top-level tree name matches the snapshot's.
@@ -213,7 +281,7 @@ fn load_fs_tree_and_mapping<'a>(
                 .unwrap();
             fixed_top.insert(last_dir.into(), node);
             // Put that into the forest and return its ID as the new top-level tree.
-            let (_bytes, fixed_id) = tree::serialize_and_hash(&fixed_top)?;
+            let (_bytes, fixed_id) = tree::serialize_and_hash(&fixed_top, chunker.hash())?;
             fs_forest.insert(fixed_id, Arc::new(fixed_top));
 
             (fixed_id, fs_forest)
@@ -244,6 +312,8 @@ fn load_fs_tree_and_mapping<'a>(
                 &paths,
                 Some(&snapshot.tree),
                 snapshot_forest,
+                chunker,
+                &mut |p| !restore::is_ours(p), // See above
             )?
         };
 
@@ -262,6 +332,8 @@ fn load_fs_tree_and_mapping<'a>(
             &snapshot.paths,
             Some(&snapshot.tree),
             snapshot_forest,
+            chunker,
+            &mut |p| !restore::is_ours(p), // See above
         )?;
         for path in &snapshot.paths {
             assert!(
@@ -290,7 +362,16 @@ fn to_timespec(t: Timestamp) -> Timespec {
 struct Restorer<'a> {
     printer: super::diff::PrintDiffs,
     path_map: FxHashMap<&'a str, Utf8PathBuf>,
-    blob_reader: ChunkReader<'a>,
+    /// Files to fill with chunks once we've walked the whole snapshot
+    plan: restore::Plan<'a>,
+    /// What an interrupted restore already wrote, if we're resuming one
+    progress: restore::Progress,
+    /// Metadata to set once every file is filled and moved into place.
+    /// (Doing that bumps modification times of files and their directories,
+    /// and fails in read-only ones.)
+    pending_metadata: Vec<(Utf8PathBuf, Node)>,
+    /// Files we wrote and the chunks they should have, for --verify
+    restored: Vec<(Utf8PathBuf, Vec<ObjectId>)>,
     args: &'a Args,
 }
 
@@ -317,6 +398,26 @@ impl Restorer<'_> {
 
     // NB: node_path is already translated for all of these
 
+    /// Plan to fill in a file later.
+    fn create_file(&mut self, node_path: &Utf8Path, node: &Node) -> Result<()> {
+        let written = self.progress.written.get(node_path);
+        self.plan
+            .add_file(node_path.to_owned(), node.contents.chunks(), written)?;
+        self.set_metadata_later(node_path, node);
+        if self.args.verify {
+            self.restored
+                .push((node_path.to_owned(), node.contents.chunks().to_vec()));
+        }
+        Ok(())
+    }
+
+    fn set_metadata_later(&mut self, node_path: &Utf8Path, node: &Node) {
+        if self.args.times || self.args.permissions {
+            self.pending_metadata
+                .push((node_path.to_owned(), node.clone()));
+        }
+    }
+
     #[cfg(unix)]
     fn set_metadata(&self, node_path: &Utf8Path, node: &Node) -> Result<()> {
         let mtime = node.metadata.modification_time();
@@ -378,9 +479,7 @@ impl Restorer<'_> {
     fn add_node(&mut self, node_path: &Utf8Path, new_node: &Node, forest: &Forest) -> Result<()> {
         match &new_node.contents {
             NodeContents::File { .. } => {
-                let fh = File::create(node_path)
-                    .with_context(|| format!("Couldn't create file {node_path}"))?;
-                fill_file(fh, new_node, &mut self.blob_reader)?;
+                return self.create_file(node_path, new_node);
             }
             NodeContents::Symlink { target } => {
                 symlink(target, node_path)?;
@@ -402,7 +501,7 @@ impl Restorer<'_> {
             }
         };
         if !matches!(&new_node.contents, NodeContents::Symlink { .. }) {
-            self.set_metadata(node_path, new_node)?;
+            self.set_metadata_later(node_path, new_node);
         }
         Ok(())
     }
@@ -415,12 +514,8 @@ impl Restorer<'_> {
     ) -> Result<()> {
         match &new_node.contents {
             NodeContents::File { .. } => {
-                let fh = File::create(node_path)
-                    .with_context(|| format!("Couldn't create file {node_path}"))?;
-                fill_file(fh, new_node, &mut self.blob_reader)?;
-
                 // Don't try to set metadata on a symlink! We can't lol
-                self.set_metadata(node_path, new_node)?;
+                self.create_file(node_path, new_node)?;
             }
             NodeContents::Symlink { target } => {
                 fs::remove_file(node_path)
@@ -436,14 +531,6 @@ impl Restorer<'_> {
     }
 }
 
-fn fill_file(mut fh: File, node: &Node, bl: &mut ChunkReader<'_>) -> Result<()> {
-    let chunks = node.contents.chunks();
-    for c in chunks {
-        fh.write_all(&bl.read_blob(c)?)?;
-    }
-    Ok(())
-}
-
 #[cfg(windows)]
 fn symlink(_target: &Utf8Path, _from: &Utf8Path) -> Result<()> {
     // Uhh, we need to figure out if it's a directory?
@@ -525,7 +612,8 @@ impl diff::Callbacks for Restorer<'_> {
         if self.args.dry_run {
             return Ok(());
         }
-        self.set_metadata(&node_path, new_node)
+        self.set_metadata_later(&node_path, new_node);
+        Ok(())
     }
 
     fn type_changed(
@@ -0,0 +1,88 @@
+use std::collections::BTreeSet;
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Parser;
+use tracing::*;
+
+use crate::config::Configuration;
+use crate::progress::ProgressMode;
+use crate::ui::{backup, forget, prune};
+
+/// Run a backup profile from the config file.
+///
+/// Backs up the profile's paths to its repository, then forgets old snapshots
+/// per its retention policy and prunes (if asked).
+///
+/// Exits like `backup` does.
+#[derive(Debug, Parser)]
+#[command(verbatim_doc_comment)]
+pub struct Args {
+    #[clap(short = 'n', long)]
+    dry_run: bool,
+
+    /// How to print backup progress (see `backup --help`)
+    #[clap(long, value_enum, default_value_t)]
+    progress: ProgressMode,
+
+    /// The `[profile.<name>]` to run
+    profile: String,
+}
+
+impl Args {
+    pub fn profile(&self) -> &str {
+        &self.profile
+    }
+
+    /// True if we want stdout to ourselves, i.e., no INFO messages there.
+    pub fn quiet_stdout(&self) -> bool {
+        self.progress == ProgressMode::Json
+    }
+}
+
+pub fn run(
+    mut config: Configuration,
+    repository: &Utf8Path,
+    args: Args,
+) -> Result<backup::Outcome> {
+    let profile = config
+        .profiles
+        .remove(&args.profile)
+        .with_context(|| format!("No [profile.{}] in the config file", args.profile))?;
+    if let Some(hooks) = &profile.hooks {
+        config.backup.hooks = hooks.clone();
+    }
+    let cache_size = config.cache_size;
+
+    info!("Running profile {}", args.profile);
+    let backup_args = backup::Args::from_profile(&profile, args.dry_run, args.progress);
+    let outcome = backup::run(config, repository, backup_args)?;
+
+    // Backup took our config; we just need the cache size back.
+    let config = Configuration {
+        cache_size,
+        ..Default::default()
+    };
+
+    if let Some(retention) = &profile.retention {
+        // Match the (canonicalized) paths backup put in its snapshots.
+        // If a post hook already cleaned them up, hope they were given canonically.
+        let paths: BTreeSet<Utf8PathBuf> = profile
+            .paths
+            .iter()
+            .map(|p| p.canonicalize_utf8().unwrap_or_else(|_| p.clone()))
+            .collect();
+        forget::forget_by_retention(&config, repository, &paths, retention, args.dry_run)?;
+    }
+    if profile.prune {
+        prune::run(
+            &config,
+            repository,
+            prune::Args {
+                dry_run: args.dry_run,
+                repack_trees: false,
+            },
+        )?;
+    }
+    Ok(outcome)
+}
@@ -4,6 +4,7 @@ use anyhow::Result;
 use camino::Utf8Path;
 use clap::Parser;
 use rustc_hash::{FxHashMap, FxHashSet};
+use serde_derive::Serialize;
 use unicode_segmentation::UnicodeSegmentation;
 
 use crate::{
@@ -60,7 +61,12 @@ pub struct Args {
     snapshots: Vec<String>,
 }
 
-pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args) -> Result<()> {
+pub fn run(
+    config: &Configuration,
+    repository: &camino::Utf8Path,
+    json: bool,
+    mut args: Args,
+) -> Result<()> {
     unsafe {
         crate::prettify::prettify_serialize();
     }
@@ -84,6 +90,9 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
         }
     };
 
+    // With --json, we gather everything up and print one big array at the end.
+    let mut json_snapshots: Option<Vec<serde_json::Value>> = json.then(Vec::new);
+
     // This is a mess. Sorry.
     // --sizes, --file-sizes, and --stat combine in annoying ways, where each permutation
     // requires us to build a slightly different set of data.
@@ -91,7 +100,7 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
     if !args.sizes {
         // Simplest case: we just walk the snapshots. We don't need their trees or anything. EZ.
         if !args.stat {
-            let it = snapshots_to_print.into_iter();
+            let it = snapshots_to_print.iter();
             let it: Box<dyn Iterator<Item = _>> = if args.reverse {
                 Box::new(it.rev())
             } else {
@@ -99,7 +108,11 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
             };
 
             for (snap, id) in it {
-                print_snapshot(&snap, &id, None);
+                if let Some(js) = &mut json_snapshots {
+                    js.push(JsonSnapshot::new(snap, id).to_value()?);
+                } else {
+                    print_snapshot(snap, id, None);
+                }
             }
         }
         // Slightly harder: We need an index to look at the trees in each snapshot,
@@ -133,13 +146,13 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
             }
             for (i, (snap, _id)) in snapshots.iter().enumerate() {
                 let i = i as isize;
-                if needed_indices.contains(&i) {
-                    if let Entry::Vacant(e) = indexed_forests.entry(i) {
-                        e.insert((
-                            snap.tree,
-                            tree::forest_from_root(&snap.tree, &mut tree_cache)?,
-                        ));
-                    }
+                if needed_indices.contains(&i)
+                    && let Entry::Vacant(e) = indexed_forests.entry(i)
+                {
+                    e.insert((
+                        snap.tree,
+                        tree::forest_from_root(&snap.tree, &mut tree_cache)?,
+                    ));
                 }
             }
             drop(needed_indices);
@@ -159,16 +172,25 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
                     let (previous_root, previous_forest) = &indexed_forests[&(i - 1)];
                     let (current_root, current_forest) = &indexed_forests[&i];
                     assert_eq!(*current_root, snap.tree);
-                    print_snapshot(snap, id, None);
+                    if json_snapshots.is_none() {
+                        print_snapshot(snap, id, None);
+                    }
                     // The --stat part:
-                    tree_diff(
+                    let changes = tree_diff(
                         (previous_root, previous_forest),
                         (current_root, current_forest),
                         args.metadata,
                         0,    // pad
                         None, // sizes
+                        json,
                     )?;
-                    println!();
+                    if let Some(js) = &mut json_snapshots {
+                        let mut j = JsonSnapshot::new(snap, id);
+                        j.changes = Some(changes);
+                        js.push(j.to_value()?);
+                    } else {
+                        println!();
+                    }
                 }
             }
         }
@@ -239,7 +261,13 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
             if !snapshots_to_print.contains(id) {
                 continue;
             }
-            print_snapshot(snapshot, id, Some(sizes));
+            let mut json_snapshot = json_snapshots.is_some().then(|| JsonSnapshot {
+                sizes: Some(sizes),
+                ..JsonSnapshot::new(snapshot, id)
+            });
+            if json_snapshot.is_none() {
+                print_snapshot(snapshot, id, Some(sizes));
+            }
             if args.stat {
                 // Time to compare trees.
                 let (previous_root, previous_forest) = if *index == 0 {
@@ -261,32 +289,46 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
                         .map(|p| (p.0.as_ref(), &p.1))
                         .collect();
                     // We want to align our size printouts on the right side of the longest path.
-                    // Calculate that.
-                    let pad = measure_path_pad(
-                        (previous_root, previous_forest),
-                        (current_root, current_forest),
-                        args.metadata,
-                    )?;
+                    // Calculate that. (Unless we're printing JSON, which doesn't care.)
+                    let pad = if json {
+                        0
+                    } else {
+                        measure_path_pad(
+                            (previous_root, previous_forest),
+                            (current_root, current_forest),
+                            args.metadata,
+                        )?
+                    };
                     // Finally, print our diff *with* sizes.
-                    tree_diff(
+                    let changes = tree_diff(
                         (previous_root, previous_forest),
                         (current_root, current_forest),
                         args.metadata,
                         pad,
                         Some(sizes),
+                        json,
                     )?;
+                    if let Some(j) = &mut json_snapshot {
+                        j.changes = Some(changes);
+                    }
                 } else {
                     // Easier case - normal --stat printout, with per-snapshot size
                     // printed by print_snapshot().
-                    tree_diff(
+                    let changes = tree_diff(
                         (previous_root, previous_forest),
                         (current_root, current_forest),
                         args.metadata,
                         0,    // pad
                         None, // sizes
+                        json,
                     )?;
+                    if let Some(j) = &mut json_snapshot {
+                        j.changes = Some(changes);
+                    }
+                }
+                if json_snapshot.is_none() {
+                    println!();
                 }
-                println!();
             } else if args.file_sizes {
                 // No --stat involved, sort files by most to least data introduced and print them.
                 let mut fs = sizes
@@ -295,7 +337,17 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
                     .filter(|(_, s)| s.introduced > 0)
                     .collect::<Vec<_>>();
 
-                if !fs.is_empty() {
+                if let Some(j) = &mut json_snapshot {
+                    fs.sort_by_key(|(_, sizes)| std::cmp::Reverse(sizes.introduced));
+                    j.file_sizes = Some(
+                        fs.into_iter()
+                            .map(|(path, size)| JsonFileSize {
+                                path: path.as_path(),
+                                size,
+                            })
+                            .collect(),
+                    );
+                } else if !fs.is_empty() {
                     let max_path = fs
                         .iter()
                         .map(|(p, _)| p.as_str().graphemes(true).count())
@@ -315,11 +367,58 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path, mut args: Args
                     println!();
                 }
             }
+            if let (Some(js), Some(j)) = (&mut json_snapshots, json_snapshot) {
+                js.push(j.to_value()?);
+            }
         }
     }
+
+    if let Some(js) = json_snapshots {
+        println!("{}", serde_json::to_string(&js)?);
+    }
     Ok(())
 }
 
+/// `snapshots --json` output for a single snapshot
+#[derive(Serialize)]
+struct JsonSnapshot<'a> {
+    id: &'a ObjectId,
+    #[serde(flatten)]
+    snapshot: &'a snapshot::Snapshot,
+    /// With --sizes
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sizes: Option<&'a ForestSizes>,
+    /// With --stat
+    #[serde(skip_serializing_if = "Option::is_none")]
+    changes: Option<Vec<ls::JsonNode>>,
+    /// With --file-sizes but not --stat
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file_sizes: Option<Vec<JsonFileSize<'a>>>,
+}
+
+impl<'a> JsonSnapshot<'a> {
+    fn new(snapshot: &'a snapshot::Snapshot, id: &'a ObjectId) -> Self {
+        Self {
+            id,
+            snapshot,
+            sizes: None,
+            changes: None,
+            file_sizes: None,
+        }
+    }
+
+    fn to_value(&self) -> Result<serde_json::Value> {
+        Ok(serde_json::to_value(self)?)
+    }
+}
+
+#[derive(Serialize)]
+struct JsonFileSize<'a> {
+    path: &'a Utf8Path,
+    #[serde(flatten)]
+    size: &'a FileSize,
+}
+
 fn print_snapshot(snapshot: &snapshot::Snapshot, id: &ObjectId, sizes: Option<&ForestSizes>) {
     print!("snapshot {}", id);
     if snapshot.tags.is_empty() {
@@ -449,19 +548,24 @@ impl diff::Callbacks for PadMeasure {
 }
 
 /// Print diffs between two trees, optionally including size changes.
+///
+/// With `json`, collect them instead of printing them.
 fn tree_diff(
     (id1, forest1): (&ObjectId, &Forest),
     (id2, forest2): (&ObjectId, &Forest),
     metadata: bool,
     pad: usize,
     sizes: Option<FxHashMap<&Utf8Path, &FileSize>>,
-) -> Result<()> {
+    json: bool,
+) -> Result<Vec<ls::JsonNode>> {
     let mut cb = PrintDiffs {
         metadata,
         pad,
         sizes,
+        json: json.then(Vec::new),
     };
-    diff::compare_trees((id1, forest1), (id2, forest2), Utf8Path::new(""), &mut cb)
+    diff::compare_trees((id1, forest1), (id2, forest2), Utf8Path::new(""), &mut cb)?;
+    Ok(cb.json.unwrap_or_default())
 }
 
 /// Just `ui::diff` machinery but with extras space in the prefixes and optional size suffixes.
@@ -469,11 +573,19 @@ struct PrintDiffs<'a> {
     metadata: bool,
     pad: usize,
     sizes: Option<FxHashMap<&'a Utf8Path, &'a FileSize>>,
+    json: Option<Vec<ls::JsonNode>>,
 }
 
 impl PrintDiffs<'_> {
-    fn printer(&self, prefix: &str, path: &Utf8Path, node: &Node) {
-        print!("{prefix}");
+    fn printer(&mut self, code: char, path: &Utf8Path, node: &Node) {
+        if let Some(json) = &mut self.json {
+            let mut j = ls::JsonNode::new(Some(code), path, node);
+            j.size = self.sizes.as_ref().and_then(|s| s.get(path)).map(|s| **s);
+            json.push(j);
+            return;
+        }
+
+        print!(" {code} ");
         let mut p = path.as_str().to_owned();
         match &node.contents {
             NodeContents::Directory { .. } => {
@@ -511,15 +623,21 @@ impl PrintDiffs<'_> {
         }
     }
 
-    fn print_node(&self, prefix: &str, path: &Utf8Path, node: &Node, should_recurse: ls::Recurse) {
-        let mut v = |p: &Utf8Path, n: &Node| self.printer(prefix, p, n);
+    fn print_node(
+        &mut self,
+        code: char,
+        path: &Utf8Path,
+        node: &Node,
+        should_recurse: ls::Recurse,
+    ) {
+        let mut v = |p: &Utf8Path, n: &Node| self.printer(code, p, n);
         ls::walk_node(&mut v, path, node, should_recurse);
     }
 }
 
 impl diff::Callbacks for PrintDiffs<'_> {
     fn node_added(&mut self, node_path: &Utf8Path, new_node: &Node, forest: &Forest) -> Result<()> {
-        self.print_node(" + ", node_path, new_node, ls::Recurse::Yes(forest));
+        self.print_node('+', node_path, new_node, ls::Recurse::Yes(forest));
         Ok(())
     }
 
@@ -529,7 +647,7 @@ impl diff::Callbacks for PrintDiffs<'_> {
         old_node: &Node,
         forest: &Forest,
     ) -> Result<()> {
-        self.print_node(" - ", node_path, old_node, ls::Recurse::Yes(forest));
+        self.print_node('-', node_path, old_node, ls::Recurse::Yes(forest));
         Ok(())
     }
 
@@ -543,10 +661,10 @@ impl diff::Callbacks for PrintDiffs<'_> {
         assert_eq!(old_node.kind(), new_node.kind());
 
         if old_node.kind() == NodeType::Symlink {
-            self.print_node(" - ", node_path, old_node, ls::Recurse::No);
-            self.print_node(" + ", node_path, new_node, ls::Recurse::No);
+            self.print_node('-', node_path, old_node, ls::Recurse::No);
+            self.print_node('+', node_path, new_node, ls::Recurse::No);
         } else {
-            self.print_node(" C ", node_path, old_node, ls::Recurse::No);
+            self.print_node('C', node_path, old_node, ls::Recurse::No);
         }
         Ok(())
     }
@@ -558,11 +676,8 @@ impl diff::Callbacks for PrintDiffs<'_> {
         new_node: &Node,
     ) -> Result<()> {
         if self.metadata {
-            let leading_char = format!(
-                " {} ",
-                meta_diff_char(&old_node.metadata, &new_node.metadata).unwrap()
-            );
-            self.print_node(&leading_char, node_path, new_node, ls::Recurse::No);
+            let code = meta_diff_char(&old_node.metadata, &new_node.metadata).unwrap();
+            self.print_node(code, node_path, new_node, ls::Recurse::No);
         }
         Ok(())
     }
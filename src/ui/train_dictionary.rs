@@ -0,0 +1,90 @@
+use std::io::prelude::*;
+
+use anyhow::{Context, Result};
+use byte_unit::Byte;
+use camino::Utf8Path;
+use clap::Parser;
+use tracing::*;
+
+use crate::backend;
+use crate::blob;
+use crate::config::Configuration;
+use crate::dictionary;
+use crate::file_util::nice_size;
+use crate::index;
+use crate::pack;
+
+/// Train a zstd dictionary on the repository's trees.
+///
+/// Trees (i.e., directories) are small bits of CBOR with the same keys
+/// over and over, and compress much better with a dictionary.
+/// Once trained, new tree packs are compressed with it.
+/// Run `prune --repack-trees` to recompress the ones you already have.
+#[derive(Debug, Parser)]
+#[command(verbatim_doc_comment)]
+pub struct Args {
+    #[clap(short = 'n', long)]
+    dry_run: bool,
+
+    /// The largest the dictionary can be (default: 110 KiB)
+    #[clap(long, value_name = "SIZE")]
+    size: Option<String>,
+}
+
+pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
+    let size = args
+        .size
+        .map(|s| Byte::parse_str(s, true)) // Don't interpret b as bits.
+        .transpose()
+        .context("Couldn't parse --size")?
+        .map_or(dictionary::DEFAULT_SIZE, |s| s.as_u64() as usize);
+
+    let (_cfg, cached_backend) = backend::open(
+        repository,
+        config.cache_size,
+        backend::CacheBehavior::Normal,
+    )?;
+    let index = index::build_master_index(&cached_backend)?;
+
+    // zstd suggests training on about 100 times the dictionary size.
+    let enough = size * 100;
+    let mut samples: Vec<Vec<u8>> = vec![];
+    let mut sampled = 0;
+    'packs: for (pack_id, manifest) in &index.packs {
+        if !manifest.iter().any(|e| e.blob_type == blob::Type::Tree) {
+            continue;
+        }
+        let mut blobs = pack::BlobReader::new(cached_backend.read_pack(pack_id)?, &cached_backend)?;
+        for entry in manifest {
+            let mut tree = Vec::with_capacity(entry.length as usize);
+            blobs.next_blob(entry)?.read_to_end(&mut tree)?;
+            if entry.blob_type != blob::Type::Tree {
+                continue;
+            }
+            sampled += tree.len();
+            samples.push(tree);
+            if sampled >= enough {
+                break 'packs;
+            }
+        }
+    }
+
+    info!(
+        "Training a dictionary on {} trees ({})",
+        samples.len(),
+        nice_size(sampled as u64)
+    );
+    let dictionary = dictionary::train(&samples, size, cached_backend.hash())?;
+    println!(
+        "Trained dictionary {} ({})",
+        dictionary.id,
+        nice_size(dictionary.bytes.len() as u64)
+    );
+
+    if !args.dry_run {
+        dictionary::upload(&dictionary, &cached_backend)?;
+        backend::set_tree_dictionary(repository, &dictionary.id)?;
+        info!("New tree packs will be compressed with it");
+    }
+    Ok(())
+}
@@ -1,11 +1,32 @@
 use anyhow::Result;
 
+use jiff::Zoned;
 use rustc_hash::FxHashSet;
+use serde_derive::Serialize;
 use tracing::warn;
 
 use crate::{backend, config::Configuration, file_util::nice_size, index, snapshot, tree};
 
-pub fn run(config: &Configuration, repository: &camino::Utf8Path) -> Result<()> {
+/// `usage --json` output. All sizes are in bytes.
+#[derive(Debug, Serialize)]
+struct JsonUsage<'a> {
+    snapshots: usize,
+    first_snapshot: Option<&'a Zoned>,
+    last_snapshot: Option<&'a Zoned>,
+    unique_bytes: u64,
+    reused_bytes: u64,
+    indexes: usize,
+    packs: usize,
+    unused_bytes: u64,
+    backend: &'static str,
+    filter: Option<&'a str>,
+    snapshot_bytes: u64,
+    index_bytes: u64,
+    pack_bytes: u64,
+    total_bytes: u64,
+}
+
+pub fn run(config: &Configuration, repository: &camino::Utf8Path, json: bool) -> Result<()> {
     // Build the usual suspects.
     let (backend_config, cached_backend) = backend::open(
         repository,
@@ -18,21 +39,23 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path) -> Result<()>
     let size_map = index::blob_to_size_map(&index)?;
 
     let mut reachable_blobs = FxHashSet::default();
-    let reachable_blob_size;
+    let mut totals = tree::ForestSizes::default();
 
     let (snapshots, snapshot_size) =
         snapshot::load_chronologically_with_total_size(&cached_backend)?;
     if snapshots.is_empty() {
-        reachable_blob_size = 0u64;
-        println!("0 snapshots");
+        if !json {
+            println!("0 snapshots");
+        }
     } else {
-        println!(
-            "{} snapshots, from {} to {}",
-            snapshots.len(),
-            snapshots.first().unwrap().0.time.datetime(),
-            snapshots.last().unwrap().0.time.datetime()
-        );
-        let mut totals = tree::ForestSizes::default();
+        if !json {
+            println!(
+                "{} snapshots, from {} to {}",
+                snapshots.len(),
+                snapshots.first().unwrap().0.time.datetime(),
+                snapshots.last().unwrap().0.time.datetime()
+            );
+        }
 
         for (snapshot, _snap_id) in &snapshots {
             totals += tree::forest_sizes(
@@ -44,12 +67,14 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path) -> Result<()>
         }
 
         // Refactor out of ui/snapshots.rs (into snapshots.rs itself?)
-        reachable_blob_size = totals.introduced;
-        let u = nice_size(totals.introduced);
-        let r = nice_size(totals.reused);
-        println!("{u} unique data");
-        println!("{r} reused (deduplicated)");
+        if !json {
+            let u = nice_size(totals.introduced);
+            let r = nice_size(totals.reused);
+            println!("{u} unique data");
+            println!("{r} reused (deduplicated)");
+        }
     }
+    let reachable_blob_size = totals.introduced;
 
     let num_indexes = index_sizes.len();
     let index_str = if num_indexes == 1 { "index" } else { "indexes" };
@@ -61,27 +86,56 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path) -> Result<()>
         .values()
         .map(|manifest| manifest.iter().map(|me| me.length as u64).sum::<u64>())
         .sum();
-    print!("\n{num_indexes} {index_str} reference {reachable_packs} packs");
-    if packed_blob_size > reachable_blob_size {
-        let ds = nice_size(packed_blob_size - reachable_blob_size);
-        println!(", including {ds} unused data.\nConsider running `backpak prune`.");
-    } else {
-        println!();
+    if !json {
+        print!("\n{num_indexes} {index_str} reference {reachable_packs} packs");
+        if packed_blob_size > reachable_blob_size {
+            let ds = nice_size(packed_blob_size - reachable_blob_size);
+            println!(", including {ds} unused data.\nConsider running `backpak prune`.");
+        } else {
+            println!();
+        }
     }
     if packed_blob_size < reachable_blob_size {
         let ds = nice_size(reachable_blob_size - packed_blob_size);
         warn!("Snapshots contain {ds} more than packs! Consider running `backpak check`.")
     }
     let all_packs = cached_backend.list_packs()?;
-    let pack_size = super::check::warn_on_unreachable_packs(&index, &all_packs)?;
+    let (pack_size, _unreachable_packs) =
+        super::check::warn_on_unreachable_packs(&index, &all_packs)?;
     let index_size = index_sizes.iter().sum();
 
     let backend_kind = match backend_config.kind {
         backend::Kind::Filesystem { .. } => "Filesystem",
         backend::Kind::Backblaze { .. } => "Backblaze",
     };
-    let filter_str = if let Some((f, _)) = &backend_config.filter {
-        let fname = f.split_whitespace().next().expect("empty filter");
+    let filter_name = backend_config
+        .filter
+        .as_ref()
+        .map(|(f, _)| f.split_whitespace().next().expect("empty filter"));
+    let total_size = pack_size + index_size + snapshot_size;
+
+    if json {
+        let usage = JsonUsage {
+            snapshots: snapshots.len(),
+            first_snapshot: snapshots.first().map(|s| &s.0.time),
+            last_snapshot: snapshots.last().map(|s| &s.0.time),
+            unique_bytes: totals.introduced,
+            reused_bytes: totals.reused,
+            indexes: num_indexes,
+            packs: reachable_packs,
+            unused_bytes: packed_blob_size.saturating_sub(reachable_blob_size),
+            backend: backend_kind,
+            filter: filter_name,
+            snapshot_bytes: snapshot_size,
+            index_bytes: index_size,
+            pack_bytes: pack_size,
+            total_bytes: total_size,
+        };
+        println!("{}", serde_json::to_string(&usage)?);
+        return Ok(());
+    }
+
+    let filter_str = if let Some(fname) = filter_name {
         " and ".to_owned() + fname
     } else {
         String::new()
@@ -91,7 +145,7 @@ pub fn run(config: &Configuration, repository: &camino::Utf8Path) -> Result<()>
     println!("indexes:   {}", nice_size(index_size));
     println!("packs:     {}", nice_size(pack_size));
     #[rustfmt::skip]
-    println!("total:     {}", nice_size(pack_size + index_size + snapshot_size) );
+    println!("total:     {}", nice_size(total_size));
 
     Ok(())
 }
@@ -0,0 +1,144 @@
+use std::fs;
+
+use anyhow::{Result, ensure};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::Parser;
+use tracing::*;
+
+use crate::backend;
+use crate::chunk;
+use crate::config::Configuration;
+use crate::hashing::ObjectId;
+use crate::index;
+use crate::restore::{self, Mismatch};
+use crate::snapshot;
+use crate::tree::{self, Forest, Node, NodeContents};
+
+/// Check that a directory matches the snapshot restored to it
+///
+/// Paths are mapped like `restore --output`:
+/// a snapshot of a single directory is compared to DIR itself,
+/// and a snapshot of several is compared to subdirectories of DIR.
+///
+/// Every file is re-read and chunked again to make sure its contents match.
+/// Files and directories in DIR that aren't in the snapshot, as well as metadata
+/// (times, permissions, etc.), are ignored; see `diff` for those.
+#[derive(Debug, Parser)]
+#[command(verbatim_doc_comment)]
+pub struct Args {
+    #[clap(name = "SNAPSHOT")]
+    snapshot: String,
+
+    #[clap(name = "DIR")]
+    dir: Utf8PathBuf,
+}
+
+pub fn run(config: &Configuration, repository: &Utf8Path, args: Args) -> Result<()> {
+    let (backend_config, cached_backend) = backend::open(
+        repository,
+        config.cache_size,
+        backend::CacheBehavior::Normal,
+    )?;
+    let index = index::build_master_index(&cached_backend)?;
+    let blob_map = index::blob_to_pack_map(&index)?;
+
+    let snapshots = snapshot::load_chronologically(&cached_backend)?;
+    let (snapshot, id) = snapshot::find(&snapshots, &args.snapshot)?;
+    let forest = tree::forest_from_root(
+        &snapshot.tree,
+        &mut tree::Cache::new(&index, &blob_map, &cached_backend),
+    )?;
+    info!("Verifying {} against snapshot {id}", args.dir);
+
+    let mut walk = Walk {
+        forest: &forest,
+        files: vec![],
+        mismatches: vec![],
+    };
+    let root = forest.get(&snapshot.tree).unwrap();
+    for (name, node) in root.iter() {
+        // Same mapping as restore --output
+        let path = if snapshot.paths.len() == 1 {
+            args.dir.clone()
+        } else {
+            args.dir.join(name)
+        };
+        walk.visit(path, node);
+    }
+
+    let chunker = chunk::Chunker::new(&backend_config.chunking, backend_config.hash);
+    info!("Reading {} files", walk.files.len());
+    let mut mismatches = walk.mismatches;
+    mismatches.extend(restore::verify_files(&walk.files, &chunker));
+    mismatches.sort_by(|a, b| a.path.cmp(&b.path));
+
+    for m in &mismatches {
+        error!("{}: {}", m.path, m.problem);
+    }
+    ensure!(
+        mismatches.is_empty(),
+        "{} paths in {} don't match snapshot {}",
+        mismatches.len(),
+        args.dir,
+        id.short_name()
+    );
+    info!("{} matches snapshot {}", args.dir, id.short_name());
+    Ok(())
+}
+
+/// Finds which files to read (and any paths that aren't even the right kind of thing)
+struct Walk<'a> {
+    forest: &'a Forest,
+    files: Vec<(Utf8PathBuf, Vec<ObjectId>)>,
+    mismatches: Vec<Mismatch>,
+}
+
+impl Walk<'_> {
+    fn mismatch(&mut self, path: Utf8PathBuf, problem: String) {
+        self.mismatches.push(Mismatch { path, problem });
+    }
+
+    fn visit(&mut self, path: Utf8PathBuf, node: &Node) {
+        // Don't follow symlinks; restore doesn't either.
+        let metadata = match fs::symlink_metadata(&path) {
+            Ok(m) => m,
+            Err(e) => {
+                let problem = if e.kind() == std::io::ErrorKind::NotFound {
+                    "Missing".to_owned()
+                } else {
+                    format!("Couldn't stat: {e}")
+                };
+                return self.mismatch(path, problem);
+            }
+        };
+
+        match &node.contents {
+            NodeContents::File { chunks } => {
+                if !metadata.is_file() {
+                    return self.mismatch(path, "Should be a file".to_owned());
+                }
+                self.files.push((path, chunks.clone()));
+            }
+            NodeContents::Symlink { target } => {
+                if !metadata.is_symlink() {
+                    return self.mismatch(path, "Should be a symlink".to_owned());
+                }
+                match path.read_link_utf8() {
+                    Ok(t) if t == *target => {}
+                    Ok(t) => self.mismatch(path, format!("Points to {t}, not {target}")),
+                    Err(e) => self.mismatch(path, format!("Couldn't read symlink: {e}")),
+                }
+            }
+            NodeContents::Directory { subtree } => {
+                if !metadata.is_dir() {
+                    return self.mismatch(path, "Should be a directory".to_owned());
+                }
+                let forest = self.forest;
+                let subtree = forest.get(subtree).unwrap();
+                for (name, child) in subtree.iter() {
+                    self.visit(path.join(name), child);
+                }
+            }
+        }
+    }
+}
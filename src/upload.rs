@@ -2,31 +2,196 @@
 //! [snapshots](crate::snapshot)) to a [backend]
 
 use std::fs::File;
-use std::sync::mpsc::Receiver;
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, channel, sync_channel};
+use std::thread;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
+use tracing::*;
 
 use crate::backend;
+use crate::parity;
 
 pub enum Mode {
     DryRun,
     LiveFire,
 }
 
+/// Upload everything from `rx`, with as many packs in flight
+/// as the backend's [concurrency](backend::CachedBackend::concurrency) allows.
+///
+/// Anything that isn't a pack (i.e., indexes and snapshots) waits for
+/// all previous uploads to finish before it goes up.
+/// An index is useless (worse, it's a lie!) if the packs it lists aren't there yet.
 pub fn upload(
     mode: Mode,
     cached_backend: &backend::CachedBackend,
     rx: Receiver<(String, File)>,
 ) -> Result<()> {
-    while let Ok((path, fh)) = rx.recv() {
-        match mode {
-            Mode::LiveFire => cached_backend.write(&path, fh)?,
-            Mode::DryRun => {
-                // Just axe it, it isn't going anywhere.
-                drop(fh);
-                std::fs::remove_file(path)?;
-            }
-        };
+    let jobs = cached_backend.concurrency();
+    if jobs <= 1 {
+        while let Ok((path, fh)) = rx.recv() {
+            upload_one(&mode, cached_backend, &path, fh)?;
+        }
+        return Ok(());
     }
+
+    // Hand packs off to a pool of workers...
+    let (pack_tx, pack_rx) = sync_channel::<(String, File)>(0);
+    let pack_rx = Mutex::new(pack_rx);
+    // ...who tell us when they're done.
+    let (done_tx, done_rx) = channel::<Result<()>>();
+
+    thread::scope(|s| -> Result<()> {
+        // Move this in so that it's dropped (and the workers exit) if we bail early.
+        let pack_tx = pack_tx;
+
+        for i in 0..jobs {
+            let pack_rx = &pack_rx;
+            let done_tx = done_tx.clone();
+            let mode = &mode;
+            thread::Builder::new()
+                .name(format!("uploader {i}"))
+                .spawn_scoped(s, move || {
+                    loop {
+                        // Drop the lock before uploading so others can grab the next pack.
+                        let next = pack_rx.lock().unwrap().recv();
+                        let Ok((path, fh)) = next else {
+                            break;
+                        };
+                        let res = upload_one(mode, cached_backend, &path, fh);
+                        if done_tx.send(res).is_err() {
+                            break;
+                        }
+                    }
+                })
+                .unwrap();
+        }
+        drop(done_tx);
+
+        let mut in_flight = 0usize;
+        while let Ok((path, fh)) = rx.recv() {
+            // Reap whatever's finished so errors show up ASAP.
+            while let Ok(res) = done_rx.try_recv() {
+                res?;
+                in_flight -= 1;
+            }
+
+            if path.ends_with(".pack") {
+                pack_tx
+                    .send((path, fh))
+                    .context("uploader -> upload workers channel exited early")?;
+                in_flight += 1;
+            } else {
+                if in_flight > 0 {
+                    trace!("Waiting on {in_flight} uploads before {path}");
+                }
+                while in_flight > 0 {
+                    done_rx.recv().context("upload workers exited early")??;
+                    in_flight -= 1;
+                }
+                upload_one(&mode, cached_backend, &path, fh)?;
+            }
+        }
+        drop(pack_tx);
+        for res in done_rx {
+            res?;
+        }
+        Ok(())
+    })
+}
+
+fn upload_one(
+    mode: &Mode,
+    cached_backend: &backend::CachedBackend,
+    path: &str,
+    fh: File,
+) -> Result<()> {
+    match mode {
+        Mode::LiveFire => {
+            // Compute parity before the pack is moved (or deleted) out from under us.
+            let parity = match cached_backend.parity() {
+                Some(p) if path.ends_with(".pack") => {
+                    Some(parity::write_for(path, &p, cached_backend.hash())?)
+                }
+                _ => None,
+            };
+            cached_backend.write(path, fh)?;
+            // Parity goes up after its pack so there's never parity for a pack that isn't there.
+            if let Some((parity_path, parity_fh)) = parity {
+                cached_backend.write(&parity_path, parity_fh)?;
+            }
+        }
+        Mode::DryRun => {
+            // Just axe it, it isn't going anywhere.
+            drop(fh);
+            std::fs::remove_file(path)?;
+        }
+    };
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use std::io::Write;
+
+    use crate::hashing::ObjectId;
+
+    #[test]
+    fn parallel_uploads() -> Result<()> {
+        let backend = backend::in_memory();
+        assert!(backend.concurrency() > 1);
+
+        let dir = tempfile::tempdir()?;
+        let (tx, rx) = sync_channel(0);
+        let mut names = vec![];
+        thread::scope(|s| -> Result<()> {
+            let uploader = s.spawn(|| upload(Mode::LiveFire, &backend, rx));
+            for i in 0..20u32 {
+                let id = ObjectId::hash(&i.to_le_bytes());
+                let ext = if i % 10 == 9 { "index" } else { "pack" };
+                let name = format!("{id}.{ext}");
+                let path = dir.path().join(&name).to_str().unwrap().to_owned();
+                let mut fh = File::options()
+                    .create_new(true)
+                    .read(true)
+                    .write(true)
+                    .open(&path)?;
+                fh.write_all(&i.to_le_bytes())?;
+                tx.send((path, fh))?;
+                names.push(name);
+            }
+            drop(tx);
+            uploader.join().unwrap()
+        })?;
+
+        // Each index went up after every pack sent before it (the ones it would list).
+        let written: Vec<String> = backend::memory_writes(&backend)
+            .iter()
+            .map(|w| w.rsplit_once('/').unwrap().1.to_owned())
+            .collect();
+        for (i, name) in names.iter().enumerate() {
+            if name.ends_with(".index") {
+                let at = written.iter().position(|w| w == name).unwrap();
+                for pack in names[..i].iter().filter(|n| n.ends_with(".pack")) {
+                    assert!(written[..at].contains(pack), "{name} went up before {pack}");
+                }
+            }
+        }
+
+        let mut uploaded: Vec<String> = backend
+            .list_packs()?
+            .into_iter()
+            .chain(backend.list_indexes()?)
+            .map(|(name, _len)| name.rsplit_once('/').unwrap().1.to_owned())
+            .collect();
+        uploaded.sort();
+        names.sort();
+        assert_eq!(uploaded, names);
+        // Everything was moved into the backend
+        assert!(names.iter().all(|n| !dir.path().join(n).exists()));
+        Ok(())
+    }
+}
@@ -0,0 +1,297 @@
+use std::path::Path;
+use std::time::Duration;
+
+use anyhow::Result;
+use backpak_b2::fake::FakeB2;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+fn starting_with<'a>(names: &'a [String], prefix: &str) -> Vec<&'a String> {
+    names.iter().filter(|n| n.starts_with(prefix)).collect()
+}
+
+/// Like [`cli_run`], but keep the backend cache out of the real `~/.cache`
+/// and our credentials out of the environment.
+fn b2_run(working_dir: &Path, home_dir: &Path, repo: &Path) -> Result<assert_cmd::Command> {
+    let mut cmd = cli_run(working_dir, repo)?;
+    cmd.env("HOME", home_dir);
+    cmd.env_remove("B2_APPLICATION_KEY_ID");
+    cmd.env_remove("B2_APPLICATION_KEY");
+    Ok(cmd)
+}
+
+#[test]
+fn backblaze_end_to_end() -> Result<()> {
+    let project_dir = std::env::current_dir()?;
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+    let home_dir = tempdir()?;
+
+    let fake = FakeB2::start("id", "key", "bukkit")?;
+    // Make sure packs get uploaded as large files.
+    fake.set_recommended_part_size(1024);
+    let hours = |h: u64| Duration::from_secs(h * 60 * 60);
+    fake.add_unfinished_large_file("packs/abandoned.pack", hours(96));
+    fake.add_unfinished_large_file("packs/slow.pack", hours(48));
+    fake.add_unfinished_large_file("packs/in-progress.pack", Duration::from_secs(60));
+    // Somebody else's upload in the same bucket is none of our business.
+    fake.add_unfinished_large_file("elsewhere/big.tar", hours(96));
+
+    let repo = working_path.join("b2.toml");
+    let b2_run = |repo: &Path| b2_run(working_path, home_dir.path(), repo);
+
+    b2_run(&repo)?
+        .args([
+            "init",
+            "backblaze",
+            "--key-id",
+            "id",
+            "--application-key",
+            "key",
+        ])
+        .args(["--bucket", "bukkit", "--auth-url", fake.url()])
+        .assert()
+        .success();
+    // Give uploads a few days before we call them abandoned.
+    let mut config: toml::Table = toml::from_str(&std::fs::read_to_string(&repo)?)?;
+    config["backend"]
+        .as_table_mut()
+        .unwrap()
+        .insert("abandoned_upload_hours".to_owned(), 72.into());
+    std::fs::write(&repo, toml::to_string(&config)?)?;
+
+    // A hiccup or two shouldn't stop us.
+    fake.fail_next(2);
+    b2_run(&repo)?
+        .arg("backup")
+        .arg(project_dir.join("tests/references"))
+        .assert()
+        .success();
+
+    let names = fake.file_names();
+    assert!(!starting_with(&names, "packs/").is_empty());
+    assert_eq!(starting_with(&names, "indexes/").len(), 1);
+    assert_eq!(starting_with(&names, "snapshots/").len(), 1);
+    // We cleaned up the old abandoned upload, but left the more recent ones
+    // (and the one that isn't ours) alone.
+    let mut unfinished = fake.unfinished_large_files();
+    unfinished.sort();
+    assert_eq!(
+        unfinished,
+        [
+            "elsewhere/big.tar",
+            "packs/in-progress.pack",
+            "packs/slow.pack"
+        ]
+    );
+
+    b2_run(&repo)?
+        .arg("backup")
+        .arg(project_dir.join("src"))
+        .assert()
+        .success();
+    let packs_before_prune = starting_with(&fake.file_names(), "packs/").len();
+
+    // Copy everything to a local repo and make sure it's all there.
+    let local_dir = tempdir()?;
+    let local_path = local_dir.path();
+    cli_run(working_path, local_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    b2_run(&repo)?
+        .arg("copy")
+        .arg("--to")
+        .arg(local_path)
+        .arg("--all")
+        .assert()
+        .success();
+    cli_run(working_path, local_path)?
+        .arg("check")
+        .arg("--read-packs")
+        .assert()
+        .success();
+    assert_eq!(count_directory_entries(local_path.join("snapshots")), 2);
+
+    // Forget the first backup and prune what it left behind.
+    b2_run(&repo)?.args(["forget", "LAST~"]).assert().success();
+    b2_run(&repo)?.arg("prune").assert().success();
+    let names = fake.file_names();
+    assert_eq!(starting_with(&names, "snapshots/").len(), 1);
+    assert!(starting_with(&names, "packs/").len() < packs_before_prune);
+
+    b2_run(&repo)?
+        .arg("check")
+        .arg("--read-packs")
+        .assert()
+        .success();
+
+    Ok(())
+}
+
+#[test]
+fn shared_bucket() -> Result<()> {
+    let project_dir = std::env::current_dir()?;
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+    let home_dir = tempdir()?;
+
+    let fake = FakeB2::start("id", "key", "bukkit")?;
+    fake.restrict_to_prefix("hosts/");
+    let b2_run = |repo: &Path| b2_run(working_path, home_dir.path(), repo);
+
+    let init = |name: &str, prefix: &str| -> Result<_> {
+        let repo = working_path.join(name);
+        b2_run(&repo)?
+            .args(["init", "backblaze", "-k", "id", "-a", "key", "-b", "bukkit"])
+            .args(["--prefix", prefix, "--auth-url", fake.url()])
+            .assert()
+            .success();
+        Ok(repo)
+    };
+    let alice = init("alice.toml", "hosts/alice")?;
+    let bob = init("bob.toml", "/hosts/bob/")?;
+    // Config is fine, but the key can't go there.
+    let eve = init("eve.toml", "eve")?;
+
+    for (repo, path) in [(&alice, "tests/references"), (&bob, "src")] {
+        b2_run(repo)?
+            .arg("backup")
+            .arg(project_dir.join(path))
+            .assert()
+            .success();
+    }
+    let eve_fails = b2_run(&eve)?.arg("snapshots").assert().failure();
+    assert!(stderr(&eve_fails).contains("hosts/"));
+
+    let names = fake.file_names();
+    assert!(
+        names
+            .iter()
+            .all(|n| n.starts_with("hosts/alice/") || n.starts_with("hosts/bob/"))
+    );
+    assert_eq!(starting_with(&names, "hosts/alice/snapshots/").len(), 1);
+    assert_eq!(starting_with(&names, "hosts/bob/snapshots/").len(), 1);
+
+    // Each repo only sees its own stuff.
+    b2_run(&alice)?.args(["forget", "LAST"]).assert().success();
+    b2_run(&alice)?.arg("prune").assert().success();
+    let names = fake.file_names();
+    assert!(starting_with(&names, "hosts/alice/snapshots/").is_empty());
+    assert!(starting_with(&names, "hosts/alice/packs/").is_empty());
+    assert_eq!(starting_with(&names, "hosts/bob/snapshots/").len(), 1);
+
+    let ls = b2_run(&bob)?.args(["ls", "LAST"]).assert().success();
+    assert!(stdout(&ls).lines().any(|l| l == "src/backend.rs"));
+
+    Ok(())
+}
+
+#[test]
+fn repack_trees() -> Result<()> {
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+    let home_dir = tempdir()?;
+
+    // Lots of little directories means lots of little trees.
+    let src_dir = tempdir()?;
+    let src_path = src_dir.path();
+    for d in 0..200 {
+        let dir = src_path.join(format!("dir{d}"));
+        std::fs::create_dir(&dir)?;
+        std::fs::write(dir.join("a.txt"), format!("{d}"))?;
+    }
+
+    let fake = FakeB2::start("id", "key", "bukkit")?;
+    let repo = working_path.join("b2.toml");
+    let b2_run = || b2_run(working_path, home_dir.path(), &repo);
+
+    b2_run()?
+        .args(["init", "backblaze", "-k", "id", "-a", "key", "-b", "bukkit"])
+        .args(["--auth-url", fake.url()])
+        .assert()
+        .success();
+    b2_run()?.arg("backup").arg(src_path).assert().success();
+    b2_run()?
+        .args(["train-dictionary", "--size", "4 KiB"])
+        .assert()
+        .success();
+
+    // Recompressing the trees gives the same pack ID, so we upload it again.
+    b2_run()?
+        .args(["prune", "--repack-trees"])
+        .assert()
+        .success();
+    let names = fake.file_names();
+    let packs = starting_with(&names, "packs/");
+    assert!(packs.iter().any(|p| fake.versions(p) > 1));
+
+    // Dropping it gets rid of every version.
+    b2_run()?.args(["forget", "LAST"]).assert().success();
+    b2_run()?.arg("prune").assert().success();
+    assert!(starting_with(&fake.file_names(), "packs/").is_empty());
+
+    Ok(())
+}
+
+#[test]
+fn heal_from_parity() -> Result<()> {
+    let project_dir = std::env::current_dir()?;
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+    let home_dir = tempdir()?;
+
+    let fake = FakeB2::start("id", "key", "bukkit")?;
+    let repo = working_path.join("b2.toml");
+    let b2_run = || b2_run(working_path, home_dir.path(), &repo);
+
+    b2_run()?
+        .args(["init", "--parity", "backblaze", "-k", "id", "-a", "key"])
+        .args(["-b", "bukkit", "--auth-url", fake.url()])
+        .assert()
+        .success();
+    b2_run()?
+        .arg("backup")
+        .arg(project_dir.join("tests/references/sr71.txt"))
+        .assert()
+        .success();
+
+    // Rot a byte in the biggest pack (of file contents) and its parity.
+    let names = fake.file_names();
+    let biggest = starting_with(&names, "packs/")
+        .into_iter()
+        .max_by_key(|p| fake.file(p).unwrap().len())
+        .unwrap();
+    fake.damage(biggest, fake.file(biggest).unwrap().len() / 2);
+    let parity = biggest
+        .replace("packs/", "parity/")
+        .replace(".pack", ".par");
+    fake.damage(&parity, fake.file(&parity).unwrap().len() / 2);
+
+    // Fixing them uploads new versions over the damaged ones...
+    b2_run()?
+        .args(["repair", "--from-parity"])
+        .assert()
+        .success();
+    assert_eq!(fake.versions(biggest), 2);
+    assert_eq!(fake.versions(&parity), 2);
+    b2_run()?
+        .args(["check", "--read-packs", "--parity"])
+        .assert()
+        .success();
+
+    // ...which we can still get rid of.
+    b2_run()?.args(["forget", "LAST"]).assert().success();
+    b2_run()?.arg("prune").assert().success();
+    let names = fake.file_names();
+    assert!(starting_with(&names, "packs/").is_empty());
+    assert!(starting_with(&names, "parity/").is_empty());
+
+    Ok(())
+}
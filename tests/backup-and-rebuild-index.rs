@@ -39,7 +39,7 @@ fn rebuild_index() -> Result<()> {
 
     // Let's backup our own code, and the test references.
     cli_run(working_path, backup_path)?
-        .args(&["backup", "--tag", "test-tag", "--tag", "another-tag", "--"])
+        .args(["backup", "--tag", "test-tag", "--tag", "another-tag", "--"])
         .args(&[
             project_dir.join("src"),
             project_dir.join("tests/references"),
@@ -49,7 +49,7 @@ fn rebuild_index() -> Result<()> {
 
     // Check that everything backed up alright.
     cli_run(working_path, backup_path)?
-        .args(&["check", "--read-packs"])
+        .args(["check", "--read-packs"])
         .assert()
         .success();
 
@@ -77,7 +77,7 @@ fn rebuild_index() -> Result<()> {
 
     // Everything should be nice and reachable from the new index.
     cli_run(working_path, backup_path)?
-        .args(&["check", "--read-packs"])
+        .args(["check", "--read-packs"])
         .assert()
         .success();
 
@@ -0,0 +1,89 @@
+use std::fs;
+
+use anyhow::Result;
+use serde_json::Value;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn deep_check_finds_damage() -> Result<()> {
+    let project_dir = std::env::current_dir()?;
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let data_path = working_path.join("data");
+    fs::create_dir_all(data_path.join("sub"))?;
+    fs::copy(
+        project_dir.join("tests/references/sr71.txt"),
+        data_path.join("sub/sr71.txt"),
+    )?;
+    fs::write(data_path.join("hello.txt"), "Hello, world!\n")?;
+
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&data_path)
+        .assert()
+        .success();
+
+    let check = check_json(working_path, backup_path, &["--deep"])?;
+    assert_eq!(check["ok"], true);
+    assert_eq!(check["deep"], true);
+    assert_eq!(check["damaged_paths"], Value::Array(vec![]));
+
+    // Lose the files' contents.
+    for pack in packs_with(working_path, backup_path, "chunk")? {
+        fs::remove_file(backup_path.join("packs").join(pack))?;
+    }
+    cli_run(working_path, backup_path)?
+        .arg("rebuild-index")
+        .assert()
+        .success();
+
+    let check = check_json(working_path, backup_path, &["--deep"])?;
+    assert_eq!(check["ok"], false);
+    assert_eq!(check["missing_chunks"], 2);
+    let mut damaged: Vec<&str> = check["damaged_paths"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|d| {
+            assert!(d["problem"].as_str().unwrap().starts_with("Missing chunk"));
+            d["path"].as_str().unwrap()
+        })
+        .collect();
+    damaged.sort();
+    assert_eq!(damaged, ["data/hello.txt", "data/sub/sr71.txt"]);
+
+    // Lose the directories too.
+    for pack in packs_with(working_path, backup_path, "tree")? {
+        fs::remove_file(backup_path.join("packs").join(pack))?;
+    }
+    cli_run(working_path, backup_path)?
+        .arg("rebuild-index")
+        .assert()
+        .success();
+
+    let check = check_json(working_path, backup_path, &["--deep"])?;
+    assert_eq!(check["ok"], false);
+    let damaged = check["damaged_paths"].as_array().unwrap();
+    assert_eq!(damaged.len(), 1);
+    assert_eq!(damaged[0]["path"], "");
+    assert!(
+        damaged[0]["problem"]
+            .as_str()
+            .unwrap()
+            .starts_with("Couldn't read tree")
+    );
+
+    Ok(())
+}
@@ -1,9 +1,11 @@
 #![allow(dead_code)]
 
+use std::fs;
 use std::path::{Path, PathBuf};
 
 use anyhow::Result;
 use assert_cmd::Command;
+use serde_json::Value;
 use walkdir::WalkDir;
 
 pub fn cli_run(working_dir: &Path, backup_path: &Path) -> Result<assert_cmd::Command> {
@@ -52,3 +54,35 @@ pub fn normalize(o: &str) -> Vec<&str> {
     // Strip Opening... Building a master index... snapshot <hash>...
     o.trim().lines().skip(3).collect()
 }
+
+/// Runs `check` with the given flags and parses its `--json` report
+pub fn check_json(working_path: &Path, backup_path: &Path, flags: &[&str]) -> Result<Value> {
+    let check = cli_run(working_path, backup_path)?
+        .args(["--json", "check"])
+        .args(flags)
+        .assert();
+    Ok(serde_json::from_str(stdout(&check))?)
+}
+
+/// Packs holding the given type of blob
+pub fn packs_with(working_path: &Path, backup_path: &Path, blob_type: &str) -> Result<Vec<String>> {
+    let mut packs = vec![];
+    for entry in fs::read_dir(backup_path.join("packs"))? {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        let id = name.strip_suffix(".pack").unwrap().to_owned();
+        let cat = cli_run(working_path, backup_path)?
+            .args(["cat", "pack", &id])
+            .assert()
+            .success();
+        let manifest: Value = serde_json::from_str(stdout(&cat))?;
+        if manifest
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|e| e["type"] == blob_type)
+        {
+            packs.push(name);
+        }
+    }
+    Ok(packs)
+}
@@ -0,0 +1,66 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn compression_settings() -> Result<()> {
+    let project_dir = std::env::current_dir()?;
+
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    // Nonsense is rejected up front.
+    cli_run(working_path, backup_path)?
+        .args(["init", "--compression", "99", "filesystem"])
+        .assert()
+        .failure();
+    cli_run(working_path, backup_path)?
+        .args(["init", "--window-log", "31", "filesystem"])
+        .assert()
+        .failure();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "--compression", "19", "--long-distance-matching"])
+        .args(["--window-log", "24", "filesystem"])
+        .assert()
+        .success();
+
+    let config = fs::read_to_string(backup_path.join("config.toml"))?;
+    let config: toml::Table = toml::from_str(&config)?;
+    let compression = config["compression"].as_table().unwrap();
+    assert_eq!(compression["level"].as_integer(), Some(19));
+    assert_eq!(compression["long_distance_matching"].as_bool(), Some(true));
+    assert_eq!(compression["window_log"].as_integer(), Some(24));
+
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(project_dir.join("tests/references"))
+        .assert()
+        .success();
+
+    // Override the level just for this run.
+    cli_run(working_path, backup_path)?
+        .args(["--compression-level", "-5", "backup"])
+        .arg(project_dir.join("src"))
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .args(["--compression-level", "23", "snapshots"])
+        .assert()
+        .failure();
+
+    cli_run(working_path, backup_path)?
+        .args(["check", "--read-packs"])
+        .assert()
+        .success();
+
+    Ok(())
+}
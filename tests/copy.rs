@@ -1,3 +1,6 @@
+use std::collections::BTreeSet;
+use std::fs;
+
 use anyhow::Result;
 use tempfile::tempdir;
 
@@ -156,3 +159,183 @@ fn copy_filtered() -> Result<()> {
     // std::mem::forget(copy_dir);
     Ok(())
 }
+
+#[test]
+fn copy_rechunked() -> Result<()> {
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    // Something big enough to be several chunks, that doesn't compress away.
+    let data_path = working_path.join("data");
+    fs::create_dir(&data_path)?;
+    let mut x = 0x9e3779b97f4a7c15u64;
+    let noise: Vec<u8> = (0..3 * 1024 * 1024)
+        .map(|_| {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            x as u8
+        })
+        .collect();
+    fs::write(data_path.join("noise"), &noise)?;
+
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+    cli_run(working_path, backup_path)?
+        .args(["init", "--chunk-size", "64 KiB", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&data_path)
+        .assert()
+        .success();
+
+    // Every repository gets its own secret chunking...
+    let copy_dir = tempdir()?;
+    let copy_path = copy_dir.path();
+    cli_run(working_path, copy_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    let chunking = |repo: &std::path::Path| -> Result<toml::Value> {
+        let config: toml::Table = toml::from_str(&fs::read_to_string(repo.join("config.toml"))?)?;
+        Ok(config["chunking"].clone())
+    };
+    assert!(chunking(copy_path)?.get("seed").is_some());
+    assert_ne!(chunking(backup_path)?, chunking(copy_path)?);
+
+    // ...so copying recuts files.
+    cli_run(working_path, backup_path)?
+        .args(["copy", "--all", "--to"])
+        .arg(copy_path)
+        .assert()
+        .success();
+    cli_run(working_path, copy_path)?
+        .args(["check", "-r"])
+        .assert()
+        .success();
+
+    // Cut the way the destination does, the file dedupes against what we copied.
+    fs::write(data_path.join("noise"), &noise)?;
+    let metrics_path = working_path.join("backpak.prom");
+    cli_run(working_path, copy_path)?
+        .arg("--metrics-file")
+        .arg(&metrics_path)
+        .arg("backup")
+        .arg(&data_path)
+        .assert()
+        .success();
+    let metrics = fs::read_to_string(&metrics_path)?;
+    let chunk_bytes = metrics
+        .lines()
+        .find(|l| l.starts_with("backpak_backup_chunk_bytes{"))
+        .and_then(|l| l.rsplit_once(' '))
+        .unwrap()
+        .1;
+    assert_eq!(chunk_bytes, "0");
+
+    let restore_dir = tempdir()?;
+    cli_run(working_path, copy_path)?
+        .args(["restore", "LAST~", "--output"])
+        .arg(restore_dir.path())
+        .assert()
+        .success();
+    assert_eq!(fs::read(restore_dir.path().join("noise"))?, noise);
+
+    // Repositories can also share chunking so copies don't have to.
+    let shared_dir = tempdir()?;
+    let shared_path = shared_dir.path();
+    cli_run(working_path, shared_path)?
+        .args(["init", "--copy-chunking-from"])
+        .arg(backup_path)
+        .arg("filesystem")
+        .assert()
+        .success();
+    assert_eq!(chunking(backup_path)?, chunking(shared_path)?);
+    Ok(())
+}
+
+#[test]
+fn copy_rehashed() -> Result<()> {
+    let project_dir = std::env::current_dir()?;
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let data_path = working_path.join("data");
+    fs::create_dir(&data_path)?;
+    let sr71 = fs::read(project_dir.join("tests/references/sr71.txt"))?;
+    fs::write(data_path.join("sr71.txt"), &sr71)?;
+
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&data_path)
+        .assert()
+        .success();
+
+    // Same chunking, different hash.
+    let copy_dir = tempdir()?;
+    let copy_path = copy_dir.path();
+    cli_run(working_path, copy_path)?
+        .args(["init", "--hash", "blake3", "--copy-chunking-from"])
+        .arg(backup_path)
+        .arg("filesystem")
+        .assert()
+        .success();
+    let config: toml::Table = toml::from_str(&fs::read_to_string(copy_path.join("config.toml"))?)?;
+    assert_eq!(config["hash"].as_str(), Some("blake3"));
+
+    cli_run(working_path, backup_path)?
+        .args(["copy", "--all", "--to"])
+        .arg(copy_path)
+        .assert()
+        .success();
+    cli_run(working_path, copy_path)?
+        .args(["check", "--read-packs"])
+        .assert()
+        .success();
+
+    // Nothing in common: every ID got rehashed.
+    let ids = |repo: &std::path::Path, dir: &str| -> Result<BTreeSet<String>> {
+        fs::read_dir(repo.join(dir))?
+            .map(|e| Ok(e?.file_name().to_string_lossy().into_owned()))
+            .collect()
+    };
+    assert!(ids(backup_path, "packs")?.is_disjoint(&ids(copy_path, "packs")?));
+    assert!(ids(backup_path, "snapshots")?.is_disjoint(&ids(copy_path, "snapshots")?));
+
+    // Backups to the new repository dedupe against what we copied...
+    fs::write(data_path.join("sr71.txt"), &sr71)?;
+    let metrics_path = working_path.join("backpak.prom");
+    cli_run(working_path, copy_path)?
+        .arg("--metrics-file")
+        .arg(&metrics_path)
+        .arg("backup")
+        .arg(&data_path)
+        .assert()
+        .success();
+    let metrics = fs::read_to_string(&metrics_path)?;
+    let chunk_bytes = metrics
+        .lines()
+        .find(|l| l.starts_with("backpak_backup_chunk_bytes{"))
+        .and_then(|l| l.rsplit_once(' '))
+        .unwrap()
+        .1;
+    assert_eq!(chunk_bytes, "0");
+
+    // ...and restore just the same.
+    let restore_dir = tempdir()?;
+    cli_run(working_path, copy_path)?
+        .args(["restore", "LAST~", "--output"])
+        .arg(restore_dir.path())
+        .assert()
+        .success();
+    assert_eq!(fs::read(restore_dir.path().join("sr71.txt"))?, sr71);
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn tree_dictionary() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    // Lots of little directories means lots of little trees.
+    let src_dir = tempdir()?;
+    let src_path = src_dir.path();
+    for d in 0..200 {
+        let dir = src_path.join(format!("dir{d}"));
+        fs::create_dir(&dir)?;
+        fs::write(dir.join("a.txt"), format!("{d}"))?;
+        fs::write(dir.join("b.txt"), format!("{}", d * 2))?;
+    }
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(src_path)
+        .assert()
+        .success();
+
+    // Dry runs don't touch anything.
+    cli_run(working_path, backup_path)?
+        .args(["train-dictionary", "--dry-run", "--size", "4 KiB"])
+        .assert()
+        .success();
+    assert_eq!(count_directory_entries(backup_path.join("dictionaries")), 0);
+
+    cli_run(working_path, backup_path)?
+        .args(["train-dictionary", "--size", "4 KiB"])
+        .assert()
+        .success();
+    assert_eq!(count_directory_entries(backup_path.join("dictionaries")), 1);
+    let config = fs::read_to_string(backup_path.join("config.toml"))?;
+    let config: toml::Table = toml::from_str(&config)?;
+    assert!(config["compression"]["tree_dictionary"].is_str());
+
+    // New trees use it...
+    fs::write(src_path.join("dir0/c.txt"), "new")?;
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(src_path)
+        .assert()
+        .success();
+
+    // ...and we can recompress the old ones with it.
+    let prune = cli_run(working_path, backup_path)?
+        .args(["prune", "--repack-trees"])
+        .assert()
+        .success();
+    // (The new backup's trees already are.)
+    assert!(stdout(&prune).contains("rewrite 1 ("));
+    // Again! Everything's compressed with the current dictionary,
+    // so there's nothing to rewrite.
+    let prune = cli_run(working_path, backup_path)?
+        .args(["prune", "--repack-trees"])
+        .assert()
+        .success();
+    assert!(stdout(&prune).contains("Nothing to do."));
+
+    cli_run(working_path, backup_path)?
+        .args(["check", "--read-packs"])
+        .assert()
+        .success();
+    for snapshot in ["LAST", "LAST~"] {
+        let ls = cli_run(working_path, backup_path)?
+            .args(["ls", snapshot])
+            .assert()
+            .success();
+        assert!(stdout(&ls).lines().any(|l| l.ends_with("dir199/b.txt")));
+    }
+
+    Ok(())
+}
@@ -22,7 +22,7 @@ fn diff_src() -> Result<()> {
     // Let's make a copy of src so we don't fudge the actual code
     assert!(
         Command::new("cp")
-            .args(&["-a", "src"])
+            .args(["-a", "src"])
             .arg(working_path)
             .status()?
             .success()
@@ -43,7 +43,7 @@ fn diff_src() -> Result<()> {
     let diffit = || {
         let diff_run = cli_run(working_path, backup_path)
             .unwrap()
-            .args(&["diff", "--metadata", "LAST"])
+            .args(["diff", "--metadata", "LAST"])
             .assert()
             .success();
         let diff_output: Vec<_> = stdout(&diff_run)
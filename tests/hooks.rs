@@ -0,0 +1,72 @@
+use std::fs;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn backup_hooks() -> Result<()> {
+    let project_dir = std::env::current_dir()?;
+
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    let config_path = working_path.join("backpak.toml");
+    let log_path = working_path.join("hooks.log");
+    let run_with_hooks = |pre: &str| -> Result<Command> {
+        fs::write(
+            &config_path,
+            format!(
+                r#"
+[backup.hooks]
+pre = "{pre}"
+post = "echo post $BACKPAK_EXIT_STATUS $BACKPAK_SNAPSHOT $BACKPAK_BYTES_UPLOADED >> {log}"
+on_error = "echo on_error $BACKPAK_EXIT_STATUS >> {log}"
+"#,
+                log = log_path.display()
+            ),
+        )?;
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME"))?;
+        cmd.arg("-C").arg(working_path);
+        cmd.arg("--config").arg(&config_path);
+        cmd.arg("--repository").arg(backup_path);
+        cmd.arg("backup").arg(project_dir.join("tests/references"));
+        Ok(cmd)
+    };
+
+    run_with_hooks(&format!(
+        "echo pre $BACKPAK_REPOSITORY >> {}",
+        log_path.display()
+    ))?
+    .assert()
+    .success();
+
+    let log = fs::read_to_string(&log_path)?;
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0], format!("pre {}", backup_path.display()));
+    let post: Vec<&str> = lines[1].split(' ').collect();
+    assert_eq!(post[..2], ["post", "0"]);
+    assert!(!post[2].is_empty()); // snapshot ID
+    assert!(post[3].parse::<u64>()? > 0); // bytes uploaded
+
+    // A failing pre hook aborts the backup.
+    fs::remove_file(&log_path)?;
+    run_with_hooks("false")?.assert().failure();
+    assert_eq!(fs::read_to_string(&log_path)?, "on_error 1\n");
+    assert_eq!(count_directory_entries(backup_path.join("snapshots")), 1);
+
+    Ok(())
+}
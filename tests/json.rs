@@ -0,0 +1,228 @@
+use std::{fs, process::Command};
+
+use anyhow::Result;
+use serde_json::Value;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn json_output() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    assert!(
+        Command::new("cp")
+            .args(["-a", "tests/references"])
+            .arg(working_path)
+            .status()?
+            .success()
+    );
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    cli_run(working_path, backup_path)?
+        .args(["backup", "--tag", "json-tag", "references"])
+        .assert()
+        .success();
+
+    // snapshots: one big array, with sizes and changes when asked.
+    let snaps = cli_run(working_path, backup_path)?
+        .args(["--json", "snapshots", "--sizes", "--stat"])
+        .assert()
+        .success();
+    let snaps: Value = serde_json::from_str(stdout(&snaps))?;
+    let snaps = snaps.as_array().unwrap();
+    assert_eq!(snaps.len(), 1);
+    let snap = &snaps[0];
+    assert!(snap["id"].is_string());
+    assert!(snap["time"].is_string());
+    assert_eq!(snap["tags"], serde_json::json!(["json-tag"]));
+    assert!(snap["sizes"]["introduced"].as_u64().unwrap() > 0);
+    assert_eq!(snap["sizes"]["reused"], 0);
+    let changes = snap["changes"].as_array().unwrap();
+    assert!(!changes.is_empty());
+    assert!(changes.iter().all(|c| c["change"] == "+"));
+
+    // ls: one object per line
+    let ls = cli_run(working_path, backup_path)?
+        .args(["--json", "ls", "LAST"])
+        .assert()
+        .success();
+    let ls = stdout(&ls)
+        .lines()
+        .map(serde_json::from_str)
+        .collect::<serde_json::Result<Vec<Value>>>()?;
+    assert_eq!(ls.len(), changes.len());
+    assert_eq!(ls[0]["path"], "references");
+    assert_eq!(ls[0]["type"], "directory");
+    assert!(ls.iter().any(|n| n["path"] == "references/sr71.txt"));
+
+    // diff: one object per line, with change codes
+    fs::remove_file(working_path.join("references/sr71.txt"))?;
+    let diff = cli_run(working_path, backup_path)?
+        .args(["--json", "diff", "LAST"])
+        .assert()
+        .success();
+    let diff: Value = serde_json::from_str(stdout(&diff).trim())?;
+    assert_eq!(
+        diff,
+        serde_json::json!({
+            "change": "-",
+            "path": "references/sr71.txt",
+            "type": "file"
+        })
+    );
+
+    // usage and check: one object each, no progress or INFO chatter.
+    let usage = cli_run(working_path, backup_path)?
+        .args(["--json", "usage"])
+        .assert()
+        .success();
+    let usage: Value = serde_json::from_str(stdout(&usage))?;
+    assert_eq!(usage["snapshots"], 1);
+    assert_eq!(usage["backend"], "Filesystem");
+    assert_eq!(usage["unused_bytes"], 0);
+
+    let check = cli_run(working_path, backup_path)?
+        .args(["--json", "check", "--read-packs"])
+        .assert()
+        .success();
+    let check: Value = serde_json::from_str(stdout(&check))?;
+    assert_eq!(check["ok"], true);
+    assert_eq!(check["broken_packs"], 0);
+    assert_eq!(check["missing_chunks"], 0);
+    assert_eq!(check["packs_read"], check["packs"]);
+
+    // Reading half at a time gets them all.
+    let mut read = 0;
+    for part in ["1/2", "2/2"] {
+        let check = cli_run(working_path, backup_path)?
+            .args(["--json", "check", "--read-packs-subset", part])
+            .assert()
+            .success();
+        let check: Value = serde_json::from_str(stdout(&check))?;
+        assert_eq!(check["read_packs"], true);
+        read += check["packs_read"].as_u64().unwrap();
+    }
+    assert_eq!(Some(read), check["packs"].as_u64());
+
+    let check = cli_run(working_path, backup_path)?
+        .args(["--json", "check", "--read-packs-subset", "1 B"])
+        .assert()
+        .success();
+    let check: Value = serde_json::from_str(stdout(&check))?;
+    assert_eq!(check["packs_read"], 1);
+
+    Ok(())
+}
+
+#[test]
+fn backup_progress_json() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    let summary = |events: &str| -> Result<Value> {
+        let events = events
+            .lines()
+            .map(serde_json::from_str)
+            .collect::<serde_json::Result<Vec<Value>>>()?;
+        // Every line should be an event, ending with the summary.
+        assert!(events.iter().all(|e| e["event"].is_string()));
+        let last = events.last().unwrap().clone();
+        assert_eq!(last["event"], "summary");
+        Ok(last)
+    };
+
+    let backup = cli_run(working_path, backup_path)?
+        .args(["backup", "--progress", "json"])
+        .arg(std::env::current_dir()?.join("tests/references"))
+        .assert()
+        .success();
+    let s = summary(stdout(&backup))?;
+    assert_eq!(s["status"], "success");
+    assert!(s["snapshot"].is_string());
+    assert!(s["chunk_bytes"].as_u64().unwrap() > 0);
+    assert_eq!(s["skipped_files"], 0);
+
+    // Nothing to back up gets its own exit code.
+    fs::create_dir(working_path.join("empty"))?;
+    let backup = cli_run(working_path, backup_path)?
+        .args(["backup", "--progress", "json", "--skip", "empty", "empty"])
+        .assert()
+        .code(10);
+    let s = summary(stdout(&backup))?;
+    assert_eq!(s["status"], "unchanged");
+    assert!(s["snapshot"].is_null());
+
+    Ok(())
+}
+
+// We need a file we can open but not read: /proc/self/mem fits the bill.
+#[cfg(target_os = "linux")]
+#[test]
+fn unreadable_files_keep_previous_version() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    let data_path = working_path.join("data");
+    fs::create_dir(&data_path)?;
+    fs::write(data_path.join("stuck"), "the last good version")?;
+    fs::write(data_path.join("fine"), "nothing to see here")?;
+
+    let backup = |code: i32| -> Result<Value> {
+        let backup = cli_run(working_path, backup_path)?
+            .args(["backup", "--dereference", "--progress", "json", "data"])
+            .assert()
+            .code(code);
+        let last = stdout(&backup).lines().last().unwrap().to_owned();
+        Ok(serde_json::from_str(&last)?)
+    };
+    assert_eq!(backup(0)?["skipped_files"], 0);
+
+    fs::remove_file(data_path.join("stuck"))?;
+    std::os::unix::fs::symlink("/proc/self/mem", data_path.join("stuck"))?;
+    fs::write(data_path.join("fine"), "still nothing to see here")?;
+    let s = backup(11)?;
+    assert_eq!(s["status"], "partial");
+    assert_eq!(s["skipped_files"], 1);
+
+    let ls = cli_run(working_path, backup_path)?
+        .args(["ls", "HEAD"])
+        .assert()
+        .success();
+    let files: Vec<&str> = stdout(&ls).lines().collect();
+    assert!(files.iter().any(|f| f.ends_with("stuck")), "{files:?}");
+    assert!(files.iter().any(|f| f.ends_with("fine")), "{files:?}");
+
+    let dump = cli_run(working_path, backup_path)?
+        .args(["dump", "HEAD", "data/stuck"])
+        .assert()
+        .success();
+    assert_eq!(stdout(&dump), "the last good version");
+    Ok(())
+}
@@ -0,0 +1,90 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+fn sample<'a>(metrics: &'a str, name: &str) -> Option<&'a str> {
+    metrics
+        .lines()
+        .find(|l| l.starts_with(name))
+        .map(|l| l.rsplit_once(' ').unwrap().1)
+}
+
+#[test]
+fn metrics_file() -> Result<()> {
+    let project_dir = std::env::current_dir()?;
+
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let metrics_path = working_path.join("backpak.prom");
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    cli_run(working_path, backup_path)?
+        .arg("--metrics-file")
+        .arg(&metrics_path)
+        .arg("backup")
+        .arg(project_dir.join("tests/references"))
+        .assert()
+        .success();
+
+    let metrics = fs::read_to_string(&metrics_path)?;
+    assert!(metrics.ends_with("# EOF\n"));
+    // Whatever's collecting these probably isn't running as us.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&metrics_path)?.permissions().mode();
+        assert_eq!(mode & 0o044, 0o044, "{mode:o}");
+    }
+    assert_eq!(sample(&metrics, "backpak_success{"), Some("1"));
+    assert_eq!(
+        sample(&metrics, "backpak_repository_snapshots{command=\"backup\"}"),
+        Some("1")
+    );
+    assert!(
+        sample(&metrics, "backpak_backup_chunk_bytes{")
+            .unwrap()
+            .parse::<u64>()?
+            > 0
+    );
+    assert!(
+        sample(&metrics, "backpak_uploaded_bytes{")
+            .unwrap()
+            .parse::<u64>()?
+            > 0
+    );
+    assert!(metrics.contains("# TYPE backpak_operations_total counter\n"));
+    assert!(metrics.contains("op=\"BackendWrite\""));
+    let last_success = sample(&metrics, "backpak_last_success_timestamp_seconds{")
+        .unwrap()
+        .to_owned();
+
+    // Failures still write metrics, but remember when we last succeeded.
+    cli_run(working_path, backup_path)?
+        .arg("--metrics-file")
+        .arg(&metrics_path)
+        .args(["backup", "no/such/path"])
+        .assert()
+        .failure();
+
+    let metrics = fs::read_to_string(&metrics_path)?;
+    assert_eq!(sample(&metrics, "backpak_success{"), Some("0"));
+    assert_eq!(
+        sample(&metrics, "backpak_last_success_timestamp_seconds{"),
+        Some(last_success.as_str())
+    );
+
+    Ok(())
+}
@@ -0,0 +1,120 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn migrate_old_snapshots() -> Result<()> {
+    let project_dir = std::env::current_dir()?;
+
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    let config_path = backup_path.join("config.toml");
+    let version = || -> Result<Option<i64>> {
+        let config: toml::Table = toml::from_str(&fs::read_to_string(&config_path)?)?;
+        Ok(config.get("version").and_then(|v| v.as_integer()))
+    };
+    assert_eq!(version()?, Some(4));
+
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(project_dir.join("tests/references"))
+        .assert()
+        .success();
+
+    // Pretend this is an old repository with a version 1 snapshot lying around.
+    let config = fs::read_to_string(&config_path)?;
+    let config: String = config
+        .lines()
+        .filter(|l| !l.starts_with("version"))
+        .map(|l| format!("{l}\n"))
+        .collect();
+    fs::write(&config_path, config)?;
+    let old_id = "4t84ab7sgsjjss803e30mdrokbnibg7ubpb4leds2e91g";
+    let mut old_snapshot = b"MKBAKSNP1".to_vec();
+    old_snapshot.extend(fs::read(
+        project_dir.join("tests/references/snapshot.stability"),
+    )?);
+    let old_path = backup_path.join(format!("snapshots/{old_id}.snapshot"));
+    fs::write(&old_path, old_snapshot)?;
+
+    let snapshots = || {
+        let mut s: Vec<_> = fs::read_dir(backup_path.join("snapshots"))
+            .unwrap()
+            .map(|e| e.unwrap().path())
+            .collect();
+        s.sort();
+        s
+    };
+    let before = snapshots();
+    assert_eq!(before.len(), 2);
+
+    cli_run(working_path, backup_path)?
+        .args(["migrate", "--dry-run"])
+        .assert()
+        .success();
+    assert_eq!(version()?, None);
+    assert_eq!(snapshots(), before);
+
+    // Older backpaks could open it, but not read the packs we write.
+    let refused = cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(project_dir.join("src"))
+        .assert()
+        .failure();
+    assert!(stderr(&refused).contains("backpak migrate"));
+
+    cli_run(working_path, backup_path)?
+        .arg("migrate")
+        .assert()
+        .success();
+    assert_eq!(version()?, Some(4));
+    let after = snapshots();
+    assert_eq!(after.len(), 2);
+    assert!(!old_path.exists());
+    for s in &after {
+        assert!(fs::read(s)?.starts_with(b"MKBAKSNP2"));
+    }
+    // Same author, same paths, new format.
+    let listed = cli_run(working_path, backup_path)?
+        .arg("snapshots")
+        .assert()
+        .success();
+    assert!(stdout(&listed).contains("Neil"));
+
+    // Nothing left to do.
+    cli_run(working_path, backup_path)?
+        .arg("migrate")
+        .assert()
+        .success();
+    assert_eq!(snapshots(), after);
+
+    // And now we can back up to it.
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(project_dir.join("src"))
+        .assert()
+        .success();
+
+    // We don't touch repositories from the future.
+    let config = fs::read_to_string(&config_path)?.replace("version = 4", "version = 99");
+    fs::write(&config_path, config)?;
+    let future = cli_run(working_path, backup_path)?
+        .arg("snapshots")
+        .assert()
+        .failure();
+    assert!(stderr(&future).contains("version 99"));
+    Ok(())
+}
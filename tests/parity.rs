@@ -0,0 +1,135 @@
+use std::fs;
+
+use anyhow::Result;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn heal_from_parity() -> Result<()> {
+    let project_dir = std::env::current_dir()?;
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let data_path = working_path.join("data");
+    fs::create_dir_all(&data_path)?;
+    fs::copy(
+        project_dir.join("tests/references/sr71.txt"),
+        data_path.join("sr71.txt"),
+    )?;
+
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+    cli_run(working_path, backup_path)?
+        .args(["init", "--parity", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&data_path)
+        .assert()
+        .success();
+
+    let packs = count_directory_entries(backup_path.join("packs"));
+    assert_eq!(count_directory_entries(backup_path.join("parity")), packs);
+    let clean = check_json(working_path, backup_path, &["--read-packs", "--parity"])?;
+    assert_eq!(clean["ok"], true);
+    assert_eq!(clean["packs_without_parity"], 0);
+
+    // Rot a byte in the pack of file contents.
+    let chunk_packs = packs_with(working_path, backup_path, "chunk")?;
+    let pack_path = backup_path.join("packs").join(&chunk_packs[0]);
+    let mut contents = fs::read(&pack_path)?;
+    let middle = contents.len() / 2;
+    contents[middle] ^= 0xff;
+    fs::write(&pack_path, contents)?;
+
+    let damaged = check_json(working_path, backup_path, &["--read-packs", "--parity"])?;
+    assert_eq!(damaged["ok"], false);
+    assert_eq!(damaged["fixable_packs"], 1);
+    assert_eq!(damaged["unfixable_packs"], 0);
+
+    // A dry run changes nothing...
+    cli_run(working_path, backup_path)?
+        .args(["repair", "-n", "--from-parity"])
+        .assert()
+        .success();
+    assert_eq!(
+        check_json(working_path, backup_path, &["--read-packs", "--parity"])?["fixable_packs"],
+        1
+    );
+
+    // ...but the real thing fixes the pack.
+    cli_run(working_path, backup_path)?
+        .args(["repair", "--from-parity"])
+        .assert()
+        .success();
+    let healed = check_json(working_path, backup_path, &["--read-packs", "--parity"])?;
+    assert_eq!(healed["ok"], true);
+    assert_eq!(healed["fixable_packs"], 0);
+
+    // Forgetting and pruning everything drops the parity along with the packs.
+    cli_run(working_path, backup_path)?
+        .args(["forget", "LAST"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .arg("prune")
+        .assert()
+        .success();
+    assert_eq!(
+        count_directory_entries(backup_path.join("parity")),
+        count_directory_entries(backup_path.join("packs"))
+    );
+
+    Ok(())
+}
+
+#[test]
+fn repair_needs_something_to_do() -> Result<()> {
+    let working_dir = tempdir()?;
+    let backup_dir = tempdir()?;
+    cli_run(working_dir.path(), backup_dir.path())?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_dir.path(), backup_dir.path())?
+        .arg("repair")
+        .assert()
+        .failure();
+    // Nothing to fix from parity if we never saved any.
+    cli_run(working_dir.path(), backup_dir.path())?
+        .args(["repair", "--from-parity"])
+        .assert()
+        .failure();
+    Ok(())
+}
+
+#[test]
+fn no_parity_for_filtered_repos() -> Result<()> {
+    let working_dir = tempdir()?;
+    let backup_dir = tempdir()?;
+    cli_run(working_dir.path(), backup_dir.path())?
+        .args(["init", "--parity", "--gpg", "someone", "filesystem"])
+        .assert()
+        .failure();
+
+    // Don't let someone sneak a filter into the config either.
+    cli_run(working_dir.path(), backup_dir.path())?
+        .args(["init", "--parity", "filesystem"])
+        .assert()
+        .success();
+    let config_path = backup_dir.path().join("config.toml");
+    let mut config: toml::Table = toml::from_str(&fs::read_to_string(&config_path)?)?;
+    config.insert("filter".to_owned(), "cat".into());
+    config.insert("unfilter".to_owned(), "cat".into());
+    fs::write(&config_path, toml::to_string(&config)?)?;
+    cli_run(working_dir.path(), backup_dir.path())?
+        .arg("check")
+        .assert()
+        .failure();
+    Ok(())
+}
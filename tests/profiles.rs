@@ -0,0 +1,95 @@
+use std::fs;
+
+use anyhow::Result;
+use assert_cmd::Command;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+#[test]
+fn run_profile() -> Result<()> {
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let data_dir = tempdir()?;
+    let data_path = data_dir.path().canonicalize()?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+
+    let config_path = working_path.join("backpak.toml");
+    fs::write(
+        &config_path,
+        format!(
+            r#"
+[profile.stuff]
+repository = "{repo}"
+paths = ["{data}"]
+skips = ["ignore-me$"]
+tags = ["profiled"]
+author = "Profile McProfileface"
+prune = true
+
+[profile.stuff.retention]
+keep_last = 1
+"#,
+            repo = backup_path.display(),
+            data = data_path.display(),
+        ),
+    )?;
+    let run_profile = || -> Result<Command> {
+        let mut cmd = Command::cargo_bin(env!("CARGO_PKG_NAME"))?;
+        cmd.arg("-C").arg(working_path);
+        cmd.arg("--config").arg(&config_path);
+        cmd.args(["run", "stuff"]);
+        Ok(cmd)
+    };
+
+    fs::write(data_path.join("one"), "one")?;
+    fs::write(data_path.join("ignore-me"), "nope")?;
+    run_profile()?.assert().success();
+
+    fs::write(data_path.join("two"), "two")?;
+    run_profile()?.assert().success();
+
+    // keep_last = 1 forgot the first snapshot, and we pruned its data.
+    let snaps = cli_run(working_path, backup_path)?
+        .args(["snapshots"])
+        .assert()
+        .success();
+    let snaps = stdout(&snaps);
+    assert_eq!(snaps.matches("snapshot ").count(), 1);
+    assert!(snaps.contains("Author: Profile McProfileface"));
+    assert!(snaps.contains("profiled"));
+
+    let ls = cli_run(working_path, backup_path)?
+        .args(["ls", "LAST"])
+        .assert()
+        .success();
+    let name = data_path.file_name().unwrap().to_str().unwrap();
+    assert_eq!(
+        stdout(&ls).lines().collect::<Vec<_>>(),
+        [
+            format!("{name}/"),
+            format!("{name}/one"),
+            format!("{name}/two")
+        ]
+    );
+
+    // Unknown profiles are an error.
+    Command::cargo_bin(env!("CARGO_PKG_NAME"))?
+        .arg("--config")
+        .arg(&config_path)
+        .args(["run", "nope"])
+        .assert()
+        .failure();
+
+    Ok(())
+}
@@ -58,7 +58,7 @@ fn backup_src() -> Result<()> {
     // can be pruned - we still need the chunks for `tests/references`
     // but not `tests/*.rs`.
     cli_run(working_path, backup_path)?
-        .args(&["forget", first_snapshot])
+        .args(["forget", first_snapshot])
         .assert()
         .success();
 
@@ -68,7 +68,7 @@ fn backup_src() -> Result<()> {
 
     // Dry run shouldn't do anything!
     cli_run(working_path, backup_path)?
-        .args(&["prune", "-n"])
+        .args(["prune", "-n"])
         .assert()
         .success()
         .stdout(
@@ -102,7 +102,7 @@ fn backup_src() -> Result<()> {
     assert_ne!(before_packs, after_packs);
 
     cli_run(working_path, backup_path)?
-        .args(&["check", "--read-packs"])
+        .args(["check", "--read-packs"])
         .assert()
         .success();
 
@@ -155,7 +155,7 @@ fn no_repacks_needed() -> Result<()> {
     // Axe the first backup. This will create a situation where the pack(s)
     // can be pruned AND we don't need to repack anything - just make a new index.
     cli_run(working_path, backup_path)?
-        .args(&["forget", first_snapshot])
+        .args(["forget", first_snapshot])
         .assert()
         .success();
 
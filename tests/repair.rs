@@ -0,0 +1,168 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::Result;
+use serde_json::Value;
+use tempfile::tempdir;
+
+mod common;
+
+use common::*;
+
+fn repair(working_path: &Path, backup_path: &Path, mode: &[&str]) -> Result<assert_cmd::Command> {
+    let mut cmd = cli_run(working_path, backup_path)?;
+    cmd.arg("repair").args(mode);
+    Ok(cmd)
+}
+
+#[test]
+fn repair_damaged_repository() -> Result<()> {
+    let project_dir = std::env::current_dir()?;
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+
+    let data_path = working_path.join("data");
+    fs::create_dir_all(data_path.join("sub"))?;
+    fs::copy(
+        project_dir.join("tests/references/sr71.txt"),
+        data_path.join("sub/sr71.txt"),
+    )?;
+    fs::write(data_path.join("hello.txt"), "Hello, world!\n")?;
+
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&data_path)
+        .assert()
+        .success();
+    let packs = count_directory_entries(backup_path.join("packs"));
+
+    // Scribble over the files' contents.
+    let chunk_packs = packs_with(working_path, backup_path, "chunk")?;
+    for pack in &chunk_packs {
+        let pack_path = backup_path.join("packs").join(pack);
+        let mut contents = fs::read(&pack_path)?;
+        let middle = contents.len() / 2;
+        contents[middle] ^= 0xff;
+        fs::write(&pack_path, contents)?;
+    }
+
+    // Index everything but the broken packs...
+    repair(working_path, backup_path, &["index"])?
+        .assert()
+        .success();
+    let damaged = check_json(working_path, backup_path, &["--deep"])?;
+    assert_eq!(damaged["ok"], false);
+    assert_eq!(damaged["unreachable_packs"], chunk_packs.len());
+    assert_eq!(damaged["missing_chunks"], 2);
+
+    // ...then throw them out...
+    repair(working_path, backup_path, &["unreachable-packs"])?
+        .assert()
+        .success();
+    assert_eq!(
+        count_directory_entries(backup_path.join("packs")),
+        packs - chunk_packs.len()
+    );
+
+    // ...and back up what they had from the files that are still here.
+    repair(working_path, backup_path, &["chunks"])?
+        .assert()
+        .success();
+    let repaired = check_json(working_path, backup_path, &["--deep"])?;
+    assert_eq!(repaired["ok"], true);
+
+    // If a file has changed since, we can't get its old chunks back.
+    for pack in packs_with(working_path, backup_path, "chunk")? {
+        fs::remove_file(backup_path.join("packs").join(pack))?;
+    }
+    repair(working_path, backup_path, &["index"])?
+        .assert()
+        .success();
+    fs::write(data_path.join("sub/sr71.txt"), "Gone, reduced to atoms\n")?;
+    repair(working_path, backup_path, &["chunks"])?
+        .assert()
+        .failure();
+    let damaged = check_json(working_path, backup_path, &["--deep"])?;
+    assert_eq!(damaged["missing_chunks"], 1);
+    assert_eq!(damaged["damaged_paths"][0]["path"], "data/sub/sr71.txt");
+
+    // So cut it out of the snapshot.
+    repair(working_path, backup_path, &["-n", "snapshots", "--forget"])?
+        .assert()
+        .success();
+    let damaged = check_json(working_path, backup_path, &["--deep"])?;
+    assert_eq!(damaged["missing_chunks"], 1);
+
+    repair(working_path, backup_path, &["snapshots", "--forget"])?
+        .assert()
+        .success();
+    let repaired = check_json(working_path, backup_path, &["--deep"])?;
+    assert_eq!(repaired["ok"], true);
+
+    let snaps = cli_run(working_path, backup_path)?
+        .args(["--json", "snapshots"])
+        .assert()
+        .success();
+    let snaps: Value = serde_json::from_str(stdout(&snaps))?;
+    let snaps = snaps.as_array().unwrap();
+    assert_eq!(snaps.len(), 1);
+    assert_eq!(snaps[0]["tags"], serde_json::json!(["repaired"]));
+
+    let restore_dir = tempdir()?;
+    cli_run(working_path, backup_path)?
+        .args(["restore", "LAST", "--output"])
+        .arg(restore_dir.path())
+        .assert()
+        .success();
+    let restored = restore_dir.path();
+    assert_eq!(
+        fs::read_to_string(restored.join("hello.txt"))?,
+        "Hello, world!\n"
+    );
+    assert_eq!(fs::read(restored.join("sub/sr71.txt"))?, b"");
+
+    Ok(())
+}
+
+#[test]
+fn keep_intact_unreachable_packs() -> Result<()> {
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(std::env::current_dir()?.join("tests/references"))
+        .assert()
+        .success();
+    let packs = count_directory_entries(backup_path.join("packs"));
+
+    // Lose the index, as if `repair index` couldn't read any of the packs.
+    for index in dir_entries(backup_path.join("indexes")) {
+        fs::remove_file(index)?;
+    }
+    // They're all fine, so they should stay put...
+    repair(working_path, backup_path, &["unreachable-packs"])?
+        .assert()
+        .success();
+    assert_eq!(count_directory_entries(backup_path.join("packs")), packs);
+
+    // ...for us to index again.
+    repair(working_path, backup_path, &["index"])?
+        .assert()
+        .success();
+    let repaired = check_json(working_path, backup_path, &["--deep"])?;
+    assert_eq!(repaired["ok"], true);
+    Ok(())
+}
@@ -5,6 +5,7 @@ use std::{
 };
 
 use anyhow::Result;
+use predicates::prelude::*;
 use tempfile::tempdir;
 
 mod common;
@@ -27,7 +28,7 @@ fn restore_src() -> Result<()> {
     // Let's make a copy of src so we don't fudge the actual code
     assert!(
         Command::new("cp")
-            .args(&["-r", "src"])
+            .args(["-r", "src"])
             .arg(working_path)
             .status()?
             .success()
@@ -43,7 +44,7 @@ fn restore_src() -> Result<()> {
     let restoreit = |args: &[&str]| {
         let restore_run = cli_run(working_path, backup_path)
             .unwrap()
-            .args(&["restore", "--delete", "--times", "--permissions", "LAST"])
+            .args(["restore", "--delete", "--times", "--permissions", "LAST"])
             .args(args)
             .assert()
             .success();
@@ -198,7 +199,7 @@ fn restore_multipath() -> Result<()> {
     // Back up multiple things! And try files as the top-level objects to boot!
     assert!(
         Command::new("cp")
-            .args(&["README.md", "LICENSE.txt"])
+            .args(["README.md", "LICENSE.txt"])
             .arg(working_path)
             .status()?
             .success()
@@ -217,7 +218,7 @@ fn restore_multipath() -> Result<()> {
     let restoreit = |args: &[&str]| {
         let restore_run = cli_run(working_path, backup_path)
             .unwrap()
-            .args(&["restore", "--delete", "--times", "--permissions", "LAST"])
+            .args(["restore", "--delete", "--times", "--permissions", "LAST"])
             .args(args)
             .assert()
             .success();
@@ -275,3 +276,156 @@ fn restore_multipath() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn resume_interrupted_restore() -> Result<()> {
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    // A few megs of noise cut into a few chunks, each in its own (tiny) pack.
+    let data_path = working_path.join("data");
+    fs::create_dir(&data_path)?;
+    let mut state = 0x2545_f491_4f6c_dd1du64;
+    let noise: Vec<u8> = (0..4 * 1024 * 1024)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect();
+    fs::write(data_path.join("noise"), &noise)?;
+    fs::write(data_path.join("hello.txt"), "Hello, world!\n")?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "--pack-size", "1 B", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&data_path)
+        .assert()
+        .success();
+
+    // Lose the last pack we'd read so the restore fails partway through...
+    let mut chunk_packs = packs_with(working_path, backup_path, "chunk")?;
+    assert!(chunk_packs.len() > 2);
+    chunk_packs.sort();
+    let lost = backup_path.join("packs").join(chunk_packs.last().unwrap());
+    let stash = working_path.join("stashed.pack");
+    fs::rename(&lost, &stash)?;
+
+    let restore_dir = tempdir()?;
+    let restore_path = restore_dir.path();
+    let restore = |resume: bool| {
+        let mut cmd = cli_run(working_path, backup_path).unwrap();
+        cmd.arg("restore");
+        if resume {
+            cmd.arg("--resume");
+        }
+        cmd.arg("--output").arg(restore_path).arg("LAST");
+        cmd.assert()
+    };
+
+    // (Nothing to resume yet.)
+    restore(true).failure();
+    restore(false).failure();
+    assert!(working_path.join("backpak-restore.journal").exists());
+    let partials: Vec<_> = dir_entries(restore_path)
+        .filter(|p| p.to_string_lossy().ends_with(".backpak-part"))
+        .collect();
+    assert!(!partials.is_empty());
+    // Resuming (and failing) again shouldn't leave the journal worse for wear.
+    restore(true)
+        .failure()
+        .stderr(predicate::str::contains("garbled").not());
+    restore(true)
+        .failure()
+        .stderr(predicate::str::contains("garbled").not());
+
+    // ...then find it and pick up where we left off.
+    fs::rename(&stash, &lost)?;
+    restore(true).success();
+    assert!(!working_path.join("backpak-restore.journal").exists());
+    assert_eq!(fs::read(restore_path.join("noise"))?, noise);
+    assert_eq!(
+        fs::read_to_string(restore_path.join("hello.txt"))?,
+        "Hello, world!\n"
+    );
+    assert!(dir_entries(restore_path).all(|p| !p.to_string_lossy().ends_with(".backpak-part")));
+
+    // Resuming a restore that finished doesn't make sense.
+    restore(true).failure();
+    Ok(())
+}
+
+#[test]
+fn verify_restored_files() -> Result<()> {
+    let project_dir = std::env::current_dir()?;
+
+    let working_dir = tempdir()?;
+    let working_path = working_dir.path();
+    let backup_dir = tempdir()?;
+    let backup_path = backup_dir.path();
+
+    let data_path = working_path.join("data");
+    fs::create_dir_all(data_path.join("sub"))?;
+    fs::copy(
+        project_dir.join("tests/references/sr71.txt"),
+        data_path.join("sub/sr71.txt"),
+    )?;
+    fs::write(data_path.join("hello.txt"), "Hello, world!\n")?;
+    unix::fs::symlink("sub/sr71.txt", data_path.join("link"))?;
+
+    cli_run(working_path, backup_path)?
+        .args(["init", "filesystem"])
+        .assert()
+        .success();
+    cli_run(working_path, backup_path)?
+        .arg("backup")
+        .arg(&data_path)
+        .assert()
+        .success();
+
+    let restore_dir = tempdir()?;
+    let restore_path = restore_dir.path();
+    cli_run(working_path, backup_path)?
+        .args(["restore", "--verify", "LAST", "--output"])
+        .arg(restore_path)
+        .assert()
+        .success();
+    let verify = || {
+        cli_run(working_path, backup_path)
+            .unwrap()
+            .args(["verify-restore", "LAST"])
+            .arg(restore_path)
+            .assert()
+    };
+    verify().success();
+
+    // Scribble on what we restored.
+    let mut sr71 = fs::read(restore_path.join("sub/sr71.txt"))?;
+    sr71[42] ^= 0xff;
+    fs::write(restore_path.join("sub/sr71.txt"), sr71)?;
+    fs::remove_file(restore_path.join("hello.txt"))?;
+    fs::remove_file(restore_path.join("link"))?;
+    unix::fs::symlink("elsewhere", restore_path.join("link"))?;
+
+    let failed = verify().failure();
+    let complaints = stderr(&failed);
+    assert!(complaints.contains("hello.txt: Missing"));
+    assert!(complaints.contains("link: Points to elsewhere, not sub/sr71.txt"));
+    assert!(complaints.contains("sr71.txt: Contents differ from the snapshot"));
+    assert!(complaints.contains("3 paths in"));
+
+    // A fresh restore puts it all back.
+    cli_run(working_path, backup_path)?
+        .args(["restore", "--verify", "LAST", "--output"])
+        .arg(restore_path)
+        .assert()
+        .success();
+    verify().success();
+    Ok(())
+}